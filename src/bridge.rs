@@ -16,82 +16,686 @@
 
 use irc;
 use matrix;
+use nicks;
+use channels;
+use links;
+use lru::LruCache;
+use util::write_private;
+use ssl;
+use config;
+use config::Config;
+use control;
+use control::ControlCommand;
 use irc::protocol::{Command,Message};
-use irc::streams::AsEvented;
+use irc::ctcp::Ctcp;
+use irc::dcc::{self, DccSend};
+use irc::streams::{SERVER_NAME, VERSION};
+use irc::streams::{AsEvented, Server};
 use mio;
 use mio::{EventLoop,Handler,Token,EventSet,PollOpt,Sender};
 use std::thread;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
 use std::io;
+use std::io::Read;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use openssl::crypto::{hash, pkcs5, rand};
+use openssl::ssl::SslContext;
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::Json;
 
 const CLIENT: Token = Token(0);
+/// How long a DCC transfer may stall before it is abandoned
+const DCC_TIMEOUT_SECS: u64 = 30;
+/// How long to wait before polling again after a poll failed
+const POLL_RETRY_MS: u64 = 5000;
+/// How long the listener pauses after failing to accept connections
+const ACCEPT_RETRY_MS: u64 = 1000;
+/// How much of a message is remembered for replies and pins
+const SNIPPET_LENGTH: usize = 100;
+/// How many rounds of PBKDF2 go into the name of a session file
+const SESSION_KDF_ROUNDS: usize = 20000;
+/// How many older messages `history` may fetch at once
+const HISTORY_LIMIT: usize = 100;
+/// How many pages `history` goes through looking for something that was said
+const HISTORY_PAGES: usize = 5;
+/// How many of the messages the user sent `redact` can go back to
+const SENT_MEMORY: usize = 10;
+/// How long the nicks in a NAMES line sent while members stream in get
+const NAMES_LENGTH: usize = 400;
 
 #[derive(Debug)]
 pub enum Event {
-    EndPoll,
-    Matrix(matrix::events::Event)
+    /// The end of a poll, with the token the next one carries on from
+    EndPoll(Option<String>),
+    /// A poll that failed, which ends polling until it's dealt with
+    PollFailed(matrix::client::ClientError),
+    Matrix(matrix::events::Event),
+    /// A file received over DCC, ready to be posted to a room
+    DccReceived(matrix::model::RoomID, String, Vec<u8>),
+    /// A DCC transfer that failed, with the file name and the reason
+    DccFailed(String, String),
+    /// The display name of a ghost, fetched in the background
+    DisplayName(matrix::model::UserID, Option<String>),
+    /// A ghost whose display name could not be fetched
+    DisplayNameFailed(matrix::model::UserID),
+    /// The name and topic of a room, fetched in the background since they
+    /// weren't in the sync
+    RoomTitle(matrix::model::RoomID, Option<String>, Option<String>),
+    /// An announcement an operator sent to every session with WALLOPS
+    Wallops(matrix::model::UserID, String)
+}
+
+/// What a session's event loop can be woken up for
+#[derive(Debug)]
+pub enum Timer {
+    /// Polling Matrix again after a failed poll
+    Poll,
+    /// Sending on what flood protection held back
+    Release,
+    /// Summing up the membership churn of rooms whose window is over
+    Churn
+}
+
+/// Every live session in the process, keyed by the token it was accepted
+/// with. The listener hands each accepted connection to a session of its
+/// own, which then runs its own event loop: a session mostly waits on its
+/// homeserver, and that shouldn't hold up the others. What one session has
+/// for another is routed by token through this table.
+pub type Sessions = Arc<Mutex<HashMap<Token, Session>>>;
+
+/// A live session, as the rest of the process sees it
+pub struct Session {
+    /// Wakes the session's event loop up with an event for it
+    channel: Sender<Event>
+}
+
+impl Session {
+    pub fn new(channel: Sender<Event>) -> Self {
+        Session {
+            channel: channel
+        }
+    }
+
+    /// Hands an event to the session, which fails once it's ending
+    pub fn send(&self, evt: Event) -> Result<(), mio::NotifyError<Event>> {
+        self.channel.send(evt)
+    }
+}
+
+/// State shared between all the sessions of the process
+#[derive(Clone)]
+pub struct Shared {
+    pub config: Config,
+    pub logins: nicks::SharedLogins,
+    pub sessions: Sessions,
+    /// What plaintext connections can upgrade to with STARTTLS, if TLS is
+    /// set up
+    pub tls: Option<Arc<SslContext>>,
+    /// Sessions whose IRC client went away, by the Matrix user they're
+    /// logged in as
+    detached: Arc<Mutex<HashMap<matrix::model::UserID, Detached>>>
+}
+
+impl Shared {
+    pub fn new(config: Config) -> Self {
+        Shared {
+            config: config,
+            logins: nicks::new_shared_logins(),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            tls: None,
+            detached: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+}
+
+/// The Matrix side of a session whose IRC client hung up, kept for a while
+/// so that a reconnect can carry on from where it was rather than sync
+/// everything again
+struct Detached {
+    matrix: matrix::client::Client,
+    rooms: HashMap<matrix::model::RoomID, Room>,
+    at: Instant
 }
 
 pub struct Bridge {
+    token: Token,
+    shared: Shared,
     client: irc::streams::Client,
     matrix: matrix::client::Client,
     rooms: HashMap<matrix::model::RoomID, Room>,
     seen_events: Vec<matrix::model::EventID>,
+    nicks: nicks::NickRegistry,
+    /// Whether the IRC client has been joined to the server notice channel
+    server_notices_joined: bool,
+    /// Who sent recent messages and how they started, so replies can name
+    /// who they're replying to and pins what was pinned without asking the
+    /// homeserver
+    recent_events: LruCache<matrix::model::EventID, (matrix::model::UserID, String)>,
+    /// Rooms we've left, whose events may still be in flight
+    left_rooms: HashSet<matrix::model::RoomID>,
+    /// Whether a poll is in flight. Only one may be, so that batches are
+    /// handled in the order the server sent them.
+    polling: bool,
+    /// Whether the IRC socket is registered for writable readiness, which
+    /// it is only while there is output queued
+    writable_interest: bool,
+    /// Where the Matrix session of this connection is stored, if it is
+    session_file: Option<PathBuf>,
+    /// Rooms we've been invited to and told the user about
+    invites: HashMap<matrix::model::RoomID, matrix::events::Invite>,
+    /// The room behind each channel, by lowercased channel name
+    channels: HashMap<String, matrix::model::RoomID>,
+    /// Whether a timer is set to send on what flood protection held back
+    release_scheduled: bool,
+    /// Whether a timer is set to sum up membership churn
+    churn_scheduled: bool,
+    /// Set when the session ends, so that a poll in flight gives up
+    cancel_poll: Arc<AtomicBool>,
+    /// Ghosts whose display names are to be fetched
+    profile_fetches: Vec<matrix::model::UserID>,
+    /// Rooms whose name and topic are to be fetched
+    title_fetches: Vec<matrix::model::RoomID>,
+    /// Whether the IRC client got through registration, having sent both
+    /// NICK and USER
+    registered: bool,
+    /// Rooms the user muted, kept next to the session file if there is one
+    muted: HashSet<matrix::model::RoomID>,
+    /// Whether messages start with the display name of their sender, as the
+    /// user asked with display-names
+    display_names: bool,
+    /// The last messages the user sent from this session, the latest last,
+    /// with the channel they went to and a snippet
+    sent: Vec<(matrix::model::RoomID, matrix::model::EventID, String, String)>,
+    /// The homeserver the user picked to log in to, None for the bridge's
+    /// own. Only the bridge's own vouches for who is an operator.
+    homeserver: Option<String>
 }
 
 impl Handler for Bridge {
-    type Timeout = ();
+    type Timeout = Timer;
     type Message = Event;
 
-    fn ready(&mut self, event_loop: &mut EventLoop<Bridge>, token: Token, _: EventSet) {
+    fn ready(&mut self, event_loop: &mut EventLoop<Bridge>, token: Token, events: EventSet) {
         match token {
-            CLIENT =>
-                self.handle_client(event_loop),
+            CLIENT => {
+                if events.is_writable() {
+                    if let Err(err) = self.client.flush() {
+                        warn!("Could not write to the IRC client: {}", err);
+                    }
+                }
+                if !events.is_writable() || events.is_readable() {
+                    self.handle_client(event_loop);
+                }
+            },
             _ => unreachable!("Got a really weird Token in the mio event loop!")
         }
+        self.rearm(event_loop);
     }
 
     fn notify(&mut self, event_loop: &mut EventLoop<Bridge>, msg: Self::Message) {
         match msg {
-            Event::EndPoll => {
+            Event::EndPoll(next_batch) => {
+                self.polling = false;
+                if let Some(token) = next_batch {
+                    self.matrix.set_since(token);
+                }
                 self.poll_matrix(event_loop.channel());
             },
+            Event::PollFailed(matrix::client::ClientError::SoftLogout) => {
+                self.polling = false;
+                self.soft_logout(event_loop.channel());
+            },
+            Event::PollFailed(matrix::client::ClientError::Unauthorized) => {
+                self.polling = false;
+                self.token_rejected();
+            },
+            Event::PollFailed(err) => {
+                self.polling = false;
+                warn!("Polling Matrix failed, retrying in {}ms: {}", POLL_RETRY_MS, err);
+                if let Err(err) = event_loop.timeout_ms(Timer::Poll, POLL_RETRY_MS) {
+                    warn!("Could not schedule another poll: {:?}", err);
+                }
+            },
             Event::Matrix(e) =>
                 match self.handle_matrix(e) {
                     Err(err) => warn!("Could not handle matrix event: {:?}", err),
                     _ => ()
+                },
+            Event::DccReceived(room_id, filename, bytes) => {
+                match self.matrix.send_file(&room_id, &bytes, dcc::content_type(filename.trim()), filename.trim()) {
+                    Ok(id) => {
+                        self.seen_events.push(id);
+                        let reply = format!("Sent {} ({} bytes)", filename, bytes.len());
+                        self.client.notice(SERVER_NAME, reply.trim()).expect("Could not send NOTICE");
+                    },
+                    Err(err) => self.notify_error(format!("Could not send {}: {}", filename, err).trim())
+                }
+            },
+            Event::DccFailed(filename, reason) =>
+                self.notify_error(format!("Could not receive {}: {}", filename, reason).trim()),
+            Event::DisplayName(user, name) =>
+                self.nicks.set_display_name(&user, name),
+            Event::DisplayNameFailed(user) =>
+                self.nicks.display_name_failed(&user),
+            Event::RoomTitle(id, name, topic) =>
+                self.fetched_title(&id, name, topic),
+            Event::Wallops(operator, text) => {
+                let announcement = format!("Announcement from {}: {}", operator, text);
+                if let Err(err) = self.client.notice(SERVER_NAME, announcement.trim()) {
+                    warn!("Could not send an announcement: {}", err);
                 }
+            }
         };
+        self.rearm(event_loop);
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<Bridge>, timer: Timer) {
+        match timer {
+            Timer::Poll => self.poll_matrix(event_loop.channel()),
+            Timer::Release => {
+                self.release_scheduled = false;
+                if let Err(err) = self.client.release() {
+                    warn!("Could not write to the IRC client: {}", err);
+                }
+                self.rearm(event_loop);
+            },
+            Timer::Churn => {
+                self.churn_scheduled = false;
+                let now = now_ms();
+                let mut messages = vec![];
+                for room in self.rooms.values_mut() {
+                    room.summarize_churn(now, &mut |msg| messages.push(msg));
+                }
+                for msg in messages {
+                    let msg = self.with_realname(msg);
+                    if let Err(err) = self.client.send(&msg) {
+                        warn!("Could not write to the IRC client: {}", err);
+                    }
+                }
+                self.rearm(event_loop);
+            }
+        }
     }
 }
 
 unsafe impl Sync for Bridge{}
 
+/// Accepts IRC connections, giving each one its own bridge session running
+/// in a thread of its own. Each server is registered with its index in
+/// `servers` as its token.
+struct Listener {
+    servers: Vec<Box<Server>>,
+    url: String,
+    shared: Shared,
+    next_session: usize
+}
+
+impl Handler for Listener {
+    type Timeout = Token;
+    type Message = ();
+
+    fn ready(&mut self, event_loop: &mut EventLoop<Listener>, token: Token, _: EventSet) {
+        let Token(idx) = token;
+        if idx >= self.servers.len() {
+            unreachable!("Got a really weird token in the server event loop!");
+        }
+        loop {
+            match self.servers[idx].accept() {
+                Ok(Some(client)) => {
+                    self.next_session += 1;
+                    let session = Token(self.next_session);
+                    let mut bridge = Bridge::new(client, self.url.trim(), session, self.shared.clone());
+                    thread::spawn(move||{
+                        bridge.run()
+                    });
+                },
+                Ok(None) => break,
+                // Only that one connection is lost
+                Err(ref err) if err.kind() == io::ErrorKind::ConnectionAborted =>
+                    warn!("Could not accept an IRC connection: {}", err),
+                // Out of file descriptors or the like. The listener is
+                // level-triggered, so it would only fail again right away:
+                // stop listening for a while instead
+                Err(err) => {
+                    warn!("Could not accept IRC connections, pausing for {}ms: {}", ACCEPT_RETRY_MS, err);
+                    if let Err(err) = event_loop.deregister(self.servers[idx].as_evented()) {
+                        warn!("Could not pause the listener: {}", err);
+                    }
+                    if let Err(err) = event_loop.timeout_ms(token, ACCEPT_RETRY_MS) {
+                        warn!("Could not schedule listening again: {:?}", err);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Listens again on a server that was paused after failing to accept
+    fn timeout(&mut self, event_loop: &mut EventLoop<Listener>, token: Token) {
+        let Token(idx) = token;
+        if let Err(err) = event_loop.register(self.servers[idx].as_evented(), token, EventSet::readable(), PollOpt::level()) {
+            warn!("Could not listen again: {}", err);
+        }
+    }
+}
+
+/// A channel topic without the mark `Room::title` puts at the end of those
+/// of encrypted rooms
+fn strip_encrypted_mark(topic: &str) -> &str {
+    if topic == "(encrypted)" {
+        ""
+    } else if topic.ends_with(" (encrypted)") {
+        &topic[..topic.len() - " (encrypted)".len()]
+    } else {
+        topic
+    }
+}
+
+fn is_loopback(addr: &SocketAddr) -> bool {
+    match addr {
+        &SocketAddr::V4(ref a) => a.ip().is_loopback(),
+        &SocketAddr::V6(ref a) => a.ip().is_loopback()
+    }
+}
+
 struct Room {
     id: matrix::model::RoomID,
     irc_name: Option<String>,
-    canonical_alias: Option<String>,
+    canonical_alias: Option<matrix::model::RoomAlias>,
     join_rules: Option<String>,
     members: Vec<matrix::model::UserID>,
-    aliases: Vec<String>,
-    pending_events: Vec<matrix::events::RoomEvent>,
-    pending_sync: bool
+    aliases: Vec<matrix::model::RoomAlias>,
+    pending_events: Vec<(matrix::events::RoomEvent, Option<u64>)>,
+    pending_sync: bool,
+    /// Set once an encrypted event has been seen, which we can't bridge
+    encrypted: bool,
+    /// The algorithm of the room's end-to-end encryption, if its state says
+    /// it has some
+    encryption: Option<String>,
+    /// Transactions we've sent whose echo we haven't seen yet
+    pending_txns: HashSet<String>,
+    /// Whether `members` is complete. Syncs lazy-load members, so until the
+    /// full list is fetched it only has those who showed up in the timeline.
+    members_loaded: bool,
+    /// The room that replaced this one, once it has been upgraded. The
+    /// channel stays around but is closed.
+    replacement: Option<matrix::model::RoomID>,
+    /// Whether guests may join, if the room says
+    guest_access: Option<bool>,
+    server_acl: Option<matrix::events::ServerAcl>,
+    /// The events pinned in the room, once its pins are known
+    pinned: Option<Vec<matrix::model::EventID>>,
+    power_levels: Option<matrix::events::PowerLevels>,
+    churn: Churn,
+    /// Our own nick, which NAMES replies are addressed to, once the room is
+    /// open
+    my_nick: Option<String>,
+    /// The room's name and topic, None inside when it has none and None
+    /// altogether until that is known
+    name: Option<Option<String>>,
+    topic: Option<Option<String>>,
+    /// Whether events of unknown types are shown, as `show_custom_events`
+    /// says
+    show_custom: bool,
+    /// Pending invites by email or phone, their display names by token
+    third_party_invites: HashMap<String, String>,
+    /// Where paging back through the room's history goes on from. None
+    /// until a sync says, and Some(None) once the start of the room is
+    /// reached.
+    prev_batch: Option<Option<String>>,
+    /// Users banned from the room
+    banned: Vec<matrix::model::UserID>,
+    /// Whether the room's messages are left out, as the user asked with
+    /// mute. Its state and members are still followed.
+    muted: bool,
+    /// Who may see the room's history, as in m.room.history_visibility
+    history_visibility: Option<String>,
+    /// When we last joined the room, if that was seen
+    joined_at: Option<u64>
+}
+
+/// Joins and parts in a room over a short window. Past a threshold, they're
+/// summed up in one notice rather than shown one by one.
+struct Churn {
+    /// How long a window lasts
+    window: Duration,
+    /// How many joins and parts a window shows on their own, 0 for all
+    threshold: usize,
+    /// When the current window started, as a Matrix timestamp. Windows go
+    /// by when events happened, so that replayed history isn't taken for
+    /// a burst.
+    start: Option<u64>,
+    /// How many have been shown in the current window
+    shown: usize,
+    /// How many were held back to be summed up
+    joined: usize,
+    left: usize
+}
+
+impl Churn {
+    fn new(window: Duration, threshold: usize) -> Self {
+        Churn {
+            window: window,
+            threshold: threshold,
+            start: None,
+            shown: 0,
+            joined: 0,
+            left: 0
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        self.joined + self.left > 0
+    }
+
+    /// How long until the current window is over
+    fn remaining(&self, now: u64) -> Duration {
+        let elapsed = match self.start {
+            Some(start) => Duration::from_millis(now.saturating_sub(start)),
+            None => return Duration::from_secs(0)
+        };
+        if elapsed < self.window {
+            self.window - elapsed
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+/// Sums up the joins and parts of a window, as in "12 users joined, 3 left"
+fn churn_summary(joined: usize, left: usize) -> String {
+    let users = |n: usize| if n == 1 { "1 user".to_string() } else { format!("{} users", n) };
+    match (joined, left) {
+        (joined, 0) => format!("{} joined", users(joined)),
+        (0, left) => format!("{} left", users(left)),
+        (joined, left) => format!("{} joined, {} left", users(joined), left)
+    }
+}
+
+/// Shows an event of a type we don't know: its type, then whatever text it
+/// carries
+fn custom_text(kind: &str, body: &str) -> String {
+    if body.is_empty() {
+        format!("[{}]", kind)
+    } else {
+        format!("[{}] {}", kind, body)
+    }
+}
+
+/// The time now as a Matrix timestamp, in milliseconds since the epoch
+fn now_ms() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    now.as_secs() * 1000 + now.subsec_nanos() as u64 / 1000000
+}
+
+/// Tags an IRC message with the Matrix timestamp it originated at
+fn time_tags(timestamp: Option<u64>) -> Vec<(String, String)> {
+    match timestamp {
+        Some(ts) => vec![("time".to_string(), irc::protocol::server_time(ts))],
+        None => vec![]
+    }
+}
+
+/// A Matrix user joining a channel. It carries the account and realname of
+/// extended-join, which the client drops unless that was negotiated.
+fn join_message(user: &matrix::model::UserID, channel: String) -> irc::protocol::Message {
+    irc::protocol::Message {
+        tags: vec![],
+        prefix: Some(format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver)),
+        command: irc::protocol::Command::Join,
+        args: vec![channel, format!("{}", user)],
+        suffix: Some(user.nickname.clone())
+    }
+}
+
+/// RPL_NAMREPLY (353), listing members of a channel
+fn names_message(my_nick: &str, channel: &str, names: &[String]) -> irc::protocol::Message {
+    irc::protocol::Message {
+        tags: vec![],
+        prefix: Some(SERVER_NAME.to_string()),
+        command: irc::protocol::Command::Numeric(353),
+        args: vec![my_nick.to_string(), "@".to_string(), channel.to_string()],
+        suffix: Some(names.join(" "))
+    }
+}
+
+/// RPL_ENDOFNAMES (366), which ends a NAMES reply
+fn end_of_names(my_nick: &str, channel: &str) -> irc::protocol::Message {
+    irc::protocol::Message {
+        tags: vec![],
+        prefix: Some(SERVER_NAME.to_string()),
+        command: irc::protocol::Command::Numeric(366),
+        args: vec![my_nick.to_string(), channel.to_string()],
+        suffix: Some("End of /NAMES list".to_string())
+    }
+}
+
+/// The channel that joining accepts an invite, named the way the room would
+/// be once joined
+fn invite_channel(invite: &matrix::events::Invite, config: &Config) -> String {
+    match invite.alias {
+        Some(ref alias) => alias.to_irc_channel(config),
+        None => channels::sanitize(format!("{}", invite.room).trim(), config)
+    }
+}
+
+/// Merges the buffered events of several rooms into one timeline ordered by
+/// origin_server_ts. An event without a timestamp stays right after the
+/// event that preceded it in its own room.
+fn chronological(rooms: Vec<(matrix::model::RoomID, Vec<(matrix::events::RoomEvent, Option<u64>)>)>)
+        -> Vec<(matrix::model::RoomID, matrix::events::RoomEvent, Option<u64>)> {
+    let mut merged = vec![];
+    for (id, events) in rooms {
+        let mut last_ts = 0;
+        for (evt, timestamp) in events {
+            last_ts = timestamp.unwrap_or(last_ts);
+            merged.push((last_ts, id.clone(), evt, timestamp));
+        }
+    }
+    // sort_by is stable, so each room's own order survives ties
+    merged.sort_by(|a, b| a.0.cmp(&b.0));
+    merged.into_iter().map(|(_, id, evt, timestamp)| (id, evt, timestamp)).collect()
+}
+
+/// The start of a message, cut at a character boundary
+fn snippet(text: &str) -> String {
+    match text.char_indices().nth(SNIPPET_LENGTH) {
+        Some((idx, _)) => format!("{}...", &text[..idx]),
+        None => text.to_string()
+    }
+}
+
+/// Whether the "@room" at the given byte offset of a message is a mention
+/// of the whole room, rather than part of an address or a user ID such as
+/// @room:example.org
+fn is_room_mention(text: &str, at: usize) -> bool {
+    let word = |c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '@' || c == '.';
+    let before = text[..at].chars().next_back();
+    let mut after = text[at + "@room".len()..].chars();
+    if before.map(&word).unwrap_or(false) {
+        return false;
+    }
+    match after.next() {
+        None => true,
+        // Trailing punctuation, unless it goes on into a server name
+        Some(':') | Some('.') => after.next().map(|c| c.is_whitespace()).unwrap_or(true),
+        Some(c) => !word(c)
+    }
+}
+
+/// Addresses a message mentioning @room to the IRC user, so that their
+/// client highlights it. The mention becomes @all, so it doesn't read as
+/// one of a user nicked room. None if the message doesn't mention @room.
+fn room_mention(my_nick: &str, text: &str) -> Option<String> {
+    let mentions: Vec<usize> = text.match_indices("@room").map(|(at, _)| at)
+        .filter(|&at| is_room_mention(text, at)).collect();
+    if mentions.is_empty() {
+        return None;
+    }
+    let mut rendered = String::new();
+    let mut last = 0;
+    for at in mentions {
+        rendered.push_str(&text[last..at]);
+        rendered.push_str("@all");
+        last = at + "@room".len();
+    }
+    rendered.push_str(&text[last..]);
+    Some(format!("{}: {}", my_nick, rendered))
+}
+
+/// Puts nicks in place of the text pills show in a message, each taken in
+/// turn after the one before, as they come in the same order in the body
+fn replace_pills(text: &str, pills: &[(String, String)]) -> String {
+    let mut rendered = String::new();
+    let mut rest = text;
+    for &(ref nick, ref shown) in pills {
+        if shown.is_empty() {
+            continue;
+        }
+        if let Some(at) = rest.find(shown.as_str()) {
+            rendered.push_str(&rest[..at]);
+            rendered.push_str(nick.trim());
+            rest = &rest[at + shown.len()..];
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Explains to the IRC user why joining a room failed
+fn join_failure(room: &str, err: &matrix::client::ClientError) -> String {
+    match err.errcode() {
+        Some("M_GUEST_ACCESS_FORBIDDEN") =>
+            format!("Could not join {}: guests aren't allowed in. Log in with a full Matrix account to join it.", room),
+        _ => format!("Could not join {}: {}", room, err)
+    }
 }
 
 impl Room {
-    fn handle_part<F>(&mut self, user: matrix::model::UserID, mut callback: &mut F)
+    fn handle_part<F>(&mut self, user: matrix::model::UserID, timestamp: Option<u64>, mut callback: &mut F)
             where F: FnMut(irc::protocol::Message) {
 
-        if self.irc_name != None && self.members.contains(&user) {
+        if self.irc_name != None && self.members.contains(&user) && self.count_churn(false, timestamp, &mut callback) {
             callback(irc::protocol::Message {
+                tags: vec![],
                 prefix: Some(format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver)),
                 command: irc::protocol::Command::Part,
                 args: vec![self.irc_name.clone().unwrap()],
                 suffix: None
             });
         }
+        self.remove_member(&user);
+    }
 
-        match self.members.iter().position(|u| u == &user) {
+    fn remove_member(&mut self, user: &matrix::model::UserID) {
+        match self.members.iter().position(|u| u == user) {
             Some(idx) => {
                 self.members.remove(idx);
             },
@@ -99,17 +703,18 @@ impl Room {
         }
     }
 
-    fn handle_join<F>(&mut self, user: matrix::model::UserID, mut callback: &mut F)
+    fn handle_join<F>(&mut self, user: matrix::model::UserID, timestamp: Option<u64>, mut callback: &mut F)
             where F: FnMut(irc::protocol::Message) {
-        if self.irc_name != None && !self.members.contains(&user) {
-            callback(irc::protocol::Message {
-                prefix: Some(format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver)),
-                command: irc::protocol::Command::Join,
-                args: vec![self.irc_name.clone().unwrap()],
-                suffix: None
-            });
+        if self.is_denied(&user) {
+            warn!("Not showing {:?} in {}, their server is denied by the room's ACL", user, self.id);
+            return;
+        }
+        if self.irc_name != None && !self.members.contains(&user) && self.count_churn(true, timestamp, &mut callback) {
+            callback(join_message(&user, self.irc_name.clone().unwrap()));
+        }
+        if !self.members.contains(&user) {
+            self.members.push(user);
         }
-        self.members.push(user);
     }
 
     fn new(id: matrix::model::RoomID) -> Self {
@@ -121,114 +726,446 @@ impl Room {
             pending_events: vec![],
             aliases: vec![],
             pending_sync: true,
-            irc_name: None
+            irc_name: None,
+            encrypted: false,
+            encryption: None,
+            pending_txns: HashSet::new(),
+            members_loaded: false,
+            replacement: None,
+            guest_access: None,
+            server_acl: None,
+            pinned: None,
+            power_levels: None,
+            churn: Churn::new(Duration::from_secs(0), 0),
+            my_nick: None,
+            name: None,
+            topic: None,
+            show_custom: false,
+            third_party_invites: HashMap::new(),
+            prev_batch: None,
+            banned: vec![],
+            muted: false,
+            history_visibility: None,
+            joined_at: None
         }
     }
 
-    fn run_pending<F>(&mut self, mut callback: &mut F)
+    /// What to show as the channel's topic: the room's topic, or else its
+    /// name. Encrypted rooms say so at the end.
+    fn title(&self) -> Option<String> {
+        let title = match (&self.topic, &self.name) {
+            (&Some(Some(ref topic)), _) => Some(topic.clone()),
+            (_, &Some(Some(ref name))) => Some(name.clone()),
+            _ => None
+        };
+        match (title, self.is_encrypted()) {
+            (Some(title), true) => Some(format!("{} (encrypted)", title)),
+            (None, true) => Some("(encrypted)".to_string()),
+            (title, false) => title
+        }
+    }
+
+    /// Whether the room is end-to-end encrypted, as its state says or as
+    /// its events show
+    fn is_encrypted(&self) -> bool {
+        self.encrypted || self.encryption.is_some()
+    }
+
+    /// Counts a join or part towards the room's churn, returning whether it
+    /// should still be shown on its own
+    fn count_churn<F>(&mut self, joined: bool, timestamp: Option<u64>, mut callback: &mut F) -> bool
             where F: FnMut(irc::protocol::Message) {
-        assert!(self.pending_sync);
-        while let Some(evt) = self.pending_events.pop() {
-            self.handle_with_alias(evt, callback);
+        if self.churn.threshold == 0 || self.irc_name == None {
+            return true;
+        }
+        let now = timestamp.unwrap_or(now_ms());
+        if self.churn.remaining(now) == Duration::from_secs(0) {
+            self.summarize_churn(now, &mut callback);
+            self.churn.start = Some(now);
+            self.churn.shown = 0;
         }
+        if self.churn.shown < self.churn.threshold {
+            self.churn.shown += 1;
+            return true;
+        }
+        if joined {
+            self.churn.joined += 1;
+        } else {
+            self.churn.left += 1;
+        }
+        false
     }
 
-    pub fn finish_sync<F>(&mut self, my_uid: &matrix::model::UserID, mut callback: &mut F)
+    /// Sums up what was held back once its window is over. The joins and
+    /// parts that weren't shown leave the client's idea of who is in the
+    /// channel behind, so the member list is sent again after.
+    fn summarize_churn<F>(&mut self, now: u64, mut callback: &mut F)
             where F: FnMut(irc::protocol::Message) {
-        for a in &self.aliases {
-            if a.ends_with(format!(":{}", my_uid.homeserver).trim()) {
-                self.irc_name = Some(a.clone());
-                break;
-            }
+        if !self.churn.is_pending() || self.churn.remaining(now) > Duration::from_secs(0) {
+            return;
         }
-        if self.irc_name == None {
-            self.irc_name = match self.canonical_alias {
-                None => {
-                    if self.aliases.len() == 0 {
-                        Some(format!("#{}:{}", self.id.id, self.id.homeserver))
-                    } else {
-                        Some(self.aliases[0].clone())
-                    }
-                },
-                Some(ref a) => Some(a.clone())
+        if let Some(ref channel) = self.irc_name {
+            callback(irc::protocol::Message {
+                tags: vec![],
+                prefix: Some(SERVER_NAME.to_string()),
+                command: irc::protocol::Command::Notice,
+                args: vec![channel.clone()],
+                suffix: Some(churn_summary(self.churn.joined, self.churn.left))
+            });
+        }
+        if let Some(nick) = self.my_nick.clone() {
+            self.names(nick.trim(), callback);
+        }
+        self.churn.joined = 0;
+        self.churn.left = 0;
+    }
+
+    /// How long until held back churn is due to be summed up, if any is
+    fn churn_due(&self, now: u64) -> Option<Duration> {
+        if self.churn.is_pending() {
+            Some(self.churn.remaining(now))
+        } else {
+            None
+        }
+    }
+
+    /// Records the room's pins, returning those that weren't pinned before.
+    /// The first set seen is what was already pinned when we got here, so
+    /// none of it is new.
+    fn update_pins(&mut self, pinned: &Vec<matrix::model::EventID>) -> Vec<matrix::model::EventID> {
+        let new_pins = match self.pinned {
+            Some(ref known) => pinned.iter().filter(|id| !known.contains(id)).cloned().collect(),
+            None => vec![]
+        };
+        self.pinned = Some(pinned.clone());
+        new_pins
+    }
+
+    /// Whether the room's server ACL keeps the user's homeserver out
+    fn is_denied(&self, user: &matrix::model::UserID) -> bool {
+        match self.server_acl {
+            Some(ref acl) => !acl.allows(user.homeserver.trim()),
+            None => false
+        }
+    }
+
+    /// Replaces the lazily-loaded member list with the full one
+    fn load_members(&mut self, members: Vec<matrix::model::UserID>) {
+        self.members = members;
+        self.members_loaded = true;
+    }
+
+    /// The channel modes that describe this room: +i if it is invite only
+    /// and +R if guests are kept out, as only registered users may join.
+    fn modes(&self) -> String {
+        let mut modes = "+".to_string();
+        if self.join_rules == Some("invite".to_string()) {
+            modes.push('i');
+        }
+        if self.guest_access == Some(false) {
+            modes.push('R');
+        }
+        // Not quite +z as some servers mean it, but as close as IRC gets
+        if self.is_encrypted() {
+            modes.push('z');
+        }
+        modes
+    }
+
+    /// Since when the room lets us see its history, if only since we
+    /// joined and we know when that was. Paging back past it would only be
+    /// refused.
+    fn history_start(&self) -> Option<u64> {
+        match self.history_visibility.as_ref().map(|v| v.trim()) {
+            // Invites and joins come close together, the join is where the
+            // sync leaves us
+            Some("joined") | Some("invited") => self.joined_at,
+            _ => None
+        }
+    }
+
+    /// The banned user a ban mask of this room stands for. Masks are
+    /// nick!user@homeserver as ghosts go by, any part of which may be *, or
+    /// a bare nick or Matrix user ID.
+    fn banned_user(&self, mask: &str) -> Option<matrix::model::UserID> {
+        if let Ok(user) = mask.parse::<matrix::model::UserID>() {
+            return if self.banned.contains(&user) { Some(user) } else { None };
+        }
+        let nick = mask.split(|c: char| c == '!' || c == '@').next().unwrap_or("");
+        let host = mask.splitn(2, '@').nth(1).unwrap_or("*");
+        self.banned.iter().find(|user| {
+            nicks::irc_lower(&user.nickname) == nicks::irc_lower(nick) && (host == "*" || host == user.homeserver)
+        }).cloned()
+    }
+
+    /// Adds to the aliases the room goes by. The legacy aliases event and
+    /// the alternatives of the canonical alias each name only some of them.
+    fn add_aliases(&mut self, aliases: Vec<matrix::model::RoomAlias>) {
+        for alias in aliases {
+            if !self.aliases.contains(&alias) {
+                self.aliases.push(alias);
             }
         }
-        callback(irc::protocol::Message {
-            prefix: Some(format!("{}!{}@{}", my_uid.nickname, my_uid.nickname, my_uid.homeserver)),
-            command: irc::protocol::Command::Join,
-            args: vec![self.irc_name.clone().unwrap()],
-            suffix: None
-        });
-        let mut usernames: Vec<String> = vec![];
-        for u in &self.members {
-            usernames.push(format!("{}", u.nickname));
+    }
+
+    /// A member's nick as NAMES lists it. Every prefix goes in, the client
+    /// drops all but the highest unless multi-prefix was negotiated.
+    fn prefixed_nick(&self, user: &matrix::model::UserID) -> String {
+        match self.power_levels {
+            Some(ref levels) => format!("{}{}", levels.prefixes(user), user.nickname),
+            None => user.nickname.clone()
         }
-        callback(irc::protocol::Message {
-            prefix: Some("pto".to_string()),
-            command: irc::protocol::Command::Numeric(353),
-            args: vec![my_uid.nickname.clone(), "@".to_string(), self.irc_name.clone().unwrap()],
-            suffix: Some(usernames.join(" "))
-        });
+    }
+
+    /// Sends the NAMES reply for this room
+    fn names<F>(&self, my_nick: &str, callback: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        let channel = self.irc_name.clone().unwrap();
+        let usernames: Vec<String> = self.members.iter().map(|u| self.prefixed_nick(u)).collect();
+        callback(names_message(my_nick, channel.trim(), &usernames));
+        callback(end_of_names(my_nick, channel.trim()));
+    }
+
+    /// Whether an event we sent is coming back to us, forgetting about the
+    /// transaction if so. This works even if the echo arrives before the
+    /// send request has returned the event's ID.
+    fn take_local_echo(&mut self, txn_id: &str) -> bool {
+        self.pending_txns.remove(txn_id)
+    }
+
+    fn take_pending(&mut self) -> Vec<(matrix::events::RoomEvent, Option<u64>)> {
+        assert!(self.pending_sync);
+        mem::replace(&mut self.pending_events, vec![])
+    }
 
-        self.run_pending(callback);
+    pub fn finish_sync<F>(&mut self, my_uid: &matrix::model::UserID, config: &Config, mut callback: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        // An alias on our own homeserver makes the shortest channel name
+        let alias = self.aliases.iter().find(|a| a.homeserver == my_uid.homeserver)
+            .or(self.canonical_alias.as_ref())
+            .or(self.aliases.first())
+            .cloned();
+        self.irc_name = Some(match alias {
+            Some(alias) => alias.to_irc_channel(config),
+            None => channels::sanitize(format!("{}", self.id).trim(), config)
+        });
+        self.my_nick = Some(my_uid.nickname.clone());
+        callback(join_message(my_uid, self.irc_name.clone().unwrap()));
+        self.names(my_uid.nickname.trim(), callback);
     }
 
-    fn handle_with_alias<F>(&mut self, evt: matrix::events::RoomEvent, mut callback: &mut F)
+    fn handle_with_alias<F>(&mut self, evt: matrix::events::RoomEvent, timestamp: Option<u64>, mut callback: &mut F)
             where F: FnMut(irc::protocol::Message) {
         if self.irc_name != None {
+            if self.muted {
+                match evt {
+                    matrix::events::RoomEvent::Message(_, _, _) | matrix::events::RoomEvent::Unknown(_, _) => return,
+                    _ => ()
+                }
+            }
             match evt {
+                matrix::events::RoomEvent::Membership(user, matrix::events::MembershipAction::Knock) => {
+                    callback(irc::protocol::Message {
+                        tags: time_tags(timestamp),
+                        prefix: Some(SERVER_NAME.to_string()),
+                        command: irc::protocol::Command::Notice,
+                        args: vec![self.irc_name.clone().unwrap()],
+                        suffix: Some(format!("{} (@{}:{}) is knocking and would like to join",
+                                             user.nickname, user.nickname, user.homeserver))
+                    });
+                },
                 matrix::events::RoomEvent::Membership(_, _) => (),
-                matrix::events::RoomEvent::Message(user, text) => {
+                matrix::events::RoomEvent::Message(user, msgtype, text) => {
+                    let denied = self.is_denied(&user);
+                    if denied {
+                        warn!("Message in {} from {:?}, whose server is denied by the room's ACL", self.id, user);
+                    }
+                    if !denied && !self.members_loaded && !self.members.contains(&user) {
+                        self.members.push(user.clone());
+                    }
+                    let (command, text) = match msgtype {
+                        matrix::events::MsgType::Emote =>
+                            (irc::protocol::Command::Privmsg, Ctcp::new("ACTION", Some(text)).to_string()),
+                        matrix::events::MsgType::Notice => (irc::protocol::Command::Notice, text),
+                        matrix::events::MsgType::Media(_, url) =>
+                            (irc::protocol::Command::Privmsg, format!("{} {}", text, url)),
+                        matrix::events::MsgType::Other(ref msgtype) if self.show_custom =>
+                            (irc::protocol::Command::Notice, custom_text(msgtype.trim(), text.trim())),
+                        matrix::events::MsgType::Text | matrix::events::MsgType::Other(_) =>
+                            (irc::protocol::Command::Privmsg, text)
+                    };
                     callback(irc::protocol::Message {
+                        tags: time_tags(timestamp),
                         prefix: Some(format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver)),
-                        command: irc::protocol::Command::Privmsg,
+                        command: command,
                         args: vec![self.irc_name.clone().unwrap()],
                         suffix: Some(text)
                     });
                 },
+                matrix::events::RoomEvent::Unknown(_, json) => {
+                    let kind = json.find("type").and_then(|t| t.as_string()).unwrap_or("unknown").to_string();
+                    let body = json.find_path(&["content", "body"]).and_then(|b| b.as_string()).unwrap_or("").to_string();
+                    let prefix = json.find("sender").and_then(|s| s.as_string())
+                        .and_then(|s| s.parse::<matrix::model::UserID>().ok())
+                        .map(|user| format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver))
+                        .unwrap_or(SERVER_NAME.to_string());
+                    callback(irc::protocol::Message {
+                        tags: time_tags(timestamp),
+                        prefix: Some(prefix),
+                        command: irc::protocol::Command::Notice,
+                        args: vec![self.irc_name.clone().unwrap()],
+                        suffix: Some(custom_text(kind.trim(), body.trim()))
+                    });
+                },
                 matrix::events::RoomEvent::Topic(user, topic) => {
                     callback(irc::protocol::Message {
+                        tags: time_tags(timestamp),
                         prefix: Some(format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver)),
                         command: irc::protocol::Command::Topic,
                         args: vec![self.irc_name.clone().unwrap()],
                         suffix: Some(topic.clone())
                     });
                 },
+                matrix::events::RoomEvent::Tombstone(successor) => {
+                    callback(irc::protocol::Message {
+                        tags: time_tags(timestamp),
+                        prefix: Some(SERVER_NAME.to_string()),
+                        command: irc::protocol::Command::Notice,
+                        args: vec![self.irc_name.clone().unwrap()],
+                        suffix: Some(format!("This room has been upgraded and is now closed. It continues in {}.", successor))
+                    });
+                },
+                matrix::events::RoomEvent::ThirdPartyInvite(sender, token, display_name) => {
+                    // Only the display name is shown, which hides most of
+                    // the address
+                    let text = match display_name {
+                        Some(name) => {
+                            self.third_party_invites.insert(token, name.clone());
+                            Some(format!("{} invited {}, who hasn't joined Matrix yet. The invite is pending.",
+                                         sender.nickname, name))
+                        },
+                        None => self.third_party_invites.remove(&token).map(|name| {
+                            format!("{} withdrew the pending invite of {}", sender.nickname, name)
+                        })
+                    };
+                    if let Some(text) = text {
+                        callback(irc::protocol::Message {
+                            tags: time_tags(timestamp),
+                            prefix: Some(SERVER_NAME.to_string()),
+                            command: irc::protocol::Command::Notice,
+                            args: vec![self.irc_name.clone().unwrap()],
+                            suffix: Some(text)
+                        });
+                    }
+                },
+                matrix::events::RoomEvent::ThirdPartyInviteClaimed(user, token) => {
+                    if let Some(name) = self.third_party_invites.remove(&token) {
+                        callback(irc::protocol::Message {
+                            tags: time_tags(timestamp),
+                            prefix: Some(SERVER_NAME.to_string()),
+                            command: irc::protocol::Command::Notice,
+                            args: vec![self.irc_name.clone().unwrap()],
+                            suffix: Some(format!("The pending invite of {} went to {} (@{}:{})",
+                                                 name, user.nickname, user.nickname, user.homeserver))
+                        });
+                    }
+                },
+                matrix::events::RoomEvent::Encrypted(_) => {
+                    if !self.encrypted {
+                        self.encrypted = true;
+                        callback(irc::protocol::Message {
+                            tags: time_tags(timestamp),
+                            prefix: Some(SERVER_NAME.to_string()),
+                            command: irc::protocol::Command::Notice,
+                            args: vec![self.irc_name.clone().unwrap()],
+                            suffix: Some("This room is end-to-end encrypted. Its messages can't be bridged to IRC.".to_string())
+                        });
+                    }
+                },
                 _ => {
                     warn!("Unhandled event {:?}", evt)
                 }
             }
         } else {
-            self.pending_events.push(evt);
+            self.pending_events.push((evt, timestamp));
         }
     }
 
-    fn handle_event<F>(&mut self, evt: matrix::events::RoomEvent, mut callback: F)
+    fn handle_event<F>(&mut self, evt: matrix::events::RoomEvent, timestamp: Option<u64>, mut callback: F)
             where F: FnMut(irc::protocol::Message) {
         match evt {
-            matrix::events::RoomEvent::CanonicalAlias(name) => {
-                self.canonical_alias = Some(name.clone());
+            matrix::events::RoomEvent::CanonicalAlias(name, alt_aliases) => {
+                self.canonical_alias = name;
+                self.add_aliases(alt_aliases);
             },
             matrix::events::RoomEvent::JoinRules(rules) =>
                 self.join_rules = Some(rules.clone()),
+            matrix::events::RoomEvent::GuestAccess(allowed) =>
+                self.guest_access = Some(allowed),
+            matrix::events::RoomEvent::ServerAcl(acl) =>
+                self.server_acl = Some(acl),
+            // The bridge announces new pins, since that needs the client
+            matrix::events::RoomEvent::PinnedEvents(_, _) => (),
             matrix::events::RoomEvent::Create => (),
             matrix::events::RoomEvent::Aliases(aliases) =>
-                self.aliases = aliases,
-            matrix::events::RoomEvent::PowerLevels => (),
-            matrix::events::RoomEvent::HistoryVisibility(_) => (),
-            matrix::events::RoomEvent::Name(_, _) => (),
+                self.add_aliases(aliases),
+            matrix::events::RoomEvent::PowerLevels(levels) =>
+                self.power_levels = Some(levels),
+            matrix::events::RoomEvent::HistoryVisibility(visibility) =>
+                self.history_visibility = Some(visibility),
+            matrix::events::RoomEvent::Encryption(algorithm) =>
+                self.encryption = Some(algorithm),
+            matrix::events::RoomEvent::Name(_, name) =>
+                self.name = Some(if name.is_empty() { None } else { Some(name) }),
+            matrix::events::RoomEvent::Topic(user, topic) => {
+                self.topic = Some(if topic.is_empty() { None } else { Some(topic.clone()) });
+                self.handle_with_alias(matrix::events::RoomEvent::Topic(user, topic), timestamp, &mut callback)
+            },
             matrix::events::RoomEvent::Avatar(_, _) => (),
             matrix::events::RoomEvent::Membership(user, matrix::events::MembershipAction::Join) => {
-                self.handle_join(user, &mut callback);
+                self.handle_join(user, timestamp, &mut callback);
             },
             matrix::events::RoomEvent::Membership(user, matrix::events::MembershipAction::Leave) => {
-                self.handle_part(user, &mut callback);
+                // Leaving a ban behind is how an unban looks
+                self.banned.retain(|banned| banned != &user);
+                self.handle_part(user, timestamp, &mut callback);
+            },
+            matrix::events::RoomEvent::Membership(user, matrix::events::MembershipAction::Ban) => {
+                if !self.banned.contains(&user) {
+                    self.banned.push(user.clone());
+                }
+                self.handle_part(user, timestamp, &mut callback);
+            },
+            // Ghost nicks come from user IDs, so a new display name only
+            // shows in WHOIS. Someone not known to be here yet did join.
+            matrix::events::RoomEvent::Renamed(user, _) | matrix::events::RoomEvent::MemberAvatar(user) => {
+                if !self.members.contains(&user) {
+                    self.handle_join(user, timestamp, &mut callback);
+                }
+            },
+            matrix::events::RoomEvent::Membership(_, matrix::events::MembershipAction::Knock) =>
+                self.handle_with_alias(evt, timestamp, &mut callback),
+            matrix::events::RoomEvent::Tombstone(successor) => {
+                self.replacement = Some(successor.clone());
+                self.handle_with_alias(matrix::events::RoomEvent::Tombstone(successor), timestamp, &mut callback)
             },
             matrix::events::RoomEvent::Unknown(unknown_type, json) => {
-                warn!("Unknown room event {}", unknown_type);
-                trace!("raw event: {:?}", json);
+                // Only messages, a room's custom state would come all at once,
+                // and only from servers the room's ACL lets in
+                let denied = json.find("sender").and_then(|s| s.as_string())
+                    .and_then(|s| s.parse::<matrix::model::UserID>().ok())
+                    .map(|user| self.is_denied(&user))
+                    .unwrap_or(false);
+                if denied {
+                    warn!("Not showing {} in {}, its sender's server is denied by the room's ACL", unknown_type, self.id);
+                } else if self.show_custom && json.find("state_key").is_none() {
+                    self.handle_with_alias(matrix::events::RoomEvent::Unknown(unknown_type, json), timestamp, &mut callback)
+                } else {
+                    warn!("Unknown room event {}", unknown_type);
+                    trace!("raw event: {:?}", json);
+                }
             }
-            _ => self.handle_with_alias(evt, &mut callback)
+            _ => self.handle_with_alias(evt, timestamp, &mut callback)
         };
     }
 }
@@ -237,7 +1174,12 @@ impl Room {
 impl Bridge {
     pub fn room_from_matrix(&mut self, id: &matrix::model::RoomID) -> &mut Room {
         if !self.rooms.contains_key(id) {
-            self.rooms.insert(id.clone(), Room::new(id.clone()));
+            let mut room = Room::new(id.clone());
+            room.churn = Churn::new(Duration::from_secs(self.shared.config.churn_window_secs),
+                                    self.shared.config.churn_threshold);
+            room.show_custom = self.shared.config.show_custom_events;
+            room.muted = self.muted.contains(id);
+            self.rooms.insert(id.clone(), room);
         }
         match self.rooms.get_mut(id) {
             Some(room) => room,
@@ -245,62 +1187,1255 @@ impl Bridge {
         }
     }
 
+    /// The room behind a channel. Channels named after any other alias of
+    /// a room lead to it as well.
     pub fn room_from_irc(&mut self, id: &String) -> Option<&mut Room> {
-        let mut room_id: Option<matrix::model::RoomID> = None;
-        for (_, r) in self.rooms.iter_mut() {
-            if let Some(ref alias) = r.irc_name {
-                if alias == id {
-                    room_id = Some(r.id.clone())
-                }
+        let wanted = nicks::irc_lower(id);
+        let room_id = match self.channels.get(&wanted) {
+            Some(room_id) => Some(room_id.clone()),
+            None => {
+                let config = &self.shared.config;
+                self.rooms.values().find(|room| {
+                    room.irc_name.is_some() && room.canonical_alias.iter().chain(room.aliases.iter())
+                        .any(|alias| nicks::irc_lower(&alias.to_irc_channel(config)) == wanted)
+                }).map(|room| room.id.clone())
             }
-        }
+        };
         match room_id {
-            Some(id) => Some(self.room_from_matrix(&id)),
+            Some(room_id) => self.rooms.get_mut(&room_id),
             None => None
         }
     }
 
-    pub fn new(client: irc::streams::Client, url: &str) -> Self {
-        Bridge {
-            client: client,
-            matrix: matrix::client::Client::new(url),
-            rooms: HashMap::new(),
-            seen_events: vec![]
+    /// Points the channel of a room at it in the channel index, once the
+    /// room got its channel name or a new one. `previous` is the name it
+    /// had before.
+    fn index_channel(&mut self, id: &matrix::model::RoomID, previous: Option<String>) {
+        if let Some(previous) = previous {
+            let previous = nicks::irc_lower(&previous);
+            if self.channels.get(&previous) == Some(id) {
+                self.channels.remove(&previous);
+            }
+        }
+        let name = self.rooms.get(id).and_then(|room| room.irc_name.clone());
+        if let Some(name) = name {
+            self.channels.insert(nicks::irc_lower(&name), id.clone());
         }
     }
 
-    pub fn run(&mut self) {
-        let mut events = EventLoop::new().unwrap();
-        events.register(self.client.as_evented(), CLIENT, EventSet::all(), PollOpt::edge()).unwrap();
-        events.run(self).unwrap();
+    /// Leaves a room on Matrix, then forgets about it
+    fn leave_room(&mut self, id: &matrix::model::RoomID) -> matrix::client::Result {
+        try!(self.matrix.leave_room(id));
+        self.forget_room(id);
+        Ok(())
     }
 
-    fn finish_sync<F>(&mut self, mut callback: &mut F)
+    /// Parts the channel of a room we left from another client, and forgets
+    /// about the room
+    fn left_elsewhere<F>(&mut self, room_id: &matrix::model::RoomID, mut callback: &mut F)
             where F: FnMut(irc::protocol::Message) {
-        for (_, mut room) in &mut self.rooms {
-            room.finish_sync(&self.matrix.uid.as_ref().unwrap(), callback);
+        // Rejecting an invite elsewhere also counts as leaving
+        self.invites.remove(room_id);
+        let channel = match self.rooms.get(room_id) {
+            Some(room) => room.irc_name.clone(),
+            None => return
+        };
+        if let (Some(channel), Some(me)) = (channel, self.matrix.uid.clone()) {
+            callback(irc::protocol::Message {
+                tags: vec![],
+                prefix: Some(format!("{}!{}@{}", me.nickname, me.nickname, me.homeserver)),
+                command: irc::protocol::Command::Part,
+                args: vec![channel],
+                suffix: None
+            });
         }
+        self.forget_room(room_id);
     }
 
-    fn handle_matrix(&mut self, evt: matrix::events::Event) -> io::Result<usize> {
-        let duplicate = match evt.id {
-            Some(ref id) =>
-                self.seen_events.contains(id),
-            _ => false
+    /// Tells the user about an invite, once, and how to accept it
+    fn invited(&mut self, invite: matrix::events::Invite) {
+        if self.rooms.contains_key(&invite.room) || self.invites.contains_key(&invite.room) {
+            return;
+        }
+        let channel = invite_channel(&invite, &self.shared.config);
+        let inviter = invite.inviter.as_ref().map(|u| u.nickname.clone()).unwrap_or("Someone".to_string());
+        let msg = match invite.name {
+            Some(ref name) => format!("{} invited you to {} ({}), join it to accept.", inviter, name, channel),
+            None => format!("{} invited you to {}, join it to accept.", inviter, channel)
         };
-        if !duplicate {
-            let mut messages: Vec<irc::protocol::Message> = vec![];
-            {
-                let mut append_msg = |msg: irc::protocol::Message| {
-                    messages.push(msg);
+        if let Err(err) = self.client.notice(SERVER_NAME, msg.trim()) {
+            warn!("Could not tell the IRC client about an invite: {}", err);
+        }
+        self.invites.insert(invite.room.clone(), invite);
+    }
+
+    /// Whether a ghost leaving this room leaves no other room it shares with
+    /// us on IRC, in which case it quits rather than parts
+    fn leaves_last_room(&self, room_id: &matrix::model::RoomID, user: &matrix::model::UserID) -> bool {
+        let shown = match self.rooms.get(room_id) {
+            Some(room) => room.irc_name.is_some() && room.members.contains(user),
+            None => false
+        };
+        shown && Some(user) != self.matrix.uid.as_ref() &&
+            !self.rooms.iter().any(|(id, room)| id != room_id && room.members.contains(user))
+    }
+
+    /// Takes a ghost off IRC once it has left the last room we share with
+    /// it, freeing its nick
+    fn quit_ghost<F>(&mut self, room_id: &matrix::model::RoomID, user: &matrix::model::UserID,
+                     timestamp: Option<u64>, mut callback: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        self.nicks.release(user);
+        let shown = {
+            let room = self.room_from_matrix(room_id);
+            room.remove_member(user);
+            room.count_churn(false, timestamp, &mut callback)
+        };
+        if !shown {
+            return;
+        }
+        callback(irc::protocol::Message {
+            tags: time_tags(timestamp),
+            prefix: Some(format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver)),
+            command: irc::protocol::Command::Quit,
+            args: vec![],
+            suffix: Some("Left all shared rooms".to_string())
+        });
+    }
+
+    /// Tells an away-notify client when a ghost goes away or comes back
+    fn presence_changed<F>(&mut self, presence: matrix::events::PresenceEvent, mut callback: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        let away = match self.nicks.set_presence(&presence) {
+            Some(away) => away,
+            None => return
+        };
+        if self.client.has_cap("away-notify") {
+            let user = presence.user;
+            callback(irc::protocol::Message {
+                tags: vec![],
+                prefix: Some(format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver)),
+                command: irc::protocol::Command::Away,
+                args: vec![],
+                suffix: if away { Some("Away".to_string()) } else { None }
+            });
+        }
+    }
+
+    /// Drops a room we're no longer in, along with the ghost nicks of the
+    /// members we don't share another room with
+    fn forget_room(&mut self, id: &matrix::model::RoomID) {
+        if let Some(room) = self.rooms.remove(id) {
+            if let Some(ref name) = room.irc_name {
+                self.channels.remove(&nicks::irc_lower(name));
+            }
+            for user in room.members {
+                if !self.rooms.values().any(|r| r.members.contains(&user)) {
+                    self.nicks.release(&user);
+                }
+            }
+        }
+        self.left_rooms.insert(id.clone());
+    }
+
+    /// Whether an event is for a room we've left. Our own join to it brings
+    /// the room back.
+    fn from_left_room(&mut self, data: &matrix::events::EventData) -> bool {
+        match data {
+            &matrix::events::EventData::Room(ref id, ref evt) if self.left_rooms.contains(id) => {
+                match (evt, &self.matrix.uid) {
+                    (&matrix::events::RoomEvent::Membership(ref user, matrix::events::MembershipAction::Join), &Some(ref me))
+                        if user == me => {
+                        self.left_rooms.remove(id);
+                        false
+                    },
+                    _ => true
+                }
+            },
+            _ => false
+        }
+    }
+
+    /// Tells the IRC user that something went wrong. It is logged too, for
+    /// the operator's sake.
+    fn notify_error(&mut self, msg: &str) {
+        warn!("{}", msg);
+        if let Err(err) = self.client.notice(SERVER_NAME, msg) {
+            warn!("Could not send error to the IRC client: {}", err);
+        }
+    }
+
+    /// Follows room upgrades to the room that is currently live, stopping at
+    /// the last one we know about.
+    fn resolve_room(&self, id: &matrix::model::RoomID) -> matrix::model::RoomID {
+        let mut current = id.clone();
+        let mut visited = HashSet::new();
+        while let Some(next) = self.rooms.get(&current).and_then(|r| r.replacement.clone()) {
+            if !self.rooms.contains_key(&next) || !visited.insert(current.clone()) {
+                break;
+            }
+            current = next;
+        }
+        current
+    }
+
+    /// Whether a nick belongs to a Matrix user in at least one of our rooms.
+    /// Presence isn't tracked yet, so this is what "online" means for ISON.
+    fn is_online(&self, nick: &str) -> bool {
+        match self.nicks.user_for(nick) {
+            Some(user) => self.rooms.values().any(|r| r.members.contains(user)),
+            None => false
+        }
+    }
+
+    /// The room that only we and `user` are in, which is what a direct
+    /// message to a ghost is sent to
+    fn direct_room(&self, user: &matrix::model::UserID) -> Option<matrix::model::RoomID> {
+        let me = match self.matrix.uid {
+            Some(ref uid) => uid,
+            None => return None
+        };
+        self.rooms.values().find(|r| {
+            r.members.len() == 2 && r.members.contains(me) && r.members.contains(user)
+        }).map(|r| r.id.clone())
+    }
+
+    /// Accepts a file offered with DCC SEND to a channel or a ghost. The
+    /// transfer runs in a thread of its own and reports back through the
+    /// event loop, where the file is uploaded to the room. Only offers from
+    /// the address the client connects from are taken, or a client could
+    /// have the bridge read from services only it can reach.
+    fn handle_dcc(&mut self, target: &str, params: &str, channel: Sender<Event>) -> io::Result<usize> {
+        let offer = match DccSend::from_str(params) {
+            Ok(offer) => offer,
+            Err(err) => return self.client.notice(SERVER_NAME, err.trim())
+        };
+        if self.client.peer_addr().map(|peer| peer.ip()) != Some(offer.addr.ip()) {
+            return self.client.notice(SERVER_NAME, format!("Not receiving {}: DCC offers have to come from the \
+                                                            address you connect from", offer.filename).trim());
+        }
+        let room_id = if target.starts_with("#") || target.starts_with("&") {
+            self.room_from_irc(&target.to_string()).map(|r| r.id.clone())
+        } else {
+            match self.nicks.user_for(target) {
+                Some(user) => self.direct_room(&user.clone()),
+                None => None
+            }
+        };
+        let room_id = match room_id {
+            Some(id) => self.resolve_room(&id),
+            None => return self.client.notice(SERVER_NAME, format!("Can't send files to {}", target).trim())
+        };
+        let max_size = self.shared.config.dcc_max_size;
+        if offer.size.map(|size| size > max_size).unwrap_or(false) {
+            return self.client.notice(SERVER_NAME, format!("{} is too large, the limit is {} bytes", offer.filename, max_size).trim());
+        }
+        debug!("Receiving {:?} for {}", offer, room_id);
+        thread::spawn(move|| {
+            let event = match offer.receive(max_size, Duration::from_secs(DCC_TIMEOUT_SECS)) {
+                Ok(bytes) => Event::DccReceived(room_id, offer.filename, bytes),
+                Err(err) => Event::DccFailed(offer.filename, format!("{}", err))
+            };
+            if channel.send(event).is_err() {
+                debug!("The session ended during a DCC transfer");
+            }
+        });
+        Ok(0)
+    }
+
+    /// Answers CTCP requests sent to the bridge itself or to a ghost
+    fn handle_ctcp(&mut self, target: &str, ctcp: Ctcp) -> io::Result<usize> {
+        let from = if target == SERVER_NAME {
+            SERVER_NAME.to_string()
+        } else {
+            match self.nicks.user_for(target) {
+                Some(user) => format!("{}!{}@{}", target, user.nickname, user.homeserver),
+                None => return Ok(0)
+            }
+        };
+        let reply = match ctcp.command.trim() {
+            "VERSION" => Some(Ctcp::new("VERSION", Some(format!("{} {}", SERVER_NAME, VERSION)))),
+            "PING" => Some(Ctcp::new("PING", ctcp.params)),
+            _ => {
+                debug!("Ignoring CTCP {} for {}", ctcp.command, target);
+                None
+            }
+        };
+        match reply {
+            Some(r) => self.client.notice(from.trim(), r.to_string().trim()),
+            None => Ok(0)
+        }
+    }
+
+    /// Listens for IRC connections on `addr`, bridging each to the Matrix
+    /// homeserver at `homeserver`. Connections from anywhere but loopback
+    /// must use TLS. If a TLS listen address is configured, TLS connections
+    /// are accepted there as well. Only returns if the listeners couldn't be
+    /// set up.
+    pub fn listen(addr: SocketAddr, homeserver: &str) -> io::Result<()> {
+        let config = Config::from_env();
+        let mut servers: Vec<Box<Server>> = vec![];
+        if is_loopback(&addr) {
+            warn!("SSL IS DISABLED ON {}!! IRC LOGINS ARE IN THE CLEAR AND UNENCRYPTED", addr);
+            servers.push(Box::new(try!(ssl::TcpServer::new(&addr))));
+        } else {
+            let tls = try!(ssl::context(config.tls_cert.trim(), config.tls_key.trim()));
+            servers.push(Box::new(try!(ssl::SslServer::new(&addr, tls))));
+        }
+        info!("Listening on {}", addr);
+        if let Some(ref tls_address) = config.tls_listen_address {
+            let tls_addr = try!(config::parse_tls_listen_address(tls_address.trim()).map_err(|err| {
+                io::Error::new(io::ErrorKind::InvalidInput, err)
+            }));
+            let tls = try!(ssl::context(config.tls_cert.trim(), config.tls_key.trim()));
+            servers.push(Box::new(try!(ssl::SslServer::new(&tls_addr, tls))));
+            info!("Listening for TLS on {}", tls_addr);
+        }
+        let mut shared = Shared::new(config.clone());
+        // Plaintext connections may upgrade with STARTTLS if there's a
+        // certificate to do it with
+        match ssl::context(config.tls_cert.trim(), config.tls_key.trim()) {
+            Ok(tls) => shared.tls = Some(Arc::new(tls)),
+            Err(err) => info!("STARTTLS is unavailable: {}", err)
+        }
+        let mut events = try!(EventLoop::new());
+        for (idx, server) in servers.iter().enumerate() {
+            try!(events.register(server.as_evented(), Token(idx), EventSet::readable(), PollOpt::level()));
+        }
+        events.run(&mut Listener {
+            servers: servers,
+            url: homeserver.to_string(),
+            shared: shared,
+            next_session: 0
+        })
+    }
+
+    pub fn new(mut client: irc::streams::Client, url: &str, token: Token, shared: Shared) -> Self {
+        if let Some(ref tls) = shared.tls {
+            client.offer_starttls(tls.clone());
+        }
+        client.set_rate_limit(shared.config.flood_rate, shared.config.flood_burst);
+        let matrix = Self::matrix_client(url, &shared.config);
+        let nicks = nicks::NickRegistry::new(shared.logins.clone());
+        let recent_events = LruCache::new(shared.config.event_cache_size);
+        Bridge {
+            token: token,
+            shared: shared,
+            client: client,
+            matrix: matrix,
+            rooms: HashMap::new(),
+            seen_events: vec![],
+            nicks: nicks,
+            server_notices_joined: false,
+            recent_events: recent_events,
+            left_rooms: HashSet::new(),
+            polling: false,
+            writable_interest: false,
+            session_file: None,
+            invites: HashMap::new(),
+            channels: HashMap::new(),
+            release_scheduled: false,
+            churn_scheduled: false,
+            cancel_poll: Arc::new(AtomicBool::new(false)),
+            profile_fetches: vec![],
+            title_fetches: vec![],
+            registered: false,
+            muted: HashSet::new(),
+            display_names: false,
+            sent: vec![],
+            homeserver: None
+        }
+    }
+
+    /// A Matrix client for the homeserver at `url`, set up as configured
+    fn matrix_client(url: &str, config: &Config) -> matrix::client::Client {
+        let mut matrix = matrix::client::Client::new(url);
+        Self::configure_matrix(&mut matrix, config);
+        matrix
+    }
+
+    /// Applies the configuration to a Matrix client, new or restored
+    fn configure_matrix(matrix: &mut matrix::client::Client, config: &Config) {
+        matrix.set_sync_limit(config.sync_limit);
+        if let Some(ref user_agent) = config.user_agent {
+            matrix.set_user_agent(user_agent.clone());
+        }
+        if let Some(ref filter) = config.sync_filter {
+            matrix.set_filter(filter.clone());
+        }
+    }
+
+    /// What to be woken up for on the IRC socket. Writable readiness only
+    /// matters while output is queued, otherwise a level-triggered socket
+    /// would wake us up all the time.
+    fn interest(&self) -> EventSet {
+        let interest = EventSet::readable() | EventSet::hup() | EventSet::error();
+        if self.writable_interest {
+            interest | EventSet::writable()
+        } else {
+            interest
+        }
+    }
+
+    /// Asks for writable readiness while output is queued, and stops once
+    /// it's all been written. Also makes sure a timer is set for whatever
+    /// flood protection is holding back.
+    fn rearm(&mut self, event_loop: &mut EventLoop<Bridge>) {
+        let wanted = self.client.has_pending_output();
+        if wanted != self.writable_interest {
+            self.writable_interest = wanted;
+            let interest = self.interest();
+            if let Err(err) = event_loop.reregister(self.client.as_evented(), CLIENT, interest, PollOpt::level()) {
+                warn!("Could not update interest in the IRC socket: {}", err);
+            }
+        }
+        if !self.release_scheduled {
+            if let Some(ms) = self.client.held_for_ms() {
+                match event_loop.timeout_ms(Timer::Release, cmp::max(ms, 1)) {
+                    Ok(_) => self.release_scheduled = true,
+                    Err(err) => warn!("Could not schedule sending held back messages: {:?}", err)
+                }
+            }
+        }
+        if !self.profile_fetches.is_empty() {
+            let users = mem::replace(&mut self.profile_fetches, vec![]);
+            let mut matrix = self.matrix.fork();
+            let channel = event_loop.channel();
+            thread::spawn(move|| {
+                for user in users {
+                    match matrix.get_display_name(&user) {
+                        Ok(name) => {
+                            if channel.send(Event::DisplayName(user, name)).is_err() {
+                                return;
+                            }
+                        },
+                        Err(err) => {
+                            debug!("Could not fetch the display name of {}: {}", user, err);
+                            if channel.send(Event::DisplayNameFailed(user)).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+        if !self.title_fetches.is_empty() {
+            let rooms = mem::replace(&mut self.title_fetches, vec![]);
+            let mut matrix = self.matrix.fork();
+            let channel = event_loop.channel();
+            thread::spawn(move|| {
+                for id in rooms {
+                    match (matrix.get_room_name(&id), matrix.get_room_topic(&id)) {
+                        (Ok(name), Ok(topic)) => {
+                            if channel.send(Event::RoomTitle(id, name, topic)).is_err() {
+                                return;
+                            }
+                        },
+                        (Err(err), _) | (_, Err(err)) => debug!("Could not fetch the name and topic of {}: {}", id, err)
+                    }
+                }
+            });
+        }
+        if !self.churn_scheduled {
+            let now = now_ms();
+            let due = self.rooms.values().filter_map(|room| room.churn_due(now)).min();
+            if let Some(due) = due {
+                let ms = due.as_secs() * 1000 + due.subsec_nanos() as u64 / 1000000;
+                match event_loop.timeout_ms(Timer::Churn, cmp::max(ms, 1)) {
+                    Ok(_) => self.churn_scheduled = true,
+                    Err(err) => warn!("Could not schedule summing up joins and parts: {:?}", err)
+                }
+            }
+        }
+    }
+
+    /// Cleans up after the IRC client went away without a QUIT. A stored
+    /// session is saved to be picked up again on the next connection, one
+    /// that isn't would be left dangling on the homeserver, so it is logged
+    /// out of.
+    fn disconnected(&mut self, events: &mut EventLoop<Bridge>) {
+        info!("Session {:?} lost its IRC client", self.token);
+        if self.matrix.is_logged_in() {
+            match self.session_file {
+                Some(ref path) => {
+                    if let Err(err) = self.matrix.save_session(path) {
+                        warn!("Could not save session to {:?}: {}", path, err);
+                    }
+                },
+                None => {
+                    if let Err(err) = self.matrix.logout() {
+                        warn!("Could not log out of Matrix: {}", err);
+                    }
+                }
+            }
+        }
+        self.detach();
+        self.shutdown(events);
+    }
+
+    /// Keeps the Matrix side of a stored session for a reconnect to pick up,
+    /// unless resuming is turned off
+    fn detach(&mut self) {
+        let window = Duration::from_secs(self.shared.config.resume_window_secs);
+        if self.session_file.is_none() || window == Duration::from_secs(0) || !self.matrix.is_logged_in() {
+            return;
+        }
+        let uid = self.matrix.uid.clone().unwrap();
+        let url = self.matrix.homeserver_url().to_string();
+        let detached = Detached {
+            matrix: mem::replace(&mut self.matrix, matrix::client::Client::new(url.trim())),
+            rooms: mem::replace(&mut self.rooms, HashMap::new()),
+            at: Instant::now()
+        };
+        debug!("Keeping the session of {} for {}s", uid, window.as_secs());
+        let mut sessions = self.shared.detached.lock().unwrap();
+        let expired: Vec<matrix::model::UserID> = sessions.iter()
+            .filter(|&(_, session)| session.at.elapsed() >= window)
+            .map(|(uid, _)| uid.clone())
+            .collect();
+        for uid in expired {
+            sessions.remove(&uid);
+        }
+        sessions.insert(uid, detached);
+    }
+
+    /// Carries on with a detached session of `uid` if there is a recent
+    /// enough one. The IRC client is joined to its channels again, and
+    /// polling goes on from where it stopped rather than with a full sync.
+    fn reattach(&mut self, uid: &matrix::model::UserID, channel: Sender<Event>) -> bool {
+        let window = Duration::from_secs(self.shared.config.resume_window_secs);
+        let detached = match self.shared.detached.lock().unwrap().remove(uid) {
+            Some(detached) => detached,
+            None => return false
+        };
+        if detached.at.elapsed() >= window {
+            return false;
+        }
+        debug!("Picking up where the session of {} left off", uid);
+        self.matrix = detached.matrix;
+        self.rooms = detached.rooms;
+        self.load_preferences();
+        let mut messages = vec![];
+        let mut named = vec![];
+        {
+            let mut append_msg = |msg: irc::protocol::Message| {
+                messages.push(msg);
+            };
+            for (id, room) in &mut self.rooms {
+                named.push((id.clone(), room.irc_name.clone()));
+                for user in &room.members {
+                    self.nicks.reserve(user);
+                }
+                room.finish_sync(uid, &self.shared.config, &mut append_msg);
+            }
+        }
+        for (id, previous) in named {
+            self.index_channel(&id, previous);
+        }
+        if let Err(err) = self.send_all(messages) {
+            warn!("Could not rejoin the IRC client to its channels: {}", err);
+        }
+        self.poll_matrix(channel);
+        true
+    }
+
+    /// Sends a message from the IRC client to the room behind a channel, as
+    /// a text, a notice or an emote. A message that doesn't make it is
+    /// reported in the channel, so the user knows which one was lost.
+    fn send_message(&mut self, channel: &str, msgtype: matrix::events::MsgType, text: String) -> io::Result<usize> {
+        let room_id = match self.room_from_irc(&channel.to_string()).map(|room| room.id.clone()) {
+            None => return self.client.numeric(403, vec![channel.to_string()], "No such channel"),
+            Some(id) => id
+        };
+        let room_id = self.resolve_room(&room_id);
+        let txn_id = self.matrix.new_txn_id();
+        self.room_from_matrix(&room_id).pending_txns.insert(txn_id.clone());
+        let me = self.matrix.uid.clone().unwrap();
+        let sent = match msgtype {
+            matrix::events::MsgType::Notice => self.matrix.send_notice(&room_id, &text, txn_id.trim()),
+            matrix::events::MsgType::Emote => self.matrix.send_emote(&room_id, &text, txn_id.trim()),
+            _ => self.matrix.send_text(&room_id, &text, txn_id.trim())
+        };
+        match sent {
+            Ok(id) => {
+                if self.sent.len() >= SENT_MEMORY {
+                    self.sent.remove(0);
+                }
+                self.sent.push((room_id.clone(), id.clone(), channel.to_string(), snippet(text.trim())));
+                let (command, text) = match msgtype {
+                    matrix::events::MsgType::Notice => (Command::Notice, text),
+                    matrix::events::MsgType::Emote => (Command::Privmsg, Ctcp::new("ACTION", Some(text)).to_string()),
+                    _ => (Command::Privmsg, text)
+                };
+                self.seen_events.push(id.clone());
+                // The Matrix echo is suppressed as ours, so this is the only
+                // copy the client sees
+                if self.client.has_cap("echo-message") {
+                    self.client.send(&Message {
+                        tags: vec![("msgid".to_string(), format!("{}", id))],
+                        prefix: Some(format!("{}!{}@{}", me.nickname, me.nickname, me.homeserver)),
+                        command: command,
+                        args: vec![channel.to_string()],
+                        suffix: Some(text)
+                    })
+                } else {
+                    Ok(0)
+                }
+            },
+            Err(err) => {
+                warn!("Could not send to {}: {}", channel, err);
+                self.room_from_matrix(&room_id).take_local_echo(txn_id.trim());
+                self.client.send(&Message {
+                    tags: vec![],
+                    prefix: Some(SERVER_NAME.to_string()),
+                    command: Command::Notice,
+                    args: vec![channel.to_string()],
+                    suffix: Some(format!("Message not delivered: {} ({})", err, snippet(text.trim())))
+                })
+            }
+        }
+    }
+
+    /// Ends the session. A poll in flight is told to give up rather than
+    /// waited for.
+    fn shutdown(&mut self, events: &mut EventLoop<Bridge>) {
+        self.cancel_poll.store(true, Ordering::SeqCst);
+        events.shutdown();
+    }
+
+    pub fn run(&mut self) {
+        let mut events = EventLoop::new().unwrap();
+        // Level-triggered, so that input left unread and a socket that
+        // drained while we weren't asking are both reported again on the
+        // next poll instead of being lost with the edge
+        events.register(self.client.as_evented(), CLIENT, self.interest(), PollOpt::level()).unwrap();
+        self.shared.sessions.lock().unwrap().insert(self.token, Session::new(events.channel()));
+        debug!("Session {:?} started", self.token);
+        events.run(self).unwrap();
+        self.cancel_poll.store(true, Ordering::SeqCst);
+        self.shared.sessions.lock().unwrap().remove(&self.token);
+        debug!("Session {:?} ended", self.token);
+    }
+
+    /// Joins every room on the IRC side, then replays what was buffered
+    /// during the sync in chronological order across all rooms.
+    fn finish_sync<F>(&mut self, mut callback: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        let mut pending = vec![];
+        let mut named = vec![];
+        for (id, mut room) in &mut self.rooms {
+            named.push((id.clone(), room.irc_name.clone()));
+            room.finish_sync(&self.matrix.uid.as_ref().unwrap(), &self.shared.config, callback);
+            pending.push((id.clone(), room.take_pending()));
+        }
+        for (id, previous) in named {
+            self.index_channel(&id, previous);
+        }
+        // Rooms whose sync left out their name and topic get them fetched,
+        // without holding up the rest
+        for room in self.rooms.values() {
+            if room.irc_name.is_some() && (room.topic.is_none() || room.name.is_none()) {
+                self.title_fetches.push(room.id.clone());
+            }
+        }
+        for (id, evt, timestamp) in chronological(pending) {
+            self.room_from_matrix(&id).handle_with_alias(evt, timestamp, callback);
+        }
+        let ids: Vec<matrix::model::RoomID> = self.rooms.keys().cloned().collect();
+        for id in ids {
+            self.follow_replacement(&id, callback);
+        }
+    }
+
+    /// Shows a notice from the homeserver in the server notice channel,
+    /// joining the IRC client to it first if needed. These are never
+    /// buffered, since they may explain why nothing else works.
+    fn server_notice<F>(&mut self, kind: Option<String>, text: &str, timestamp: Option<u64>, mut callback: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        let channel = self.shared.config.server_notice_channel.clone();
+        if !self.server_notices_joined {
+            if let Some(ref uid) = self.matrix.uid {
+                callback(join_message(uid, channel.clone()));
+                self.server_notices_joined = true;
+            }
+        }
+        let label = match kind {
+            Some(kind) => format!("[Server notice: {}]", kind),
+            None => "[Server notice]".to_string()
+        };
+        callback(irc::protocol::Message {
+            tags: time_tags(timestamp),
+            prefix: Some(SERVER_NAME.to_string()),
+            command: irc::protocol::Command::Notice,
+            args: vec![channel],
+            suffix: Some(format!("{} {}", label, text))
+        });
+    }
+
+    fn remember_message(&mut self, id: &matrix::model::EventID, user: &matrix::model::UserID, text: &str) {
+        self.recent_events.insert(id.clone(), (user.clone(), snippet(text)));
+    }
+
+    /// Who sent a message and the start of what it said, from the recent
+    /// events if it's one of them and from the homeserver otherwise. Events
+    /// that aren't messages, or that we can't see, give None.
+    fn recall(&mut self, room_id: &matrix::model::RoomID, id: &matrix::model::EventID)
+            -> Option<(matrix::model::UserID, String)> {
+        if let Some(recent) = self.recent_events.get(id) {
+            return Some(recent.clone());
+        }
+        match self.matrix.get_event(room_id, id) {
+            Ok(evt) => match evt.data {
+                matrix::events::EventData::Room(_, matrix::events::RoomEvent::Message(sender, _, text)) |
+                matrix::events::EventData::Room(_, matrix::events::RoomEvent::Reply(sender, _, text)) => {
+                    self.remember_message(id, &sender, text.trim());
+                    Some((sender, snippet(text.trim())))
+                },
+                _ => None
+            },
+            Err(matrix::client::ClientError::EventNotFound) => None,
+            Err(err) => {
+                warn!("Could not fetch event {} in {}: {}", id, room_id, err);
+                None
+            }
+        }
+    }
+
+    /// What a pinned event said
+    fn pinned_text(&mut self, room_id: &matrix::model::RoomID, id: &matrix::model::EventID) -> String {
+        match self.recall(room_id, id) {
+            Some((sender, text)) => format!("<{}> {}", sender.nickname, text),
+            None => "a message we can't see".to_string()
+        }
+    }
+
+    /// Tells the channel about the events that were just pinned in a room
+    fn announce_pins<F>(&mut self, room_id: &matrix::model::RoomID, user: &matrix::model::UserID,
+                        pinned: Vec<matrix::model::EventID>, timestamp: Option<u64>, mut callback: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        let channel = match self.room_from_matrix(room_id).irc_name.clone() {
+            Some(channel) => channel,
+            None => return
+        };
+        for id in pinned {
+            let text = self.pinned_text(room_id, &id);
+            callback(irc::protocol::Message {
+                tags: time_tags(timestamp),
+                prefix: Some(format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver)),
+                command: irc::protocol::Command::Notice,
+                args: vec![channel.clone()],
+                suffix: Some(format!("\u{1F4CC} pinned: {}", text))
+            });
+        }
+    }
+
+    /// The channel a room alias or ID is bridged to, if we're in that room
+    fn channel_for(&self, room: &str) -> Option<String> {
+        let alias = room.parse::<matrix::model::RoomAlias>().ok();
+        self.rooms.values().find(|r| {
+            match alias {
+                Some(ref alias) => r.canonical_alias.as_ref() == Some(alias) || r.aliases.contains(alias),
+                None => format!("{}", r.id) == room
+            }
+        }).and_then(|r| r.irc_name.clone())
+    }
+
+    /// Turns matrix.to links to users and rooms into nicks and channels
+    fn render_links(&self, text: &str) -> String {
+        links::rewrite(text, |link| {
+            match link {
+                &links::Link::User(ref id) => id.parse::<matrix::model::UserID>().ok().map(|u| u.nickname),
+                &links::Link::Room(ref id) => self.channel_for(id)
+            }
+        })
+    }
+
+    /// Puts the nicks of users a message mentions with pills in place of the
+    /// text standing for them, usually display names, so that IRC clients
+    /// highlight them. Users without a nick here keep that text.
+    fn render_pills(&self, data: matrix::events::EventData, mentions: &[(matrix::model::UserID, String)])
+            -> matrix::events::EventData {
+        let pills: Vec<(String, String)> = mentions.iter().filter(|&&(ref user, _)| {
+            self.matrix.uid.as_ref() == Some(user) || self.nicks.user_for(user.nickname.trim()) == Some(user)
+        }).map(|&(ref user, ref shown)| (user.nickname.clone(), shown.clone())).collect();
+        if pills.is_empty() {
+            return data;
+        }
+        match data {
+            matrix::events::EventData::Room(room_id, matrix::events::RoomEvent::Message(user, msgtype, text)) =>
+                matrix::events::EventData::Room(room_id,
+                    matrix::events::RoomEvent::Message(user, msgtype, replace_pills(&text, &pills))),
+            matrix::events::EventData::Room(room_id, matrix::events::RoomEvent::Reply(user, target, text)) =>
+                matrix::events::EventData::Room(room_id,
+                    matrix::events::RoomEvent::Reply(user, target, replace_pills(&text, &pills))),
+            data => data
+        }
+    }
+
+    /// Starts a message with its sender's display name, if the user asked
+    /// for that and the ghost has one. The nick it comes from stays the
+    /// same. A display name not known yet is fetched for later messages.
+    fn render_display_name(&mut self, sender: &matrix::model::UserID, text: String) -> String {
+        if !self.display_names || self.matrix.uid.as_ref() == Some(sender) {
+            return text;
+        }
+        match self.nicks.realname(sender) {
+            Some(ref name) if name != &sender.nickname => format!("<{}> {}", name, text),
+            Some(_) => text,
+            None => {
+                if self.nicks.want_display_name(sender) {
+                    self.profile_fetches.push(sender.clone());
+                }
+                text
+            }
+        }
+    }
+
+    /// Addresses messages mentioning @room to the IRC user, if the config
+    /// says so. Their own messages are left alone.
+    fn render_mentions(&self, sender: &matrix::model::UserID, text: String) -> String {
+        if !self.shared.config.room_mentions {
+            return text;
+        }
+        match self.matrix.uid {
+            Some(ref me) if me != sender => room_mention(me.nickname.trim(), &text).unwrap_or(text),
+            _ => text
+        }
+    }
+
+    /// Turns a reply into a plain message that starts by saying who it
+    /// replies to, links into nicks and channels, and uploads into links
+    /// IRC clients can open
+    fn render_reply(&mut self, data: matrix::events::EventData) -> matrix::events::EventData {
+        match data {
+            matrix::events::EventData::Room(room_id, matrix::events::RoomEvent::Message(user, msgtype, text)) => {
+                let text = self.render_mentions(&user, self.render_links(&text));
+                // An emote already reads as said by the nick
+                let text = match msgtype {
+                    matrix::events::MsgType::Emote => text,
+                    _ => self.render_display_name(&user, text)
+                };
+                let msgtype = match msgtype {
+                    matrix::events::MsgType::Media(kind, mxc) => {
+                        let url = self.matrix.download_url(mxc.trim()).unwrap_or(mxc);
+                        matrix::events::MsgType::Media(kind, url)
+                    },
+                    msgtype => msgtype
+                };
+                matrix::events::EventData::Room(room_id, matrix::events::RoomEvent::Message(user, msgtype, text))
+            },
+            matrix::events::EventData::Room(room_id, matrix::events::RoomEvent::Reply(user, target, text)) => {
+                let replied = self.recall(&room_id, &target).map(|(sender, _)| sender.nickname);
+                let text = self.render_mentions(&user, self.render_links(&text));
+                let text = match replied {
+                    Some(nick) => format!("<replying to {}> {}", nick, text),
+                    None => format!("<replying> {}", text)
+                };
+                let text = self.render_display_name(&user, text);
+                matrix::events::EventData::Room(room_id,
+                    matrix::events::RoomEvent::Message(user, matrix::events::MsgType::Text, text))
+            },
+            data => data
+        }
+    }
+
+    /// Brings a room joined after the initial sync to the IRC side
+    fn open_room<F>(&mut self, id: &matrix::model::RoomID, mut callback: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        let uid = self.matrix.uid.clone().unwrap();
+        let config = self.shared.config.clone();
+        let previous = self.room_from_matrix(id).irc_name.clone();
+        self.room_from_matrix(id).finish_sync(&uid, &config, callback);
+        self.index_channel(id, previous);
+        // Without a timeline of its own yet, history starts from where
+        // the sync is at
+        let since = self.matrix.since().map(|since| since.to_string());
+        {
+            let room = self.room_from_matrix(id);
+            if room.prev_batch.is_none() {
+                room.prev_batch = Some(since);
+            }
+            for (evt, timestamp) in room.take_pending() {
+                room.handle_with_alias(evt, timestamp, callback);
+            }
+        }
+        self.follow_replacement(id, callback);
+    }
+
+    /// Joins a channel, or knocks on it if its room wants that and the
+    /// homeserver knows how
+    fn join_or_knock(&mut self, channel: &str) -> io::Result<usize> {
+        if !self.matrix.supports_knock() {
+            return self.join_channel(channel);
+        }
+        let me = self.matrix.uid.clone();
+        let known = self.room_from_irc(&channel.to_string()).map(|room| {
+            let knock = room.join_rules == Some("knock".to_string()) &&
+                !me.map(|uid| room.members.contains(&uid)).unwrap_or(false);
+            (room.id.clone(), knock)
+        });
+        let knock_on = match known {
+            Some((id, true)) => Some(id),
+            Some((_, false)) => None,
+            None => self.knock_rule(channel)
+        };
+        match knock_on {
+            Some(room_id) => {
+                let reply = match self.matrix.knock(format!("{}", room_id).trim()) {
+                    Ok(_) => format!("Knocked on {}. You can join once someone lets you in.", channel),
+                    Err(err) => format!("Could not knock on {}: {}", channel, err)
+                };
+                self.client.notice(SERVER_NAME, reply.trim())
+            },
+            None => self.join_channel(channel)
+        }
+    }
+
+    /// The room behind a channel we aren't in, if it takes knocks. Rooms
+    /// we're invited to are joined instead, and rooms whose state can't be
+    /// read from outside are left for the join to sort out.
+    fn knock_rule(&mut self, channel: &str) -> Option<matrix::model::RoomID> {
+        let wanted = nicks::irc_lower(channel);
+        if self.invites.values().any(|invite| nicks::irc_lower(&invite_channel(invite, &self.shared.config)) == wanted) {
+            return None;
+        }
+        let homeserver = self.matrix.uid.as_ref().map(|u| u.homeserver.clone()).unwrap_or(String::new());
+        let alias = channels::alias_for(channel, &self.shared.config, homeserver.trim());
+        let room_id = match self.matrix.resolve_alias(alias.trim()) {
+            Ok(id) => id,
+            Err(err) => {
+                debug!("Could not look up {}: {}", alias, err);
+                return None;
+            }
+        };
+        match self.matrix.get_join_rule(&room_id) {
+            Ok(Some(ref rule)) if rule == "knock" => Some(room_id),
+            Ok(_) => None,
+            Err(err) => {
+                debug!("Could not read the join rule of {}: {}", room_id, err);
+                None
+            }
+        }
+    }
+
+    /// Joins a channel on Matrix, and only once that worked and the room's
+    /// state is known, on IRC. Matrix rooms have no passwords, and a key
+    /// must not end up in a membership event where the whole room can read
+    /// it, so channel keys never get here. A refused join gets 475, which has
+    /// clients ask for a key, the closest IRC has to "invite only".
+    fn join_channel(&mut self, channel: &str) -> io::Result<usize> {
+        let known = self.room_from_irc(&channel.to_string()).map(|room| format!("{}", room.id));
+        let wanted = nicks::irc_lower(channel);
+        let invited = self.invites.values()
+            .find(|invite| nicks::irc_lower(&invite_channel(invite, &self.shared.config)) == wanted)
+            .map(|invite| format!("{}", invite.room));
+        let target = match known.or(invited) {
+            Some(id) => id,
+            None => {
+                let homeserver = self.matrix.uid.as_ref().map(|u| u.homeserver.clone()).unwrap_or(String::new());
+                channels::alias_for(channel, &self.shared.config, homeserver.trim())
+            }
+        };
+        let mut messages = vec![];
+        let joined = self.join_room(target.trim(), &mut |msg| messages.push(msg));
+        match joined {
+            Ok(room_id) => {
+                self.invites.remove(&room_id);
+                self.send_all(messages)
+            },
+            Err(err) => {
+                let (numeric, reason) = match (err.errcode(), &err) {
+                    (Some("M_FORBIDDEN"), &matrix::client::ClientError::Matrix(ref e)) if e.error.contains("banned") =>
+                        (474, "Cannot join channel (+b)"),
+                    (Some("M_FORBIDDEN"), _) => (475, "Cannot join channel (+k)"),
+                    (Some("M_GUEST_ACCESS_FORBIDDEN"), _) => (477, "Cannot join channel (+R)"),
+                    _ => (403, "No such channel")
                 };
+                self.notify_error(join_failure(channel, &err).trim());
+                self.client.numeric(numeric, vec![channel.to_string()], reason)
+            }
+        }
+    }
+
+    /// Joins a room on Matrix and brings it to IRC the same way the initial
+    /// sync does. The state of a room that isn't open yet is fetched first,
+    /// so that its name, topic and members are known.
+    fn join_room<F>(&mut self, id_or_alias: &str, mut callback: &mut F) -> matrix::client::Result<matrix::model::RoomID>
+            where F: FnMut(irc::protocol::Message) {
+        let open = self.rooms.values().any(|r| r.irc_name.is_some() && format!("{}", r.id) == id_or_alias);
+        let room_id = if open {
+            try!(self.matrix.join_room(id_or_alias))
+        } else {
+            let (room_id, state) = try!(self.matrix.join_room_with_state(id_or_alias));
+            for evt in state {
+                if let Err(err) = self.handle_matrix(evt) {
+                    warn!("Could not handle state of {}: {}", room_id, err);
+                }
+            }
+            let room = self.room_from_matrix(&room_id);
+            room.members_loaded = true;
+            // The full state has the name and topic if the room has them
+            room.name = room.name.take().or(Some(None));
+            room.topic = room.topic.take().or(Some(None));
+            room_id
+        };
+        self.open_room(&room_id, callback);
+        Ok(room_id)
+    }
+
+    /// Answers a NICK sent once logged in. The nick has to stay the Matrix
+    /// localpart, since that is the ghost nick everyone else sees us by, so
+    /// the Matrix display name changes instead and the nick is left alone.
+    /// Going back to the localpart itself does nothing.
+    fn change_display_name(&mut self, name: &str) -> io::Result<usize> {
+        let me = self.matrix.uid.clone().unwrap();
+        if nicks::irc_lower(name) == nicks::irc_lower(me.nickname.trim()) {
+            return Ok(0);
+        }
+        let reply = match self.matrix.set_display_name(name) {
+            Ok(_) => format!("Your nick stays {}, your Matrix username, but your display name is now {}",
+                             me.nickname, name),
+            Err(err) => format!("Nick changes aren't supported, and your display name could not be set instead: {}", err)
+        };
+        self.client.notice(SERVER_NAME, reply.trim())
+    }
+
+    /// Fills in the name and topic of a room that the sync left out. The
+    /// client only hears of it if that changes what the channel's topic is.
+    fn fetched_title(&mut self, id: &matrix::model::RoomID, name: Option<String>, topic: Option<String>) {
+        let reply = {
+            let room = self.room_from_matrix(id);
+            let before = room.title();
+            room.name = room.name.take().or(Some(name));
+            room.topic = room.topic.take().or(Some(topic));
+            match (room.irc_name.clone(), room.title()) {
+                (Some(channel), Some(title)) if Some(&title) != before.as_ref() => Some((channel, title)),
+                _ => None
+            }
+        };
+        if let Some((channel, title)) = reply {
+            if let Err(err) = self.client.numeric(332, vec![channel], title.trim()) {
+                warn!("Could not send the topic: {}", err);
+            }
+        }
+    }
+
+    /// Changes the topic of a channel's room. Clients offer the topic that
+    /// was shown for editing, so the mark `title` gives encrypted rooms is
+    /// taken off again.
+    fn set_topic(&mut self, channel: &str, text: &str) -> io::Result<usize> {
+        let (room_id, encrypted) = match self.room_from_irc(&channel.to_string()).map(|room| (room.id.clone(), room.is_encrypted())) {
+            Some(room) => room,
+            None => return self.client.numeric(403, vec![channel.to_string()], "No such channel")
+        };
+        let topic = if encrypted { strip_encrypted_mark(text) } else { text };
+        match self.matrix.set_room_topic(&room_id, topic) {
+            Ok(_) => Ok(0),
+            Err(matrix::client::ClientError::Forbidden(reason)) => {
+                self.notify_error(format!("Could not change the topic of {}: {}", channel, reason).trim());
+                self.client.numeric(482, vec![channel.to_string()], "You're not channel operator")
+            },
+            Err(err) => {
+                self.notify_error(format!("Could not change the topic of {}: {}", channel, err).trim());
+                Ok(0)
+            }
+        }
+    }
+
+    /// Answers a TOPIC query with RPL_TOPIC (332), or RPL_NOTOPIC (331).
+    /// Whatever the sync didn't say is fetched first, once.
+    fn topic(&mut self, channel: &str) -> io::Result<usize> {
+        let id = match self.room_from_irc(&channel.to_string()).map(|room| room.id.clone()) {
+            Some(id) => id,
+            None => return self.client.numeric(403, vec![channel.to_string()], "No such channel")
+        };
+        if self.room_from_matrix(&id).topic.is_none() {
+            match self.matrix.get_room_topic(&id) {
+                Ok(topic) => self.room_from_matrix(&id).topic = Some(topic),
+                Err(err) => warn!("Could not fetch the topic of {}: {}", id, err)
+            }
+        }
+        if self.room_from_matrix(&id).name.is_none() {
+            match self.matrix.get_room_name(&id) {
+                Ok(name) => self.room_from_matrix(&id).name = Some(name),
+                Err(err) => warn!("Could not fetch the name of {}: {}", id, err)
+            }
+        }
+        match self.room_from_matrix(&id).title() {
+            Some(title) => self.client.numeric(332, vec![channel.to_string()], title.trim()),
+            None => self.client.numeric(331, vec![channel.to_string()], "No topic is set")
+        }
+    }
+
+    /// Sends the configured message of the day, or else one about the bridge
+    /// and the homeserver it talks to
+    fn motd(&mut self) -> io::Result<usize> {
+        let lines: Vec<String> = match self.shared.config.motd {
+            Some(ref motd) => motd.lines().map(|line| line.to_string()).collect(),
+            None => vec![
+                format!("This is PTO {}, a bridge from IRC to Matrix.", VERSION),
+                format!("Your homeserver is {}", self.matrix.homeserver_url()),
+                "".to_string(),
+                format!("Channels are Matrix rooms, as in /join #room{}example.com for #room:example.com.",
+                        self.shared.config.channel_separator),
+                format!("Say /msg {} help to see what else the bridge can do.", SERVER_NAME)
+            ]
+        };
+        self.client.motd(&lines)
+    }
+
+    /// Fills in the realname of a ghost's JOIN, which extended-join shows,
+    /// with its display name. One that isn't known yet is fetched for next
+    /// time, rather than have the JOIN wait on the homeserver.
+    fn with_realname(&mut self, mut msg: irc::protocol::Message) -> irc::protocol::Message {
+        if msg.command != Command::Join || msg.args.len() < 2 {
+            return msg;
+        }
+        let user = match msg.args[1].parse::<matrix::model::UserID>() {
+            Ok(user) => user,
+            Err(_) => return msg
+        };
+        match self.nicks.realname(&user) {
+            Some(name) => msg.suffix = Some(name),
+            None => if self.nicks.want_display_name(&user) {
+                self.profile_fetches.push(user);
+            }
+        }
+        msg
+    }
+
+    fn send_all(&mut self, messages: Vec<irc::protocol::Message>) -> io::Result<usize> {
+        let mut res = Ok(0);
+        for msg in messages {
+            let msg = self.with_realname(msg);
+            res = res.and(self.client.send(&msg));
+        }
+        res
+    }
+
+    /// Joins the room that replaced an upgraded one, if configured to
+    fn follow_tombstone<F>(&mut self, successor: &matrix::model::RoomID, mut callback: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        if !self.shared.config.follow_tombstones || self.rooms.contains_key(successor) ||
+            self.left_rooms.contains(successor) {
+            return;
+        }
+        if let Err(err) = self.join_room(format!("{}", successor).trim(), callback) {
+            self.notify_error(join_failure(format!("{}", successor).trim(), &err).trim());
+        }
+    }
+
+    /// Follows the upgrade of a room that only just opened. Its tombstone
+    /// may have come with the same sync as the successor, which is then
+    /// already joined.
+    fn follow_replacement<F>(&mut self, id: &matrix::model::RoomID, callback: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        let successor = self.rooms.get(id).and_then(|room| room.replacement.clone());
+        if let Some(successor) = successor {
+            self.follow_tombstone(&successor, callback);
+        }
+    }
+
+    fn handle_matrix(&mut self, evt: matrix::events::Event) -> io::Result<usize> {
+        let duplicate = match evt.id {
+            Some(ref id) =>
+                self.seen_events.contains(id),
+            _ => false
+        } || match (&evt.data, &evt.txn_id, &self.matrix.uid) {
+            (&matrix::events::EventData::Room(ref room_id, matrix::events::RoomEvent::Message(ref sender, _, _)),
+             &Some(ref txn_id), &Some(ref me)) if sender == me =>
+                self.rooms.get_mut(room_id).map(|r| r.take_local_echo(txn_id)).unwrap_or(false),
+            _ => false
+        } || self.from_left_room(&evt.data);
+        if !duplicate {
+            let mut messages: Vec<irc::protocol::Message> = vec![];
+            if let Some(ref id) = evt.id {
                 match evt.data {
+                    matrix::events::EventData::Room(_, matrix::events::RoomEvent::Message(ref user, _, ref text)) |
+                    matrix::events::EventData::Room(_, matrix::events::RoomEvent::Reply(ref user, _, ref text)) =>
+                        self.remember_message(id, user, text),
+                    _ => ()
+                }
+            }
+            let data = self.render_pills(evt.data, &evt.mentions);
+            let data = self.render_reply(data);
+            {
+                let mut append_msg = |msg: irc::protocol::Message| {
+                    messages.push(msg);
+                };
+                match data {
+                    matrix::events::EventData::Room(_, matrix::events::RoomEvent::ServerNotice(_, kind, text)) =>
+                        self.server_notice(kind, text.trim(), evt.timestamp, &mut append_msg),
                     matrix::events::EventData::Room(room_id, room_event) => {
-                        self.room_from_matrix(&room_id).handle_event(room_event, append_msg);
+                        match room_event {
+                            matrix::events::RoomEvent::Membership(ref user, matrix::events::MembershipAction::Join) |
+                            matrix::events::RoomEvent::Renamed(ref user, _) |
+                            matrix::events::RoomEvent::MemberAvatar(ref user) => {
+                                if !self.room_from_matrix(&room_id).is_denied(user) {
+                                    self.nicks.reserve(user);
+                                }
+                            },
+                            _ => ()
+                        }
+                        if let matrix::events::RoomEvent::Renamed(ref user, ref name) = room_event {
+                            self.nicks.set_display_name(user, name.clone());
+                        }
+                        if let matrix::events::RoomEvent::Membership(ref user, matrix::events::MembershipAction::Join) = room_event {
+                            if self.matrix.uid.as_ref() == Some(user) && evt.timestamp.is_some() {
+                                self.room_from_matrix(&room_id).joined_at = evt.timestamp;
+                            }
+                        }
+                        let quitting = match room_event {
+                            matrix::events::RoomEvent::Membership(ref user, matrix::events::MembershipAction::Leave)
+                                if self.leaves_last_room(&room_id, user) => Some(user.clone()),
+                            _ => None
+                        };
+                        let successor = match room_event {
+                            matrix::events::RoomEvent::Tombstone(ref successor) => Some(successor.clone()),
+                            _ => None
+                        };
+                        if let matrix::events::RoomEvent::PinnedEvents(ref user, ref pinned) = room_event {
+                            let new_pins = self.room_from_matrix(&room_id).update_pins(pinned);
+                            self.announce_pins(&room_id, user, new_pins, evt.timestamp, &mut append_msg);
+                        }
+                        match quitting {
+                            Some(user) => self.quit_ghost(&room_id, &user, evt.timestamp, &mut append_msg),
+                            None => self.room_from_matrix(&room_id).handle_event(room_event, evt.timestamp, &mut append_msg)
+                        }
+                        // Rooms that aren't open yet follow theirs once they are
+                        if let Some(successor) = successor {
+                            if self.room_from_matrix(&room_id).irc_name.is_some() {
+                                self.follow_tombstone(&successor, &mut append_msg);
+                            }
+                        }
                     },
                     matrix::events::EventData::Typing(_) => (),
+                    matrix::events::EventData::LeftRoom(room_id) =>
+                        self.left_elsewhere(&room_id, &mut append_msg),
+                    matrix::events::EventData::Invited(invite) => self.invited(invite),
+                    matrix::events::EventData::Presence(presence) =>
+                        self.presence_changed(presence, &mut append_msg),
                     matrix::events::EventData::EndOfSync => self.finish_sync(&mut append_msg),
-                    _ => warn!("Unhandled {}", evt.data.type_str())
+                    // Each poll has one for the room's newest events, paging
+                    // back goes on from the first
+                    matrix::events::EventData::PrevBatch(room_id, token) => {
+                        let room = self.room_from_matrix(&room_id);
+                        if room.prev_batch.is_none() {
+                            room.prev_batch = Some(Some(token));
+                        }
+                    },
+                    // Custom event types are only known by the room they're in
+                    matrix::events::EventData::Unknown(kind, json) => {
+                        let room_id = json.find("room_id").and_then(|id| id.as_string())
+                            .and_then(|id| id.parse::<matrix::model::RoomID>().ok());
+                        match room_id {
+                            Some(ref id) if self.rooms.contains_key(id) =>
+                                self.room_from_matrix(id).handle_event(matrix::events::RoomEvent::Unknown(kind, json),
+                                                                        evt.timestamp, &mut append_msg),
+                            _ => warn!("Unhandled {}", kind)
+                        }
+                    },
+                    _ => warn!("Unhandled {}", data.type_str())
                 }
             }
             match evt.id {
@@ -309,10 +2444,11 @@ impl Bridge {
                 None => ()
             };
             let mut res: Option<io::Result<usize>> = None;
-            for ref msg in messages {
+            for msg in messages {
+                let msg = self.with_realname(msg);
                 res = Some(match res {
-                    None => self.client.send(msg),
-                    Some(r) => r.and(self.client.send(msg))
+                    None => self.client.send(&msg),
+                    Some(r) => r.and(self.client.send(&msg))
                 })
             }
             match res {
@@ -324,39 +2460,735 @@ impl Bridge {
         }
     }
 
-    fn poll_matrix(&mut self, channel: mio::Sender<Event>) ->
-        thread::JoinHandle<matrix::client::Result> {
-        let poll = self.matrix.poll_async();
-        thread::spawn(move|| {
-            poll.send().and_then(|evts| {
-                for evt in evts {
-                    channel.send(Event::Matrix(evt)).unwrap();
+    fn poll_matrix(&mut self, channel: mio::Sender<Event>) {
+        if self.polling {
+            debug!("A poll is already running");
+            return;
+        }
+        if !self.matrix.is_logged_in() {
+            debug!("Not polling until we're logged in again");
+            return;
+        }
+        self.polling = true;
+        let poll = self.matrix.poll_async(self.cancel_poll.clone());
+        thread::spawn(move|| {
+            // A send only fails once the session is gone, and then nobody
+            // is left to tell
+            match poll.send() {
+                Ok((evts, next_batch)) => {
+                    for evt in evts {
+                        if channel.send(Event::Matrix(evt)).is_err() {
+                            debug!("Session ended while handing over a poll");
+                            return;
+                        }
+                    };
+                    let _ = channel.send(Event::EndPoll(next_batch));
+                },
+                Err(matrix::client::ClientError::Cancelled) => debug!("Poll cancelled"),
+                Err(err) => {
+                    let _ = channel.send(Event::PollFailed(err));
+                }
+            }
+        });
+    }
+
+    /// Deals with the homeserver logging us out softly. A refreshed token
+    /// carries on as if nothing happened. Otherwise the user has to log in
+    /// again, and everything but the token is kept so that can resume.
+    fn soft_logout(&mut self, channel: mio::Sender<Event>) {
+        match self.matrix.refresh() {
+            Ok(_) => {
+                debug!("Refreshed the access token after a soft logout");
+                self.poll_matrix(channel);
+            },
+            Err(err) => {
+                debug!("Could not refresh the access token: {}", err);
+                self.matrix.soft_logout();
+                self.notify_error("Your homeserver logged this session out. To carry on, /msg pto login <password>, \
+                                   or /msg pto login-token <token> if you use single sign-on.");
+            }
+        }
+    }
+
+    /// Stops polling with an access token the homeserver turned down for
+    /// good, since retrying won't change its mind. The rooms are kept for
+    /// the user to log in again.
+    fn token_rejected(&mut self) {
+        self.matrix.soft_logout();
+        self.notify_error("Your homeserver no longer accepts this session. To carry on, /msg pto login <password>, \
+                           or /msg pto login-token <token> if you use single sign-on.");
+    }
+
+    /// Picks up where a session left off after logging in again, or starts
+    /// a new one with the initial sync
+    fn resume_matrix(&mut self, channel: mio::Sender<Event>) -> matrix::client::Result {
+        if self.rooms.is_empty() {
+            self.start_matrix(channel)
+        } else {
+            self.poll_matrix(channel);
+            Ok(())
+        }
+    }
+
+    fn start_matrix(&mut self, channel: mio::Sender<Event>) ->
+        matrix::client::Result {
+        if self.client.has_cap("away-notify") || self.shared.config.presence {
+            self.matrix.want_presence();
+        }
+        self.load_preferences();
+        self.matrix.sync().and_then(|sync| {
+            sync.each(|e| {
+                match self.handle_matrix(e) {
+                    // FIXME: Return error
+                    Err(err) => warn!("Could not handle matrix event: {:?}", err),
+                    _ => ()
+                }
+            })
+        }).and_then(|next_batch| {
+            if let Some(token) = next_batch {
+                self.matrix.set_since(token);
+            }
+            self.poll_matrix(channel);
+            Ok(())
+        })
+    }
+
+    /// Where the session for a set of credentials is stored. The file is
+    /// named after a PBKDF2 of both username and password, so that a stored
+    /// token can only be resumed by someone who knows the password. Each
+    /// user has a random salt of their own, kept next to the sessions, so
+    /// file names make for slow password guessing at best.
+    fn session_path(&self, username: &str, password: &str) -> Option<PathBuf> {
+        let dir = match self.shared.config.session_dir {
+            Some(ref dir) => Path::new(dir).to_path_buf(),
+            None => return None
+        };
+        let user = hash::hash(hash::Type::SHA256, username.as_bytes()).to_hex();
+        let salt_path = dir.join(format!("{}.salt", user));
+        let mut salt = String::new();
+        if let Err(err) = fs::File::open(&salt_path).and_then(|mut file| file.read_to_string(&mut salt)) {
+            debug!("No salt at {:?} yet: {}", salt_path, err);
+        }
+        if salt.trim().is_empty() {
+            salt = rand::rand_bytes(16).to_hex();
+            if let Err(err) = write_private(&salt_path, salt.as_bytes()) {
+                warn!("Could not save a salt to {:?}, not keeping the session: {}", salt_path, err);
+                return None;
+            }
+        }
+        let credentials = format!("{}:{}", username, password);
+        let key = pkcs5::pbkdf2_hmac_sha1(&credentials, salt.trim().as_bytes(), SESSION_KDF_ROUNDS, 32);
+        Some(dir.join(format!("{}.json", key.to_hex())))
+    }
+
+    /// Resumes a stored session if there is a usable one, otherwise logs in
+    /// to Matrix with a password. Either way, the initial sync follows,
+    /// unless the session's last IRC client only just hung up.
+    fn login(&mut self, username: &str, password: &str, channel: Sender<Event>) -> matrix::client::Result {
+        let session = self.session_path(username, password);
+        self.session_file = session.clone();
+        if let Some(ref path) = session {
+            match matrix::client::Client::load_session(path) {
+                Ok(mut client) => {
+                    client.negotiate_version();
+                    match client.whoami() {
+                        Ok(uid) => {
+                            if self.reattach(&uid, channel.clone()) {
+                                return Ok(());
+                            }
+                            debug!("Resuming stored session for {:?} from {:?}", uid, path);
+                            Self::configure_matrix(&mut client, &self.shared.config);
+                            self.matrix = client;
+                            match self.start_matrix(channel.clone()) {
+                                Ok(_) => return Ok(()),
+                                Err(err) => warn!("Could not sync stored session, logging in again: {:?}", err)
+                            }
+                        },
+                        Err(matrix::client::ClientError::Unauthorized) =>
+                            debug!("Stored session token was rejected, logging in again"),
+                        Err(err) =>
+                            warn!("Could not validate stored session, logging in again: {:?}", err)
+                    }
+                },
+                Err(err) => debug!("No stored session at {:?}: {}", path, err)
+            }
+        }
+        self.matrix.negotiate_version();
+        match self.matrix.login_flows() {
+            Ok(ref flows) if !flows.iter().any(|f| f == "m.login.password") =>
+                return Err(matrix::client::ClientError::UnsupportedFlows(flows.clone())),
+            Ok(_) => (),
+            // Try anyway, the login itself will say what's wrong
+            Err(err) => warn!("Could not ask the homeserver how to log in: {}", err)
+        }
+        try!(self.matrix.login(username, password));
+        if let Some(ref path) = session {
+            if let Err(err) = self.matrix.save_session(path) {
+                warn!("Could not save session to {:?}: {}", path, err);
+            }
+        }
+        self.start_matrix(channel)
+    }
+
+    /// Runs a command sent to the bridge with /msg pto
+    fn handle_control(&mut self, text: &str, events: &mut EventLoop<Bridge>) -> io::Result<usize> {
+        let channel = events.channel();
+        match ControlCommand::from_str(text) {
+            Err(usage) => self.client.notice(SERVER_NAME, usage.trim()),
+            Ok(ControlCommand::Help) => {
+                let mut res = Ok(0);
+                for line in control::HELP {
+                    res = res.and(self.client.notice(SERVER_NAME, line));
+                }
+                res
+            },
+            Ok(ControlCommand::Register(username, password)) => {
+                if self.matrix.uid.is_some() {
+                    return self.client.notice(SERVER_NAME, "You are already logged in.");
+                }
+                self.matrix.negotiate_version();
+                match self.matrix.register(username.trim(), password.trim()) {
+                    Ok(_) => {
+                        self.session_file = self.session_path(username.trim(), password.trim());
+                        if let Some(ref path) = self.session_file {
+                            if let Err(err) = self.matrix.save_session(path) {
+                                warn!("Could not save session to {:?}: {}", path, err);
+                            }
+                        }
+                        let res = self.client.notice(SERVER_NAME, format!("Registered and logged in as {}.", username).trim());
+                        if let Err(err) = self.start_matrix(channel) {
+                            self.notify_error(format!("Could not sync after registering: {}", err).trim());
+                        }
+                        res
+                    },
+                    Err(ref err) if err.errcode() == Some("M_USER_IN_USE") =>
+                        self.client.notice(SERVER_NAME, format!("The username {} is already taken.", username).trim()),
+                    Err(err) =>
+                        self.client.notice(SERVER_NAME, format!("Could not register: {}", err).trim())
+                }
+            },
+            Ok(ControlCommand::LoginToken(token)) => {
+                if self.matrix.is_logged_in() {
+                    return self.client.notice(SERVER_NAME, "You are already logged in.");
+                }
+                self.matrix.negotiate_version();
+                match self.matrix.login_flows() {
+                    Ok(ref flows) if !flows.iter().any(|f| f == "m.login.token") =>
+                        return self.client.notice(SERVER_NAME,
+                            format!("This homeserver doesn't support token login, it offers: {}", flows.join(", ")).trim()),
+                    Ok(_) => (),
+                    Err(err) => warn!("Could not ask the homeserver how to log in: {}", err)
+                }
+                match self.matrix.login_with_token(token.trim()) {
+                    Ok(_) => {
+                        let uid = format!("{}", self.matrix.uid.as_ref().unwrap());
+                        let res = self.client.notice(SERVER_NAME, format!("Logged in as {}.", uid).trim());
+                        if let Err(err) = self.resume_matrix(channel) {
+                            self.notify_error(format!("Could not sync after logging in: {}", err).trim());
+                        }
+                        res
+                    },
+                    // Servers without token login reject the login type itself
+                    Err(ref err) if err.errcode() == Some("M_UNKNOWN") || err.errcode() == Some("M_UNRECOGNIZED") =>
+                        self.client.notice(SERVER_NAME, format!("This homeserver doesn't support token login: {}", err).trim()),
+                    Err(ref err) if err.errcode() == Some("M_FORBIDDEN") =>
+                        self.client.notice(SERVER_NAME, "The login token was rejected. Tokens are short-lived and work only once, get a new one and try again."),
+                    Err(err) =>
+                        self.client.notice(SERVER_NAME, format!("Could not log in: {}", err).trim())
+                }
+            },
+            Ok(ControlCommand::Login(password)) => {
+                let username = match self.matrix.uid {
+                    Some(ref uid) if !self.matrix.is_logged_in() => uid.nickname.clone(),
+                    Some(_) => return self.client.notice(SERVER_NAME, "You are already logged in."),
+                    None => return self.client.notice(SERVER_NAME, "Log in by reconnecting with your password as the server password.")
+                };
+                match self.matrix.login(username.trim(), password.trim()) {
+                    Ok(_) => {
+                        let res = self.client.notice(SERVER_NAME, "Logged in again.");
+                        if let Err(err) = self.resume_matrix(channel) {
+                            self.notify_error(format!("Could not sync after logging in: {}", err).trim());
+                        }
+                        res
+                    },
+                    Err(err) =>
+                        self.client.notice(SERVER_NAME, format!("Could not log in: {}", err).trim())
+                }
+            },
+            Ok(ControlCommand::LogoutAll) => {
+                if !self.matrix.is_logged_in() {
+                    return self.client.notice(SERVER_NAME, "You aren't logged in.");
+                }
+                match self.matrix.logout_all() {
+                    Ok(_) => {
+                        // The stored token is dead, and must not be tried again
+                        if let Some(path) = self.session_file.take() {
+                            if let Err(err) = fs::remove_file(&path) {
+                                warn!("Could not remove session at {:?}: {}", path, err);
+                            }
+                        }
+                        let res = self.client.notice(SERVER_NAME, "Logged out every device of your account, disconnecting.");
+                        if let Err(err) = self.client.flush() {
+                            warn!("Could not write to the IRC client: {}", err);
+                        }
+                        self.shutdown(events);
+                        res
+                    },
+                    Err(err) =>
+                        self.client.notice(SERVER_NAME, format!("Could not log out: {}", err).trim())
+                }
+            },
+            Ok(ControlCommand::Devices) => {
+                if !self.matrix.is_logged_in() {
+                    return self.client.notice(SERVER_NAME, "You aren't logged in.");
+                }
+                match self.matrix.devices() {
+                    Ok(devices) => {
+                        let mut res = Ok(0);
+                        for device in devices {
+                            let mut line = device.device_id.clone();
+                            if let Some(name) = device.display_name {
+                                line.push_str(format!(" \"{}\"", name).trim_right());
+                            }
+                            if let Some(ip) = device.last_seen_ip {
+                                line.push_str(format!(", last seen from {}", ip).trim_right());
+                            }
+                            if self.matrix.device_id() == Some(device.device_id.trim()) {
+                                line.push_str(" (this bridge)");
+                            }
+                            res = res.and(self.client.notice(SERVER_NAME, line.trim()));
+                        }
+                        res
+                    },
+                    Err(err) =>
+                        self.client.notice(SERVER_NAME, format!("Could not list devices: {}", err).trim())
+                }
+            },
+            Ok(ControlCommand::DeleteDevice(device_id)) => {
+                if !self.matrix.is_logged_in() {
+                    return self.client.notice(SERVER_NAME, "You aren't logged in.");
+                }
+                if self.matrix.device_id() == Some(device_id.trim()) {
+                    return self.client.notice(SERVER_NAME, "That is this bridge's device, disconnect instead.");
+                }
+                let reply = match self.matrix.delete_device(device_id.trim()) {
+                    Ok(_) => format!("Logged out device {}.", device_id),
+                    Err(matrix::client::ClientError::UnsupportedFlows(ref flows)) =>
+                        format!("Your homeserver wants you to confirm deleting a device ({}), which can't be done \
+                                 from IRC. Delete it from another Matrix client instead.", flows.join(", ")),
+                    Err(err) => format!("Could not delete device {}: {}", device_id, err)
+                };
+                self.client.notice(SERVER_NAME, reply.trim())
+            },
+            Ok(ControlCommand::History(channel, count)) => self.history(channel.trim(), count),
+            Ok(ControlCommand::Mute(channel)) => self.mute(channel.trim(), true),
+            Ok(ControlCommand::Unmute(channel)) => self.mute(channel.trim(), false),
+            Ok(ControlCommand::Redact(n)) => self.redact(n),
+            Ok(ControlCommand::Avatar(channel, mxc)) => self.avatar(channel.trim(), mxc),
+            Ok(ControlCommand::DisplayNames(on)) => {
+                self.display_names = on;
+                self.save_preferences();
+                let reply = if on {
+                    "Messages now start with the display name of their sender, once it is known."
+                } else {
+                    "Messages no longer start with the display name of their sender."
+                };
+                self.client.notice(SERVER_NAME, reply)
+            }
+        }
+    }
+
+    /// Stops showing the messages of a channel, or starts again. The
+    /// room's state and members are followed all along.
+    fn mute(&mut self, channel: &str, muted: bool) -> io::Result<usize> {
+        let room_id = match self.room_from_irc(&channel.to_string()).map(|room| room.id.clone()) {
+            Some(id) => id,
+            None => return self.client.numeric(403, vec![channel.to_string()], "No such channel")
+        };
+        self.room_from_matrix(&room_id).muted = muted;
+        if muted {
+            self.muted.insert(room_id);
+        } else {
+            self.muted.remove(&room_id);
+        }
+        self.save_preferences();
+        let reply = if muted {
+            format!("Muted {}. Say \"unmute {}\" to see its messages again.", channel, channel)
+        } else {
+            format!("Unmuted {}.", channel)
+        };
+        self.client.notice(SERVER_NAME, reply.trim())
+    }
+
+    /// The user's bridge preferences, as they are kept in account data
+    fn preferences(&self) -> Json {
+        let mut muted: Vec<String> = self.muted.iter().map(|id| format!("{}", id)).collect();
+        muted.sort();
+        let mut prefs = BTreeMap::new();
+        prefs.insert("muted".to_string(), Json::Array(muted.into_iter().map(Json::String).collect()));
+        prefs.insert("display_names".to_string(), Json::Boolean(self.display_names));
+        Json::Object(prefs)
+    }
+
+    /// Keeps the user's preferences in their account data, so that they
+    /// follow them to any bridge. Should the homeserver not take them, the
+    /// muted rooms are kept next to the session file instead.
+    fn save_preferences(&mut self) {
+        let prefs = self.preferences();
+        if let Err(err) = self.matrix.set_account_data(matrix::client::ACCOUNT_DATA_TYPE, &prefs) {
+            warn!("Could not save preferences to account data, keeping mutes locally: {}", err);
+            self.save_mutes();
+        }
+    }
+
+    /// Restores the preferences kept in account data. Without any there,
+    /// the muted rooms kept next to the session file are used.
+    fn load_preferences(&mut self) {
+        match self.matrix.get_account_data(matrix::client::ACCOUNT_DATA_TYPE) {
+            Ok(ref prefs) if prefs.find("muted").is_some() => {
+                self.muted = prefs.find("muted").and_then(|m| m.as_array()).map(|muted| {
+                    muted.iter().filter_map(|id| id.as_string()).filter_map(|id| id.parse().ok()).collect()
+                }).unwrap_or(HashSet::new());
+                if let Some(on) = prefs.find("display_names").and_then(|d| d.as_boolean()) {
+                    self.display_names = on;
+                }
+            },
+            Ok(_) => self.load_mutes(),
+            Err(err) => {
+                debug!("Could not read preferences from account data: {}", err);
+                self.load_mutes();
+            }
+        }
+        for room in self.rooms.values_mut() {
+            room.muted = self.muted.contains(&room.id);
+        }
+    }
+
+    /// Where the rooms muted in a stored session are kept, next to it
+    fn mutes_path(&self) -> Option<PathBuf> {
+        self.session_file.as_ref().map(|path| path.with_extension("muted"))
+    }
+
+    /// Writes the muted rooms out, one room ID per line
+    fn save_mutes(&self) {
+        if let Some(path) = self.mutes_path() {
+            let rooms: Vec<String> = self.muted.iter().map(|id| format!("{}", id)).collect();
+            // Which rooms someone is in is as private as their session
+            if let Err(err) = write_private(&path, rooms.join("\n").as_bytes()) {
+                warn!("Could not save muted rooms to {:?}: {}", path, err);
+            }
+        }
+    }
+
+    fn load_mutes(&mut self) {
+        let mut contents = String::new();
+        if let Some(path) = self.mutes_path() {
+            if let Err(err) = fs::File::open(&path).and_then(|mut file| file.read_to_string(&mut contents)) {
+                debug!("No muted rooms at {:?}: {}", path, err);
+            }
+        }
+        self.muted = contents.lines().filter_map(|line| line.trim().parse().ok()).collect();
+    }
+
+    /// Shows messages of a channel from before those already shown, with
+    /// the time they were sent in their server-time tags. Each time goes
+    /// further back.
+    fn history(&mut self, channel: &str, count: usize) -> io::Result<usize> {
+        let room = self.room_from_irc(&channel.to_string()).map(|room| (room.id.clone(), room.prev_batch.clone(), room.history_start()));
+        let (room_id, from, start) = match room {
+            Some((id, Some(Some(from)), start)) => (id, from, start),
+            Some((_, _, Some(_))) =>
+                return self.client.notice(SERVER_NAME, format!("{} only shows its members what was said since they joined.", channel).trim()),
+            Some(_) => return self.client.notice(SERVER_NAME, format!("There is nothing older in {}.", channel).trim()),
+            None => return self.client.numeric(403, vec![channel.to_string()], "No such channel")
+        };
+        let mut from = from;
+        let mut more = false;
+        let mut messages = vec![];
+        // A page may hold nothing that was said, like a run of state
+        // changes, so keep going back while the token does
+        for _ in 0..HISTORY_PAGES {
+            let (evts, end) = match self.matrix.get_messages(&room_id, from.trim(), cmp::min(count, HISTORY_LIMIT)) {
+                Ok(page) => page,
+                Err(err) => return self.client.notice(SERVER_NAME, format!("Could not fetch older messages of {}: {}", channel, err).trim())
+            };
+            // Stop at our join if the room hides what came before
+            let reached_start = match start {
+                Some(start) => evts.iter().any(|evt| evt.timestamp.map_or(false, |t| t <= start)),
+                None => false
+            };
+            let end = if reached_start { None } else { end };
+            self.room_from_matrix(&room_id).prev_batch = Some(end.clone());
+            for evt in evts {
+                let timestamp = evt.timestamp;
+                if let (Some(start), Some(timestamp)) = (start, timestamp) {
+                    if timestamp < start {
+                        continue;
+                    }
+                }
+                // Only what was said, the room's state is already known
+                let data = self.render_pills(evt.data, &evt.mentions);
+                if let matrix::events::EventData::Room(_, evt @ matrix::events::RoomEvent::Message(_, _, _)) = self.render_reply(data) {
+                    self.room_from_matrix(&room_id).handle_with_alias(evt, timestamp, &mut |msg| messages.push(msg));
+                }
+            }
+            match end {
+                Some(end) => if messages.is_empty() { from = end; more = true } else { break },
+                None => { more = false; break }
+            }
+        }
+        if messages.is_empty() && more {
+            return self.client.notice(SERVER_NAME, format!("Nothing was said lately in {}, ask again to go further back.", channel).trim());
+        }
+        if messages.is_empty() {
+            return self.client.notice(SERVER_NAME, format!("There is nothing older in {}.", channel).trim());
+        }
+        self.send_all(messages)
+    }
+
+    /// Shows where the avatar of a channel can be downloaded, or changes it
+    /// to media uploaded before
+    fn avatar(&mut self, channel: &str, mxc: Option<String>) -> io::Result<usize> {
+        let room_id = match self.room_from_irc(&channel.to_string()).map(|room| room.id.clone()) {
+            Some(id) => self.resolve_room(&id),
+            None => return self.client.numeric(403, vec![channel.to_string()], "No such channel")
+        };
+        let reply = match mxc {
+            None => match self.matrix.get_room_avatar(&room_id) {
+                Ok(Some(uri)) => {
+                    let download = self.matrix.download_url(uri.trim());
+                    let link = download.unwrap_or(uri);
+                    format!("The avatar of {} is {}", channel, link)
+                },
+                Ok(None) => format!("{} has no avatar.", channel),
+                Err(err) => format!("Could not fetch the avatar of {}: {}", channel, err)
+            },
+            Some(mxc) => match self.matrix.set_room_avatar(&room_id, mxc.trim()) {
+                Ok(()) => format!("Changed the avatar of {}.", channel),
+                Err(matrix::client::ClientError::Forbidden(reason)) =>
+                    format!("You can't change the avatar of {}: {}", channel, reason),
+                Err(err) => format!("Could not change the avatar of {}: {}", channel, err)
+            }
+        };
+        self.client.notice(SERVER_NAME, reply.trim())
+    }
+
+    /// Deletes the n-th last message the user sent from this session, 1
+    /// being the last one
+    fn redact(&mut self, n: usize) -> io::Result<usize> {
+        if n > self.sent.len() {
+            return self.client.notice(SERVER_NAME, format!("You only sent {} messages lately that can be deleted.",
+                                                           self.sent.len()).trim());
+        }
+        let idx = self.sent.len() - n;
+        let (room_id, event_id, channel, text) = self.sent[idx].clone();
+        match self.matrix.redact_event(&room_id, &event_id, None) {
+            Ok(_) => {
+                self.sent.remove(idx);
+                self.client.notice(SERVER_NAME, format!("Deleted your message to {}: {}", channel, text).trim())
+            },
+            Err(matrix::client::ClientError::Forbidden(reason)) =>
+                self.client.notice(SERVER_NAME, format!("You may not delete your message to {}: {}", channel, reason).trim()),
+            Err(err) =>
+                self.client.notice(SERVER_NAME, format!("Could not delete your message to {}: {}", channel, err).trim())
+        }
+    }
+
+    /// Lifts a ban, given as a MODE -b mask. The user's membership change
+    /// comes back from Matrix, so the ban list is updated right away and
+    /// the mode change echoed.
+    fn unban(&mut self, channel: &str, mask: &str) -> io::Result<usize> {
+        let (room_id, user) = match self.room_from_irc(&channel.to_string()).map(|room| (room.id.clone(), room.banned_user(mask))) {
+            Some((id, Some(user))) => (id, user),
+            Some((_, None)) => return self.client.numeric(401, vec![mask.to_string()], "No such nick/channel"),
+            None => return self.client.numeric(403, vec![channel.to_string()], "No such channel")
+        };
+        match self.matrix.unban_user(&room_id, &user) {
+            Ok(_) => {
+                self.room_from_matrix(&room_id).banned.retain(|banned| banned != &user);
+                let me = self.matrix.uid.clone().unwrap();
+                self.client.send(&Message {
+                    tags: vec![],
+                    prefix: Some(format!("{}!{}@{}", me.nickname, me.nickname, me.homeserver)),
+                    command: Command::Mode,
+                    args: vec![channel.to_string(), "-b".to_string(), format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver)],
+                    suffix: None
+                })
+            },
+            Err(matrix::client::ClientError::Forbidden(reason)) => {
+                self.notify_error(format!("Could not unban {} from {}: {}", user, channel, reason).trim());
+                self.client.numeric(482, vec![channel.to_string()], "You're not channel operator")
+            },
+            Err(err) => {
+                self.notify_error(format!("Could not unban {} from {}: {}", user, channel, err).trim());
+                Ok(0)
+            }
+        }
+    }
+
+    /// Sends an announcement, such as of maintenance, to every session of
+    /// the bridge, this one included. Only operators may, logged in to the
+    /// bridge's own homeserver: any other could claim to be anyone.
+    fn wallops(&mut self, text: &str) -> io::Result<usize> {
+        let operator = match self.matrix.uid {
+            Some(ref uid) if self.homeserver.is_none() && self.shared.config.operators.contains(&format!("{}", uid)) =>
+                uid.clone(),
+            _ => return self.client.numeric(481, vec![], "Permission Denied- You're not an IRC operator")
+        };
+        if text.is_empty() {
+            return self.client.numeric(461, vec!["WALLOPS".to_string()], "Not enough parameters");
+        }
+        for (token, session) in self.shared.sessions.lock().unwrap().iter() {
+            // A session that is ending can't take it anymore
+            if let Err(err) = session.send(Event::Wallops(operator.clone(), text.to_string())) {
+                debug!("Session {:?} missed an announcement: {:?}", token, err);
+            }
+        }
+        Ok(0)
+    }
+
+    /// Answers NAMES, first fetching the full member list if the sync only
+    /// lazy-loaded part of it
+    fn names(&mut self, channel: &str) -> io::Result<usize> {
+        let my_nick = match self.matrix.uid {
+            Some(ref uid) => uid.nickname.clone(),
+            None => "*".to_string()
+        };
+        let room_id = match self.room_from_irc(&channel.to_string()).map(|room| room.id.clone()) {
+            Some(id) => id,
+            None => return self.client.numeric(366, vec![channel.to_string()], "End of /NAMES list")
+        };
+        if !self.room_from_matrix(&room_id).members_loaded {
+            match self.matrix.members(&room_id) {
+                Ok(stream) => return self.stream_names(&room_id, my_nick.trim(), stream),
+                Err(err) => self.notify_error(format!("Could not fetch the members of {}: {}", channel, err).trim())
+            }
+        }
+        let mut messages = vec![];
+        self.room_from_matrix(&room_id).names(my_nick.trim(), &mut |msg| messages.push(msg));
+        self.send_all(messages)
+    }
+
+    /// Answers NAMES with the members of a room as they are read from
+    /// `stream`, so that huge rooms start listing right away. Once all of
+    /// them are in, they replace the lazily-loaded member list.
+    fn stream_names(&mut self, room_id: &matrix::model::RoomID, my_nick: &str,
+                    stream: matrix::client::MemberStream) -> io::Result<usize> {
+        let channel = self.room_from_matrix(room_id).irc_name.clone().unwrap();
+        let mut members = vec![];
+        let mut line = vec![];
+        let mut length = 0;
+        let res = {
+            let nicks = &mut self.nicks;
+            let client = &mut self.client;
+            let room = &self.rooms[room_id];
+            stream.each(|evt| {
+                // The last change of a member may have been to their profile
+                let user = match evt.data {
+                    matrix::events::EventData::Room(_, matrix::events::RoomEvent::Membership(user, matrix::events::MembershipAction::Join)) |
+                    matrix::events::EventData::Room(_, matrix::events::RoomEvent::Renamed(user, _)) |
+                    matrix::events::EventData::Room(_, matrix::events::RoomEvent::MemberAvatar(user)) => user,
+                    _ => return
                 };
-                channel.send(Event::EndPoll).unwrap();
-                Ok(())
+                nicks.reserve(&user);
+                let nick = room.prefixed_nick(&user);
+                length += nick.len() + 1;
+                line.push(nick);
+                members.push(user);
+                if length >= NAMES_LENGTH {
+                    if let Err(err) = client.send(&names_message(my_nick, channel.trim(), &line)) {
+                        warn!("Could not send NAMES of {}: {}", channel, err);
+                    }
+                    line.clear();
+                    length = 0;
+                }
             })
-        })
+        };
+        if !line.is_empty() {
+            try!(self.client.send(&names_message(my_nick, channel.trim(), &line)));
+        }
+        match res {
+            Ok(()) => self.room_from_matrix(room_id).load_members(members),
+            Err(err) => self.notify_error(format!("Could not fetch all the members of {}: {}", channel, err).trim())
+        }
+        self.client.send(&end_of_names(my_nick, channel.trim()))
     }
 
-    fn start_matrix(&mut self, channel: mio::Sender<Event>) ->
-        matrix::client::Result {
-        self.matrix.sync().and_then(|events| {
-            for e in events {
-                match self.handle_matrix(e) {
-                    // FIXME: Return error
-                    Err(err) => warn!("Could not handle matrix event: {:?}", err),
-                    _ => ()
+    /// Completes registration once both NICK and USER have arrived, in
+    /// whichever order, by welcoming the client and logging in to Matrix
+    /// with what it sent. A client negotiating capabilities is only
+    /// registered once it sends CAP END, so that nothing goes out before the
+    /// capabilities it asks for are on.
+    fn register(&mut self, events: &mut EventLoop<Bridge>) {
+        if self.registered || self.client.is_negotiating() ||
+            !self.client.has_nickname() || !self.client.auth.has_username() {
+            return;
+        }
+        self.registered = true;
+        let auth = self.client.auth.consume();
+        if let Some(ref homeserver) = auth.homeserver {
+            let url = matrix::client::discover(format!("https://{}", homeserver).trim());
+            info!("Using the homeserver at {} for this connection", url);
+            self.matrix = Self::matrix_client(url.trim(), &self.shared.config);
+            self.homeserver = Some(homeserver.clone());
+        }
+        let username = auth.username.unwrap();
+        self.client.welcome(username.trim()).expect("Could not send welcome");
+        self.motd().expect("Could not send MOTD");
+        match auth.password {
+            Some(password) => {
+                match self.login(username.trim(), password.trim(), events.channel()) {
+                    Ok(_) => debug!("Logged in a user"),
+                    Err(matrix::client::ClientError::UnsupportedFlows(ref flows))
+                            if flows.iter().any(|f| f == "m.login.token") => {
+                        self.notify_error(format!("This homeserver doesn't accept passwords, it offers: {}", flows.join(", ")).trim());
+                        self.client.notice(SERVER_NAME, "Sign in through your homeserver, then /msg pto login-token <token>")
+                            .expect("Could not send NOTICE");
+                    },
+                    Err(err) => {
+                        self.notify_error(format!("Could not log in to Matrix: {}", err).trim());
+                        self.client.notice(SERVER_NAME, "To create a new account, /msg pto register <username> <password>")
+                            .expect("Could not send NOTICE");
+                    }
                 }
+            },
+            None => {
+                // Users of single sign-on have no password, and log in
+                // with a token once they're connected
+                self.notify_error("A password is needed to log in to Matrix, anonymous access isn't supported yet.");
+                self.client.notice(SERVER_NAME, "If your homeserver uses single sign-on, /msg pto login-token <token>")
+                    .expect("Could not send NOTICE");
             }
-            self.poll_matrix(channel);
-            Ok(())
-        })
+        }
+    }
+
+    /// Answers WHO for a channel or the nick of a ghost, flagging ghosts as
+    /// here (H) or gone (G) according to their presence
+    fn who(&mut self, mask: &str) -> io::Result<usize> {
+        let users: Vec<(String, matrix::model::UserID)> = match self.room_from_irc(&mask.to_string())
+                .map(|room| room.members.clone()) {
+            Some(members) => members.into_iter().map(|user| (mask.to_string(), user)).collect(),
+            None => self.nicks.user_for(mask).map(|user| ("*".to_string(), user.clone())).into_iter().collect()
+        };
+        for (channel, user) in users {
+            let flag = if self.nicks.is_away(&user) { "G" } else { "H" };
+            let realname = self.nicks.realname(&user).unwrap_or(user.nickname.clone());
+            try!(self.client.numeric(352, vec![channel, user.nickname.clone(), user.homeserver.clone(),
+                                               SERVER_NAME.to_string(), user.nickname.clone(), flag.to_string()],
+                                     format!("0 {}", realname).trim()));
+        }
+        self.client.numeric(315, vec![mask.to_string()], "End of /WHO list")
     }
 
     fn handle_client(&mut self, events: &mut EventLoop<Bridge>) {
         loop {
             match self.client.read_message() {
-                None => return,
+                None => {
+                    if self.client.is_closed() {
+                        self.disconnected(events);
+                    }
+                    return;
+                },
                 Some(message) => {
                     match message.command {
                         Command::Pass => {
@@ -367,52 +3199,200 @@ impl Bridge {
                                 None => message.args[0].clone(),
                                 Some(n) => n
                             };
-                            self.client.set_nickname(nickname)
+                            if self.matrix.uid.is_some() {
+                                self.change_display_name(nickname.trim()).expect("Could not reply to NICK");
+                            } else if self.nicks.is_taken(nickname.trim(), None) {
+                                self.client.nick_in_use(nickname.trim()).expect("Could not send 433");
+                            } else {
+                                self.nicks.claim_login(nickname.trim());
+                                self.client.set_nickname(nickname);
+                                self.register(events);
+                            }
                         },
                         Command::User => {
-                            self.client.auth.set_username(message.args[0].clone());
-                            let auth = self.client.auth.consume();
-                            match (auth.username, auth.password) {
-                                (Some(username), Some(password)) => {
-                                    self.matrix.login(username.trim(), password.trim())
-                                        .and_then(|_| {
-                                            self.start_matrix(events.channel())
-                                        })
-                                        .and_then(|_| {
-                                            self.client.welcome(username.trim()).unwrap();
-                                            debug!("Logged in a user");
-                                            Ok(())
-                                        }).expect("Could not login!");
-                                },
-                                _ => panic!("Username and/or password missing, and anonymous access isn't built yet.")
-                            };
+                            if self.registered {
+                                self.client.numeric(462, vec![], "You may not reregister").expect("Could not send 462");
+                            } else if message.args.is_empty() {
+                                self.client.numeric(461, vec!["USER".to_string()], "Not enough parameters")
+                                    .expect("Could not send 461");
+                            } else {
+                                let split = irc::security::split_user_id(message.args[0].trim(),
+                                                                         &self.shared.config.allowed_homeservers);
+                                match split {
+                                    Ok((homeserver, username)) => {
+                                        if let Some(homeserver) = homeserver {
+                                            self.client.auth.set_homeserver(homeserver);
+                                        }
+                                        self.client.auth.set_username(username);
+                                        self.register(events);
+                                    },
+                                    Err(err) => {
+                                        self.client.numeric(464, vec![], err.trim()).expect("Could not send 464");
+                                    }
+                                }
+                            }
                         },
                         Command::Join => {
-                            self.client.join(&message.args[0]).expect("Could not send JOIN");
+                            // Each channel of a list is joined on its own, so
+                            // one that fails doesn't keep out the others. Keys
+                            // are left out, see join_channel, and the user is
+                            // told so.
+                            let keys: Vec<&str> = message.args.get(1).map(|k| k.split(",").collect()).unwrap_or(vec![]);
+                            for (i, channel) in message.args[0].split(",").enumerate().filter(|&(_, c)| !c.is_empty()) {
+                                if keys.get(i).map(|k| !k.is_empty()).unwrap_or(false) {
+                                    self.client.notice(SERVER_NAME, format!("Joining {} without its key: Matrix rooms have \
+                                                                             no passwords, ask to be invited instead.", channel).trim())
+                                        .expect("Could not send NOTICE");
+                                }
+                                self.join_or_knock(channel).expect("Could not send JOIN");
+                            }
+                        },
+                        Command::Part => {
+                            for channel in message.args[0].split(",") {
+                                let room_id = match self.room_from_irc(&channel.to_string()).map(|room| room.id.clone()) {
+                                    Some(id) => id,
+                                    None => {
+                                        self.client.numeric(403, vec![channel.to_string()], "No such channel")
+                                            .expect("Could not send 403");
+                                        continue;
+                                    }
+                                };
+                                match self.leave_room(&room_id) {
+                                    Ok(_) => {
+                                        self.client.part(channel).expect("Could not send PART");
+                                    },
+                                    Err(err) => self.notify_error(format!("Could not leave {}: {}", channel, err).trim())
+                                }
+                            }
+                        },
+                        Command::Mode => {
+                            let target = message.args.get(0).cloned().unwrap_or(String::new());
+                            if target.starts_with("#") || target.starts_with("&") {
+                                let modes = self.room_from_irc(&target).map(|r| r.modes());
+                                match (modes, message.args.get(1).map(|m| m.trim())) {
+                                    (None, _) =>
+                                        self.client.numeric(403, vec![target], "No such channel"),
+                                    (Some(modes), None) =>
+                                        self.client.numeric(324, vec![target, modes], ""),
+                                    (Some(_), Some("b")) => {
+                                        let banned = self.room_from_irc(&target).unwrap().banned.clone();
+                                        for user in banned {
+                                            let mask = format!("{}!{}@{}", user.nickname, user.nickname, user.homeserver);
+                                            self.client.numeric(367, vec![target.clone(), mask], "")
+                                                .expect("Could not send MODE reply");
+                                        }
+                                        self.client.numeric(368, vec![target], "End of channel ban list")
+                                    },
+                                    (Some(_), Some("-b")) => match message.args.get(2) {
+                                        Some(mask) => self.unban(target.trim(), mask.trim()),
+                                        None => self.client.numeric(461, vec!["MODE".to_string()], "Not enough parameters")
+                                    },
+                                    (Some(_), Some(_)) =>
+                                        self.client.numeric(482, vec![target], "Channel modes can't be changed from IRC")
+                                }.expect("Could not send MODE reply");
+                            } else if message.args.len() == 1 {
+                                self.client.numeric(221, vec![], "+").expect("Could not send MODE reply");
+                            }
+                        },
+                        Command::Cap => {
+                            self.client.handle_cap(&message).expect("Could not send CAP reply");
+                            self.register(events);
+                        },
+                        Command::StartTls => {
+                            let reply = if self.registered {
+                                self.client.numeric(691, vec![], "STARTTLS has to come before registration")
+                            } else {
+                                self.client.start_tls()
+                            };
+                            if let Err(err) = reply {
+                                warn!("Could not start TLS: {}", err);
+                                self.disconnected(events);
+                                return;
+                            }
+                        },
+                        Command::Topic if message.suffix.is_none() => {
+                            self.topic(message.args[0].trim()).expect("Could not send TOPIC reply");
+                        },
+                        Command::Topic => {
+                            let text = message.suffix.clone().unwrap_or(String::new());
+                            self.set_topic(message.args[0].trim(), text.trim()).expect("Could not reply to TOPIC");
+                        },
+                        Command::Motd => {
+                            self.motd().expect("Could not send MOTD");
+                        },
+                        Command::Names => {
+                            let channels = message.args.get(0).cloned().unwrap_or(String::new());
+                            for channel in channels.split(",").filter(|c| c.len() > 0) {
+                                self.names(channel).expect("Could not send NAMES reply");
+                            }
+                        },
+                        Command::Who => {
+                            let mask = message.args.get(0).cloned().unwrap_or("*".to_string());
+                            self.who(mask.trim()).expect("Could not send WHO reply");
+                        },
+                        Command::Wallops => {
+                            let text = message.suffix.clone().unwrap_or(message.args.join(" "));
+                            self.wallops(text.trim()).expect("Could not reply to WALLOPS");
+                        },
+                        Command::Ison => {
+                            let mut requested = message.args.clone();
+                            if let Some(ref nicks) = message.suffix {
+                                requested.extend(nicks.split_whitespace().map(|n| n.to_string()));
+                            }
+                            let online: Vec<String> = requested.into_iter().filter(|nick| {
+                                self.is_online(nick)
+                            }).collect();
+                            self.client.numeric(303, vec![], online.join(" ").trim()).expect("Could not send ISON reply");
                         },
                         Command::Ping => {
                             self.client.pong().expect("Could not send PONG");
                         },
                         Command::Quit => {
-                            // FIXME: Logout of matrix and exit thread
+                            self.shutdown(events);
                             return;
                         },
                         Command::Privmsg => {
-                            let room_id = match self.room_from_irc(&message.args[0]) {
-                                None => return (),
-                                Some(room) => room.id.clone()
-                            };
-                            let evt = {
-                                let id = self.matrix.uid.clone().unwrap();
-                                matrix::events::EventData::Room(
-                                    room_id,
-                                    matrix::events::RoomEvent::Message(
-                                        id, message.suffix.unwrap()))
-                            };
-                            self.seen_events.push(self.matrix.send(evt).expect("Could not send event"));
+                            if let Some(ctcp) = message.suffix.as_ref().and_then(|s| Ctcp::from_str(s)) {
+                                if ctcp.command == "DCC" {
+                                    let params = ctcp.params.unwrap_or(String::new());
+                                    self.handle_dcc(&message.args[0], params.trim(), events.channel()).expect("Could not reply to DCC");
+                                    continue;
+                                }
+                            }
+                            if let Some(ctcp) = message.suffix.as_ref().and_then(|s| Ctcp::from_str(s)) {
+                                if message.args[0].starts_with("#") && ctcp.command == "ACTION" {
+                                    let text = ctcp.params.unwrap_or(String::new());
+                                    self.send_message(message.args[0].trim(), matrix::events::MsgType::Emote, text)
+                                        .expect("Could not reply to ACTION");
+                                    continue;
+                                }
+                                if !message.args[0].starts_with("#") {
+                                    self.handle_ctcp(&message.args[0], ctcp).expect("Could not send CTCP reply");
+                                    continue;
+                                }
+                            }
+                            if message.args[0] == SERVER_NAME {
+                                let text = message.suffix.clone().unwrap_or(String::new());
+                                self.handle_control(text.trim(), events).expect("Could not reply to command");
+                                continue;
+                            }
+                            let text = message.suffix.clone().unwrap_or(String::new());
+                            self.send_message(message.args[0].trim(), matrix::events::MsgType::Text, text)
+                                .expect("Could not reply to PRIVMSG");
+                        },
+                        // Notices only make sense in channels, and are never answered
+                        Command::Notice if message.args.get(0).map(|c| c.starts_with("#")).unwrap_or(false) => {
+                            let text = message.suffix.clone().unwrap_or(String::new());
+                            self.send_message(message.args[0].trim(), matrix::events::MsgType::Notice, text)
+                                .expect("Could not reply to NOTICE");
                         },
-                        _ =>
-                            warn!("unhandled {:?}", message)
+                        // Neither of these ever gets a reply, not even an error
+                        Command::Notice | Command::Pong => debug!("Ignoring {:?}", message),
+                        _ => {
+                            warn!("unhandled {:?}", message);
+                            self.client.numeric(421, vec![message.command.as_string()], "Unknown command")
+                                .expect("Could not send 421");
+                        }
                     }
                 }
             }
@@ -420,3 +3400,1377 @@ impl Bridge {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{Bridge, Churn, Room, Session, Shared, chronological, churn_summary, invite_channel, join_message,
+                room_mention, strip_encrypted_mark};
+    use config::Config;
+    use irc;
+    use matrix;
+    use matrix::events::{Event, EventData, Invite, MembershipAction, MsgType, PresenceEvent, RoomEvent, ServerAcl};
+    use matrix::model::{EventID, RoomID, UserID};
+    use mio;
+    use mio::{EventLoop, Token};
+    use openssl::crypto::rand;
+    use rustc_serialize::hex::ToHex;
+    use rustc_serialize::json::Json;
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// A directory of its own under the system's temporary one, so that
+    /// tests running at once don't step on each other's files
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("pto-{}-{}", name, rand::rand_bytes(8).to_hex()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    /// A bridge whose IRC client is a socket nobody reads from
+    fn bridge() -> Bridge {
+        let listener = mio::tcp::TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let stream = mio::tcp::TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let client = irc::streams::Client::new(Box::new(stream));
+        let mut bridge = Bridge::new(client, "https://example.com/_matrix/client/r0/", Token(1), Shared::new(Config::new()));
+        bridge.matrix.uid = Some("@me:example.com".parse::<UserID>().unwrap());
+        bridge
+    }
+
+    /// A bridge talking to a homeserver at `url`, along with the other end
+    /// of its IRC connection
+    fn connected_bridge(url: &str) -> (Bridge, TcpStream) {
+        connected_session(url, Shared::new(Config::new()))
+    }
+
+    /// Likewise, as one of the sessions sharing `shared`
+    fn connected_session(url: &str, shared: Shared) -> (Bridge, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = mio::tcp::TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let (irc, _) = listener.accept().unwrap();
+        irc.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let client = irc::streams::Client::new(Box::new(stream));
+        let mut bridge = Bridge::new(client, url, Token(1), shared);
+        bridge.matrix.uid = Some("@me:example.com".parse::<UserID>().unwrap());
+        (bridge, irc)
+    }
+
+    /// Everything the bridge wrote to the IRC client so far
+    fn irc_output(irc: &mut TcpStream) -> String {
+        let mut output = vec![];
+        let mut buf = [0; 1024];
+        loop {
+            match irc.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(count) => output.extend_from_slice(&buf[..count])
+            }
+        }
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    /// A homeserver answering one request per response, in order, as in
+    /// "200 OK\n\n{}". Returns the base URL to point a bridge at.
+    fn homeserver<S: AsRef<str> + Send + 'static>(responses: Vec<S>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/_matrix/client/r0/", listener.local_addr().unwrap());
+        thread::spawn(move|| {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                // Read the whole request, hanging up on part of it resets
+                // the connection
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                loop {
+                    let text = String::from_utf8_lossy(&request).into_owned();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let length = text[..end].lines()
+                            .filter_map(|line| {
+                                let line = line.to_lowercase();
+                                if line.starts_with("content-length:") {
+                                    line["content-length:".len()..].trim().parse::<usize>().ok()
+                                } else {
+                                    None
+                                }
+                            })
+                            .next().unwrap_or(0);
+                        if request.len() >= end + 4 + length {
+                            break;
+                        }
+                    }
+                    let count = stream.read(&mut buf).unwrap();
+                    if count == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..count]);
+                }
+                let response = response.as_ref();
+                let status = response.split("\n\n").next().unwrap();
+                let body = response.split("\n\n").nth(1).unwrap_or("");
+                write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                                Connection: close\r\n\r\n{}", status, body.len(), body).unwrap();
+            }
+        });
+        url
+    }
+
+    fn event(room: &RoomID, data: RoomEvent) -> Event {
+        Event {
+            id: None,
+            timestamp: None,
+            txn_id: None,
+            mentions: vec![],
+            data: EventData::Room(room.clone(), data)
+        }
+    }
+
+    fn message(text: &str) -> RoomEvent {
+        RoomEvent::Message("@alice:example.com".parse::<UserID>().unwrap(), MsgType::Text, text.to_owned())
+    }
+
+    #[test]
+    fn sync_replay_is_chronological() {
+        let a = "!a:example.com".parse::<RoomID>().unwrap();
+        let b = "!b:example.com".parse::<RoomID>().unwrap();
+        let merged = chronological(vec![
+            (a.clone(), vec![(message("a1"), Some(10)), (message("a2"), None), (message("a3"), Some(30))]),
+            (b.clone(), vec![(message("b1"), Some(5)), (message("b2"), Some(20))])
+        ]);
+        let order: Vec<String> = merged.into_iter().map(|(_, evt, _)| {
+            match evt {
+                RoomEvent::Message(_, _, text) => text,
+                _ => unreachable!()
+            }
+        }).collect();
+        assert_eq!(order, vec!["b1", "a1", "a2", "b2", "a3"]);
+    }
+
+    #[test]
+    fn local_echo_before_send_returns() {
+        let (mut bridge, mut irc) = connected_bridge("http://localhost:1");
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let me = bridge.matrix.uid.clone().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        bridge.room_from_matrix(&room).pending_txns.insert("1".to_owned());
+        bridge.room_from_matrix(&room).pending_txns.insert("2".to_owned());
+        irc_output(&mut irc);
+
+        // The echoes are synced before either send request has returned an
+        // event ID, the second message first
+        let echo = |text: &str, txn_id: &str| {
+            let mut evt = event(&room, RoomEvent::Message(me.clone(), MsgType::Text, text.to_owned()));
+            evt.id = Some(format!("${}:example.com", txn_id).parse().unwrap());
+            evt.txn_id = Some(txn_id.to_owned());
+            evt
+        };
+        bridge.handle_matrix(echo("second", "2")).unwrap();
+        bridge.handle_matrix(echo("first", "1")).unwrap();
+        assert_eq!(irc_output(&mut irc), "");
+        assert!(bridge.room_from_matrix(&room).pending_txns.is_empty());
+
+        // What we say from another device isn't ours to hold back
+        let mut other = echo("elsewhere", "3");
+        other.txn_id = None;
+        bridge.handle_matrix(other).unwrap();
+        assert_eq!(irc_output(&mut irc).matches("PRIVMSG").count(), 1);
+    }
+
+    #[test]
+    fn leaving_forgets_room() {
+        let mut bridge = bridge();
+        let me = "@me:example.com".parse::<UserID>().unwrap();
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let bob = "@bob:example.com".parse::<UserID>().unwrap();
+        let left = "!left:example.com".parse::<RoomID>().unwrap();
+        let kept = "!kept:example.com".parse::<RoomID>().unwrap();
+        for &(ref room, ref user) in &[(&left, &alice), (&left, &bob), (&kept, &bob)] {
+            bridge.handle_matrix(event(room, RoomEvent::Membership((*user).clone(), MembershipAction::Join))).unwrap();
+        }
+        bridge.forget_room(&left);
+        assert!(!bridge.rooms.contains_key(&left));
+        assert!(!bridge.nicks.is_reserved("alice"));
+        assert!(bridge.nicks.is_reserved("bob"));
+
+        // Events for the room that were already in flight are dropped
+        bridge.handle_matrix(event(&left, message("hello"))).unwrap();
+        assert!(!bridge.rooms.contains_key(&left));
+
+        // Unless we join it again
+        bridge.handle_matrix(event(&left, RoomEvent::Membership(me, MembershipAction::Join))).unwrap();
+        assert!(bridge.rooms.contains_key(&left));
+    }
+
+    #[test]
+    fn leaving_last_room_quits() {
+        let mut bridge = bridge();
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let first = "!first:example.com".parse::<RoomID>().unwrap();
+        let second = "!second:example.com".parse::<RoomID>().unwrap();
+        for room in &[&first, &second] {
+            bridge.handle_matrix(event(room, RoomEvent::Membership(alice.clone(), MembershipAction::Join))).unwrap();
+            bridge.room_from_matrix(room).irc_name = Some(format!("#{}", room.id));
+        }
+        assert!(!bridge.leaves_last_room(&first, &alice));
+        bridge.handle_matrix(event(&first, RoomEvent::Membership(alice.clone(), MembershipAction::Leave))).unwrap();
+        assert!(bridge.nicks.is_reserved("alice"));
+
+        assert!(bridge.leaves_last_room(&second, &alice));
+        let mut messages = vec![];
+        bridge.quit_ghost(&second, &alice, None, &mut |msg| messages.push(msg));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].command, irc::protocol::Command::Quit);
+        assert_eq!(messages[0].prefix, Some("alice!alice@example.com".to_owned()));
+        assert!(!bridge.rooms[&second].members.contains(&alice));
+        assert!(!bridge.nicks.is_reserved("alice"));
+    }
+
+    #[test]
+    fn left_elsewhere() {
+        let mut bridge = bridge();
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.room_from_matrix(&room).irc_name = Some("#room".to_owned());
+        let mut messages = vec![];
+        bridge.left_elsewhere(&room, &mut |msg| messages.push(msg));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].command, irc::protocol::Command::Part);
+        assert_eq!(messages[0].prefix, Some("me!me@example.com".to_owned()));
+        assert_eq!(messages[0].args, vec!["#room".to_owned()]);
+        assert!(!bridge.rooms.contains_key(&room));
+        assert!(bridge.left_rooms.contains(&room));
+    }
+
+    #[test]
+    fn left_and_invited_while_polling() {
+        let url = homeserver(vec![
+            "200 OK\n\n{\"next_batch\": \"s2\", \"rooms\": {\"leave\": {\"!room:example.com\": {\"timeline\": \
+             {\"events\": [{\"type\": \"m.room.member\", \"sender\": \"@me:example.com\", \
+             \"state_key\": \"@me:example.com\", \"content\": {\"membership\": \"leave\"}}]}}}, \
+             \"invite\": {\"!new:example.com\": {\"invite_state\": {\"events\": [{\"type\": \"m.room.member\", \
+             \"sender\": \"@alice:example.com\", \"state_key\": \"@me:example.com\", \
+             \"content\": {\"membership\": \"invite\"}}]}}}}}"
+        ]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.room_from_matrix(&room).irc_name = Some("#room".to_owned());
+        bridge.matrix.set_since("s1".to_owned());
+        let (evts, next_batch) = bridge.matrix.poll_async(bridge.cancel_poll.clone()).send().unwrap();
+        for evt in evts {
+            bridge.handle_matrix(evt).unwrap();
+        }
+        assert_eq!(next_batch, Some("s2".to_owned()));
+        assert!(irc_output(&mut irc).contains(" PART #room"));
+        assert!(!bridge.rooms.contains_key(&room));
+        assert!(bridge.invites.contains_key(&"!new:example.com".parse::<RoomID>().unwrap()));
+    }
+
+    #[test]
+    fn tombstone_in_initial_sync() {
+        let (mut bridge, mut irc) = connected_bridge("https://example.com/_matrix/client/r0/");
+        let mut config = Config::new();
+        config.follow_tombstones = true;
+        bridge.shared = Shared::new(config);
+        let old = "!old:example.com".parse::<RoomID>().unwrap();
+        let new = "!new:example.com".parse::<RoomID>().unwrap();
+        let me = bridge.matrix.uid.clone().unwrap();
+        // The successor is part of the same sync, after the tombstone
+        bridge.handle_matrix(event(&old, RoomEvent::CanonicalAlias(Some("#old:example.com".parse().unwrap()), vec![]))).unwrap();
+        bridge.handle_matrix(event(&old, RoomEvent::Tombstone(new.clone()))).unwrap();
+        bridge.handle_matrix(event(&new, RoomEvent::CanonicalAlias(Some("#new:example.com".parse().unwrap()), vec![]))).unwrap();
+        bridge.handle_matrix(event(&new, RoomEvent::Membership(me, MembershipAction::Join))).unwrap();
+        bridge.handle_matrix(Event {
+            id: None,
+            timestamp: None,
+            txn_id: None,
+            mentions: vec![],
+            data: EventData::EndOfSync
+        }).unwrap();
+        let output = irc_output(&mut irc);
+        assert_eq!(output.lines().filter(|line| line.contains(" JOIN #new")).count(), 1);
+        assert_eq!(output.lines().filter(|line| line.contains(" JOIN #old")).count(), 1);
+        assert!(!output.contains("Could not join"));
+    }
+
+    #[test]
+    fn invites() {
+        let mut bridge = bridge();
+        let room = "!new:example.com".parse::<RoomID>().unwrap();
+        let invite = |alias: Option<&str>| Event {
+            id: None,
+            timestamp: None,
+            txn_id: None,
+            mentions: vec![],
+            data: EventData::Invited(Invite {
+                room: room.clone(),
+                inviter: Some("@alice:example.com".parse::<UserID>().unwrap()),
+                name: Some("New room".to_owned()),
+                alias: alias.map(|a| a.parse().unwrap())
+            })
+        };
+        bridge.handle_matrix(invite(Some("#new:example.com"))).unwrap();
+        // Syncs keep listing the invite until it's accepted
+        bridge.handle_matrix(invite(None)).unwrap();
+        assert_eq!(bridge.invites[&room].alias, Some("#new:example.com".parse().unwrap()));
+        assert_eq!(invite_channel(&bridge.invites[&room], &bridge.shared.config), "#new/example.com");
+        assert!(!bridge.rooms.contains_key(&room));
+
+        bridge.left_elsewhere(&room, &mut |_| ());
+        assert!(bridge.invites.is_empty());
+    }
+
+    #[test]
+    fn channel_names_from_aliases() {
+        let me = "@me:example.com".parse::<UserID>().unwrap();
+        let config = Config::new();
+        let mut room = Room::new("!room:example.com".parse::<RoomID>().unwrap());
+        room.handle_event(RoomEvent::CanonicalAlias(Some("#main:elsewhere.org".parse().unwrap()), vec![]), None, |_| ());
+        room.handle_event(RoomEvent::Aliases(vec!["#other:elsewhere.org".parse().unwrap(),
+                                                  "#local:example.com".parse().unwrap()]), None, |_| ());
+        room.finish_sync(&me, &config, &mut |_| ());
+        assert_eq!(room.irc_name, Some("#local/example.com".to_owned()));
+
+        let mut room = Room::new("!room:example.com".parse::<RoomID>().unwrap());
+        room.handle_event(RoomEvent::CanonicalAlias(Some("#main:elsewhere.org".parse().unwrap()), vec![]), None, |_| ());
+        room.finish_sync(&me, &config, &mut |_| ());
+        assert_eq!(room.irc_name, Some("#main/elsewhere.org".to_owned()));
+
+        let mut room = Room::new("!room:example.com".parse::<RoomID>().unwrap());
+        room.finish_sync(&me, &config, &mut |_| ());
+        assert_eq!(room.irc_name, Some("#room/example.com".to_owned()));
+    }
+
+    #[test]
+    fn channel_index() {
+        let mut bridge = bridge();
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#old:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        assert_eq!(bridge.room_from_irc(&"#OLD/elsewhere.org".to_owned()).map(|r| r.id.clone()), Some(room.clone()));
+
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#new:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        assert!(bridge.room_from_irc(&"#old/elsewhere.org".to_owned()).is_none());
+        assert_eq!(bridge.room_from_irc(&"#new/elsewhere.org".to_owned()).map(|r| r.id.clone()), Some(room.clone()));
+
+        bridge.forget_room(&room);
+        assert!(bridge.room_from_irc(&"#new/elsewhere.org".to_owned()).is_none());
+        assert!(bridge.channels.is_empty());
+    }
+
+    #[test]
+    fn message_types() {
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let mut room = Room::new("!room:example.com".parse::<RoomID>().unwrap());
+        room.irc_name = Some("#room".to_owned());
+        let mut messages = vec![];
+        for &(ref msgtype, text) in &[(MsgType::Text, "hi"), (MsgType::Emote, "waves"), (MsgType::Notice, "beep"),
+                                      (MsgType::Media("m.image".to_owned(), "https://example.com/cat".to_owned()), "cat.png")] {
+            let evt = RoomEvent::Message(alice.clone(), msgtype.clone(), text.to_owned());
+            room.handle_with_alias(evt, None, &mut |msg| messages.push(msg));
+        }
+        let lines: Vec<(irc::protocol::Command, String)> = messages.into_iter()
+            .map(|msg| (msg.command, msg.suffix.unwrap()))
+            .collect();
+        assert_eq!(lines, vec![
+            (irc::protocol::Command::Privmsg, "hi".to_owned()),
+            (irc::protocol::Command::Privmsg, "\x01ACTION waves\x01".to_owned()),
+            (irc::protocol::Command::Notice, "beep".to_owned()),
+            (irc::protocol::Command::Privmsg, "cat.png https://example.com/cat".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn pins_are_new_once() {
+        let mut room = Room::new("!room:example.com".parse::<RoomID>().unwrap());
+        let first = "$first".parse::<EventID>().unwrap();
+        let second = "$second".parse::<EventID>().unwrap();
+        // What is pinned when we join, or reconnect, isn't announced
+        assert_eq!(room.update_pins(&vec![first.clone()]), vec![]);
+        assert_eq!(room.update_pins(&vec![first.clone(), second.clone()]), vec![second.clone()]);
+        assert_eq!(room.update_pins(&vec![second.clone()]), vec![]);
+        assert_eq!(room.update_pins(&vec![first.clone(), second.clone()]), vec![first]);
+    }
+
+    #[test]
+    fn membership_churn() {
+        let mut room = Room::new("!room:example.com".parse::<RoomID>().unwrap());
+        room.irc_name = Some("#room".to_owned());
+        room.churn = Churn::new(Duration::from_secs(60), 3);
+        room.my_nick = Some("me".to_owned());
+        let mut messages = vec![];
+        for name in &["a", "b", "c", "d", "e"] {
+            let user = format!("@{}:example.com", name).parse::<UserID>().unwrap();
+            room.handle_event(RoomEvent::Membership(user, MembershipAction::Join), Some(1000), |m| messages.push(m));
+        }
+        let alice = "@a:example.com".parse::<UserID>().unwrap();
+        room.handle_event(RoomEvent::Membership(alice, MembershipAction::Leave), Some(2000), |m| messages.push(m));
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().all(|m| m.command == irc::protocol::Command::Join));
+        assert_eq!(room.members.len(), 4);
+
+        // Nothing is summed up before the window is over
+        let mut summary = vec![];
+        room.summarize_churn(3000, &mut |m| summary.push(m));
+        assert!(summary.is_empty());
+        assert!(room.churn_due(3000).is_some());
+
+        // The summary is followed by the members as they are now
+        room.summarize_churn(62000, &mut |m| summary.push(m));
+        assert_eq!(summary.len(), 3);
+        assert_eq!(summary[0].command, irc::protocol::Command::Notice);
+        assert_eq!(summary[0].suffix, Some("2 users joined, 1 left".to_owned()));
+        assert_eq!(summary[1].command, irc::protocol::Command::Numeric(353));
+        assert_eq!(summary[1].suffix.as_ref().map(|names| names.split(' ').count()), Some(4));
+        assert_eq!(summary[2].command, irc::protocol::Command::Numeric(366));
+        assert_eq!(room.churn_due(62000), None);
+
+        // Replayed history goes by when it happened, not by when it shows up
+        let mut messages = vec![];
+        for (i, name) in ["f", "g", "h", "i", "j"].iter().enumerate() {
+            let user = format!("@{}:example.com", name).parse::<UserID>().unwrap();
+            room.handle_event(RoomEvent::Membership(user, MembershipAction::Join), Some(100000 + i as u64 * 120000),
+                              |m| messages.push(m));
+        }
+        assert_eq!(messages.len(), 5);
+
+        assert_eq!(churn_summary(1, 0), "1 user joined");
+        assert_eq!(churn_summary(0, 12), "12 users left");
+    }
+
+    #[test]
+    fn undelivered_messages() {
+        let url = homeserver(vec!["500 Internal Server Error\n\n{\"errcode\": \"M_UNKNOWN\", \"error\": \"Internal server error\"}"]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        irc_output(&mut irc);
+
+        bridge.send_message("#pto/elsewhere.org", MsgType::Text, "hello".to_owned()).unwrap();
+        assert!(bridge.rooms[&room].pending_txns.is_empty());
+        assert_eq!(irc_output(&mut irc),
+                   ":pto NOTICE #pto/elsewhere.org :Message not delivered: Internal server error (M_UNKNOWN) (hello)\r\n");
+    }
+
+    #[test]
+    fn join_several_channels() {
+        let url = homeserver(vec![
+            "200 OK\n\n{\"room_id\": \"!a:example.com\"}",
+            "200 OK\n\n[]",
+            "404 Not Found\n\n{\"errcode\": \"M_NOT_FOUND\", \"error\": \"Room alias not found\"}",
+            "200 OK\n\n{\"room_id\": \"!c:example.com\"}",
+            "200 OK\n\n[]"
+        ]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        irc.write_all(b"JOIN #a,#b,#c\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        assert!(bridge.rooms.contains_key(&"!a:example.com".parse::<RoomID>().unwrap()));
+        assert!(bridge.rooms.contains_key(&"!c:example.com".parse::<RoomID>().unwrap()));
+        let output = irc_output(&mut irc);
+        assert_eq!(output.lines().filter(|line| line.contains(" JOIN ")).count(), 2);
+        assert!(output.lines().any(|line| line.contains(" 403 ") && line.contains("#b :No such channel")));
+    }
+
+    #[test]
+    fn dcc_only_from_the_client() {
+        let (mut bridge, mut irc) = connected_bridge("https://example.com/_matrix/client/r0/");
+        let events = EventLoop::<Bridge>::new().unwrap();
+        // 10.0.0.1, which the client could reach through the bridge
+        bridge.handle_dcc("#room", "SEND cat.png 167772161 5000 1024", events.channel()).unwrap();
+        assert!(irc_output(&mut irc).contains("Not receiving cat.png"));
+        // 127.0.0.1, where the client is, gets as far as looking for the room
+        bridge.handle_dcc("#room", "SEND cat.png 2130706433 5000 1024", events.channel()).unwrap();
+        assert!(irc_output(&mut irc).contains("Can't send files to #room"));
+    }
+
+    #[test]
+    fn invite_only_channels() {
+        let forbidden = "403 Forbidden\n\n{\"errcode\": \"M_FORBIDDEN\", \"error\": \"You are not invited to this room.\"}";
+        let url = homeserver(vec![forbidden]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        bridge.join_or_knock("#secret").unwrap();
+        assert!(irc_output(&mut irc).lines().any(|line| line.contains(" 475 ") && line.contains("#secret")));
+    }
+
+    #[test]
+    fn join_only_once_joined() {
+        let url = homeserver(vec![
+            "403 Forbidden\n\n{\"errcode\": \"M_FORBIDDEN\", \"error\": \"You are banned from this room\"}",
+            "200 OK\n\n{\"room_id\": \"!broken:example.com\"}",
+            "500 Internal Server Error\n\n{\"errcode\": \"M_UNKNOWN\", \"error\": \"Internal server error\"}",
+            "200 OK\n\n{\"room_id\": \"!fine:example.com\"}",
+            "200 OK\n\n[]"
+        ]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        bridge.join_or_knock("#banned").unwrap();
+        let output = irc_output(&mut irc);
+        assert!(!output.contains(" JOIN "));
+        assert!(output.lines().any(|line| line.contains(" 474 ") && line.contains("#banned")));
+
+        // Joined on Matrix, but without its state there is nothing to show
+        bridge.join_or_knock("#broken").unwrap();
+        let output = irc_output(&mut irc);
+        assert!(!output.contains(" JOIN "));
+        assert!(output.lines().any(|line| line.contains(" 403 ") && line.contains("#broken")));
+
+        bridge.join_or_knock("#fine").unwrap();
+        assert!(irc_output(&mut irc).lines().any(|line| line.contains(" JOIN ")));
+    }
+
+    #[test]
+    fn guest_access() {
+        let url = homeserver(vec![
+            "403 Forbidden\n\n{\"errcode\": \"M_GUEST_ACCESS_FORBIDDEN\", \"error\": \"Guest access not allowed\"}"
+        ]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        bridge.join_or_knock("#members").unwrap();
+        let output = irc_output(&mut irc);
+        assert!(output.contains("Could not join #members: guests aren't allowed in."));
+        assert!(output.lines().any(|line| line.contains(" 477 ") && line.contains("#members")));
+
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::GuestAccess(false))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        irc_output(&mut irc);
+        irc.write_all(b"MODE #pto/elsewhere.org\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        assert!(irc_output(&mut irc).contains(" 324 * #pto/elsewhere.org +R "));
+    }
+
+    #[test]
+    fn channel_keys_are_not_passed_on() {
+        let forbidden = "403 Forbidden\n\n{\"errcode\": \"M_FORBIDDEN\", \"error\": \"You are not invited to this room.\"}";
+        let url = homeserver(vec![forbidden, forbidden]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        irc.write_all(b"JOIN #secret,#open hunter2\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        let output = irc_output(&mut irc);
+        assert!(output.contains(":Joining #secret without its key: Matrix rooms have no passwords"));
+        assert!(!output.contains("Joining #open without its key"));
+        assert!(!output.contains("hunter2"));
+        assert_eq!(output.lines().filter(|line| line.contains(" 475 ")).count(), 2);
+    }
+
+    #[test]
+    fn knock_on_unknown_channels() {
+        let url = homeserver(vec![
+            "200 OK\n\n{\"versions\": [\"r0.6.1\", \"v1.1\"]}",
+            "200 OK\n\n{\"room_id\": \"!secret:example.com\", \"servers\": [\"example.com\"]}",
+            "200 OK\n\n{\"join_rule\": \"knock\"}",
+            "200 OK\n\n{\"room_id\": \"!secret:example.com\"}"
+        ]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        bridge.matrix.negotiate_version();
+        bridge.join_or_knock("#secret").unwrap();
+        assert!(irc_output(&mut irc).contains("Knocked on #secret."));
+    }
+
+    #[test]
+    fn nick_changes() {
+        let url = homeserver(vec!["200 OK\n\n{}"]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        // Before logging in, NICK picks the nick to log in with
+        bridge.matrix.uid = None;
+        irc.write_all(b"NICK alice\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        assert!(bridge.nicks.is_taken("alice", None));
+        assert_eq!(irc_output(&mut irc), "");
+
+        // Afterwards, it sets the display name and keeps the nick
+        bridge.matrix.uid = Some("@alice:example.com".parse::<UserID>().unwrap());
+        irc.write_all(b"NICK Alice_in_Wonderland\r\nNICK alice\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        assert!(!bridge.nicks.is_taken("Alice_in_Wonderland", None));
+        assert_eq!(irc_output(&mut irc), ":pto NOTICE alice :Your nick stays alice, your Matrix username, \
+                                          but your display name is now Alice_in_Wonderland\r\n");
+    }
+
+    #[test]
+    fn fetched_topics() {
+        let url = homeserver(vec![
+            "200 OK\n\n{\"topic\": \"Only fetched\"}",
+            "404 Not Found\n\n{\"errcode\": \"M_NOT_FOUND\", \"error\": \"Event not found.\"}"
+        ]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        irc_output(&mut irc);
+
+        // Fetched only the first time, the second answer comes from what
+        // was learned, the room having no name included
+        for _ in 0..2 {
+            bridge.topic("#pto/elsewhere.org").unwrap();
+            assert_eq!(irc_output(&mut irc), ":pto 332 * #pto/elsewhere.org :Only fetched\r\n");
+        }
+        assert_eq!(bridge.rooms[&room].name, Some(None));
+    }
+
+    #[test]
+    fn custom_events() {
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let bot_event = Json::from_str("{\"type\": \"org.example.build\", \"sender\": \"@ci:example.com\", \
+                                        \"content\": {\"body\": \"Build 42 passed\"}}").unwrap();
+        for &show_custom in &[false, true] {
+            let mut room = Room::new("!room:example.com".parse::<RoomID>().unwrap());
+            room.irc_name = Some("#room".to_owned());
+            room.show_custom = show_custom;
+            let mut messages = vec![];
+            let evt = RoomEvent::Message(alice.clone(), MsgType::Other("org.example.poll".to_owned()), "Lunch?".to_owned());
+            room.handle_event(evt, None, |msg| messages.push(msg));
+            room.handle_event(RoomEvent::Unknown("org.example.build".to_owned(), bot_event.clone()), None,
+                              |msg| messages.push(msg));
+            let lines: Vec<(irc::protocol::Command, String)> = messages.into_iter()
+                .map(|msg| (msg.command, msg.suffix.unwrap()))
+                .collect();
+            if show_custom {
+                assert_eq!(lines, vec![
+                    (irc::protocol::Command::Notice, "[org.example.poll] Lunch?".to_owned()),
+                    (irc::protocol::Command::Notice, "[org.example.build] Build 42 passed".to_owned())
+                ]);
+            } else {
+                assert_eq!(lines, vec![(irc::protocol::Command::Privmsg, "Lunch?".to_owned())]);
+            }
+        }
+
+        // Nor is anything from a server the room's ACL keeps out
+        let mut room = Room::new("!room:example.com".parse::<RoomID>().unwrap());
+        room.irc_name = Some("#room".to_owned());
+        room.show_custom = true;
+        room.server_acl = Some(ServerAcl { allow: vec!["*".to_owned()], deny: vec!["example.com".to_owned()], allow_ip_literals: true });
+        let mut messages = vec![];
+        room.handle_event(RoomEvent::Unknown("org.example.build".to_owned(), bot_event), None, |msg| messages.push(msg));
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn who_flags_away_ghosts() {
+        let (mut bridge, mut irc) = connected_bridge("https://example.com/_matrix/client/r0/");
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::Membership(alice.clone(), MembershipAction::Join))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        irc_output(&mut irc);
+
+        bridge.who("#pto/elsewhere.org").unwrap();
+        assert_eq!(irc_output(&mut irc), ":pto 352 * #pto/elsewhere.org alice example.com pto alice H :0 alice\r\n\
+                                          :pto 315 * #pto/elsewhere.org :End of /WHO list\r\n");
+
+        bridge.nicks.set_presence(&PresenceEvent {
+            presence: "unavailable".to_owned(),
+            user: alice.clone(),
+            currently_active: None,
+            last_active_ago: Some(60 * 1000)
+        });
+        bridge.who("alice").unwrap();
+        assert_eq!(irc_output(&mut irc), ":pto 352 * * alice example.com pto alice G :0 alice\r\n\
+                                          :pto 315 * alice :End of /WHO list\r\n");
+    }
+
+    #[test]
+    fn starttls_failures() {
+        // Without TLS set up, and again after registration
+        let (mut bridge, mut irc) = connected_bridge("https://example.com/_matrix/client/r0/");
+        irc.write_all(b"STARTTLS\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        assert!(irc_output(&mut irc).contains(" 691 "));
+
+        bridge.registered = true;
+        irc.write_all(b"STARTTLS\r\nPING :still here\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        let output = irc_output(&mut irc);
+        assert!(output.contains(" 691 * :STARTTLS has to come before registration"));
+        assert!(output.contains("PONG"));
+    }
+
+    #[test]
+    fn registration_in_any_order() {
+        let orderings: Vec<&[u8]> = vec![
+            b"NICK alice\r\nUSER alice 0 * :Alice\r\n",
+            b"USER alice 0 * :Alice\r\nNICK alice\r\n",
+            b"USER alice 0 * :Alice\r\nCAP LS\r\nNICK alice\r\nCAP END\r\n",
+            b"CAP LS 302\r\nNICK alice\r\nUSER alice 0 * :Alice\r\nCAP REQ :server-time\r\nCAP END\r\n"
+        ];
+        for ordering in orderings {
+            let (mut bridge, mut irc) = connected_bridge("https://example.com/_matrix/client/r0/");
+            bridge.matrix.uid = None;
+            irc.write_all(ordering).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            bridge.handle_client(&mut EventLoop::new().unwrap());
+            let output = irc_output(&mut irc);
+            assert_eq!(output.lines().filter(|line| line.contains(" 001 alice ")).count(), 1);
+            assert!(output.contains("A password is needed"));
+            // Capabilities are all settled before the welcome
+            if let Some(ack) = output.find(" ACK ") {
+                assert!(ack < output.find(" 001 alice ").unwrap());
+            }
+        }
+
+        // Registration waits for the end of capability negotiation
+        let (mut bridge, mut irc) = connected_bridge("https://example.com/_matrix/client/r0/");
+        bridge.matrix.uid = None;
+        irc.write_all(b"CAP LS 302\r\nNICK alice\r\nUSER alice 0 * :Alice\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        assert!(!irc_output(&mut irc).contains(" 001 "));
+        irc.write_all(b"CAP END\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        assert!(irc_output(&mut irc).contains(" 001 alice "));
+
+        // Nothing happens until both have arrived, and only once
+        let (mut bridge, mut irc) = connected_bridge("https://example.com/_matrix/client/r0/");
+        bridge.matrix.uid = None;
+        irc.write_all(b"USER\r\nUSER alice 0 * :Alice\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        let output = irc_output(&mut irc);
+        assert!(output.contains(" 461 * USER "));
+        assert!(!output.contains(" 001 "));
+        irc.write_all(b"NICK alice\r\nUSER alice 0 * :Alice\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        let output = irc_output(&mut irc);
+        assert!(output.contains(" 001 alice "));
+        assert!(output.contains(" 462 alice "));
+    }
+
+    #[test]
+    fn session_files() {
+        let dir = temp_dir("sessions");
+        let mut config = Config::new();
+        config.session_dir = Some(dir.to_string_lossy().into_owned());
+        let mut bridge = bridge();
+        bridge.shared = Shared::new(config);
+        let path = bridge.session_path("me", "hunter2").unwrap();
+        assert_eq!(bridge.session_path("me", "hunter2"), Some(path.clone()));
+        assert!(bridge.session_path("me", "hunter3") != Some(path.clone()));
+        assert!(bridge.session_path("you", "hunter2") != Some(path.clone()));
+        // Unsalted, the name would be the SHA256 of the credentials
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        assert!(name != ::openssl::crypto::hash::hash(::openssl::crypto::hash::Type::SHA256, b"me:hunter2").to_hex());
+
+        // A session file left readable by others is locked down again
+        File::create(&path).unwrap().write_all(b"{\"version\": 1, \"baseurl\": \"https://example.com\", \
+                                                 \"access_token\": \"abc\", \"user_id\": \"@me:example.com\"}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let client = matrix::client::Client::load_session(&path).unwrap();
+        client.save_session(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        for entry in fs::read_dir(&dir).unwrap() {
+            assert_eq!(entry.unwrap().metadata().unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reconnect_resumes_session() {
+        let url = homeserver(vec![
+            "200 OK\n\n{\"versions\": [\"r0.6.1\"]}",
+            "200 OK\n\n{\"user_id\": \"@me:example.com\"}",
+            "200 OK\n\n{}"
+        ]);
+        let mut config = Config::new();
+        let dir = temp_dir("reconnect");
+        config.session_dir = Some(dir.to_string_lossy().into_owned());
+        let shared = Shared::new(config);
+        let password = format!("hunter2 for {}", url);
+
+        // The first connection drops, leaving its rooms behind
+        let (mut bridge, irc) = connected_bridge(url.trim());
+        bridge.shared = shared.clone();
+        let path = bridge.session_path("me", password.trim()).unwrap();
+        File::create(&path).unwrap().write_all(format!("{{\"version\": 1, \"baseurl\": \"{}\", \
+                                                         \"access_token\": \"abc\", \"user_id\": \"@me:example.com\"}}",
+                                                       url).as_bytes()).unwrap();
+        bridge.matrix = matrix::client::Client::load_session(&path).unwrap();
+        bridge.session_file = Some(path.clone());
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        drop(irc);
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        assert!(bridge.rooms.is_empty());
+
+        // The reconnect only checks the token, and is back in its channels
+        // without syncing
+        let (mut bridge, mut irc) = connected_bridge("https://example.com/_matrix/client/r0/");
+        bridge.shared = shared.clone();
+        bridge.matrix.uid = None;
+        let events = EventLoop::<Bridge>::new().unwrap();
+        bridge.cancel_poll.store(true, Ordering::SeqCst);
+        bridge.login("me", password.trim(), events.channel()).unwrap();
+        assert!(bridge.rooms.contains_key(&room));
+        assert!(irc_output(&mut irc).starts_with(":me!me@example.com JOIN #pto/elsewhere.org"));
+        assert!(shared.detached.lock().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn quit_while_polling() {
+        // A homeserver that takes the poll and never answers it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/_matrix/client/r0/", listener.local_addr().unwrap());
+        let (polled, poll_arrived) = mpsc::channel();
+        thread::spawn(move|| {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf);
+            polled.send(()).unwrap();
+            thread::sleep(Duration::from_secs(30));
+        });
+        let dir = temp_dir("quit-while-polling");
+        let path = dir.join("session.json");
+        File::create(&path).unwrap().write_all(format!("{{\"version\": 1, \"baseurl\": \"{}\", \
+                                                         \"access_token\": \"abc\", \"user_id\": \"@me:example.com\"}}",
+                                                       url).as_bytes()).unwrap();
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        bridge.matrix = matrix::client::Client::load_session(&path).unwrap();
+        let shared = bridge.shared.clone();
+        thread::spawn(move|| {
+            // Start polling once the session is up, then quit in the middle
+            // of the poll
+            loop {
+                if let Some(session) = shared.sessions.lock().unwrap().get(&Token(1)) {
+                    session.send(super::Event::EndPoll(None)).unwrap();
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            poll_arrived.recv().unwrap();
+            irc.write_all(b"QUIT\r\n").unwrap();
+            thread::sleep(Duration::from_secs(1));
+        });
+        let started = Instant::now();
+        bridge.run();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(bridge.cancel_poll.load(Ordering::SeqCst));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn abrupt_disconnect() {
+        let (mut bridge, irc) = connected_bridge("https://example.com/_matrix/client/r0/");
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        assert!(!bridge.cancel_poll.load(Ordering::SeqCst));
+
+        drop(irc);
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        assert!(bridge.client.is_closed());
+        assert!(bridge.cancel_poll.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn third_party_invites() {
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let bob = "@bob:example.org".parse::<UserID>().unwrap();
+        let mut room = Room::new("!room:example.com".parse::<RoomID>().unwrap());
+        room.irc_name = Some("#room".to_owned());
+        let mut notices = vec![];
+        room.handle_event(RoomEvent::ThirdPartyInvite(alice.clone(), "abc".to_owned(), Some("b...@example.org".to_owned())),
+                          None, |msg| notices.push(msg.suffix.unwrap()));
+        room.handle_event(RoomEvent::ThirdPartyInvite(alice.clone(), "def".to_owned(), Some("c...@example.org".to_owned())),
+                          None, |msg| notices.push(msg.suffix.unwrap()));
+        assert_eq!(room.third_party_invites.len(), 2);
+        room.handle_event(RoomEvent::ThirdPartyInviteClaimed(bob, "abc".to_owned()), None,
+                          |msg| notices.push(msg.suffix.unwrap()));
+        room.handle_event(RoomEvent::ThirdPartyInvite(alice, "def".to_owned(), None), None,
+                          |msg| notices.push(msg.suffix.unwrap()));
+        assert!(room.third_party_invites.is_empty());
+        assert_eq!(notices, vec![
+            "alice invited b...@example.org, who hasn't joined Matrix yet. The invite is pending.".to_owned(),
+            "alice invited c...@example.org, who hasn't joined Matrix yet. The invite is pending.".to_owned(),
+            "The pending invite of b...@example.org went to bob (@bob:example.org)".to_owned(),
+            "alice withdrew the pending invite of c...@example.org".to_owned()
+        ]);
+    }
+
+    #[test]
+    fn room_mentions() {
+        assert_eq!(room_mention("me", "@room meeting in 5"), Some("me: @all meeting in 5".to_owned()));
+        assert_eq!(room_mention("me", "Heads up @room: it's down, @room."),
+                   Some("me: Heads up @all: it's down, @all.".to_owned()));
+        assert_eq!(room_mention("me", "ask @room:example.org"), None);
+        assert_eq!(room_mention("me", "mail me@room.example.org"), None);
+        assert_eq!(room_mention("me", "@roomba is out of battery"), None);
+        assert_eq!(room_mention("me", "room service"), None);
+
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let text = |data: EventData| match data {
+            EventData::Room(_, RoomEvent::Message(_, MsgType::Text, text)) => text,
+            data => panic!("Unexpected {:?}", data)
+        };
+        let mut bridge = bridge();
+        let said = EventData::Room(room.clone(), RoomEvent::Message(alice.clone(), MsgType::Text, "@room lunch?".to_owned()));
+        assert_eq!(text(bridge.render_reply(said)), "@room lunch?");
+        bridge.shared.config.room_mentions = true;
+        let said = EventData::Room(room.clone(), RoomEvent::Message(alice, MsgType::Text, "@room lunch?".to_owned()));
+        assert_eq!(text(bridge.render_reply(said)), "me: @all lunch?");
+        let me = bridge.matrix.uid.clone().unwrap();
+        let said = EventData::Room(room, RoomEvent::Message(me, MsgType::Text, "@room lunch?".to_owned()));
+        assert_eq!(text(bridge.render_reply(said)), "@room lunch?");
+    }
+
+    #[test]
+    fn display_name_prefix() {
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let bob = "@bob:example.com".parse::<UserID>().unwrap();
+        let said = |user: &UserID, msgtype: MsgType| EventData::Room(room.clone(),
+            RoomEvent::Message(user.clone(), msgtype, "hi".to_owned()));
+        let text = |data: EventData| match data {
+            EventData::Room(_, RoomEvent::Message(user, _, text)) => (user.nickname, text),
+            data => panic!("Unexpected {:?}", data)
+        };
+        let mut bridge = bridge();
+        bridge.nicks.reserve(&alice);
+        bridge.nicks.set_display_name(&alice, Some("Alice Liddell".to_owned()));
+        bridge.nicks.reserve(&bob);
+        assert_eq!(text(bridge.render_reply(said(&alice, MsgType::Text))), ("alice".to_owned(), "hi".to_owned()));
+
+        bridge.display_names = true;
+        assert_eq!(text(bridge.render_reply(said(&alice, MsgType::Text))),
+                   ("alice".to_owned(), "<Alice Liddell> hi".to_owned()));
+        assert_eq!(text(bridge.render_reply(said(&alice, MsgType::Emote))), ("alice".to_owned(), "hi".to_owned()));
+        // Unknown display names are fetched, rather than waited for
+        assert_eq!(text(bridge.render_reply(said(&bob, MsgType::Text))), ("bob".to_owned(), "hi".to_owned()));
+        assert_eq!(bridge.profile_fetches, vec![bob.clone()]);
+
+        bridge.display_names = false;
+        assert_eq!(text(bridge.render_reply(said(&alice, MsgType::Text))), ("alice".to_owned(), "hi".to_owned()));
+    }
+
+    #[test]
+    fn pills_to_nicks() {
+        let mut bridge = bridge();
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let bob = "@bob:example.org".parse::<UserID>().unwrap();
+        let carol = "@carol:example.org".parse::<UserID>().unwrap();
+        let me = bridge.matrix.uid.clone().unwrap();
+        bridge.nicks.reserve(&bob);
+        let mentions = vec![(bob, "Bob".to_owned()), (carol, "Carol".to_owned()), (me, "Just Me".to_owned())];
+        let said = EventData::Room(room, RoomEvent::Message(alice, MsgType::Text,
+                                                            "Bob, Carol and Just Me: Bobby is here".to_owned()));
+        match bridge.render_pills(said, &mentions) {
+            EventData::Room(_, RoomEvent::Message(_, MsgType::Text, text)) =>
+                assert_eq!(text, "bob, Carol and me: Bobby is here"),
+            data => panic!("Unexpected {:?}", data)
+        }
+    }
+
+    #[test]
+    fn rename_is_not_a_join() {
+        let mut bridge = bridge();
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let bob = "@bob:example.com".parse::<UserID>().unwrap();
+        bridge.room_from_matrix(&room).irc_name = Some("#room".to_owned());
+        let mut messages = vec![];
+        bridge.room_from_matrix(&room).handle_event(RoomEvent::Membership(alice.clone(), MembershipAction::Join), None,
+                                                    |m| messages.push(m));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].command, irc::protocol::Command::Join);
+
+        bridge.handle_matrix(event(&room, RoomEvent::Renamed(alice.clone(), Some("Alice Liddell".to_owned())))).unwrap();
+        bridge.room_from_matrix(&room).handle_event(RoomEvent::Renamed(alice.clone(), Some("Alice Liddell".to_owned())),
+                                                    None, |m| messages.push(m));
+        bridge.room_from_matrix(&room).handle_event(RoomEvent::MemberAvatar(alice.clone()), None, |m| messages.push(m));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(bridge.room_from_matrix(&room).members, vec![alice.clone()]);
+        assert_eq!(bridge.nicks.realname(&alice), Some("Alice Liddell".to_owned()));
+
+        // Someone not seen joining yet is in the room all the same
+        bridge.room_from_matrix(&room).handle_event(RoomEvent::Renamed(bob.clone(), None), None, |m| messages.push(m));
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].command, irc::protocol::Command::Join);
+        assert_eq!(bridge.room_from_matrix(&room).members, vec![alice, bob]);
+    }
+
+    #[test]
+    fn wallops_reaches_every_session() {
+        let mut config = Config::new();
+        config.operators = vec!["@me:example.com".to_owned()];
+        let shared = Shared::new(config);
+        let (mut first, mut first_irc) = connected_session("https://example.com/_matrix/client/r0/", shared.clone());
+        let (mut second, mut second_irc) = connected_session("https://example.com/_matrix/client/r0/", shared.clone());
+        second.matrix.uid = Some("@you:example.com".parse::<UserID>().unwrap());
+        let mut first_loop = EventLoop::new().unwrap();
+        let mut second_loop = EventLoop::new().unwrap();
+        shared.sessions.lock().unwrap().insert(Token(1), Session::new(first_loop.channel()));
+        shared.sessions.lock().unwrap().insert(Token(2), Session::new(second_loop.channel()));
+
+        second.wallops("Free pizza").unwrap();
+        assert!(irc_output(&mut second_irc).contains(" 481 "));
+        // Another homeserver can say anyone logged in
+        second.matrix.uid = Some("@me:example.com".parse::<UserID>().unwrap());
+        second.homeserver = Some("example.org".to_owned());
+        second.wallops("Free pizza").unwrap();
+        assert!(irc_output(&mut second_irc).contains(" 481 "));
+
+        first.wallops("Restarting in 5 minutes").unwrap();
+        first_loop.run_once(&mut first, Some(100)).unwrap();
+        second_loop.run_once(&mut second, Some(100)).unwrap();
+        for irc in &mut [first_irc, second_irc] {
+            let output = irc_output(irc);
+            assert!(output.contains("Announcement from @me:example.com: Restarting in 5 minutes"), "{}", output);
+            assert!(!output.contains("Free pizza"));
+        }
+    }
+
+    #[test]
+    fn muted_rooms() {
+        let mut room = Room::new("!room:example.com".parse::<RoomID>().unwrap());
+        room.irc_name = Some("#room".to_owned());
+        room.muted = true;
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let mut messages = vec![];
+        room.handle_event(message("anyone there?"), None, |m| messages.push(m));
+        room.handle_event(RoomEvent::Membership(alice.clone(), MembershipAction::Join), None, |m| messages.push(m));
+        room.handle_event(RoomEvent::Message(alice.clone(), MsgType::Notice, "beep".to_owned()), None,
+                          |m| messages.push(m));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].command, irc::protocol::Command::Join);
+        assert!(room.members.contains(&alice));
+        room.muted = false;
+        room.handle_event(message("hello?"), None, |m| messages.push(m));
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].command, irc::protocol::Command::Privmsg);
+
+        // Mutes outlive the connection, in account data
+        let url = homeserver(vec![
+            "200 OK\n\n{}",
+            "200 OK\n\n{\"muted\": [\"!pto:example.com\"], \"display_names\": true}",
+            "500 Internal Server Error\n\n{\"errcode\": \"M_UNKNOWN\", \"error\": \"Internal server error\"}",
+            "404 Not Found\n\n{\"errcode\": \"M_NOT_FOUND\", \"error\": \"Account data not found\"}"
+        ]);
+        let id = "!pto:example.com".parse::<RoomID>().unwrap();
+        let dir = temp_dir("muted-rooms");
+        let path = dir.join("session.json");
+        let (mut first, _irc) = connected_bridge(url.trim());
+        first.handle_matrix(event(&id, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        first.open_room(&id, &mut |_| ());
+        first.session_file = Some(path.clone());
+        first.mute("#pto/elsewhere.org", true).unwrap();
+        assert!(first.room_from_matrix(&id).muted);
+        assert!(fs::metadata(path.with_extension("muted")).is_err());
+        let (mut again, _irc) = connected_bridge(url.trim());
+        again.load_preferences();
+        assert!(again.room_from_matrix(&id).muted);
+        assert!(again.display_names);
+
+        // Or next to the session, if the homeserver won't keep them
+        first.mute("#pto/elsewhere.org", true).unwrap();
+        assert_eq!(fs::metadata(path.with_extension("muted")).unwrap().permissions().mode() & 0o777, 0o600);
+        let (mut again, _irc) = connected_bridge(url.trim());
+        again.session_file = Some(path.clone());
+        again.load_preferences();
+        assert!(again.room_from_matrix(&id).muted);
+        assert!(!again.display_names);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn channel_avatars() {
+        let url = homeserver(vec![
+            "200 OK\n\n{\"url\": \"mxc://example.com/cat\"}",
+            "403 Forbidden\n\n{\"errcode\": \"M_FORBIDDEN\", \
+             \"error\": \"You don't have permission to post that to the room.\"}"
+        ]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        irc_output(&mut irc);
+
+        bridge.avatar("#pto/elsewhere.org", None).unwrap();
+        assert!(irc_output(&mut irc).contains(":The avatar of #pto/elsewhere.org is http://"));
+        bridge.avatar("#pto/elsewhere.org", Some("mxc://example.com".to_owned())).unwrap();
+        assert!(irc_output(&mut irc).contains("Not an mxc:// URI: mxc://example.com"));
+        bridge.avatar("#pto/elsewhere.org", Some("mxc://example.com/dog".to_owned())).unwrap();
+        assert!(irc_output(&mut irc).contains("You can't change the avatar of #pto/elsewhere.org: You don't have permission"));
+        bridge.avatar("#nowhere", None).unwrap();
+        assert!(irc_output(&mut irc).contains(" 403 * #nowhere "));
+    }
+
+    #[test]
+    fn alt_aliases_lead_to_the_room() {
+        let mut bridge = bridge();
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#main:elsewhere.org".parse().unwrap()),
+                                                                    vec!["#other:elsewhere.org".parse().unwrap()]))).unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::Aliases(vec!["#legacy:elsewhere.org".parse().unwrap()]))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        assert_eq!(bridge.room_from_matrix(&room).aliases,
+                   vec!["#other:elsewhere.org".parse::<matrix::model::RoomAlias>().unwrap(),
+                        "#legacy:elsewhere.org".parse::<matrix::model::RoomAlias>().unwrap()]);
+        assert_eq!(bridge.room_from_matrix(&room).irc_name, Some("#main/elsewhere.org".to_owned()));
+        for channel in &["#main/elsewhere.org", "#Other/elsewhere.org", "#legacy/elsewhere.org"] {
+            assert_eq!(bridge.room_from_irc(&channel.to_string()).map(|r| r.id.clone()), Some(room.clone()));
+        }
+        assert!(bridge.room_from_irc(&"#unknown/elsewhere.org".to_owned()).is_none());
+    }
+
+    #[test]
+    fn unban_round_trip() {
+        let url = homeserver(vec![
+            "200 OK\n\n{}",
+            "403 Forbidden\n\n{\"errcode\": \"M_FORBIDDEN\", \"error\": \"You don't have permission to unban\"}"
+        ]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let bob = "@bob:example.org".parse::<UserID>().unwrap();
+        let carol = "@carol:example.org".parse::<UserID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::Membership(bob.clone(), MembershipAction::Ban))).unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::Membership(carol.clone(), MembershipAction::Ban))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        irc_output(&mut irc);
+
+        bridge.unban("#pto/elsewhere.org", "dave!*@*").unwrap();
+        assert_eq!(irc_output(&mut irc), ":pto 401 * dave!*@* :No such nick/channel\r\n");
+        bridge.unban("#pto/elsewhere.org", "bob!*@example.org").unwrap();
+        assert_eq!(irc_output(&mut irc), ":me!me@example.com MODE #pto/elsewhere.org -b bob!bob@example.org\r\n");
+        assert_eq!(bridge.room_from_matrix(&room).banned, vec![carol.clone()]);
+        bridge.unban("#pto/elsewhere.org", "carol").unwrap();
+        assert!(irc_output(&mut irc).contains(" 482 * #pto/elsewhere.org "));
+        assert_eq!(bridge.room_from_matrix(&room).banned, vec![carol.clone()]);
+
+        // The leave that comes back through the sync finds bob gone already
+        bridge.handle_matrix(event(&room, RoomEvent::Membership(bob, MembershipAction::Leave))).unwrap();
+        assert_eq!(bridge.room_from_matrix(&room).banned, vec![carol]);
+    }
+
+    #[test]
+    fn encrypted_rooms_say_so() {
+        let url = homeserver(vec![
+            "200 OK\n\n{\"event_id\": \"$topic:example.com\"}",
+            "403 Forbidden\n\n{\"errcode\": \"M_FORBIDDEN\", \"error\": \"You don't have permission to post that to the room\"}"
+        ]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::JoinRules("invite".to_owned()))).unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::Topic("@alice:example.com".parse().unwrap(), "Secrets".to_owned()))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        assert_eq!(bridge.room_from_matrix(&room).title(), Some("Secrets".to_owned()));
+        irc_output(&mut irc);
+        irc.write_all(b"MODE #pto/elsewhere.org\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        assert!(irc_output(&mut irc).contains(" 324 * #pto/elsewhere.org +i "));
+
+        bridge.handle_matrix(event(&room, RoomEvent::Encryption("m.megolm.v1.aes-sha2".to_owned()))).unwrap();
+        irc_output(&mut irc);
+        irc.write_all(b"MODE #pto/elsewhere.org\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        assert!(irc_output(&mut irc).contains(" 324 * #pto/elsewhere.org +iz "));
+        assert_eq!(bridge.room_from_matrix(&room).title(), Some("Secrets (encrypted)".to_owned()));
+        assert_eq!(bridge.room_from_matrix(&room).encryption, Some("m.megolm.v1.aes-sha2".to_owned()));
+
+        // The topic shown, edited, goes back without the mark
+        assert_eq!(strip_encrypted_mark("Top secrets (encrypted)"), "Top secrets");
+        assert_eq!(strip_encrypted_mark("(encrypted)"), "");
+        assert_eq!(strip_encrypted_mark("Secrets"), "Secrets");
+        bridge.set_topic("#pto/elsewhere.org", "Top secrets (encrypted)").unwrap();
+        assert_eq!(irc_output(&mut irc), "");
+        bridge.set_topic("#pto/elsewhere.org", "Top secrets").unwrap();
+        assert!(irc_output(&mut irc).contains(" 482 * #pto/elsewhere.org "));
+    }
+
+    #[test]
+    fn no_history_before_joining() {
+        // Only one page: paging back past our join would be a second request
+        let url = homeserver(vec![
+            "200 OK\n\n{\"start\": \"t5\", \"end\": \"t3\", \"chunk\": [\
+             {\"type\": \"m.room.message\", \"sender\": \"@alice:example.com\", \
+             \"origin_server_ts\": 2000, \"content\": {\"msgtype\": \"m.text\", \"body\": \"after\"}}, \
+             {\"type\": \"m.room.member\", \"sender\": \"@me:example.com\", \"state_key\": \"@me:example.com\", \
+             \"origin_server_ts\": 1500, \"content\": {\"membership\": \"join\"}}, \
+             {\"type\": \"m.room.message\", \"sender\": \"@alice:example.com\", \
+             \"origin_server_ts\": 1000, \"content\": {\"msgtype\": \"m.text\", \"body\": \"before\"}}]}"
+        ]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let me = bridge.matrix.uid.clone().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::HistoryVisibility("joined".to_owned()))).unwrap();
+        let mut join = event(&room, RoomEvent::Membership(me, MembershipAction::Join));
+        join.timestamp = Some(1500);
+        bridge.handle_matrix(join).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        bridge.room_from_matrix(&room).prev_batch = Some(Some("t5".to_owned()));
+        irc_output(&mut irc);
+
+        bridge.history("#pto/elsewhere.org", 20).unwrap();
+        let output = irc_output(&mut irc);
+        assert!(output.contains(" :after\r\n"));
+        assert!(!output.contains("before"));
+        assert_eq!(bridge.room_from_matrix(&room).prev_batch, Some(None));
+        bridge.history("#pto/elsewhere.org", 20).unwrap();
+        assert_eq!(irc_output(&mut irc),
+                   ":pto NOTICE * :#pto/elsewhere.org only shows its members what was said since they joined.\r\n");
+    }
+
+    #[test]
+    fn history_pages_past_state() {
+        let url = homeserver(vec![
+            "200 OK\n\n{\"start\": \"s9\", \"end\": \"t3\", \"chunk\": [\
+             {\"type\": \"m.room.topic\", \"sender\": \"@alice:example.com\", \"state_key\": \"\", \
+             \"origin_server_ts\": 2000, \"content\": {\"topic\": \"Chatter\"}}]}",
+            "200 OK\n\n{\"start\": \"t3\", \"end\": \"t1\", \"chunk\": [\
+             {\"type\": \"m.room.message\", \"sender\": \"@alice:example.com\", \
+             \"origin_server_ts\": 1000, \"content\": {\"msgtype\": \"m.text\", \"body\": \"older\"}}]}"
+        ]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        // Joined after the initial sync, so only the sync token is there
+        bridge.matrix.set_since("s9".to_owned());
+        bridge.open_room(&room, &mut |_| ());
+        assert_eq!(bridge.room_from_matrix(&room).prev_batch, Some(Some("s9".to_owned())));
+        irc_output(&mut irc);
+
+        bridge.history("#pto/elsewhere.org", 20).unwrap();
+        assert!(irc_output(&mut irc).contains(" :older\r\n"));
+        assert_eq!(bridge.room_from_matrix(&room).prev_batch, Some(Some("t1".to_owned())));
+    }
+
+    #[test]
+    fn names_as_members_arrive() {
+        // More long nicks than fit in one NAMES line
+        let members: Vec<String> = (0..20).map(|i| {
+            format!("{{\"type\": \"m.room.member\", \"sender\": \"@member{0:02}_with_a_rather_long_nick:example.com\", \
+                     \"state_key\": \"@member{0:02}_with_a_rather_long_nick:example.com\", \
+                     \"content\": {{\"membership\": \"join\"}}}}", i)
+        }).collect();
+        let url = homeserver(vec![format!("200 OK\n\n{{\"chunk\": [{}]}}", members.join(", "))]);
+        let (mut bridge, mut irc) = connected_bridge(url.trim());
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        irc_output(&mut irc);
+
+        bridge.names("#pto/elsewhere.org").unwrap();
+        let output = irc_output(&mut irc);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with(":pto 353 me @ #pto/elsewhere.org :member00_with_a_rather_long_nick "));
+        assert!(lines[1].starts_with(":pto 353 me @ #pto/elsewhere.org :"));
+        assert!(lines[1].ends_with(" member19_with_a_rather_long_nick"));
+        assert_eq!(lines[2], ":pto 366 me #pto/elsewhere.org :End of /NAMES list");
+        assert!(bridge.room_from_matrix(&room).members_loaded);
+        assert_eq!(bridge.room_from_matrix(&room).members.len(), 20);
+    }
+
+    #[test]
+    fn join_realnames() {
+        let mut bridge = bridge();
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let bob = "@bob:example.com".parse::<UserID>().unwrap();
+        bridge.nicks.reserve(&alice);
+        bridge.nicks.set_display_name(&alice, Some("Alice Liddell".to_owned()));
+        bridge.nicks.reserve(&bob);
+
+        let join = bridge.with_realname(join_message(&alice, "#room".to_owned()));
+        assert_eq!(join.args, vec!["#room".to_owned(), "@alice:example.com".to_owned()]);
+        assert_eq!(join.suffix, Some("Alice Liddell".to_owned()));
+        // Until the display name is in, the localpart stands in for it
+        let join = bridge.with_realname(join_message(&bob, "#room".to_owned()));
+        assert_eq!(join.suffix, Some("bob".to_owned()));
+        assert_eq!(bridge.profile_fetches, vec![bob]);
+    }
+
+    #[test]
+    fn rejected_token_stops_polling() {
+        let (mut bridge, mut irc) = connected_bridge("http://localhost:1");
+        let dir = temp_dir("rejected-token");
+        let path = dir.join("session.json");
+        File::create(&path).unwrap().write_all(b"{\"version\": 1, \"baseurl\": \"http://localhost:1\", \
+                                                 \"access_token\": \"abc\", \"user_id\": \"@me:example.com\"}").unwrap();
+        bridge.matrix = matrix::client::Client::load_session(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        irc_output(&mut irc);
+        assert!(bridge.matrix.is_logged_in());
+        bridge.token_rejected();
+        assert!(!bridge.matrix.is_logged_in());
+        assert!(irc_output(&mut irc).contains("/msg pto login <password>"));
+    }
+
+    #[test]
+    fn unknown_commands() {
+        let (mut bridge, mut irc) = connected_bridge("https://example.com/_matrix/client/r0/");
+        irc.write_all(b"PONG :pto\r\nNOTICE alice :hi\r\nFROBNICATE\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        let output = irc_output(&mut irc);
+        assert_eq!(output.lines().filter(|line| line.contains(" 421 ")).count(), 1);
+        assert!(output.contains("FROBNICATE :Unknown command"));
+    }
+
+    #[test]
+    fn ison_known_members() {
+        let (mut bridge, mut irc) = connected_bridge("https://example.com/_matrix/client/r0/");
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let bob = "@bob:example.com".parse::<UserID>().unwrap();
+        bridge.nicks.reserve(&alice);
+        // Known, but not in any room we're in
+        bridge.nicks.reserve(&bob);
+        bridge.room_from_matrix(&room).members = vec![alice];
+        irc.write_all(b"ISON alice bob :carol\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        bridge.handle_client(&mut EventLoop::new().unwrap());
+        assert_eq!(irc_output(&mut irc), ":pto 303 * :alice\r\n");
+    }
+
+    #[test]
+    fn encrypted_messages_warn_once() {
+        let (mut bridge, mut irc) = connected_bridge("https://example.com/_matrix/client/r0/");
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.open_room(&room, &mut |_| ());
+        irc_output(&mut irc);
+        for _ in 0..3 {
+            bridge.handle_matrix(event(&room, RoomEvent::Encrypted("@alice:example.com".parse().unwrap()))).unwrap();
+        }
+        assert_eq!(irc_output(&mut irc), ":pto NOTICE #pto/elsewhere.org :This room is end-to-end encrypted. \
+                                          Its messages can't be bridged to IRC.\r\n");
+        assert!(bridge.room_from_matrix(&room).encrypted);
+    }
+
+    #[test]
+    fn fetched_titles() {
+        let (mut bridge, mut irc) = connected_bridge("https://example.com/_matrix/client/r0/");
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        bridge.handle_matrix(event(&room, RoomEvent::CanonicalAlias(Some("#pto:elsewhere.org".parse().unwrap()), vec![]))).unwrap();
+        bridge.room_from_matrix(&room).irc_name = Some("#pto/elsewhere.org".to_owned());
+        irc_output(&mut irc);
+
+        bridge.fetched_title(&room, Some("PTO".to_owned()), None);
+        assert_eq!(irc_output(&mut irc), ":pto 332 * #pto/elsewhere.org :PTO\r\n");
+        // What the sync said first stays, and nothing changed
+        bridge.fetched_title(&room, Some("Other".to_owned()), None);
+        assert_eq!(irc_output(&mut irc), "");
+        assert_eq!(bridge.room_from_matrix(&room).title(), Some("PTO".to_owned()));
+    }
+}
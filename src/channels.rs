@@ -0,0 +1,89 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use config::Config;
+
+/// Characters no IRC client accepts in a channel name
+fn is_illegal(c: char) -> bool {
+    match c {
+        ' ' | ',' | ':' | '\x07' | '\0' | '\r' | '\n' => true,
+        _ => false
+    }
+}
+
+/// Turns a room alias, or a room ID if the room has no alias, into an IRC
+/// channel name. The colon before the server name is replaced by the
+/// configured separator, and anything IRC can't carry is dropped.
+pub fn sanitize(alias: &str, config: &Config) -> String {
+    let body = alias.trim_left_matches(|c| c == '#' || c == '!');
+    let mut ret = "#".to_string();
+    for c in body.chars() {
+        if c == ':' {
+            ret.extend(config.channel_separator.chars().filter(|c| !is_illegal(*c)));
+        } else if !is_illegal(c) {
+            if config.channel_lowercase {
+                ret.extend(c.to_lowercase());
+            } else {
+                ret.push(c);
+            }
+        }
+    }
+    ret
+}
+
+/// Guesses the room alias an IRC channel name stands for, undoing what
+/// `sanitize` did as far as possible. A channel without a server part is
+/// taken to be on `homeserver`.
+pub fn alias_for(channel: &str, config: &Config, homeserver: &str) -> String {
+    let name = channel.trim_left_matches(|c| c == '#' || c == '&');
+    if name.contains(':') {
+        return format!("#{}", name);
+    }
+    let separator = config.channel_separator.trim();
+    match name.find(separator) {
+        Some(idx) if separator.len() > 0 =>
+            format!("#{}:{}", &name[..idx], &name[idx + separator.len()..]),
+        _ => format!("#{}:{}", name, homeserver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+
+    #[test]
+    fn sanitize_channel_names() {
+        let config = Config::new();
+        assert_eq!(sanitize("#pto:oob.systems", &config), "#pto/oob.systems");
+        assert_eq!(sanitize("!AbCdEf:example.com", &config), "#abcdef/example.com");
+        assert_eq!(sanitize("#a:b:example.com:8448", &config), "#a/b/example.com/8448");
+        assert_eq!(sanitize("#Café ☕,bar:example.com", &config), "#café☕bar/example.com");
+
+        let mut config = Config::new();
+        config.channel_separator = ":.".to_owned();
+        config.channel_lowercase = false;
+        assert_eq!(sanitize("#Pto:oob.systems", &config), "#Pto.oob.systems");
+    }
+
+    #[test]
+    fn channel_aliases() {
+        let config = Config::new();
+        assert_eq!(alias_for("#pto/oob.systems", &config, "example.com"), "#pto:oob.systems");
+        assert_eq!(alias_for("#pto:oob.systems", &config, "example.com"), "#pto:oob.systems");
+        assert_eq!(alias_for("#the-oob", &config, "oob.systems"), "#the-oob:oob.systems");
+    }
+}
@@ -0,0 +1,240 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use rustc_serialize::json::Json;
+
+/// The port IRC connections are accepted on when the address doesn't say
+pub const DEFAULT_PORT: u16 = 8001;
+/// Likewise for IRC over TLS
+pub const DEFAULT_TLS_PORT: u16 = 6697;
+
+/// Settings shared by every bridge in the process. Everything can be set
+/// through PTO_* environment variables and has a default that keeps the
+/// historical behavior.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Number of messages per room to replay on the initial sync
+    pub sync_limit: u32,
+    /// Where Matrix sessions are persisted between restarts, if anywhere
+    pub session_dir: Option<String>,
+    /// Whether to join the replacement of a room that gets upgraded
+    pub follow_tombstones: bool,
+    /// The channel that notices from the homeserver itself are shown in
+    pub server_notice_channel: String,
+    /// Largest file, in bytes, that may be sent to Matrix over DCC
+    pub dcc_max_size: u64,
+    /// What replaces the colon between a room's name and server in channel
+    /// names, since IRC doesn't allow colons there
+    pub channel_separator: String,
+    /// Whether channel names are lowercased
+    pub channel_lowercase: bool,
+    /// How many recent messages to remember the sender and start of, for
+    /// replies and pins
+    pub event_cache_size: usize,
+    /// Where to accept IRC connections, as in 127.0.0.1:8001 or [::]:8001
+    pub listen_address: String,
+    /// Where to accept IRC connections over TLS, if anywhere besides the
+    /// listen address
+    pub tls_listen_address: Option<String>,
+    /// The PEM certificate and private key for TLS
+    pub tls_cert: String,
+    pub tls_key: String,
+    /// How many messages and notices per second are sent to the IRC
+    /// client, 0 for no limit
+    pub flood_rate: u32,
+    /// How many can be sent at once before that rate kicks in
+    pub flood_burst: u32,
+    /// How long, in seconds, joins and parts in a room are counted over
+    pub churn_window_secs: u64,
+    /// How many joins and parts in that time are shown one by one before
+    /// the rest are summed up in a notice, 0 to always show them all
+    pub churn_threshold: usize,
+    /// The message of the day, one line per line. None for one describing
+    /// the bridge, empty for none at all.
+    pub motd: Option<String>,
+    /// Whether messages of kinds the bridge doesn't know, such as those of
+    /// bots, are shown as notices rather than left out
+    pub show_custom_events: bool,
+    /// Whether presence is followed even for clients that didn't ask for
+    /// away-notify, so that WHO shows who is away. It can make for a lot of
+    /// traffic on accounts in big rooms.
+    pub presence: bool,
+    /// How long, in seconds, the Matrix side of a stored session is kept
+    /// after its IRC client hangs up, for a reconnect to carry on with
+    /// instead of syncing from scratch. 0 to always sync from scratch.
+    pub resume_window_secs: u64,
+    /// Whether a message mentioning @room, the whole room, is addressed to
+    /// the IRC user's nick so that their client highlights it, rather than
+    /// shown as it is
+    pub room_mentions: bool,
+    /// The Matrix users, as in @alice:example.com, who may send WALLOPS to
+    /// everyone connected to the bridge
+    pub operators: Vec<String>,
+    /// The homeservers, other than the bridge's own, that users may log in
+    /// to by giving their full user ID as their IRC username
+    pub allowed_homeservers: Vec<String>,
+    /// What the bridge calls itself in the User-Agent of its requests to
+    /// Matrix, None for pto and its version
+    pub user_agent: Option<String>,
+    /// The sync filter to use instead of the one built from the sync limit
+    pub sync_filter: Option<Json>
+}
+
+fn var<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => match value.trim().parse() {
+            Ok(v) => v,
+            Err(_) => {
+                warn!("Ignoring invalid value {:?} for {}", value, name);
+                default
+            }
+        },
+        Err(_) => default
+    }
+}
+
+/// Reads a JSON object, ignoring anything else
+fn json(name: &str, default: Option<Json>) -> Option<Json> {
+    match env::var(name) {
+        Ok(value) => match Json::from_str(value.trim()) {
+            Ok(js) if js.is_object() => Some(js),
+            _ => {
+                warn!("Ignoring invalid value {:?} for {}", value, name);
+                default
+            }
+        },
+        Err(_) => default
+    }
+}
+
+/// Reads a comma-separated list, leaving out empty entries
+fn list(name: &str, default: Vec<String>) -> Vec<String> {
+    match env::var(name) {
+        Ok(value) => value.split(',')
+            .map(|entry| entry.trim().to_string())
+            .filter(|entry| !entry.is_empty())
+            .collect(),
+        Err(_) => default
+    }
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Config {
+            sync_limit: 0,
+            session_dir: None,
+            follow_tombstones: false,
+            server_notice_channel: "&server-notices".to_string(),
+            dcc_max_size: 10 * 1024 * 1024,
+            channel_separator: "/".to_string(),
+            channel_lowercase: true,
+            event_cache_size: 256,
+            listen_address: format!("127.0.0.1:{}", DEFAULT_PORT),
+            tls_listen_address: None,
+            tls_cert: "pto.crt".to_string(),
+            tls_key: "pto.key".to_string(),
+            flood_rate: 5,
+            flood_burst: 20,
+            churn_window_secs: 10,
+            churn_threshold: 10,
+            motd: None,
+            show_custom_events: false,
+            presence: false,
+            resume_window_secs: 300,
+            room_mentions: false,
+            operators: vec![],
+            allowed_homeservers: vec![],
+            user_agent: None,
+            sync_filter: None
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let defaults = Config::new();
+        Config {
+            sync_limit: var("PTO_SYNC_LIMIT", defaults.sync_limit),
+            session_dir: env::var("PTO_SESSION_DIR").ok().or(defaults.session_dir),
+            follow_tombstones: var("PTO_FOLLOW_TOMBSTONES", defaults.follow_tombstones),
+            server_notice_channel: var("PTO_SERVER_NOTICE_CHANNEL", defaults.server_notice_channel),
+            dcc_max_size: var("PTO_DCC_MAX_SIZE", defaults.dcc_max_size),
+            channel_separator: var("PTO_CHANNEL_SEPARATOR", defaults.channel_separator),
+            channel_lowercase: var("PTO_CHANNEL_LOWERCASE", defaults.channel_lowercase),
+            event_cache_size: var("PTO_EVENT_CACHE_SIZE", defaults.event_cache_size),
+            listen_address: var("PTO_LISTEN_ADDRESS", defaults.listen_address),
+            tls_listen_address: env::var("PTO_TLS_LISTEN_ADDRESS").ok().or(defaults.tls_listen_address),
+            tls_cert: var("PTO_TLS_CERT", defaults.tls_cert),
+            tls_key: var("PTO_TLS_KEY", defaults.tls_key),
+            flood_rate: var("PTO_FLOOD_RATE", defaults.flood_rate),
+            flood_burst: var("PTO_FLOOD_BURST", defaults.flood_burst),
+            churn_window_secs: var("PTO_CHURN_WINDOW", defaults.churn_window_secs),
+            churn_threshold: var("PTO_CHURN_THRESHOLD", defaults.churn_threshold),
+            motd: env::var("PTO_MOTD").ok().or(defaults.motd),
+            show_custom_events: var("PTO_SHOW_CUSTOM_EVENTS", defaults.show_custom_events),
+            presence: var("PTO_PRESENCE", defaults.presence),
+            resume_window_secs: var("PTO_RESUME_WINDOW", defaults.resume_window_secs),
+            room_mentions: var("PTO_ROOM_MENTIONS", defaults.room_mentions),
+            operators: list("PTO_OPERATORS", defaults.operators),
+            allowed_homeservers: list("PTO_ALLOWED_HOMESERVERS", defaults.allowed_homeservers),
+            user_agent: env::var("PTO_USER_AGENT").ok().or(defaults.user_agent),
+            sync_filter: json("PTO_SYNC_FILTER", defaults.sync_filter)
+        }
+    }
+}
+
+/// Parses an address to listen on. IPv6 addresses need brackets when a
+/// port follows, as in [::1]:8001. A bare address gets the default port.
+pub fn parse_listen_address(address: &str) -> Result<SocketAddr, String> {
+    parse_address(address, DEFAULT_PORT)
+}
+
+/// Like `parse_listen_address`, for the TLS listener
+pub fn parse_tls_listen_address(address: &str) -> Result<SocketAddr, String> {
+    parse_address(address, DEFAULT_TLS_PORT)
+}
+
+fn parse_address(address: &str, default_port: u16) -> Result<SocketAddr, String> {
+    let address = address.trim();
+    if let Ok(addr) = address.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let bare = address.trim_left_matches('[').trim_right_matches(']');
+    match bare.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, default_port)),
+        Err(_) => Err(format!("Invalid listen address {:?}, expected something like 127.0.0.1:{} or [::]:{}",
+                              address, default_port, default_port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn listen_addresses() {
+        assert_eq!(parse_listen_address("0.0.0.0:4242"), Ok("0.0.0.0:4242".parse::<SocketAddr>().unwrap()));
+        assert_eq!(parse_listen_address("[::]:4242"), Ok("[::]:4242".parse::<SocketAddr>().unwrap()));
+        assert_eq!(parse_listen_address("::1"), Ok("[::1]:8001".parse::<SocketAddr>().unwrap()));
+        assert_eq!(parse_listen_address("[::1]"), Ok("[::1]:8001".parse::<SocketAddr>().unwrap()));
+        assert_eq!(parse_listen_address(" 127.0.0.1 "), Ok("127.0.0.1:8001".parse::<SocketAddr>().unwrap()));
+        assert!(parse_listen_address("localhost:8001").is_err());
+        assert!(parse_listen_address("127.0.0.1:99999").is_err());
+        assert_eq!(parse_tls_listen_address("0.0.0.0"), Ok("0.0.0.0:6697".parse::<SocketAddr>().unwrap()));
+    }
+}
@@ -0,0 +1,159 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// Commands understood by the bridge when it is messaged directly, as in
+/// `/msg pto register alice hunter2`
+#[derive(Debug, PartialEq)]
+pub enum ControlCommand {
+    Help,
+    Register(String, String),
+    /// Logs in with a token from the homeserver's single sign-on
+    LoginToken(String),
+    /// Logs in again with a password, after the homeserver logged us out
+    Login(String),
+    /// Logs every device of the account out, not just the bridge
+    LogoutAll,
+    /// Lists the sessions of the account
+    Devices,
+    /// Logs out one session of the account
+    DeleteDevice(String),
+    /// Shows older messages of a channel, this many of them
+    History(String, usize),
+    /// Stops showing the messages of a channel, while staying in it
+    Mute(String),
+    /// Shows the messages of a muted channel again
+    Unmute(String),
+    /// Turns starting each message with its sender's display name on or off
+    DisplayNames(bool),
+    /// Deletes one of the messages the user sent lately, counting back from
+    /// the last one, which is 1
+    Redact(usize),
+    /// Shows the avatar of a channel, or changes it to an mxc:// URI
+    Avatar(String, Option<String>)
+}
+
+/// How many older messages `history` shows when not told
+const HISTORY_COUNT: usize = 20;
+
+pub const HELP: &'static [&'static str] = &[
+    "help: show this list",
+    "register <username> <password>: create a new Matrix account and log in",
+    "login-token <token>: log in with a token from your homeserver's single sign-on",
+    "login <password>: log in again after your homeserver logged you out",
+    "logout-all: log out every device of your account, including this one, and disconnect",
+    "devices: list the devices logged in to your account",
+    "delete-device <id>: log out one of your devices",
+    "history <channel> [count]: show older messages of a channel, further back each time",
+    "mute <channel>: stop showing messages of a channel, while staying in it",
+    "unmute <channel>: show messages of a muted channel again",
+    "display-names on|off: start each message with the Matrix display name of whoever sent it",
+    "redact [n]: delete the n-th last message you sent, the last one if not told",
+    "avatar <channel> [mxc://...]: show the avatar of a channel, or change it to uploaded media"
+];
+
+impl ControlCommand {
+    /// Parses a message sent to the bridge. Errors are meant to be shown to
+    /// the user as-is.
+    pub fn from_str(text: &str) -> Result<Self, String> {
+        let args: Vec<&str> = text.split_whitespace().collect();
+        let command = match args.first() {
+            Some(c) => c.to_lowercase(),
+            None => return Err("Say \"help\" for a list of commands.".to_string())
+        };
+        match (command.trim(), args.len()) {
+            ("help", _) => Ok(ControlCommand::Help),
+            ("register", 3) => Ok(ControlCommand::Register(args[1].to_string(), args[2].to_string())),
+            ("register", _) => Err("Usage: register <username> <password>".to_string()),
+            ("login-token", 2) => Ok(ControlCommand::LoginToken(args[1].to_string())),
+            ("login-token", _) => Err("Usage: login-token <token>".to_string()),
+            ("login", 2) => Ok(ControlCommand::Login(args[1].to_string())),
+            ("login", _) => Err("Usage: login <password>".to_string()),
+            ("logout-all", 1) => Ok(ControlCommand::LogoutAll),
+            ("logout-all", _) => Err("Usage: logout-all".to_string()),
+            ("devices", _) => Ok(ControlCommand::Devices),
+            ("delete-device", 2) => Ok(ControlCommand::DeleteDevice(args[1].to_string())),
+            ("delete-device", _) => Err("Usage: delete-device <id>".to_string()),
+            ("history", 2) => Ok(ControlCommand::History(args[1].to_string(), HISTORY_COUNT)),
+            ("history", 3) => match args[2].parse::<usize>() {
+                Ok(count) if count > 0 => Ok(ControlCommand::History(args[1].to_string(), count)),
+                _ => Err("Usage: history <channel> [count]".to_string())
+            },
+            ("history", _) => Err("Usage: history <channel> [count]".to_string()),
+            ("mute", 2) => Ok(ControlCommand::Mute(args[1].to_string())),
+            ("mute", _) => Err("Usage: mute <channel>".to_string()),
+            ("unmute", 2) => Ok(ControlCommand::Unmute(args[1].to_string())),
+            ("unmute", _) => Err("Usage: unmute <channel>".to_string()),
+            ("display-names", 2) => match args[1].to_lowercase().trim() {
+                "on" => Ok(ControlCommand::DisplayNames(true)),
+                "off" => Ok(ControlCommand::DisplayNames(false)),
+                _ => Err("Usage: display-names on|off".to_string())
+            },
+            ("display-names", _) => Err("Usage: display-names on|off".to_string()),
+            ("redact", 1) => Ok(ControlCommand::Redact(1)),
+            ("redact", 2) => match args[1].parse::<usize>() {
+                Ok(n) if n > 0 => Ok(ControlCommand::Redact(n)),
+                _ => Err("Usage: redact [n]".to_string())
+            },
+            ("redact", _) => Err("Usage: redact [n]".to_string()),
+            ("avatar", 2) => Ok(ControlCommand::Avatar(args[1].to_string(), None)),
+            ("avatar", 3) => Ok(ControlCommand::Avatar(args[1].to_string(), Some(args[2].to_string()))),
+            ("avatar", _) => Err("Usage: avatar <channel> [mxc://...]".to_string()),
+            (c, _) => Err(format!("Unknown command \"{}\". Say \"help\" for a list of commands.", c))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(ControlCommand::from_str("help"), Ok(ControlCommand::Help));
+        assert_eq!(ControlCommand::from_str("  REGISTER alice hunter2 "),
+                   Ok(ControlCommand::Register("alice".to_owned(), "hunter2".to_owned())));
+        assert!(ControlCommand::from_str("register alice").is_err());
+        assert_eq!(ControlCommand::from_str("login-token abc123"),
+                   Ok(ControlCommand::LoginToken("abc123".to_owned())));
+        assert!(ControlCommand::from_str("login-token").is_err());
+        assert_eq!(ControlCommand::from_str("login hunter2"), Ok(ControlCommand::Login("hunter2".to_owned())));
+        assert_eq!(ControlCommand::from_str("logout-all"), Ok(ControlCommand::LogoutAll));
+        assert!(ControlCommand::from_str("logout-all now").is_err());
+        assert_eq!(ControlCommand::from_str("devices"), Ok(ControlCommand::Devices));
+        assert_eq!(ControlCommand::from_str("delete-device ABCDEF"),
+                   Ok(ControlCommand::DeleteDevice("ABCDEF".to_owned())));
+        assert!(ControlCommand::from_str("delete-device").is_err());
+        assert_eq!(ControlCommand::from_str("history #room"), Ok(ControlCommand::History("#room".to_owned(), 20)));
+        assert_eq!(ControlCommand::from_str("history #room 50"), Ok(ControlCommand::History("#room".to_owned(), 50)));
+        assert!(ControlCommand::from_str("history #room lots").is_err());
+        assert!(ControlCommand::from_str("history").is_err());
+        assert_eq!(ControlCommand::from_str("mute #room"), Ok(ControlCommand::Mute("#room".to_owned())));
+        assert_eq!(ControlCommand::from_str("UNMUTE #room"), Ok(ControlCommand::Unmute("#room".to_owned())));
+        assert!(ControlCommand::from_str("mute").is_err());
+        assert_eq!(ControlCommand::from_str("display-names ON"), Ok(ControlCommand::DisplayNames(true)));
+        assert_eq!(ControlCommand::from_str("display-names off"), Ok(ControlCommand::DisplayNames(false)));
+        assert!(ControlCommand::from_str("display-names maybe").is_err());
+        assert_eq!(ControlCommand::from_str("redact"), Ok(ControlCommand::Redact(1)));
+        assert_eq!(ControlCommand::from_str("redact 3"), Ok(ControlCommand::Redact(3)));
+        assert!(ControlCommand::from_str("redact 0").is_err());
+        assert_eq!(ControlCommand::from_str("avatar #room"), Ok(ControlCommand::Avatar("#room".to_owned(), None)));
+        assert_eq!(ControlCommand::from_str("avatar #room mxc://example.com/cat"),
+                   Ok(ControlCommand::Avatar("#room".to_owned(), Some("mxc://example.com/cat".to_owned()))));
+        assert!(ControlCommand::from_str("avatar").is_err());
+        assert!(ControlCommand::from_str("frobnicate").is_err());
+        assert!(ControlCommand::from_str("").is_err());
+    }
+}
@@ -0,0 +1,73 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+const DELIM: char = '\x01';
+
+/// A CTCP request or reply, carried inside a PRIVMSG or NOTICE body
+#[derive(Debug, PartialEq)]
+pub struct Ctcp {
+    pub command: String,
+    pub params: Option<String>
+}
+
+impl Ctcp {
+    pub fn new(command: &str, params: Option<String>) -> Self {
+        Ctcp {
+            command: command.to_string(),
+            params: params
+        }
+    }
+
+    /// Parses a message body, returning None if it isn't a CTCP message.
+    /// The closing delimiter is optional, as some clients leave it off.
+    pub fn from_str(text: &str) -> Option<Self> {
+        if !text.starts_with(DELIM) {
+            return None;
+        }
+        let inner = text[1..].trim_right_matches(DELIM);
+        let mut parts = inner.splitn(2, " ");
+        let command = match parts.next() {
+            Some(c) if c.len() > 0 => c.to_uppercase(),
+            _ => return None
+        };
+        Some(Ctcp {
+            command: command,
+            params: parts.next().map(|p| p.to_string())
+        })
+    }
+
+    pub fn to_string(&self) -> String {
+        match self.params {
+            Some(ref params) => format!("{}{} {}{}", DELIM, self.command, params, DELIM),
+            None => format!("{}{}{}", DELIM, self.command, DELIM)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ctcp() {
+        assert_eq!(Ctcp::from_str("\x01VERSION\x01"), Some(Ctcp::new("VERSION", None)));
+        assert_eq!(Ctcp::from_str("\x01ping 1234 5678\x01"), Some(Ctcp::new("PING", Some("1234 5678".to_owned()))));
+        assert_eq!(Ctcp::from_str("\x01ACTION waves"), Some(Ctcp::new("ACTION", Some("waves".to_owned()))));
+        assert_eq!(Ctcp::from_str("Hello \x01world\x01"), None);
+        assert_eq!(Ctcp::from_str("\x01\x01"), None);
+        assert_eq!(Ctcp::new("PING", Some("42".to_owned())).to_string(), "\x01PING 42\x01");
+    }
+}
@@ -0,0 +1,176 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// A file offered with `DCC SEND`. The sender listens for us to connect.
+#[derive(Debug, PartialEq)]
+pub struct DccSend {
+    pub filename: String,
+    pub addr: SocketAddr,
+    pub size: Option<u64>
+}
+
+impl DccSend {
+    /// Parses the parameters of a DCC CTCP, as in
+    /// `SEND "my file.txt" 3232235777 5000 1024`. The address is either the
+    /// traditional 32 bit integer or a textual IPv4 or IPv6 address.
+    pub fn from_str(params: &str) -> Result<Self, String> {
+        let params = params.trim();
+        let mut words = params.splitn(2, " ");
+        match words.next() {
+            Some(kind) if kind.to_uppercase() == "SEND" => (),
+            _ => return Err("Only DCC SEND is supported".to_string())
+        }
+        let rest = words.next().unwrap_or("").trim_left();
+        let (filename, rest) = if rest.starts_with("\"") {
+            match rest[1..].find('"') {
+                Some(end) => (rest[1..end + 1].to_string(), &rest[end + 2..]),
+                None => return Err("Unterminated filename".to_string())
+            }
+        } else {
+            let mut parts = rest.splitn(2, " ");
+            (parts.next().unwrap_or("").to_string(), parts.next().unwrap_or(""))
+        };
+        let args: Vec<&str> = rest.split_whitespace().collect();
+        if filename.len() == 0 || args.len() < 2 {
+            return Err("Usage: DCC SEND <filename> <address> <port> [size]".to_string());
+        }
+        let ip = match args[0].parse::<u32>() {
+            Ok(n) => IpAddr::V4(Ipv4Addr::new((n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8)),
+            Err(_) => match args[0].parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => return Err(format!("Invalid address {}", args[0]))
+            }
+        };
+        let port = match args[1].parse::<u16>() {
+            Ok(0) => return Err("Passive DCC isn't supported".to_string()),
+            Ok(p) => p,
+            Err(_) => return Err(format!("Invalid port {}", args[1]))
+        };
+        let size = match args.get(2) {
+            Some(s) => match s.parse::<u64>() {
+                Ok(size) => Some(size),
+                Err(_) => return Err(format!("Invalid size {}", s))
+            },
+            None => None
+        };
+        Ok(DccSend {
+            // Never let a filename name a path
+            filename: filename.rsplit(|c| c == '/' || c == '\\').next().unwrap().to_string(),
+            addr: SocketAddr::new(ip, port),
+            size: size
+        })
+    }
+
+    /// Connects to the sender and reads the whole file, acknowledging what
+    /// has been received as the protocol requires. Fails if the file grows
+    /// past `max_size`, the sender can't be reached, or it stalls for longer
+    /// than `timeout` once connected.
+    pub fn receive(&self, max_size: u64, timeout: Duration) -> io::Result<Vec<u8>> {
+        let mut stream = try!(TcpStream::connect(&self.addr));
+        try!(stream.set_read_timeout(Some(timeout)));
+        try!(stream.set_write_timeout(Some(timeout)));
+        let mut data = vec![];
+        let mut buf = [0; 4096];
+        loop {
+            if self.size.map(|size| data.len() as u64 >= size).unwrap_or(false) {
+                break;
+            }
+            let count = try!(stream.read(&mut buf));
+            if count == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..count]);
+            if data.len() as u64 > max_size {
+                return Err(io::Error::new(io::ErrorKind::Other, "File is too large"));
+            }
+            let acked = data.len() as u32;
+            try!(stream.write_all(&[(acked >> 24) as u8, (acked >> 16) as u8, (acked >> 8) as u8, acked as u8]));
+        }
+        match self.size {
+            Some(size) if (data.len() as u64) < size =>
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Transfer ended early")),
+            _ => Ok(data)
+        }
+    }
+}
+
+/// Guesses a MIME type from a filename, since DCC doesn't carry one
+pub fn content_type(filename: &str) -> &'static str {
+    let extension = filename.rsplit('.').next().unwrap_or("").to_lowercase();
+    match extension.trim() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "txt" | "log" => "text/plain",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        _ => "application/octet-stream"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_dcc_send() {
+        let offer = DccSend::from_str("SEND cat.png 3232235777 5000 1024").unwrap();
+        assert_eq!(offer.filename, "cat.png");
+        assert_eq!(offer.addr, "192.168.1.1:5000".parse::<SocketAddr>().unwrap());
+        assert_eq!(offer.size, Some(1024));
+
+        let offer = DccSend::from_str("SEND \"my cat.png\" ::1 5000").unwrap();
+        assert_eq!(offer.filename, "my cat.png");
+        assert_eq!(offer.addr, "[::1]:5000".parse::<SocketAddr>().unwrap());
+        assert_eq!(offer.size, None);
+
+        assert_eq!(DccSend::from_str("SEND ../../etc/passwd 1 5000").unwrap().filename, "passwd");
+        assert!(DccSend::from_str("CHAT chat 3232235777 5000").is_err());
+        assert!(DccSend::from_str("SEND cat.png 3232235777 0 1024").is_err());
+        assert!(DccSend::from_str("SEND cat.png").is_err());
+        assert_eq!(content_type("Cat.JPG"), "image/jpeg");
+        assert_eq!(content_type("README"), "application/octet-stream");
+    }
+
+    #[test]
+    fn receive_file() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let offer = DccSend {
+            filename: "hello.txt".to_string(),
+            addr: listener.local_addr().unwrap(),
+            size: Some(5)
+        };
+        thread::spawn(move|| {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"hello").unwrap();
+            let mut ack = [0; 4];
+            stream.read_exact(&mut ack).unwrap();
+        });
+        assert_eq!(offer.receive(10, Duration::from_secs(5)).unwrap(), b"hello".to_vec());
+        // Nobody listens anymore
+        assert!(offer.receive(10, Duration::from_secs(5)).is_err());
+    }
+}
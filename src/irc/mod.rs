@@ -17,4 +17,6 @@
 pub mod protocol;
 pub mod streams;
 pub mod security;
+pub mod ctcp;
+pub mod dcc;
 mod util;
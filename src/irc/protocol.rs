@@ -16,7 +16,7 @@
 
 use std::str::FromStr;
 
-#[derive(Debug,PartialEq,Eq)]
+#[derive(Clone,Debug,PartialEq,Eq)]
 pub enum Command {
     Nick,
     User,
@@ -28,7 +28,16 @@ pub enum Command {
     Pong,
     Pass,
     Privmsg,
+    Notice,
     Topic,
+    Cap,
+    Ison,
+    Names,
+    StartTls,
+    Away,
+    Motd,
+    Who,
+    Wallops,
     Numeric(u32),
     Unknown(String)
 }
@@ -41,21 +50,109 @@ impl Command {
             &Command::Part => "PART".to_string(),
             &Command::Pong => "PONG".to_string(),
             &Command::Privmsg => "PRIVMSG".to_string(),
+            &Command::Notice => "NOTICE".to_string(),
             &Command::User => "USER".to_string(),
             &Command::Quit => "QUIT".to_string(),
             &Command::Ping => "PING".to_string(),
             &Command::Mode => "MODE".to_string(),
             &Command::Pass => "PASS".to_string(),
             &Command::Topic => "TOPIC".to_string(),
+            &Command::Cap => "CAP".to_string(),
+            &Command::Ison => "ISON".to_string(),
+            &Command::Names => "NAMES".to_string(),
+            &Command::StartTls => "STARTTLS".to_string(),
+            &Command::Away => "AWAY".to_string(),
+            &Command::Motd => "MOTD".to_string(),
+            &Command::Who => "WHO".to_string(),
+            &Command::Wallops => "WALLOPS".to_string(),
             &Command::Numeric(n)=> format!("{:0>3}", n),
             &Command::Unknown(ref s) => s.clone()
         }
     }
 }
 
+fn escape_tag_value(value: &str) -> String {
+    let mut ret = String::new();
+    for c in value.chars() {
+        match c {
+            ';' => ret.push_str("\\:"),
+            ' ' => ret.push_str("\\s"),
+            '\\' => ret.push_str("\\\\"),
+            '\r' => ret.push_str("\\r"),
+            '\n' => ret.push_str("\\n"),
+            c => ret.push(c)
+        }
+    }
+    ret
+}
+
+fn unescape_tag_value(value: &str) -> String {
+    let mut ret = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(':') => ret.push(';'),
+                Some('s') => ret.push(' '),
+                Some('r') => ret.push('\r'),
+                Some('n') => ret.push('\n'),
+                Some(c) => ret.push(c),
+                None => ()
+            }
+        } else {
+            ret.push(c);
+        }
+    }
+    ret
+}
+
+/// Formats a Matrix origin_server_ts (milliseconds since the epoch) the way
+/// the IRCv3 server-time tag wants it.
+pub fn server_time(ms: u64) -> String {
+    let secs = ms / 1000;
+    let days = secs / 86400;
+    let day_secs = secs % 86400;
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day,
+            day_secs / 3600, (day_secs / 60) % 60, day_secs % 60,
+            ms % 1000)
+}
+
 impl Message {
     pub fn to_string(&self) -> String {
+        self.to_string_filtered(|_| true)
+    }
+
+    /// Serializes the message, keeping only the tags that `allowed` accepts.
+    pub fn to_string_filtered<F>(&self, allowed: F) -> String
+            where F: Fn(&str) -> bool {
         let mut ret = String::new();
+        let tags: Vec<String> = self.tags.iter().filter(|&&(ref name, _)| {
+            allowed(&name[..])
+        }).map(|&(ref name, ref value)| {
+            if value.len() == 0 {
+                name.clone()
+            } else {
+                format!("{}={}", name, escape_tag_value(value))
+            }
+        }).collect();
+        if tags.len() > 0 {
+            ret.push('@');
+            ret.push_str(tags.join(";").trim());
+            ret.push(' ');
+        }
         match self.prefix {
             Some(ref pfx) => {
                 ret.push(':');
@@ -120,7 +217,26 @@ impl Message {
         (prefix, args, suffix)
     }
 
+    fn split_tags(line: &str) -> (Vec<(String, String)>, &str) {
+        if !line.starts_with("@") {
+            return (vec![], line);
+        }
+        let (raw_tags, rest) = match line.find(' ') {
+            Some(idx) => (&line[1..idx], &line[idx+1..]),
+            None => (&line[1..], "")
+        };
+        let mut tags = vec![];
+        for tag in raw_tags.split(";") {
+            let mut kv = tag.splitn(2, "=");
+            let name = kv.next().unwrap_or("").to_string();
+            let value = unescape_tag_value(kv.next().unwrap_or(""));
+            tags.push((name, value));
+        }
+        (tags, rest)
+    }
+
     pub fn from_str(line: &str) -> Self {
+        let (tags, line) = Self::split_tags(line.trim());
         let parts = Self::split_parts(line.trim());
         let split: Vec<&str> = parts.1.split(" ").collect();
         let mut args = Vec::new();
@@ -129,6 +245,7 @@ impl Message {
         }
         let parsed_command: Result<Command, Command> = split[0].parse();
         Message{
+            tags: tags,
             prefix: parts.0,
             command: parsed_command.ok().unwrap(),
             args: args,
@@ -140,6 +257,7 @@ impl Message {
 impl From<Command> for Message {
     fn from(c: Command) -> Message {
         Message {
+            tags: vec![],
             prefix: None,
             command: c,
             args: vec![],
@@ -162,14 +280,24 @@ impl FromStr for Command {
             "MODE" => Ok(Command::Mode),
             "PASS" => Ok(Command::Pass),
             "TOPIC" => Ok(Command::Topic),
+            "CAP" => Ok(Command::Cap),
+            "ISON" => Ok(Command::Ison),
+            "NAMES" => Ok(Command::Names),
+            "STARTTLS" => Ok(Command::StartTls),
+            "AWAY" => Ok(Command::Away),
+            "MOTD" => Ok(Command::Motd),
+            "WHO" => Ok(Command::Who),
+            "WALLOPS" => Ok(Command::Wallops),
             "PRIVMSG" => Ok(Command::Privmsg),
+            "NOTICE" => Ok(Command::Notice),
             _ => Ok(Command::Unknown(s.to_string()))
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Message {
+    pub tags: Vec<(String, String)>,
     pub prefix: Option<String>,
     pub command: Command,
     pub args: Vec<String>,
@@ -241,4 +369,26 @@ mod tests {
         assert_eq!(msg.args, &["#héhé"]);
         assert_eq!(msg.suffix, Some("In a chan with utf8 in its name!".to_owned()));
     }
+
+    #[test]
+    fn message_tags() {
+        let msg = Message::from_str("@time=2016-01-01T00:00:00.000Z;x=a\\sb\\:c :nick!nick@hostname PRIVMSG #foo :Hi");
+        assert_eq!(msg.tags, vec![("time".to_owned(), "2016-01-01T00:00:00.000Z".to_owned()),
+                                  ("x".to_owned(), "a b;c".to_owned())]);
+        assert_eq!(msg.prefix, Some("nick!nick@hostname".to_owned()));
+        assert_eq!(msg.command, Command::Privmsg);
+        assert_eq!(msg.args, &["#foo"]);
+        assert_eq!(msg.suffix, Some("Hi".to_owned()));
+        assert_eq!(msg.to_string(), "@time=2016-01-01T00:00:00.000Z;x=a\\sb\\:c :nick!nick@hostname PRIVMSG #foo :Hi");
+        assert_eq!(msg.to_string_filtered(|t| t == "time"), "@time=2016-01-01T00:00:00.000Z :nick!nick@hostname PRIVMSG #foo :Hi");
+        assert_eq!(msg.to_string_filtered(|_| false), ":nick!nick@hostname PRIVMSG #foo :Hi");
+    }
+
+    #[test]
+    fn server_times() {
+        assert_eq!(server_time(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(server_time(1451606400000), "2016-01-01T00:00:00.000Z");
+        assert_eq!(server_time(1456704000123), "2016-02-29T00:00:00.123Z");
+        assert_eq!(server_time(1476614096789), "2016-10-16T10:34:56.789Z");
+    }
 }
\ No newline at end of file
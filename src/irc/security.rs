@@ -19,7 +19,9 @@ use std::fmt;
 #[derive(Clone)]
 pub struct Auth {
     pub password: Option<String>,
-    pub username: Option<String>
+    pub username: Option<String>,
+    /// The homeserver to log in to, if not the bridge's own
+    pub homeserver: Option<String>
 }
 
 impl fmt::Debug for Auth {
@@ -43,10 +45,16 @@ impl AuthSession {
     fn new_auth() -> Auth {
         Auth {
             password: None,
-            username: None
+            username: None,
+            homeserver: None
         }
     }
 
+    /// Whether USER has been seen, which with NICK is all registration needs
+    pub fn has_username(&self) -> bool {
+        self.auth.username.is_some()
+    }
+
     pub fn consume(&mut self) -> Auth {
         let ret = self.auth.clone();
         self.auth = AuthSession::new_auth();
@@ -60,4 +68,66 @@ impl AuthSession {
     pub fn set_username(&mut self, username: String) {
         self.auth.username = Some(username);
     }
+
+    pub fn set_homeserver(&mut self, homeserver: String) {
+        self.auth.homeserver = Some(homeserver);
+    }
+}
+
+fn is_server_name(host: &str) -> bool {
+    if host.starts_with("[") {
+        host.ends_with("]") && host.len() > 2 &&
+            host[1..host.len() - 1].chars().all(|c| c.is_digit(16) || c == ':' || c == '.')
+    } else {
+        !host.is_empty() && host.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '.')
+    }
+}
+
+/// Splits a username given as a full Matrix user ID, as in
+/// `@alice:example.com`, into the homeserver to log in to and the localpart.
+/// Localparts can't hold a ':', so a plain username is never taken for one,
+/// and passwords are left alone. Only the homeservers in `allowed` may be
+/// named, anything else would have the bridge connect wherever a client
+/// asks.
+pub fn split_user_id(username: &str, allowed: &[String]) -> Result<(Option<String>, String), String> {
+    if !username.starts_with("@") {
+        return Ok((None, username.to_string()));
+    }
+    let (localpart, server) = match username.find(':') {
+        Some(idx) => (&username[1..idx], &username[idx + 1..]),
+        None => return Err(format!("{} is not a Matrix user ID", username))
+    };
+    let host = match server.rfind(':') {
+        Some(idx) if !server.ends_with("]") && server[idx + 1..].chars().all(|c| c.is_digit(10)) => &server[..idx],
+        _ => server
+    };
+    if localpart.is_empty() || !is_server_name(host) {
+        return Err(format!("{} is not a Matrix user ID", username));
+    }
+    if !allowed.iter().any(|a| a == server) {
+        return Err(format!("{} is not a homeserver this bridge may log in to", server));
+    }
+    Ok((Some(server.to_string()), localpart.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_user_id;
+
+    #[test]
+    fn homeserver_in_username() {
+        let allowed = vec!["example.com".to_string(), "example.org:8448".to_string(), "[::1]:8008".to_string()];
+        assert_eq!(split_user_id("alice", &allowed), Ok((None, "alice".to_string())));
+        assert_eq!(split_user_id("@alice:example.com", &allowed),
+                   Ok((Some("example.com".to_string()), "alice".to_string())));
+        assert_eq!(split_user_id("@alice:example.org:8448", &allowed),
+                   Ok((Some("example.org:8448".to_string()), "alice".to_string())));
+        assert_eq!(split_user_id("@alice:[::1]:8008", &allowed),
+                   Ok((Some("[::1]:8008".to_string()), "alice".to_string())));
+        assert!(split_user_id("@alice:internal.example.net", &allowed).is_err());
+        assert!(split_user_id("@alice:example.com", &[]).is_err());
+        assert!(split_user_id("@alice", &allowed).is_err());
+        assert!(split_user_id("@:example.com", &allowed).is_err());
+        assert!(split_user_id("@alice:exa mple.com", &allowed).is_err());
+    }
 }
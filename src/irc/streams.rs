@@ -16,22 +16,110 @@
 
 use std::io::{Read, Write};
 use std::io;
-use mio::Evented;
-use openssl::ssl::SslStream;
+use std::collections::{HashSet, VecDeque};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use mio::{Evented, EventSet, PollOpt, Selector, Token};
 use mio::tcp::TcpStream;
+use log::LogLevel;
+use openssl::ssl::SslContext;
 
-use irc::util::LineReader;
+use irc::util::{LineReader, OutputBuffer, RateLimiter};
 use irc::protocol::*;
 use irc::security::AuthSession;
+use ssl::TlsStream;
+
+pub const SERVER_NAME: &'static str = "pto";
+pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+/// How wide lines of the MOTD are, as a terminal would show them
+const MOTD_WIDTH: usize = 80;
+/// Matrix localparts may be up to 255 characters long
+pub const NICKLEN: usize = 255;
+/// IRCv3 capabilities the bridge knows how to speak
+pub const SUPPORTED_CAPS: &'static [&'static str] = &["server-time", "message-tags", "extended-join",
+                                                        "away-notify", "multi-prefix", "echo-message"];
+/// AUTHENTICATE arguments that aren't credentials and are safe to log
+const SASL_KEYWORDS: &'static [&'static str] = &["PLAIN", "EXTERNAL", "SCRAM-SHA-1", "SCRAM-SHA-256", "+", "*"];
+const REDACTED: &'static str = "<redacted>";
+/// How many lines flood protection holds back before it drops further ones
+const MAX_HELD: usize = 1000;
+
+/// Returns a copy of `message` with its credentials masked, or None if
+/// there is nothing in it that shouldn't end up in the logs.
+fn redacted(message: &Message) -> Option<Message> {
+    let mut safe = message.clone();
+    match message.command {
+        Command::Pass => {
+            safe.args = message.args.iter().map(|_| REDACTED.to_string()).collect();
+            safe.suffix = message.suffix.as_ref().map(|_| REDACTED.to_string());
+        },
+        Command::Unknown(ref name) if name.to_uppercase() == "AUTHENTICATE" => {
+            safe.args = message.args.iter().map(|arg| {
+                if SASL_KEYWORDS.contains(&arg.to_uppercase().trim()) {
+                    arg.clone()
+                } else {
+                    REDACTED.to_string()
+                }
+            }).collect();
+            safe.suffix = message.suffix.as_ref().map(|_| REDACTED.to_string());
+        },
+        Command::Privmsg if message.args.get(0).map(|t| t.to_lowercase() == SERVER_NAME).unwrap_or(false) => {
+            // Control commands such as `register <username> <password>`
+            let text = message.suffix.clone().unwrap_or(String::new());
+            let words: Vec<&str> = text.split_whitespace().collect();
+            let command = words.get(0).map(|w| w.to_lowercase()).unwrap_or(String::new());
+            match command.trim() {
+                "register" if words.len() > 2 =>
+                    safe.suffix = Some(format!("{} {} {}", words[0], words[1], REDACTED)),
+                "login-token" | "login" if words.len() > 1 =>
+                    safe.suffix = Some(format!("{} {}", words[0], REDACTED)),
+                _ => return None
+            }
+        },
+        _ => return None
+    }
+    Some(safe)
+}
 
 pub trait AsEvented {
     fn as_evented(&self) -> &Evented;
 }
 
-pub trait IrcStream: Read + Write + AsEvented + Send {}
+pub trait IrcStream: Read + Write + AsEvented + Send {
+    fn is_tls(&self) -> bool {
+        false
+    }
+
+    /// Switches the connection to TLS, for STARTTLS. If that fails the
+    /// connection is handed back as it was, along with the error.
+    fn start_tls(self: Box<Self>, ssl: &SslContext) -> Result<Box<IrcStream>, (io::Error, Box<IrcStream>)>;
+
+    /// Where the client connects from, if that is known
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl IrcStream for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
 
-impl IrcStream for SslStream<TcpStream> {}
-impl IrcStream for TcpStream {}
+    fn start_tls(self: Box<Self>, ssl: &SslContext) -> Result<Box<IrcStream>, (io::Error, Box<IrcStream>)> {
+        // The handshake takes the socket, so a copy of it is kept to go on
+        // in the clear with if it can't start
+        let fallback = match self.try_clone() {
+            Ok(socket) => socket,
+            Err(err) => return Err((err, self))
+        };
+        match TlsStream::accept(ssl, *self) {
+            Ok(stream) => Ok(Box::new(stream)),
+            Err(err) => Err((err, Box::new(fallback)))
+        }
+    }
+}
 
 impl AsEvented for TcpStream {
     fn as_evented(&self) -> &Evented {
@@ -39,9 +127,48 @@ impl AsEvented for TcpStream {
     }
 }
 
-impl AsEvented for SslStream<TcpStream> {
+/// Stands in for the connection while it's being switched to TLS
+struct Closed;
+
+impl Read for Closed {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for Closed {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "The connection is switching to TLS"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for Closed {
+    fn register(&self, _selector: &mut Selector, _token: Token, _interest: EventSet, _opts: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&self, _selector: &mut Selector, _token: Token, _interest: EventSet, _opts: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&self, _selector: &mut Selector) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsEvented for Closed {
     fn as_evented(&self) -> &Evented {
-        self.get_ref()
+        self
+    }
+}
+
+impl IrcStream for Closed {
+    fn start_tls(self: Box<Self>, _ssl: &SslContext) -> Result<Box<IrcStream>, (io::Error, Box<IrcStream>)> {
+        Err((io::Error::new(io::ErrorKind::NotConnected, "There is no connection to switch to TLS"), self))
     }
 }
 
@@ -54,8 +181,23 @@ impl AsEvented for Client {
 pub struct Client {
     stream: Box<IrcStream>,
     line_reader: LineReader,
+    /// What the socket hasn't taken yet, flushed once it's writable
+    output: OutputBuffer,
+    /// Paces messages and notices, if flood protection is on
+    limiter: Option<RateLimiter>,
+    /// Lines held back by the limiter, along with whether each needs a
+    /// token. Everything queues behind them, so that order is kept.
+    held: VecDeque<(String, bool)>,
+    /// Lines dropped since `held` filled up, owned up to once it drains
+    dropped: usize,
     nickname: Option<String>,
     username: Option<String>,
+    caps: HashSet<String>,
+    /// Whether capability negotiation was started with CAP LS or REQ and
+    /// hasn't been ended yet
+    negotiating: bool,
+    /// What STARTTLS would use, if it's offered
+    starttls: Option<Arc<SslContext>>,
     pub auth: AuthSession,
 }
 
@@ -64,8 +206,15 @@ impl Client {
         Client {
             stream: stream,
             line_reader: LineReader::new(),
+            output: OutputBuffer::new(),
+            limiter: None,
+            held: VecDeque::new(),
+            dropped: 0,
             nickname: None,
             username: None,
+            caps: HashSet::new(),
+            negotiating: false,
+            starttls: None,
             auth: AuthSession::new(),
         }
     }
@@ -73,13 +222,80 @@ impl Client {
     pub fn read_message(&mut self) -> Option<Message> {
         match self.line_reader.read(&mut self.stream) {
             Some(line) => {
-                trace!("<< {}", line);
-                Some(Message::from_str(line.trim()))
+                let message = Message::from_str(line.trim());
+                if log_enabled!(LogLevel::Trace) {
+                    match redacted(&message) {
+                        Some(safe) => trace!("<< {} {:?}", safe.to_string(), safe),
+                        None => trace!("<< {} {:?}", line.trim(), message)
+                    }
+                }
+                Some(message)
             },
             None => None
         }
     }
 
+    /// Whether the client hung up or the connection broke
+    pub fn is_closed(&self) -> bool {
+        self.line_reader.is_closed()
+    }
+
+    /// Where the client connects from, if that is known
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Lets the client upgrade a plaintext connection with STARTTLS
+    pub fn offer_starttls(&mut self, ssl: Arc<SslContext>) {
+        self.starttls = Some(ssl);
+    }
+
+    fn is_tls(&self) -> bool {
+        self.stream.is_tls()
+    }
+
+    /// The capabilities this connection may request
+    fn available_caps(&self) -> Vec<&'static str> {
+        let mut caps = SUPPORTED_CAPS.to_vec();
+        if self.starttls.is_some() && !self.is_tls() {
+            caps.push("tls");
+        }
+        caps
+    }
+
+    /// Answers STARTTLS. RPL_STARTTLS (670) goes out in the clear, then the
+    /// TLS handshake starts on the same socket. Whatever the client sent
+    /// after STARTTLS but before the handshake is thrown away, and nothing
+    /// more is read until the handshake is done. If the handshake can't
+    /// start the connection stays in the clear, with ERR_STARTTLS (691).
+    pub fn start_tls(&mut self) -> io::Result<usize> {
+        let ssl = match (self.starttls.clone(), self.is_tls()) {
+            (Some(_), true) => return self.numeric(691, vec![], "TLS is already in use"),
+            (Some(ssl), false) => ssl,
+            (None, _) => return self.numeric(691, vec![], "STARTTLS isn't available")
+        };
+        try!(self.numeric(670, vec![], "STARTTLS successful, proceed with TLS handshake"));
+        if self.has_pending_output() {
+            return Err(io::Error::new(io::ErrorKind::Other, "Could not confirm STARTTLS"));
+        }
+        self.line_reader.clear();
+        let stream = mem::replace(&mut self.stream, Box::new(Closed));
+        match stream.start_tls(&ssl) {
+            Ok(stream) => {
+                self.stream = stream;
+                Ok(0)
+            },
+            Err((err, stream)) => {
+                self.stream = stream;
+                self.numeric(691, vec![], format!("STARTTLS failed: {}", err).trim())
+            }
+        }
+    }
+
+    pub fn has_nickname(&self) -> bool {
+        self.nickname.is_some()
+    }
+
     pub fn set_nickname(&mut self, nickname: String) {
         self.nickname = Some(nickname);
     }
@@ -87,6 +303,7 @@ impl Client {
     pub fn join(&mut self, channel: &str) -> io::Result<usize> {
         let pfx = self.nickname.clone().unwrap();
         self.send(&Message {
+            tags: vec![],
             prefix: Some(pfx),
             command: Command::Join,
             args: vec![channel.to_string()],
@@ -94,26 +311,401 @@ impl Client {
         })
     }
 
+    pub fn part(&mut self, channel: &str) -> io::Result<usize> {
+        let pfx = self.nickname.clone().unwrap();
+        self.send(&Message {
+            tags: vec![],
+            prefix: Some(pfx),
+            command: Command::Part,
+            args: vec![channel.to_string()],
+            suffix: None
+        })
+    }
+
     pub fn pong(&mut self) -> io::Result<usize> {
         self.send(&Message::from(Command::Pong))
     }
 
-    pub fn welcome(&mut self, message: &str) -> io::Result<usize> {
+    pub fn numeric(&mut self, num: u32, args: Vec<String>, suffix: &str) -> io::Result<usize> {
+        let mut full_args = vec![self.nickname.clone().unwrap_or("*".to_string())];
+        full_args.extend(args);
         self.send(&Message {
-            prefix: Some("pto".to_string()),
-            command: Command::Numeric(1),
-            args: vec![message.to_string()],
-            suffix: None
+            tags: vec![],
+            prefix: Some(SERVER_NAME.to_string()),
+            command: Command::Numeric(num),
+            args: full_args,
+            suffix: Some(suffix.to_string())
         })
     }
 
+    /// Sends the RPL_WELCOME (001) through RPL_MYINFO (004) burst, followed
+    /// by RPL_ISUPPORT (005). Clients consider registration complete once
+    /// they've seen these.
+    pub fn welcome(&mut self, nickname: &str) -> io::Result<usize> {
+        let isupport = vec![
+            "CHANTYPES=#&".to_string(),
+            format!("NICKLEN={}", NICKLEN),
+            "CASEMAPPING=rfc1459".to_string(),
+            "PREFIX=(ov)@+".to_string(),
+            "NETWORK=Matrix".to_string()
+        ];
+        self.numeric(1, vec![], format!("Welcome to Matrix, {}", nickname).trim())
+            .and(self.numeric(2, vec![], format!("Your host is {}, running version {}", SERVER_NAME, VERSION).trim()))
+            .and(self.numeric(3, vec![], "This server bridges IRC to Matrix"))
+            .and(self.numeric(4, vec![SERVER_NAME.to_string(), VERSION.to_string(), "i".to_string()], "iRovz"))
+            .and(self.numeric(5, isupport, "are supported by this server"))
+    }
+
+    /// Sends the message of the day, wrapped to fit, or ERR_NOMOTD (422) if
+    /// there is none
+    pub fn motd(&mut self, lines: &[String]) -> io::Result<usize> {
+        if lines.iter().all(|line| line.trim().is_empty()) {
+            return self.numeric(422, vec![], "MOTD File is missing");
+        }
+        let mut res = self.numeric(375, vec![], format!("- {} Message of the Day -", SERVER_NAME).trim());
+        for line in lines {
+            for part in wrap(line.trim_right(), MOTD_WIDTH) {
+                res = res.and(self.numeric(372, vec![], format!("- {}", part).trim_right()));
+            }
+        }
+        res.and(self.numeric(376, vec![], "End of /MOTD command."))
+    }
+
+    /// Sends a NOTICE from `from` to the connected user
+    pub fn notice(&mut self, from: &str, text: &str) -> io::Result<usize> {
+        let target = self.nickname.clone().unwrap_or("*".to_string());
+        self.send(&Message {
+            tags: vec![],
+            prefix: Some(from.to_string()),
+            command: Command::Notice,
+            args: vec![target],
+            suffix: Some(text.to_string())
+        })
+    }
+
+    pub fn nick_in_use(&mut self, nickname: &str) -> io::Result<usize> {
+        self.numeric(433, vec![nickname.to_string()], "Nickname is already in use")
+    }
+
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.contains(cap)
+    }
+
+    /// Whether registration has to wait for CAP END
+    pub fn is_negotiating(&self) -> bool {
+        self.negotiating
+    }
+
+    fn cap_reply(&mut self, subcommand: &str, caps: &str) -> io::Result<usize> {
+        let target = self.nickname.clone().unwrap_or("*".to_string());
+        self.send(&Message {
+            tags: vec![],
+            prefix: Some(SERVER_NAME.to_string()),
+            command: Command::Cap,
+            args: vec![target, subcommand.to_string()],
+            suffix: Some(caps.to_string())
+        })
+    }
+
+    /// Answers CAP LS, LIST and REQ. A REQ is acknowledged only if every
+    /// requested capability is supported, as the spec requires. LS and REQ
+    /// hold up registration until END.
+    pub fn handle_cap(&mut self, message: &Message) -> io::Result<usize> {
+        let subcommand = match message.args.get(0) {
+            Some(s) => s.to_uppercase(),
+            None => return Ok(0)
+        };
+        match subcommand.trim() {
+            "LS" => {
+                self.negotiating = true;
+                let caps = self.available_caps();
+                self.cap_reply("LS", caps.join(" ").trim())
+            },
+            "LIST" => {
+                let enabled: Vec<String> = self.caps.iter().cloned().collect();
+                self.cap_reply("LIST", enabled.join(" ").trim())
+            },
+            "REQ" => {
+                self.negotiating = true;
+                let requested = message.suffix.clone()
+                    .or(message.args.get(1).cloned())
+                    .unwrap_or(String::new());
+                let available = self.available_caps();
+                let acceptable = requested.split_whitespace().all(|cap| {
+                    available.contains(&cap.trim_left_matches('-'))
+                });
+                if acceptable {
+                    for cap in requested.split_whitespace() {
+                        if cap.starts_with("-") {
+                            self.caps.remove(&cap[1..]);
+                        } else {
+                            self.caps.insert(cap.to_string());
+                        }
+                    }
+                    self.cap_reply("ACK", requested.trim())
+                } else {
+                    self.cap_reply("NAK", requested.trim())
+                }
+            },
+            "END" => {
+                self.negotiating = false;
+                Ok(0)
+            },
+            _ => Ok(0)
+        }
+    }
+
+    /// Whether a message tag may be sent given the negotiated capabilities
+    fn tag_allowed(&self, tag: &str) -> bool {
+        self.has_cap("message-tags") || (tag == "time" && self.has_cap("server-time"))
+    }
+
+    /// The form of a message the negotiated capabilities allow, if it
+    /// differs. JOINs are built with the account and realname of
+    /// extended-join, and NAMES with every prefix of multi-prefix, which only
+    /// go out if those were negotiated.
+    fn downgraded(&self, message: &Message) -> Option<Message> {
+        match message.command {
+            Command::Join if message.args.len() > 1 && !self.has_cap("extended-join") => {
+                let mut plain = message.clone();
+                plain.args.truncate(1);
+                plain.suffix = None;
+                Some(plain)
+            },
+            Command::Numeric(353) if !self.has_cap("multi-prefix") => {
+                let mut plain = message.clone();
+                plain.suffix = message.suffix.as_ref().map(|names| highest_prefixes(names));
+                Some(plain)
+            },
+            _ => None
+        }
+    }
+
     pub fn send(&mut self, message: &Message) -> io::Result<usize> {
-        trace!(">>> {}", message.to_string());
-        self.stream.write(&message.to_string().trim().as_bytes())
-            .and(self.stream.write("\r\n".as_bytes()))
+        let downgraded = self.downgraded(message);
+        let message = downgraded.as_ref().unwrap_or(message);
+        let line = message.to_string_filtered(|tag| self.tag_allowed(tag));
+        if log_enabled!(LogLevel::Trace) {
+            match redacted(message) {
+                Some(safe) => trace!(">>> {} {:?}", safe.to_string(), safe),
+                None => trace!(">>> {} {:?}", line.trim(), message)
+            }
+        }
+        let limited = message.command == Command::Privmsg || message.command == Command::Notice;
+        if self.held.is_empty() && (!limited || self.take_token()) {
+            self.output.queue(line.trim().as_bytes());
+            self.output.queue(b"\r\n");
+        } else if self.held.len() < MAX_HELD {
+            self.held.push_back((line.trim().to_string(), limited));
+        } else {
+            self.dropped += 1;
+        }
+        self.flush()
+    }
+
+    /// Paces messages and notices to `rate` per second, with bursts of up
+    /// to `burst`. A rate of 0 turns flood protection off.
+    pub fn set_rate_limit(&mut self, rate: u32, burst: u32) {
+        self.limiter = if rate > 0 {
+            Some(RateLimiter::new(rate, burst, Instant::now()))
+        } else {
+            None
+        };
+    }
+
+    fn take_token(&mut self) -> bool {
+        match self.limiter {
+            Some(ref mut limiter) => limiter.take(Instant::now()),
+            None => true
+        }
+    }
+
+    /// Sends on whatever the limiter held back and now allows
+    pub fn release(&mut self) -> io::Result<usize> {
+        loop {
+            let limited = match self.held.front() {
+                Some(&(_, limited)) => limited,
+                None => break
+            };
+            if limited && !self.take_token() {
+                break;
+            }
+            let (line, _) = self.held.pop_front().unwrap();
+            self.output.queue(line.as_bytes());
+            self.output.queue(b"\r\n");
+        }
+        if self.held.is_empty() && self.dropped > 0 {
+            let dropped = mem::replace(&mut self.dropped, 0);
+            warn!("Dropped {} lines that came faster than flood protection lets out", dropped);
+            return self.notice(SERVER_NAME, &format!("{} lines were dropped, as they came faster than \
+                                                      flood protection lets them out", dropped));
+        }
+        self.flush()
+    }
+
+    /// How long until held back lines may go out, if any are
+    pub fn held_for_ms(&mut self) -> Option<u64> {
+        if self.held.is_empty() {
+            return None;
+        }
+        Some(match self.limiter {
+            Some(ref mut limiter) => limiter.wait_ms(Instant::now()),
+            None => 0
+        })
+    }
+
+    /// Writes out as much queued output as the socket takes
+    pub fn flush(&mut self) -> io::Result<usize> {
+        self.output.flush(&mut self.stream)
+    }
+
+    /// Whether there is output waiting for the socket to be writable
+    pub fn has_pending_output(&self) -> bool {
+        !self.output.is_empty()
     }
 }
 
+/// Breaks text into lines of at most `width` characters, between words
+/// where it can
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut line = String::new();
+    for word in text.split(' ') {
+        let mut word = word.to_string();
+        // A word too long for a line of its own is cut
+        while word.chars().count() > width {
+            if !line.is_empty() {
+                lines.push(line);
+                line = String::new();
+            }
+            let idx = word.char_indices().nth(width).map(|(idx, _)| idx).unwrap_or(word.len());
+            lines.push(word[..idx].to_string());
+            word = word[idx..].to_string();
+        }
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+            lines.push(line);
+            line = String::new();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word.trim());
+    }
+    lines.push(line);
+    lines
+}
+
+/// Keeps only the highest channel prefix of each name in a NAMES reply
+fn highest_prefixes(names: &str) -> String {
+    let names: Vec<String> = names.split_whitespace().map(|name| {
+        let nick = name.trim_left_matches(|c| c == '@' || c == '+');
+        match name.chars().next() {
+            Some(prefix) if nick.len() < name.len() => format!("{}{}", prefix, nick),
+            _ => nick.to_string()
+        }
+    }).collect();
+    names.join(" ")
+}
+
 pub trait Server: AsEvented {
-    fn accept(&mut self) -> Option<Client>;
+    /// Accepts a pending connection, if there is one. An error concerns
+    /// that one connection, the server keeps accepting others.
+    fn accept(&mut self) -> io::Result<Option<Client>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Client, MAX_HELD, redacted, wrap};
+    use irc::protocol::{Command, Message};
+    use mio;
+
+    #[test]
+    fn extended_join() {
+        let listener = mio::tcp::TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let stream = mio::tcp::TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let mut client = Client::new(Box::new(stream));
+        let join = Message::from_str(":alice!alice@example.com JOIN #pto @alice:example.com :Alice");
+        assert_eq!(client.downgraded(&join).unwrap().to_string(), ":alice!alice@example.com JOIN #pto");
+        client.caps.insert("extended-join".to_string());
+        assert!(client.downgraded(&join).is_none());
+        assert!(client.downgraded(&Message::from_str(":alice!alice@example.com JOIN #pto")).is_none());
+    }
+
+    #[test]
+    fn multi_prefix() {
+        let listener = mio::tcp::TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let stream = mio::tcp::TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let mut client = Client::new(Box::new(stream));
+        let names = Message {
+            tags: vec![],
+            prefix: Some("pto".to_string()),
+            command: Command::Numeric(353),
+            args: vec!["me".to_string(), "=".to_string(), "#pto".to_string()],
+            suffix: Some("@+admin +helper bob".to_string())
+        };
+        assert_eq!(client.downgraded(&names).unwrap().suffix, Some("@admin +helper bob".to_string()));
+        client.caps.insert("multi-prefix".to_string());
+        assert!(client.downgraded(&names).is_none());
+    }
+
+    #[test]
+    fn motd_wrapping() {
+        assert_eq!(wrap("short line", 80), vec!["short line"]);
+        assert_eq!(wrap("", 80), vec![""]);
+        assert_eq!(wrap("one two three", 7), vec!["one two", "three"]);
+        assert_eq!(wrap("https://example.com/long", 10), vec!["https://ex", "ample.com/", "long"]);
+    }
+
+    #[test]
+    fn flood_protection() {
+        let listener = mio::tcp::TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let stream = mio::tcp::TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let mut client = Client::new(Box::new(stream));
+        client.set_rate_limit(1, 2);
+        for text in &["one", "two", "three"] {
+            client.send(&Message::from_str(format!(":alice PRIVMSG #pto :{}", text).trim())).unwrap();
+        }
+        // Anything else waits its turn behind what is held back
+        client.send(&Message::from_str(":pto PONG pto")).unwrap();
+        assert_eq!(client.held.len(), 2);
+        assert!(client.held_for_ms().unwrap() > 0);
+        client.release().unwrap();
+        assert_eq!(client.held.len(), 2);
+
+        client.set_rate_limit(0, 0);
+        client.release().unwrap();
+        assert!(client.held.is_empty());
+        assert_eq!(client.held_for_ms(), None);
+
+        // Past the cap, lines are dropped and owned up to once things drain
+        client.set_rate_limit(1, 1);
+        for _ in 0..MAX_HELD + 5 {
+            client.send(&Message::from_str(":alice PRIVMSG #pto :spam")).unwrap();
+        }
+        assert_eq!(client.held.len(), MAX_HELD);
+        assert_eq!(client.dropped, 4);
+        client.set_rate_limit(0, 0);
+        client.release().unwrap();
+        assert!(client.held.is_empty());
+        assert_eq!(client.dropped, 0);
+    }
+
+    #[test]
+    fn redact_credentials() {
+        let safe = redacted(&Message::from_str("PASS hunter2")).unwrap();
+        assert_eq!(safe.to_string(), "PASS <redacted>");
+        let safe = redacted(&Message::from_str("AUTHENTICATE PLAIN")).unwrap();
+        assert_eq!(safe.to_string(), "AUTHENTICATE PLAIN");
+        let safe = redacted(&Message::from_str("AUTHENTICATE YWxpY2UAYWxpY2UAaHVudGVyMg==")).unwrap();
+        assert_eq!(safe.to_string(), "AUTHENTICATE <redacted>");
+        let safe = redacted(&Message::from_str("PRIVMSG pto :register alice hunter2")).unwrap();
+        assert_eq!(safe.suffix, Some("register alice <redacted>".to_string()));
+        let safe = redacted(&Message::from_str("PRIVMSG pto :login-token abc123")).unwrap();
+        assert_eq!(safe.suffix, Some("login-token <redacted>".to_string()));
+        let safe = redacted(&Message::from_str("PRIVMSG pto :login hunter2")).unwrap();
+        assert_eq!(safe.suffix, Some("login <redacted>".to_string()));
+        assert!(redacted(&Message::from_str("PRIVMSG pto :help")).is_none());
+        assert!(redacted(&Message::from_str("PRIVMSG #pto :register alice hunter2")).is_none());
+        assert!(redacted(&Message::from_str("NICK alice")).is_none());
+    }
 }
@@ -14,21 +14,37 @@
  * limitations under the License.
  */
 
-use std::io::Read;
+use std::io;
+use std::io::{Read, Write};
 use std::str;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct LineReader {
-    linebuf: String
+    linebuf: String,
+    /// Whether the other end hung up, or the connection broke
+    closed: bool
 }
 
 impl LineReader {
     pub fn new() -> Self {
         LineReader {
-            linebuf: String::new()
+            linebuf: String::new(),
+            closed: false
         }
     }
 
+    /// Throws away whatever was read but not returned as a line yet
+    pub fn clear(&mut self) {
+        self.linebuf.clear();
+    }
+
+    /// Whether the stream is done for, as opposed to having nothing to read
+    /// right now
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
     pub fn read(&mut self, stream: &mut Read) -> Option<String> {
         match self.split_next_line() {
             None => self.read_and_split(stream),
@@ -40,11 +56,19 @@ impl LineReader {
         let mut buf = [0; 1024];
         let next_msg = stream.read(&mut buf);
         match next_msg {
+            Ok(0) => {
+                self.closed = true;
+                None
+            },
             Ok(count) => {
                 self.linebuf.push_str(str::from_utf8(&buf[0..count]).unwrap());
                 self.split_next_line()
             }
-            Err(_) => None
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::Interrupted => None,
+            Err(_) => {
+                self.closed = true;
+                None
+            }
         }
     }
 
@@ -72,3 +96,170 @@ impl LineReader {
     }
 }
 
+
+/// Bytes waiting to be written to a non-blocking stream. Whatever the
+/// stream doesn't take right away stays queued until it is writable again.
+#[derive(Debug)]
+pub struct OutputBuffer {
+    pending: Vec<u8>
+}
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        OutputBuffer {
+            pending: vec![]
+        }
+    }
+
+    pub fn queue(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Writes as much as the stream will take, returning how many bytes
+    /// that was. A stream that would block isn't an error, the rest waits
+    /// for the next flush.
+    pub fn flush(&mut self, stream: &mut Write) -> io::Result<usize> {
+        let mut written = 0;
+        while !self.pending.is_empty() {
+            match stream.write(&self.pending) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "Connection closed")),
+                Ok(count) => {
+                    self.pending.drain(..count);
+                    written += count;
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// A token bucket: lines may go out `rate` per second on average, and up to
+/// `burst` at once after a quiet spell
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32, now: Instant) -> Self {
+        let burst = if burst > 0 { burst } else { 1 };
+        RateLimiter {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last: now
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.last {
+            let tokens = self.tokens + seconds(now.duration_since(self.last)) * self.rate;
+            self.tokens = if tokens > self.burst { self.burst } else { tokens };
+            self.last = now;
+        }
+    }
+
+    /// Takes a token if there is one
+    pub fn take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until the next token, in milliseconds
+    pub fn wait_ms(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            0
+        } else {
+            ((1.0 - self.tokens) / self.rate * 1000.0).ceil() as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp;
+    use std::io;
+    use std::io::Write;
+    use std::time::{Duration, Instant};
+
+    /// Takes at most a few bytes per write, and then nothing until drained
+    struct SlowStream {
+        written: Vec<u8>,
+        budget: usize
+    }
+
+    impl Write for SlowStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.budget == 0 {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "Full"));
+            }
+            let count = cmp::min(cmp::min(buf.len(), 3), self.budget);
+            self.budget -= count;
+            self.written.extend_from_slice(&buf[..count]);
+            Ok(count)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_writes() {
+        let mut stream = SlowStream { written: vec![], budget: 10 };
+        let mut output = OutputBuffer::new();
+        output.queue(b"PRIVMSG #pto :hello\r\n");
+        output.queue(b"PING pto\r\n");
+        assert_eq!(output.flush(&mut stream).unwrap(), 10);
+        assert!(!output.is_empty());
+
+        stream.budget = 100;
+        assert_eq!(output.flush(&mut stream).unwrap(), 21);
+        assert!(output.is_empty());
+        assert_eq!(stream.written, b"PRIVMSG #pto :hello\r\nPING pto\r\n".to_vec());
+        assert_eq!(output.flush(&mut stream).unwrap(), 0);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(2, 3, start);
+        for _ in 0..3 {
+            assert!(limiter.take(start));
+        }
+        assert!(!limiter.take(start));
+        assert_eq!(limiter.wait_ms(start), 500);
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.take(later));
+        assert!(!limiter.take(later));
+
+        // A long pause only ever refills up to the burst
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.take(much_later));
+        }
+        assert!(!limiter.take(much_later));
+    }
+}
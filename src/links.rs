@@ -0,0 +1,183 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+const PREFIXES: &'static [&'static str] = &["https://matrix.to/#/", "http://matrix.to/#/", "matrix:"];
+
+/// What a matrix.to or matrix: link points at
+#[derive(Debug, PartialEq)]
+pub enum Link {
+    /// A user ID, as in @alice:example.com
+    User(String),
+    /// A room alias or room ID, possibly a permalink to an event in it
+    Room(String)
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut ret = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 3 <= bytes.len() {
+            match ((bytes[i + 1] as char).to_digit(16), (bytes[i + 2] as char).to_digit(16)) {
+                (Some(high), Some(low)) => {
+                    ret.push((high * 16 + low) as u8);
+                    i += 3;
+                    continue;
+                },
+                _ => ()
+            }
+        }
+        ret.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+/// Parses what follows the scheme of a link
+fn parse(prefix: &str, target: &str) -> Option<Link> {
+    let target = target.split('?').next().unwrap_or("");
+    let identifier = if prefix == "matrix:" {
+        // matrix:u/alice:example.com, matrix:r/room:example.com or
+        // matrix:roomid/abc:example.com, optionally followed by /e/event
+        let mut parts = target.splitn(3, '/');
+        let sigil = match parts.next() {
+            Some("u") => "@",
+            Some("r") => "#",
+            Some("roomid") => "!",
+            _ => return None
+        };
+        format!("{}{}", sigil, percent_decode(parts.next().unwrap_or("")))
+    } else {
+        percent_decode(target.split('/').next().unwrap_or(""))
+    };
+    if !identifier.contains(':') {
+        return None;
+    }
+    match identifier.chars().next() {
+        Some('@') => Some(Link::User(identifier)),
+        Some('#') | Some('!') => Some(Link::Room(identifier)),
+        _ => None
+    }
+}
+
+/// Replaces matrix.to and matrix: links in a message with whatever
+/// `resolve` maps them to, such as a nick or channel name. Links `resolve`
+/// doesn't know about are left alone.
+pub fn rewrite<F>(text: &str, resolve: F) -> String where F: Fn(&Link) -> Option<String> {
+    let mut ret = String::new();
+    let mut rest = text;
+    loop {
+        let next = PREFIXES.iter()
+            .filter_map(|p| rest.find(p).map(|idx| (idx, *p)))
+            .min_by_key(|&(idx, _)| idx);
+        let (start, prefix) = match next {
+            Some(n) => n,
+            None => break
+        };
+        let end = rest[start..].find(|c: char| c.is_whitespace() || c == '<' || c == '>' || c == '"')
+            .map(|e| start + e)
+            .unwrap_or(rest.len());
+        // Leave punctuation that ends a sentence out of the link
+        let url = rest[start..end].trim_right_matches(|c| c == '.' || c == ',' || c == ')' || c == '!');
+        ret.push_str(&rest[..start]);
+        match parse(prefix, &url[prefix.len()..]).and_then(|link| resolve(&link)) {
+            Some(name) => ret.push_str(name.trim()),
+            None => ret.push_str(url)
+        }
+        rest = &rest[start + url.len()..];
+    }
+    ret.push_str(rest);
+    ret
+}
+
+/// Undoes the escaping of text taken out of HTML
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"")
+        .replace("&#39;", "'").replace("&amp;", "&")
+}
+
+/// Finds the pills in the HTML body of a message, that is links to users
+/// and rooms, along with the text they show, such as a display name
+pub fn pills(html: &str) -> Vec<(Link, String)> {
+    let mut ret = vec![];
+    let mut rest = html;
+    while let Some(start) = rest.find("<a href=\"") {
+        rest = &rest[start + "<a href=\"".len()..];
+        let href = match rest.find('"') {
+            Some(end) => &rest[..end],
+            None => break
+        };
+        let text = match (rest.find('>'), rest.find("</a>")) {
+            (Some(open), Some(close)) if open < close => &rest[open + 1..close],
+            _ => break
+        };
+        let link = PREFIXES.iter()
+            .find(|p| href.starts_with(**p))
+            .and_then(|p| parse(p, &href[p.len()..]));
+        if let Some(link) = link {
+            ret.push((link, unescape(text)));
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(link: &Link) -> Option<String> {
+        match link {
+            &Link::User(ref id) if id == "@alice:example.com" => Some("alice".to_owned()),
+            &Link::Room(ref id) if id == "#pto:oob.systems" || id == "!abc:oob.systems" =>
+                Some("#pto/oob.systems".to_owned()),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn rewrite_links() {
+        assert_eq!(rewrite("hi https://matrix.to/#/@alice:example.com!", resolve), "hi alice!");
+        assert_eq!(rewrite("see https://matrix.to/#/%23pto%3Aoob.systems?via=oob.systems", resolve),
+                   "see #pto/oob.systems");
+        assert_eq!(rewrite("https://matrix.to/#/!abc:oob.systems/$event:oob.systems?via=oob.systems.", resolve),
+                   "#pto/oob.systems.");
+        assert_eq!(rewrite("matrix:u/alice:example.com and matrix:roomid/abc:oob.systems/e/event", resolve),
+                   "alice and #pto/oob.systems");
+        assert_eq!(rewrite("who is https://matrix.to/#/@bob:example.com", resolve),
+                   "who is https://matrix.to/#/@bob:example.com");
+        assert_eq!(rewrite("no links", resolve), "no links");
+    }
+
+    #[test]
+    fn trailing_escapes() {
+        assert_eq!(percent_decode("foo%20"), "foo ");
+        assert_eq!(percent_decode("%23pto%3Aoob.systems"), "#pto:oob.systems");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("50%2"), "50%2");
+    }
+
+    #[test]
+    fn find_pills() {
+        let html = "<a href=\"https://matrix.to/#/@alice:example.com\">Alice &amp; co</a>: see \
+                    <a href=\"https://matrix.to/#/#pto:oob.systems\">#pto</a> and \
+                    <a href=\"https://example.com\">this</a>";
+        assert_eq!(pills(html), vec![
+            (Link::User("@alice:example.com".to_owned()), "Alice & co".to_owned()),
+            (Link::Room("#pto:oob.systems".to_owned()), "#pto".to_owned())
+        ]);
+        assert_eq!(pills("no <b>links</b>"), vec![]);
+    }
+}
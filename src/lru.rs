@@ -0,0 +1,100 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::VecDeque;
+
+/// A small cache that forgets whatever was used least recently once it
+/// holds `capacity` entries. Lookups are linear, which is fine for the few
+/// hundred entries it's meant for.
+#[derive(Debug)]
+pub struct LruCache<K, V> {
+    capacity: usize,
+    entries: VecDeque<(K, V)>
+}
+
+impl<K: PartialEq, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity: capacity,
+            entries: VecDeque::new()
+        }
+    }
+
+    /// Adds or replaces an entry, making it the most recently used
+    pub fn insert(&mut self, key: K, value: V) {
+        if let Some(idx) = self.entries.iter().position(|&(ref k, _)| k == &key) {
+            self.entries.remove(idx);
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, value));
+    }
+
+    /// Looks up an entry, making it the most recently used
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let entry = match self.entries.iter().position(|&(ref k, _)| k == key) {
+            Some(idx) => self.entries.remove(idx).unwrap(),
+            None => return None
+        };
+        self.entries.push_back(entry);
+        self.entries.back().map(|&(_, ref v)| v)
+    }
+
+    /// Looks up an entry without counting it as a use
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        self.entries.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| v)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        match self.entries.iter().position(|&(ref k, _)| k == key) {
+            Some(idx) => self.entries.remove(idx).map(|(_, v)| v),
+            None => None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        cache.insert("c", 3);
+        assert_eq!(cache.peek(&"b"), None);
+        assert_eq!(cache.peek(&"a"), Some(&1));
+        assert_eq!(cache.peek(&"c"), Some(&3));
+        cache.insert("a", 4);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.remove(&"a"), Some(4));
+        assert_eq!(cache.len(), 1);
+
+        let mut none = LruCache::new(0);
+        none.insert("a", 1);
+        assert_eq!(none.get(&"a"), None);
+    }
+}
@@ -18,78 +18,38 @@ extern crate hyper;
 extern crate mio;
 extern crate env_logger;
 extern crate openssl;
+extern crate flate2;
 #[macro_use]
 extern crate log;
 mod irc;
 mod matrix;
 mod bridge;
 mod ssl;
-use mio::{EventLoop,Handler,Token,EventSet,PollOpt};
-use std::thread;
+mod nicks;
+mod config;
+mod control;
+mod channels;
+mod links;
+mod lru;
+mod util;
 use bridge::Bridge;
 use std::env;
-use std::path::Path;
 use std::net::SocketAddr;
-use openssl::ssl::{SslContext, SslMethod};
-use openssl::x509::X509FileType;
-use irc::streams::{Server, AsEvented};
-
-struct IrcHandler {
-    server: Box<Server>,
-    url: String
-}
-
-impl Handler for IrcHandler {
-    type Timeout = ();
-    type Message = ();
-
-    fn ready(&mut self, _event_loop: &mut EventLoop<IrcHandler>, token: Token, _: EventSet) {
-        match token {
-            SERVER => {
-                match self.server.accept() {
-                    Some(client) => {
-                        let mut bridge = Bridge::new(client, self.url.trim());
-                        thread::spawn(move||{
-                            bridge.run()
-                        });
-                    },
-                    None => ()
-                }
-            },
-            _ => unreachable!("Got a really weird token in the server event loop!")
-        }
-    }
-}
-
-const SERVER: Token = Token(0);
+use std::process;
 
 fn main() {
     env_logger::init().unwrap();
-    let addr: SocketAddr = match env::args().nth(2) {
-        Some(arg) => arg,
-        None => "127.0.0.1:8001".to_string()
-    }.parse().unwrap();
-    let url =  env::args().nth(1).unwrap();
-    let is_loopback = match addr {
-        SocketAddr::V4(ref a) => {
-            a.ip().octets() == [127, 0, 0, 1]
-        },
-        _ => true
-    };
-    let server: Box<Server> = if !is_loopback {
-        let mut ssl = SslContext::new(SslMethod::Sslv23).expect("SSL setup failed");
-        ssl.set_certificate_file(Path::new("pto.crt"), X509FileType::PEM).expect("Could not load pto.crt");
-        ssl.set_private_key_file(Path::new("pto.key"), X509FileType::PEM).expect("Could not load pto.key");
-        Box::new(ssl::SslServer::new(&addr, ssl))
-    } else {
-        warn!("SSL IS DISABLED!! IRC LOGINS ARE IN THE CLEAR AND UNENCRYPTED");
-        Box::new(ssl::TcpServer::new(&addr))
+    let address = env::args().nth(2).unwrap_or(config::Config::from_env().listen_address);
+    let addr: SocketAddr = match config::parse_listen_address(address.trim()) {
+        Ok(addr) => addr,
+        Err(err) => {
+            error!("{}", err);
+            process::exit(1);
+        }
     };
-    info!("Listening on {}", addr);
-    let mut events = EventLoop::new().unwrap();
-    events.register(server.as_evented(), SERVER, EventSet::all(), PollOpt::edge()).unwrap();
-    events.run(&mut IrcHandler{
-        server: server,
-        url: url
-    }).unwrap();
+    let url =  env::args().nth(1).unwrap();
+    if let Err(err) = Bridge::listen(addr, url.trim()) {
+        error!("Could not listen on {}: {}", addr, err);
+        process::exit(1);
+    }
 }
@@ -17,66 +17,386 @@
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use hyper;
+use hyper::header::{ContentType, UserAgent};
+use hyper::method::Method;
 use rustc_serialize::json::Json;
 use rustc_serialize::json;
 use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::Path;
 use std::result;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use lru::LruCache;
+use util::write_private;
+use openssl::crypto::rand;
+use rustc_serialize::hex::ToHex;
 use matrix::json as mjson;
 use matrix::events;
 use matrix::model;
 
+/// An error response from the homeserver, as described by the spec
+#[derive(Debug)]
+pub struct MatrixError {
+    pub status: hyper::status::StatusCode,
+    pub errcode: String,
+    pub error: String,
+    pub body: Json
+}
+
 #[derive(Debug)]
 pub enum ClientError {
     Http(hyper::Error),
     UrlNotFound,
-    Json(json::ParserError)
+    Unauthorized,
+    /// The homeserver invalidated our access token, but the session can
+    /// resume by refreshing it or logging in again
+    SoftLogout,
+    Json(json::ParserError),
+    Matrix(MatrixError),
+    /// The server wants authentication stages we can't complete. Carries the
+    /// flows it offered.
+    UnsupportedFlows(Vec<String>),
+    /// We aren't allowed to do that, for the reason given
+    Forbidden(String),
+    NotInRoom,
+    /// The event doesn't exist, or we aren't allowed to see it
+    EventNotFound,
+    /// The server sent an identifier that isn't valid
+    InvalidId(model::IdError),
+    /// The response wasn't JSON at all, with its content type and how it
+    /// starts. Usually a reverse proxy answering in the homeserver's place.
+    NotJson(String, String),
+    /// The request was given up on, because the session is ending
+    Cancelled,
+    /// A media URI that isn't an mxc:// one
+    InvalidMxc(String)
+}
+
+impl ClientError {
+    pub fn errcode(&self) -> Option<&str> {
+        match self {
+            &ClientError::Matrix(ref err) => Some(err.errcode.trim()),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ClientError::Http(ref err) => write!(f, "HTTP error: {}", err),
+            &ClientError::UrlNotFound => write!(f, "Not found"),
+            &ClientError::Unauthorized => write!(f, "Access token was rejected"),
+            &ClientError::SoftLogout => write!(f, "The homeserver logged this session out"),
+            &ClientError::Json(ref err) => write!(f, "Invalid JSON: {:?}", err),
+            &ClientError::Matrix(ref err) => write!(f, "{} ({})", err.error, err.errcode),
+            &ClientError::UnsupportedFlows(ref flows) =>
+                write!(f, "Unsupported authentication, the server offers: {}", flows.join(", ")),
+            &ClientError::Forbidden(ref reason) => write!(f, "Forbidden: {}", reason),
+            &ClientError::NotInRoom => write!(f, "Not in that room"),
+            &ClientError::EventNotFound => write!(f, "No such event"),
+            &ClientError::InvalidId(ref err) => write!(f, "{}", err),
+            &ClientError::NotJson(ref content_type, ref body) =>
+                write!(f, "Expected JSON but got {}, is a proxy in the way? It starts with: {}", content_type, body),
+            &ClientError::Cancelled => write!(f, "Cancelled"),
+            &ClientError::InvalidMxc(ref uri) => write!(f, "Not an mxc:// URI: {}", uri)
+        }
+    }
 }
 
 pub type Result<T = ()> = result::Result<T, ClientError>;
 
+/// The body of a request that may carry a reason
+fn reason_body(reason: Option<&str>) -> String {
+    let mut d = BTreeMap::new();
+    if let Some(reason) = reason {
+        d.insert("reason".to_string(), Json::String(reason.to_string()));
+    }
+    Json::Object(d).to_string()
+}
+
+/// Percent-encodes a path segment or query component. Only the characters
+/// RFC 3986 calls unreserved are left as they are.
+fn encode(component: &str) -> String {
+    let mut ret = String::new();
+    for b in component.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => ret.push(b as char),
+            b => ret.push_str(format!("%{:02X}", b).trim())
+        }
+    }
+    ret
+}
+
+/// Splits an mxc:// URI into its server name and media ID, None unless it
+/// has both
+fn parse_mxc(mxc: &str) -> Option<(&str, &str)> {
+    if !mxc.starts_with("mxc://") {
+        return None;
+    }
+    let mut parts = mxc["mxc://".len()..].splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(server), Some(media_id)) if server.len() > 0 && media_id.len() > 0 => Some((server, media_id)),
+        _ => None
+    }
+}
+
+/// Builds an endpoint out of path segments, encoding each of them
+fn path(segments: &[&str]) -> String {
+    let encoded: Vec<String> = segments.iter().map(|s| encode(s)).collect();
+    encoded.join("/")
+}
+
+/// The flows listed in a response, either login types as `GET /login`
+/// gives them or the stages of interactive authentication
+fn offered_flows(js: &Json) -> Vec<String> {
+    js.find("flows").and_then(|f| f.as_array()).map(|flows| {
+        flows.iter().filter_map(|flow| {
+            match (flow.find("type").and_then(|t| t.as_string()), flow.find("stages").and_then(|s| s.as_array())) {
+                (Some(login_type), _) => Some(login_type.to_string()),
+                (None, Some(stages)) => {
+                    let stages: Vec<&str> = stages.iter().filter_map(|s| s.as_string()).collect();
+                    Some(stages.join(" -> "))
+                },
+                (None, None) => None
+            }
+        }).collect()
+    }).unwrap_or(vec![])
+}
+
+fn parse_id<T: FromStr<Err=model::IdError>>(id: &str) -> Result<T> {
+    id.parse().map_err(|err| ClientError::InvalidId(err))
+}
+
+/// Reduces a homeserver URL to its scheme and authority, so that
+/// `https://hs`, `https://hs/` and `https://hs/_matrix/client/r0/` all
+/// name the same server
+fn server_root(baseurl: &str) -> String {
+    let root = match baseurl.find("/_matrix") {
+        Some(idx) => &baseurl[..idx],
+        None => baseurl
+    };
+    root.trim().trim_right_matches('/').to_string()
+}
+
+/// Finds the client API of a homeserver through .well-known, falling back to
+/// the server itself if it doesn't point anywhere else
+pub fn discover(server: &str) -> String {
+    let root = server_root(server);
+    let mut http = hyper::Client::new();
+    http.set_redirect_policy(hyper::client::RedirectPolicy::FollowAll);
+    let url = format!("{}/.well-known/matrix/client", root);
+    match http::json(http.get(url.trim()).header(UserAgent(USER_AGENT.to_string()))) {
+        Ok(js) => match js.find_path(&["m.homeserver", "base_url"]).and_then(|url| url.as_string()) {
+            Some(base_url) => server_root(base_url),
+            None => root
+        },
+        Err(err) => {
+            debug!("No usable .well-known for {}: {}", root, err);
+            root
+        }
+    }
+}
+
+/// Picks the endpoint prefix for the spec versions a server supports. The
+/// v3 endpoints arrived with spec v1.1, servers that only know the r0.x
+/// releases get r0.
+fn pick_version(versions: &[String]) -> &'static str {
+    let v3 = versions.iter().any(|v| {
+        if !v.starts_with("v") {
+            return false;
+        }
+        let mut parts = v[1..].splitn(2, '.');
+        match (parts.next().and_then(|n| n.parse::<u32>().ok()), parts.next().and_then(|n| n.parse::<u32>().ok())) {
+            (Some(major), _) if major > 1 => true,
+            (Some(1), Some(minor)) => minor >= 1,
+            _ => false
+        }
+    });
+    if v3 { "v3" } else { "r0" }
+}
+
+/// What the bridge calls itself to homeservers, unless told otherwise
+const USER_AGENT: &'static str = concat!("pto/", env!("CARGO_PKG_VERSION"));
+
+/// What the bridge's sessions are called in the user's list of devices
+const DEVICE_DISPLAY_NAME: &'static str = "pto IRC bridge";
+
+/// The account data type the bridge keeps the user's preferences under
+pub const ACCOUNT_DATA_TYPE: &'static str = "net.hackerbots.pto";
+
+/// How many fetched events to keep around for `get_event`
+const EVENT_CACHE_SIZE: usize = 128;
+
+/// Bumped whenever the on-disk session format changes
+const SESSION_VERSION: u64 = 1;
+
+/// How long the server may hold each request of a poll. A poll keeps asking
+/// until something happens, checking in between whether it was cancelled,
+/// so this is also about how long cancelling it can take.
+const POLL_SLICE_MS: u64 = 5000;
+
 mod http {
     use rustc_serialize::json::Json;
+    use flate2::read::{GzDecoder, ZlibDecoder};
     use hyper;
+    use hyper::header::{AcceptEncoding, ContentEncoding, Encoding, qitem};
     use std::io::Read;
-    use matrix::client::{Result,ClientError};
+    use matrix::client::{Result,ClientError,MatrixError};
 
-    pub fn json(http: hyper::client::RequestBuilder) -> Result<Json> {
-        let mut response = String::new();
-        http.send().map_err(|err|{
+    /// A response body, decompressed if the server compressed it
+    pub type Body = Box<Read + Send>;
+
+    /// Reads a response through whatever its Content-Encoding calls for
+    pub fn body(res: hyper::client::Response) -> Result<Body> {
+        let encoding = res.headers.get::<ContentEncoding>().and_then(|enc| enc.last().cloned());
+        match encoding {
+            Some(Encoding::Gzip) => GzDecoder::new(res).map(|body| Box::new(body) as Body).map_err(|err| {
+                ClientError::Http(hyper::Error::Io(err))
+            }),
+            Some(Encoding::Deflate) => Ok(Box::new(ZlibDecoder::new(res))),
+            _ => Ok(Box::new(res))
+        }
+    }
+
+    /// Turns a failed response into the most specific error we can
+    fn error(res: hyper::client::Response) -> ClientError {
+        let status = res.status;
+        let mut text = String::new();
+        let parsed = match body(res).map(|mut body| body.read_to_string(&mut text)) {
+            Ok(Ok(_)) => Json::from_str(text.trim()).ok(),
+            _ => None
+        };
+        let errcode = parsed.as_ref()
+            .and_then(|js| js.find("errcode"))
+            .and_then(|e| e.as_string())
+            .map(|e| e.to_string());
+        let soft_logout = parsed.as_ref()
+            .and_then(|js| js.find("soft_logout"))
+            .and_then(|s| s.as_boolean())
+            .unwrap_or(false);
+        match (status, errcode, parsed) {
+            (hyper::status::StatusCode::Unauthorized, Some(ref code), _)
+                if code == "M_UNKNOWN_TOKEN" && soft_logout =>
+                ClientError::SoftLogout,
+            (hyper::status::StatusCode::Unauthorized, Some(ref code), _)
+                if code == "M_UNKNOWN_TOKEN" || code == "M_MISSING_TOKEN" =>
+                ClientError::Unauthorized,
+            (status, errcode, Some(js)) =>
+                ClientError::Matrix(MatrixError {
+                    status: status,
+                    errcode: errcode.unwrap_or(String::new()),
+                    error: js.find("error").and_then(|e| e.as_string()).unwrap_or("").to_string(),
+                    body: js.clone()
+                }),
+            (hyper::status::StatusCode::Unauthorized, _, None) => ClientError::Unauthorized,
+            (_, _, None) => ClientError::UrlNotFound
+        }
+    }
+
+    pub fn response(http: hyper::client::RequestBuilder) -> Result<hyper::client::Response> {
+        let compressed = AcceptEncoding(vec![qitem(Encoding::Gzip), qitem(Encoding::Deflate)]);
+        http.header(compressed).send().map_err(|err|{
             ClientError::Http(err)
-        }).and_then(|mut res|{
+        }).and_then(|res|{
             match res.status  {
-                hyper::status::StatusCode::Ok =>  {
-                    res.read_to_string(&mut response).expect("Could not read response");
-                    Json::from_str(response.trim()).map_err(|err|{
-                        ClientError::Json(err)
-                    })
-                },
-                _ => Err(ClientError::UrlNotFound)
+                hyper::status::StatusCode::Ok => Ok(res),
+                _ => Err(error(res))
             }
         })
     }
+
+    /// How much of a response that isn't JSON goes into the error
+    const BODY_SNIPPET_LENGTH: usize = 200;
+
+    /// Whether a content type is JSON, whatever its parameters
+    fn is_json(content_type: &str) -> bool {
+        let essence = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+        essence == "application/json" || essence.ends_with("+json")
+    }
+
+    pub fn json(http: hyper::client::RequestBuilder) -> Result<Json> {
+        self::response(http).and_then(|res|{
+            let content_type = res.headers.get::<hyper::header::ContentType>().map(|ct| format!("{}", ct));
+            let mut response = String::new();
+            // The connection may well drop halfway through a large response
+            try!(try!(body(res)).read_to_string(&mut response).map_err(|err| {
+                ClientError::Http(hyper::Error::Io(err))
+            }));
+            if let Some(content_type) = content_type {
+                if !is_json(content_type.trim()) {
+                    let snippet = response.trim().chars().take(BODY_SNIPPET_LENGTH).collect();
+                    return Err(ClientError::NotJson(content_type, snippet));
+                }
+            }
+            Json::from_str(response.trim()).map_err(|err|{
+                ClientError::Json(err)
+            })
+        })
+    }
 }
 
 pub struct AsyncPoll {
     http: hyper::client::Client,
-    url: hyper::Url
+    /// The sync endpoint with its arguments, ready for more
+    url: String,
+    since: Option<String>,
+    user_agent: String,
+    slice: Duration,
+    cancel: Arc<AtomicBool>
 }
 
 impl AsyncPoll {
-    pub fn send(self) -> Result<Vec<events::Event>> {
-        http::json(self.http.get(self.url)).and_then(|json| {
+    /// Waits for new events, returning them along with the token to poll
+    /// from next time. Gives up with `Cancelled` soon after the flag it was
+    /// made with is set.
+    pub fn send(mut self) -> Result<(Vec<events::Event>, Option<String>)> {
+        // A server that stops answering shouldn't hold us up past the slice
+        self.http.set_read_timeout(Some(self.slice * 2));
+        loop {
+            if self.cancel.load(Ordering::SeqCst) {
+                return Err(ClientError::Cancelled);
+            }
+            let mut url = self.url.clone();
+            if let Some(ref since) = self.since {
+                url.push_str(format!("since={}&", encode(since.trim())).trim());
+            }
+            let url = hyper::Url::parse(url.trim()).unwrap();
+            let res = http::response(self.http.get(url).header(UserAgent(self.user_agent.clone())));
+            if self.cancel.load(Ordering::SeqCst) {
+                return Err(ClientError::Cancelled);
+            }
+            let mut sync = SyncStream {
+                parser: json::Parser::new(mjson::Chars::new(try!(res.and_then(http::body))))
+            };
             let mut ret: Vec<events::Event> = vec![];
-            let events = mjson::array(&json, "chunk");
-            for ref evt in events {
-                trace!("<<< {}", evt);
-                ret.push(events::Event::from_json(evt))
+            let next_batch = try!(sync.read_events(&mut |evt| ret.push(evt)));
+            if ret.is_empty() {
+                // Nothing happened during this slice, ask again
+                if next_batch.is_some() {
+                    self.since = next_batch;
+                }
+                continue;
             }
-            Ok(ret)
-        })
+            // Servers don't promise to hand out a batch in order
+            return Ok((events::sort_chronologically(ret), next_batch));
+        }
     }
 }
 
+/// A session of the account, as the homeserver lists them
+#[derive(Debug)]
+pub struct Device {
+    pub device_id: String,
+    pub display_name: Option<String>,
+    pub last_seen_ip: Option<String>
+}
+
 #[derive(Clone)]
 pub struct AccessToken {
     access: String,
@@ -87,7 +407,30 @@ pub struct Client {
     http: hyper::Client,
     token: Option<AccessToken>,
     next_id: u32,
+    /// Random for each client and put in front of `next_id`, so that after
+    /// a restart transaction IDs don't repeat ones the server has seen, and
+    /// that it would drop the messages of as duplicates
+    txn_prefix: String,
+    /// The homeserver, without a trailing slash or API prefix
     baseurl: String,
+    sync_limit: u32,
+    filter: Option<Json>,
+    filter_id: Option<String>,
+    /// The login types the homeserver offers, once asked
+    login_flows: Option<Vec<String>>,
+    /// The endpoint version in use, r0 until `negotiate_version` says
+    /// otherwise
+    api_version: &'static str,
+    /// Events fetched recently, as the server sent them
+    event_cache: LruCache<model::EventID, Json>,
+    /// Where the next poll carries on from, once a sync or poll said so
+    next_batch: Option<String>,
+    /// Whether syncs include presence, which only away-notify needs
+    presence: bool,
+    /// Sent with every request, so server admins know who's calling
+    user_agent: String,
+    /// The device our access token belongs to, once the homeserver said
+    device_id: Option<String>,
     pub uid: Option<model::UserID>
 }
 
@@ -108,109 +451,1862 @@ impl Client {
             http: http,
             token: None,
             next_id: 0,
-            baseurl: baseurl.to_string(),
+            txn_prefix: rand::rand_bytes(8).to_hex(),
+            baseurl: server_root(baseurl),
+            sync_limit: 0,
+            filter: None,
+            filter_id: None,
+            login_flows: None,
+            api_version: "r0",
+            event_cache: LruCache::new(EVENT_CACHE_SIZE),
+            next_batch: None,
+            presence: false,
+            user_agent: USER_AGENT.to_string(),
+            device_id: None,
             uid: None
         }
     }
 
+    /// The homeserver this client talks to
+    pub fn homeserver_url(&self) -> &str {
+        self.baseurl.trim()
+    }
+
+    /// Another client for the same session, for requests made from another
+    /// thread
+    pub fn fork(&self) -> Client {
+        let mut client = Client::new(self.baseurl.trim());
+        client.token = self.token.clone();
+        client.api_version = self.api_version;
+        client.user_agent = self.user_agent.clone();
+        client.device_id = self.device_id.clone();
+        client.uid = self.uid.clone();
+        client
+    }
+
+    /// Writes the access token and identity of this client to `path`,
+    /// readable only by the owner, so it can be resumed by `load_session`.
+    pub fn save_session(&self, path: &Path) -> io::Result<()> {
+        let (token, uid) = match (&self.token, &self.uid) {
+            (&Some(ref token), &Some(ref uid)) => (token, uid),
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "Not logged in"))
+        };
+        let mut session = BTreeMap::new();
+        session.insert("version".to_string(), Json::U64(SESSION_VERSION));
+        session.insert("baseurl".to_string(), Json::String(self.baseurl.clone()));
+        session.insert("access_token".to_string(), Json::String(token.access.clone()));
+        session.insert("refresh_token".to_string(), Json::String(token.refresh.clone()));
+        session.insert("user_id".to_string(), Json::String(format!("@{}:{}", uid.nickname, uid.homeserver)));
+        if let Some(ref device_id) = self.device_id {
+            session.insert("device_id".to_string(), Json::String(device_id.clone()));
+        }
+        write_private(path, Json::Object(session).to_string().as_bytes())
+    }
+
+    /// Creates a client from a session stored with `save_session`
+    pub fn load_session(path: &Path) -> io::Result<Client> {
+        let mut contents = String::new();
+        try!(try!(fs::File::open(path)).read_to_string(&mut contents));
+        let invalid = |what: &str| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid session file: {}", what))
+        };
+        let js = try!(Json::from_str(contents.trim()).map_err(|_| invalid("not JSON")));
+        let field = |name: &str| {
+            js.find(name).and_then(|v| v.as_string()).map(|v| v.to_string())
+        };
+        match js.find("version").and_then(|v| v.as_u64()) {
+            Some(SESSION_VERSION) => (),
+            _ => return Err(invalid("unsupported version"))
+        }
+        let baseurl = try!(field("baseurl").ok_or(invalid("missing baseurl")));
+        let access = try!(field("access_token").ok_or(invalid("missing access_token")));
+        let refresh = field("refresh_token").unwrap_or(String::new());
+        let uid = try!(field("user_id").ok_or(invalid("missing user_id")));
+        let mut client = Client::new(baseurl.trim());
+        client.token = Some(AccessToken {
+            access: access,
+            refresh: refresh
+        });
+        client.uid = Some(try!(uid.parse().map_err(|_| invalid("malformed user_id"))));
+        client.device_id = field("device_id");
+        Ok(client)
+    }
+
+    /// Creates a new account and logs in as it. Only registration that needs
+    /// no interactive authentication, or just the m.login.dummy stage, is
+    /// supported.
+    pub fn register(&mut self, username: &str, password: &str) -> Result {
+        let mut d = BTreeMap::new();
+        d.insert("username".to_string(), Json::String(username.to_string()));
+        d.insert("password".to_string(), Json::String(password.to_string()));
+        d.insert("initial_device_display_name".to_string(), Json::String(DEVICE_DISPLAY_NAME.to_string()));
+        let url = self.url("register", &HashMap::new());
+        debug!("Registering a new matrix account");
+        let first = http::json(self.post(url.clone(), Json::Object(d.clone()).to_string().trim()));
+        let js = match first {
+            Err(ClientError::Matrix(ref err)) if err.body.find("flows").is_some() => {
+                let mut offered: Vec<String> = vec![];
+                let mut dummy = false;
+                for flow in err.body.find("flows").and_then(|f| f.as_array()).unwrap_or(&vec![]) {
+                    let stages: Vec<String> = flow.find("stages")
+                        .and_then(|s| s.as_array())
+                        .unwrap_or(&vec![])
+                        .iter()
+                        .filter_map(|s| s.as_string().map(|s| s.to_string()))
+                        .collect();
+                    dummy = dummy || stages == vec!["m.login.dummy".to_string()];
+                    offered.push(stages.join(" -> "));
+                }
+                if !dummy {
+                    return Err(ClientError::UnsupportedFlows(offered));
+                }
+                let mut auth = BTreeMap::new();
+                auth.insert("type".to_string(), Json::String("m.login.dummy".to_string()));
+                if let Some(session) = err.body.find("session") {
+                    auth.insert("session".to_string(), session.clone());
+                }
+                d.insert("auth".to_string(), Json::Object(auth));
+                try!(http::json(self.post(url, Json::Object(d).to_string().trim())))
+            },
+            Err(err) => return Err(err),
+            Ok(js) => js
+        };
+        try!(self.take_token(&js));
+        self.uid = Some(try!(parse_id(mjson::string(&js, "user_id"))));
+        Ok(())
+    }
+
+    /// Lists the spec versions the homeserver supports
+    pub fn versions(&mut self) -> Result<Vec<String>> {
+        let url = self.url_from(format!("{}/_matrix/client/", self.baseurl).trim(), "versions", &HashMap::new());
+        http::json(self.get(url)).map(|js| {
+            js.find("versions")
+                .and_then(|v| v.as_array())
+                .map(|v| v.iter().filter_map(|v| v.as_string()).map(|v| v.to_string()).collect())
+                .unwrap_or(vec![])
+        })
+    }
+
+    /// Picks the newest endpoint version both sides know. Servers that
+    /// can't say keep getting r0.
+    pub fn negotiate_version(&mut self) {
+        self.api_version = match self.versions() {
+            Ok(versions) => pick_version(&versions),
+            Err(err) => {
+                warn!("Could not ask the homeserver for its versions, using r0: {}", err);
+                "r0"
+            }
+        };
+        debug!("Using the {} endpoints", self.api_version);
+    }
+
+    /// Keeps the access token of a successful login. A response without one
+    /// is asking for more authentication than we can give.
+    fn take_token(&mut self, js: &Json) -> Result {
+        let access = match js.find("access_token").and_then(|t| t.as_string()) {
+            Some(access) => access.to_string(),
+            None => return Err(ClientError::UnsupportedFlows(offered_flows(js)))
+        };
+        self.token = Some(AccessToken {
+            access: access,
+            refresh: js.find("refresh_token").and_then(|r| r.as_string()).unwrap_or("").to_string()
+        });
+        // Refreshing keeps the device, and doesn't repeat it
+        if let Some(device_id) = js.find("device_id").and_then(|d| d.as_string()) {
+            self.device_id = Some(device_id.to_string());
+        }
+        Ok(())
+    }
+
+    /// Trades the refresh token for a new access token, for when the old
+    /// one expired
+    pub fn refresh(&mut self) -> Result {
+        let refresh = match self.token {
+            Some(ref token) if token.refresh.len() > 0 => token.refresh.clone(),
+            _ => return Err(ClientError::Unauthorized)
+        };
+        let mut d = BTreeMap::new();
+        d.insert("refresh_token".to_string(), Json::String(refresh));
+        // The expired access token mustn't go along
+        self.token = None;
+        let js = try!(http::json(self.post(self.url("refresh", &HashMap::new()), Json::Object(d).to_string().trim())));
+        self.take_token(&js)
+    }
+
+    /// Forgets the access token after a soft logout, but not who we are, so
+    /// that logging in again picks up the same session
+    pub fn soft_logout(&mut self) {
+        self.token = None;
+    }
+
+    pub fn is_logged_in(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Invalidates our access token, ending this session
+    pub fn logout(&mut self) -> Result {
+        try!(http::json(self.post(self.url("logout", &HashMap::new()), "{}")));
+        self.token = None;
+        self.uid = None;
+        self.device_id = None;
+        self.next_batch = None;
+        Ok(())
+    }
+
+    /// Invalidates every access token of the account, those of the user's
+    /// other devices as well as ours. Unlike a soft logout nothing can be
+    /// resumed afterwards, so everything we knew about the session goes.
+    pub fn logout_all(&mut self) -> Result {
+        try!(http::json(self.post(self.url("logout/all", &HashMap::new()), "{}")));
+        self.token = None;
+        self.uid = None;
+        self.device_id = None;
+        self.next_batch = None;
+        Ok(())
+    }
+
+    pub fn device_id(&self) -> Option<&str> {
+        self.device_id.as_ref().map(|id| id.trim())
+    }
+
+    /// Lists every session of the account, ours included
+    pub fn devices(&mut self) -> Result<Vec<Device>> {
+        let js = try!(http::json(self.get(self.url("devices", &HashMap::new()))));
+        let field = |device: &Json, name: &str| {
+            device.find(name).and_then(|v| v.as_string()).map(|v| v.to_string())
+        };
+        Ok(js.find("devices").and_then(|d| d.as_array()).map(|devices| {
+            devices.iter().filter_map(|device| {
+                field(device, "device_id").map(|id| Device {
+                    device_id: id,
+                    display_name: field(device, "display_name"),
+                    last_seen_ip: field(device, "last_seen_ip")
+                })
+            }).collect()
+        }).unwrap_or(vec![]))
+    }
+
+    /// The display name of a user, or None if they haven't set one
+    pub fn get_display_name(&mut self, user: &model::UserID) -> Result<Option<String>> {
+        let url = self.url(path(&["profile", format!("{}", user).trim(), "displayname"]).trim(), &HashMap::new());
+        match http::json(self.get(url)) {
+            Ok(js) => Ok(js.find("displayname").and_then(|name| name.as_string()).map(|name| name.to_string())),
+            Err(ClientError::Matrix(ref err))
+                if err.errcode == "M_NOT_FOUND" || err.status == hyper::status::StatusCode::NotFound =>
+                Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Changes our display name
+    pub fn set_display_name(&mut self, name: &str) -> Result {
+        let uid = match self.uid {
+            Some(ref uid) => format!("{}", uid),
+            None => return Err(ClientError::Unauthorized)
+        };
+        let url = self.url(path(&["profile", uid.trim(), "displayname"]).trim(), &HashMap::new());
+        let mut d = BTreeMap::new();
+        d.insert("displayname".to_string(), Json::String(name.to_string()));
+        let body = Json::Object(d).to_string();
+        http::json(self.request(Method::Put, url).header(ContentType::json()).body(body.trim())).and_then(|_| Ok(()))
+    }
+
+    /// Logs a device out and forgets it. Homeservers usually want the user
+    /// to authenticate again for this, which comes back as the flows they
+    /// would accept.
+    pub fn delete_device(&mut self, device_id: &str) -> Result {
+        let url = self.url(path(&["devices", device_id]).trim(), &HashMap::new());
+        match http::json(self.request(Method::Delete, url).header(ContentType::json()).body("{}")) {
+            Err(ClientError::Matrix(ref err)) if err.body.find("flows").is_some() =>
+                Err(ClientError::UnsupportedFlows(offered_flows(&err.body))),
+            Err(err) => Err(err),
+            Ok(_) => Ok(())
+        }
+    }
+
+    /// The login types the homeserver offers, such as m.login.password or
+    /// m.login.sso. Asked only once per client.
+    pub fn login_flows(&mut self) -> Result<Vec<String>> {
+        if let Some(ref flows) = self.login_flows {
+            return Ok(flows.clone());
+        }
+        let flows = try!(http::json(self.get(self.url("login", &HashMap::new())))
+            .map(|js| offered_flows(&js)));
+        self.login_flows = Some(flows.clone());
+        Ok(flows)
+    }
+
+    /// Asks the homeserver who our access token belongs to, which also
+    /// checks that the token is still valid.
+    pub fn whoami(&mut self) -> Result<model::UserID> {
+        http::json(self.get(self.url("account/whoami", &HashMap::new()))).and_then(|js| {
+            let uid: model::UserID = try!(parse_id(mjson::string(&js, "user_id")));
+            self.uid = Some(uid.clone());
+            Ok(uid)
+        })
+    }
+
+    /// Sets how many messages per room the initial sync fetches as backlog
+    pub fn set_sync_limit(&mut self, limit: u32) {
+        self.sync_limit = limit;
+        self.filter_id = None;
+    }
+
     pub fn login(&mut self, username: &str, password: &str) -> Result {
         let mut d = BTreeMap::new();
         d.insert("user".to_string(), Json::String(username.to_string()));
         d.insert("password".to_string(), Json::String(password.to_string()));
         d.insert("type".to_string(), Json::String("m.login.password".to_string()));
+        d.insert("initial_device_display_name".to_string(), Json::String(DEVICE_DISPLAY_NAME.to_string()));
         debug!("Logging in to matrix");
-        http::json(self.http.post(self.url("login", &HashMap::new()))
-            .body(Json::Object(d).to_string().trim()))
+        http::json(self.post(self.url("login", &HashMap::new()), Json::Object(d).to_string().trim()))
             .and_then(|js| {
-                let obj = js.as_object().unwrap();
-                self.token = Some(AccessToken {
-                    access: obj.get("access_token").unwrap().as_string().unwrap().to_string(),
-                    refresh: obj.get("refresh_token").unwrap().as_string().unwrap().to_string()
-                });
+                try!(self.take_token(&js));
                 let url = hyper::Url::parse(self.baseurl.trim()).unwrap();
                 let domain = url.host().unwrap().serialize();
-                self.uid = Some(model::UserID::from_str(format!("@{}:{}", username, domain).trim()));
+                self.uid = Some(try!(parse_id(format!("@{}:{}", username, domain).trim())));
                 Ok(())
             })
     }
 
+    /// Logs in with a token the homeserver handed out after single sign-on.
+    /// The token says who we are, so the user ID comes from the response.
+    pub fn login_with_token(&mut self, token: &str) -> Result {
+        let mut d = BTreeMap::new();
+        d.insert("token".to_string(), Json::String(token.to_string()));
+        d.insert("type".to_string(), Json::String("m.login.token".to_string()));
+        d.insert("initial_device_display_name".to_string(), Json::String(DEVICE_DISPLAY_NAME.to_string()));
+        debug!("Logging in to matrix with a login token");
+        http::json(self.post(self.url("login", &HashMap::new()), Json::Object(d).to_string().trim()))
+            .and_then(|js| {
+                try!(self.take_token(&js));
+                self.uid = Some(try!(parse_id(mjson::string(&js, "user_id"))));
+                Ok(())
+            })
+    }
+
+    /// Replaces the User-Agent every request carries
+    pub fn set_user_agent(&mut self, user_agent: String) {
+        self.user_agent = user_agent;
+    }
+
+    /// Starts a request with the headers every request carries
+    fn request(&self, method: Method, url: hyper::Url) -> hyper::client::RequestBuilder {
+        self.http.request(method, url).header(UserAgent(self.user_agent.clone()))
+    }
+
+    fn get(&self, url: hyper::Url) -> hyper::client::RequestBuilder {
+        self.request(Method::Get, url)
+    }
+
+    /// Starts a POST of a JSON body
+    fn post<'a>(&'a self, url: hyper::Url, body: &'a str) -> hyper::client::RequestBuilder<'a> {
+        self.request(Method::Post, url).header(ContentType::json()).body(body)
+    }
+
     fn url(&self, endpoint: &str, args: &HashMap<&str, &str>) -> hyper::Url {
-        let mut ret = self.baseurl.clone();
-        ret.push_str(endpoint);
+        self.url_from(format!("{}/_matrix/client/{}/", self.baseurl, self.api_version).trim(), endpoint, args)
+    }
+
+    /// Like `url`, but for the media repository, which lives next to the
+    /// client API rather than under it
+    fn media_url(&self, endpoint: &str, args: &HashMap<&str, &str>) -> hyper::Url {
+        self.url_from(format!("{}/_matrix/media/{}/", self.baseurl, self.api_version).trim(), endpoint, args)
+    }
+
+    /// Where an mxc:// URI can be downloaded from over HTTP. The access token
+    /// stays out of it, since the link is meant to be shared.
+    pub fn download_url(&self, mxc: &str) -> Option<String> {
+        parse_mxc(mxc).map(|(server, media_id)| {
+            format!("{}/_matrix/media/{}/download/{}", self.baseurl, self.api_version, path(&[server, media_id]))
+        })
+    }
+
+    /// Appends an endpoint to an API prefix ending in a slash, then the
+    /// access token and query arguments
+    fn url_from(&self, base: &str, endpoint: &str, args: &HashMap<&str, &str>) -> hyper::Url {
+        let mut ret = base.to_string();
+        ret.push_str(endpoint.trim_left_matches('/'));
         ret.push_str("?");
         match self.token {
             None => (),
             Some(ref token) => {
                 ret.push_str("access_token=");
-                ret.push_str(token.access.trim());
+                ret.push_str(encode(token.access.trim()).trim());
                 ret.push_str("&");
             }
         }
         for (name, value) in args {
-            ret.push_str(name);
+            ret.push_str(encode(name).trim());
             ret.push_str("=");
-            ret.push_str(value);
+            ret.push_str(encode(value).trim());
             ret.push_str("&");
         }
         hyper::Url::parse(ret.trim()).unwrap()
     }
 
-    pub fn poll_async(&mut self) -> AsyncPoll {
-        let url = self.url("events", &HashMap::new());
+    /// The token the next poll starts from
+    pub fn since(&self) -> Option<&str> {
+        self.next_batch.as_ref().map(|token| token.trim())
+    }
+
+    pub fn set_since(&mut self, token: String) {
+        self.next_batch = Some(token);
+    }
+
+    /// A poll for new events, to be sent from another thread. Setting
+    /// `cancel` makes it give up.
+    pub fn poll_async(&mut self, cancel: Arc<AtomicBool>) -> AsyncPoll {
+        let mut args = HashMap::new();
+        let timeout = format!("{}", POLL_SLICE_MS);
+        args.insert("timeout", timeout.trim());
+        let filter = self.poll_filter().to_string();
+        args.insert("filter", filter.trim());
+        let url = self.url("sync", &args);
         let mut http = hyper::client::Client::new();
         http.set_redirect_policy(hyper::client::RedirectPolicy::FollowAll);
         AsyncPoll {
             http: http,
-            url: url
+            url: url.serialize(),
+            since: self.next_batch.clone(),
+            user_agent: self.user_agent.clone(),
+            slice: Duration::from_millis(POLL_SLICE_MS),
+            cancel: cancel
         }
     }
 
-    pub fn send(&mut self, evt: events::EventData) -> Result<model::EventID> {
+    /// Joins a room by ID or alias, returning the ID of the joined room
+    pub fn join_room(&mut self, id_or_alias: &str) -> Result<model::RoomID> {
+        // A room ID can be joined directly, an alias has to be resolved by
+        // the server first
+        let endpoint = if id_or_alias.starts_with("!") {
+            path(&["rooms", id_or_alias, "join"])
+        } else {
+            path(&["join", id_or_alias])
+        };
+        let url = self.url(endpoint.trim(), &HashMap::new());
+        http::json(self.post(url, "{}")).and_then(|js| {
+            parse_id(mjson::string(&js, "room_id"))
+        })
+    }
+
+    /// Joins a room like `join_room`, and also fetches its current state so
+    /// the room can be shown right away
+    pub fn join_room_with_state(&mut self, id_or_alias: &str) -> Result<(model::RoomID, Vec<events::Event>)> {
+        let id = try!(self.join_room(id_or_alias));
+        let state = try!(self.get_full_state(&id));
+        Ok((id, state))
+    }
+
+    pub fn leave_room(&mut self, id: &model::RoomID) -> Result {
+        let url = self.url(path(&["rooms", format!("{}", id).trim(), "leave"]).trim(), &HashMap::new());
+        match http::json(self.post(url, "{}")) {
+            Ok(_) => Ok(()),
+            Err(ClientError::Matrix(ref err))
+                if err.errcode == "M_NOT_FOUND" || err.status == hyper::status::StatusCode::NotFound =>
+                Err(ClientError::NotInRoom),
+            Err(ClientError::Matrix(ref err)) if err.errcode == "M_FORBIDDEN" =>
+                Err(ClientError::Forbidden(err.error.clone())),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Lifts the ban of a user from a room. They can join again, but aren't
+    /// brought back.
+    pub fn unban_user(&mut self, room: &model::RoomID, user: &model::UserID) -> Result {
+        let url = self.url(path(&["rooms", format!("{}", room).trim(), "unban"]).trim(), &HashMap::new());
+        let mut body = BTreeMap::new();
+        body.insert("user_id".to_string(), Json::String(format!("{}", user)));
+        match http::json(self.post(url, Json::Object(body).to_string().trim())) {
+            Ok(_) => Ok(()),
+            Err(ClientError::Matrix(ref err)) if err.errcode == "M_FORBIDDEN" =>
+                Err(ClientError::Forbidden(err.error.clone())),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Whether the homeserver knows about knocking, which came along with
+    /// the v3 endpoints
+    pub fn supports_knock(&self) -> bool {
+        self.api_version == "v3"
+    }
+
+    /// Looks up the room an alias points at
+    pub fn resolve_alias(&mut self, alias: &str) -> Result<model::RoomID> {
+        let url = self.url(path(&["directory", "room", alias]).trim(), &HashMap::new());
+        http::json(self.get(url)).and_then(|js| {
+            match js.find("room_id").and_then(|id| id.as_string()) {
+                Some(id) => parse_id(id),
+                None => Err(ClientError::UrlNotFound)
+            }
+        })
+    }
+
+    /// Asks to be let into a room whose join rule is "knock"
+    pub fn knock(&mut self, room: &str) -> Result {
+        let url = self.url(path(&["knock", room]).trim(), &HashMap::new());
+        http::json(self.post(url, "{}")).and_then(|_| Ok(()))
+    }
+
+    /// Reserves a transaction ID for a later `send_txn`
+    pub fn new_txn_id(&mut self) -> String {
         self.next_id += 1;
+        format!("{}.{}", self.txn_prefix, self.next_id)
+    }
+
+    pub fn send(&mut self, evt: events::EventData) -> Result<model::EventID> {
+        let txn_id = self.new_txn_id();
+        self.send_txn(evt, txn_id.trim())
+    }
+
+    /// Sends an event with a transaction ID from `new_txn_id`. The server
+    /// echoes the transaction ID back to us along with the event.
+    pub fn send_txn(&mut self, evt: events::EventData, txn_id: &str) -> Result<model::EventID> {
         match evt {
-            events::EventData::Room(ref id, _) => {
-                let url = self.url(format!("rooms/{}/send/{}/{}",
-                                           id,
-                                           evt.type_str(),
-                                           self.next_id).trim(),
-                                   &HashMap::new());
-                trace!("Sending events to {:?}", url);
-                // FIXME: This seems needed since hyper will pool HTTP client
-                // connections for pipelining. Sometimes the server will close
-                // the pooled connection and everything will catch on fire here.
-                let mut http = hyper::client::Client::new();
-                http.set_redirect_policy(hyper::client::RedirectPolicy::FollowAll);
-                http::json(http.put(url).body(format!("{}", evt.to_json()).trim()))
-            },
+            events::EventData::Room(ref id, _) => self.put_event(id, evt.type_str().trim(), txn_id, &evt.to_json()),
             _ => panic!("Don't know where to send {}", evt.to_json())
-        }.and_then(|response| {
-            trace!(">>> {} {:?}", evt.to_json(), response);
-            Ok(model::EventID::from_str(mjson::string(&response, "event_id")))
+        }
+    }
+
+    /// Sends a plain message to a room. Like `send_txn`, it takes a
+    /// transaction ID from `new_txn_id`.
+    pub fn send_text(&mut self, room: &model::RoomID, text: &str, txn_id: &str) -> Result<model::EventID> {
+        self.send_msgtype(room, events::MsgType::Text, text, txn_id)
+    }
+
+    /// Sends a notice, which bots send and shouldn't answer, to a room
+    pub fn send_notice(&mut self, room: &model::RoomID, text: &str, txn_id: &str) -> Result<model::EventID> {
+        self.send_msgtype(room, events::MsgType::Notice, text, txn_id)
+    }
+
+    /// Sends an emote, an IRC ACTION, to a room
+    pub fn send_emote(&mut self, room: &model::RoomID, text: &str, txn_id: &str) -> Result<model::EventID> {
+        self.send_msgtype(room, events::MsgType::Emote, text, txn_id)
+    }
+
+    fn send_msgtype(&mut self, room: &model::RoomID, msgtype: events::MsgType, text: &str, txn_id: &str)
+            -> Result<model::EventID> {
+        let mut content = BTreeMap::new();
+        content.insert("msgtype".to_string(), Json::String(msgtype.as_str().to_string()));
+        content.insert("body".to_string(), Json::String(text.to_string()));
+        self.put_event(room, "m.room.message", txn_id, &Json::Object(content))
+    }
+
+    fn put_event(&mut self, room: &model::RoomID, event_type: &str, txn_id: &str, content: &Json)
+            -> Result<model::EventID> {
+        let url = self.url(path(&["rooms", format!("{}", room).trim(), "send", event_type, txn_id]).trim(),
+                           &HashMap::new());
+        trace!("Sending events to {:?}", url);
+        // FIXME: This seems needed since hyper will pool HTTP client
+        // connections for pipelining. Sometimes the server will close
+        // the pooled connection and everything will catch on fire here.
+        let mut http = hyper::client::Client::new();
+        http.set_redirect_policy(hyper::client::RedirectPolicy::FollowAll);
+        http::json(http.put(url)
+            .header(UserAgent(self.user_agent.clone()))
+            .header(ContentType::json())
+            .body(format!("{}", content).trim()))
+            .and_then(|response| {
+                trace!(">>> {} {:?}", content, response);
+                parse_id(mjson::string(&response, "event_id"))
+            })
+    }
+
+    /// Redacts an event, which strips it of its content for everyone. Only
+    /// its sender or someone with enough power may.
+    pub fn redact_event(&mut self, room: &model::RoomID, event: &model::EventID, reason: Option<&str>) -> Result {
+        let txn_id = self.new_txn_id();
+        let url = self.url(path(&["rooms", format!("{}", room).trim(), "redact", format!("{}", event).trim(),
+                                  txn_id.trim()]).trim(), &HashMap::new());
+        match http::json(self.request(Method::Put, url).header(ContentType::json()).body(reason_body(reason).trim())) {
+            Ok(_) => Ok(()),
+            Err(ClientError::Matrix(ref err)) if err.errcode == "M_FORBIDDEN" =>
+                Err(ClientError::Forbidden(err.error.clone())),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Uploads a file to the media repository, returning its mxc:// URI
+    pub fn upload_media(&mut self, bytes: &[u8], content_type: &str, filename: &str) -> Result<String> {
+        let mut args = HashMap::new();
+        args.insert("filename", filename);
+        let url = self.media_url("upload", &args);
+        let mime: hyper::mime::Mime = content_type.parse().unwrap_or_else(|_| {
+            warn!("Invalid content type {:?}, uploading {} as binary", content_type, filename);
+            "application/octet-stream".parse().unwrap()
+        });
+        debug!("Uploading {} ({} bytes of {})", filename, bytes.len(), mime);
+        // A body of known length gets a Content-Length header from hyper
+        http::json(self.request(Method::Post, url)
+            .header(ContentType(mime))
+            .body(bytes)).and_then(|js| {
+            Ok(mjson::string(&js, "content_uri").to_string())
         })
     }
 
-    pub fn sync(&mut self) -> Result<Vec<events::Event>> {
-        debug!("Syncing...");
+    /// Uploads a file and posts it to a room, as an image if it is one
+    pub fn send_file(&mut self, room: &model::RoomID, bytes: &[u8], content_type: &str, filename: &str) -> Result<model::EventID> {
+        let uid = match self.uid {
+            Some(ref uid) => uid.clone(),
+            None => return Err(ClientError::Unauthorized)
+        };
+        let url = try!(self.upload_media(bytes, content_type, filename));
+        self.send(events::EventData::Room(room.clone(), events::RoomEvent::File(uid, events::FileInfo {
+            name: filename.to_string(),
+            url: url,
+            mimetype: content_type.to_string(),
+            size: bytes.len() as u64
+        })))
+    }
+
+    /// Reads a piece of the user's account data, such as the bridge's
+    /// preferences under `ACCOUNT_DATA_TYPE`. Data never set is an empty
+    /// object.
+    pub fn get_account_data(&mut self, type_: &str) -> Result<Json> {
+        let uid = match self.uid {
+            Some(ref uid) => format!("{}", uid),
+            None => return Err(ClientError::Unauthorized)
+        };
+        let url = self.url(path(&["user", uid.trim(), "account_data", type_]).trim(), &HashMap::new());
+        match http::json(self.get(url)) {
+            Ok(js) => Ok(js),
+            Err(ClientError::Matrix(ref err))
+                if err.errcode == "M_NOT_FOUND" || err.status == hyper::status::StatusCode::NotFound =>
+                Ok(Json::Object(BTreeMap::new())),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Replaces a piece of the user's account data. It follows them to any
+    /// session, on any bridge.
+    pub fn set_account_data(&mut self, type_: &str, content: &Json) -> Result {
+        let uid = match self.uid {
+            Some(ref uid) => format!("{}", uid),
+            None => return Err(ClientError::Unauthorized)
+        };
+        let url = self.url(path(&["user", uid.trim(), "account_data", type_]).trim(), &HashMap::new());
+        http::json(self.request(Method::Put, url).header(ContentType::json()).body(content.to_string().trim()))
+            .and_then(|_| Ok(()))
+    }
+
+    /// Uploads a filter definition, returning the ID to refer to it by
+    pub fn create_filter(&mut self, filter: &Json) -> Result<String> {
+        let uid = match self.uid {
+            Some(ref uid) => format!("@{}:{}", uid.nickname, uid.homeserver),
+            None => return Err(ClientError::Unauthorized)
+        };
+        let url = self.url(path(&["user", uid.trim(), "filter"]).trim(), &HashMap::new());
+        http::json(self.post(url, filter.to_string().trim())).and_then(|js| {
+            Ok(mjson::string(&js, "filter_id").to_string())
+        })
+    }
+
+    /// Fetches the member events of those who joined a room, for when they
+    /// weren't all part of a lazy-loading sync
+    pub fn members(&mut self, room: &model::RoomID) -> Result<MemberStream> {
+        self.get_members(room, Some("join"), None)
+    }
+
+    /// Fetches the member events of a room. Only members whose membership
+    /// is `membership` are asked for, and `at` pins the list to a sync
+    /// token. The server sends them all at once, so they are parsed as they
+    /// are read instead of waiting for the whole list.
+    pub fn get_members(&mut self, room: &model::RoomID, membership: Option<&str>, at: Option<&str>)
+            -> Result<MemberStream> {
         let mut args = HashMap::new();
-        args.insert("limit", "0");
-        let url = self.url("initialSync", &args);
-        http::json(self.http.get(url)).and_then(|js| {
-            let rooms = mjson::array(&js, "rooms");
-            let mut ret: Vec<events::Event> = vec![];
-            for ref r in rooms {
-                let room_state = mjson::array(r, "state");
-                for ref evt in room_state {
+        if let Some(membership) = membership {
+            args.insert("membership", membership);
+        }
+        if let Some(at) = at {
+            args.insert("at", at);
+        }
+        let url = self.url(path(&["rooms", format!("{}", room).trim(), "members"]).trim(), &args);
+        http::response(self.get(url)).and_then(http::body).and_then(|body| {
+            Ok(MemberStream {
+                parser: json::Parser::new(mjson::Chars::new(body)),
+                room: format!("{}", room)
+            })
+        })
+    }
+
+    /// Pages back through the history of a room from `from`, a pagination
+    /// or sync token. Returns up to `limit` events, oldest first, along with
+    /// the token to page on from. There is none once the start of the room
+    /// is reached.
+    pub fn get_messages(&mut self, room: &model::RoomID, from: &str, limit: usize)
+            -> Result<(Vec<events::Event>, Option<String>)> {
+        let limit = format!("{}", limit);
+        let mut args = HashMap::new();
+        args.insert("from", from);
+        args.insert("dir", "b");
+        args.insert("limit", limit.trim());
+        let url = self.url(path(&["rooms", format!("{}", room).trim(), "messages"]).trim(), &args);
+        http::json(self.get(url)).and_then(|js| {
+            let mut evts: Vec<events::Event> = mjson::array(&js, "chunk").iter().map(|evt| {
+                trace!("<<< {}", evt);
+                let mut evt = evt.clone();
+                // Like in a sync, the room may be left out
+                if let Json::Object(ref mut obj) = evt {
+                    if !obj.contains_key("room_id") {
+                        obj.insert("room_id".to_string(), Json::String(format!("{}", room)));
+                    }
+                }
+                events::Event::from_json(&evt)
+            }).collect();
+            evts.reverse();
+            let end = js.find("end").and_then(|end| end.as_string())
+                .and_then(|end| if end.is_empty() || end == from || evts.is_empty() { None } else { Some(end.to_string()) });
+            Ok((evts, end))
+        })
+    }
+
+    /// Fetches a single event of a room. Events are immutable, so ones
+    /// fetched recently are answered from a cache.
+    pub fn get_event(&mut self, room: &model::RoomID, id: &model::EventID) -> Result<events::Event> {
+        if let Some(js) = self.event_cache.get(id) {
+            return Ok(events::Event::from_json(js));
+        }
+        let url = self.url(path(&["rooms", format!("{}", room).trim(), "event", format!("{}", id).trim()]).trim(), &HashMap::new());
+        match http::json(self.get(url)) {
+            Ok(js) => {
+                trace!("<<< {}", js);
+                let evt = events::Event::from_json(&js);
+                self.event_cache.insert(id.clone(), js);
+                Ok(evt)
+            },
+            Err(ref err) if err.errcode() == Some("M_NOT_FOUND") => Err(ClientError::EventNotFound),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Reads one piece of room state, returning its content. State that
+    /// isn't set is a ClientError::Matrix with M_NOT_FOUND.
+    pub fn get_state(&mut self, room: &model::RoomID, event_type: &str, state_key: &str) -> Result<Json> {
+        let url = self.url(path(&["rooms", format!("{}", room).trim(), "state", event_type, state_key]).trim(), &HashMap::new());
+        http::json(self.get(url))
+    }
+
+    /// Sets one piece of room state. Lacking the power level for it is a
+    /// ClientError::Forbidden.
+    pub fn set_state(&mut self, room: &model::RoomID, event_type: &str, state_key: &str, content: &Json)
+            -> Result<model::EventID> {
+        let url = self.url(path(&["rooms", format!("{}", room).trim(), "state", event_type, state_key]).trim(), &HashMap::new());
+        match http::json(self.request(Method::Put, url).header(ContentType::json()).body(content.to_string().trim())) {
+            Ok(js) => parse_id(mjson::string(&js, "event_id")),
+            Err(ClientError::Matrix(ref err)) if err.errcode == "M_FORBIDDEN" =>
+                Err(ClientError::Forbidden(err.error.clone())),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// The avatar of a room as an mxc:// URI, None if it has none
+    pub fn get_room_avatar(&mut self, room: &model::RoomID) -> Result<Option<String>> {
+        self.get_state_field(room, "m.room.avatar", "url")
+    }
+
+    /// Changes the avatar of a room to media already uploaded, as with
+    /// `upload_media`
+    pub fn set_room_avatar(&mut self, room: &model::RoomID, mxc: &str) -> Result {
+        let mxc = mxc.trim();
+        if parse_mxc(mxc).is_none() {
+            return Err(ClientError::InvalidMxc(mxc.to_string()));
+        }
+        let mut content = BTreeMap::new();
+        content.insert("url".to_string(), Json::String(mxc.to_string()));
+        self.set_state(room, "m.room.avatar", "", &Json::Object(content)).and_then(|_| Ok(()))
+    }
+
+    /// The name of a room, None if it has none
+    pub fn get_room_name(&mut self, room: &model::RoomID) -> Result<Option<String>> {
+        self.get_state_field(room, "m.room.name", "name")
+    }
+
+    /// The topic of a room, None if it has none
+    pub fn get_room_topic(&mut self, room: &model::RoomID) -> Result<Option<String>> {
+        self.get_state_field(room, "m.room.topic", "topic")
+    }
+
+    /// Changes the topic of a room
+    pub fn set_room_topic(&mut self, room: &model::RoomID, topic: &str) -> Result {
+        let mut content = BTreeMap::new();
+        content.insert("topic".to_string(), Json::String(topic.to_string()));
+        self.set_state(room, "m.room.topic", "", &Json::Object(content)).and_then(|_| Ok(()))
+    }
+
+    /// Who may join a room, such as "public" or "knock", as far as its
+    /// state can be read
+    pub fn get_join_rule(&mut self, room: &model::RoomID) -> Result<Option<String>> {
+        self.get_state_field(room, "m.room.join_rules", "join_rule")
+    }
+
+    /// A field of a state event with an empty state key. A room without
+    /// the event, or with the field empty, has None.
+    fn get_state_field(&mut self, room: &model::RoomID, event_type: &str, field: &str) -> Result<Option<String>> {
+        match self.get_state(room, event_type, "") {
+            Ok(js) => Ok(js.find(field).and_then(|value| value.as_string())
+                         .and_then(|value| if value.is_empty() { None } else { Some(value.to_string()) })),
+            Err(ClientError::Matrix(ref err))
+                if err.errcode == "M_NOT_FOUND" || err.status == hyper::status::StatusCode::NotFound =>
+                Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    /// Reads every state event of a room
+    pub fn get_full_state(&mut self, room: &model::RoomID) -> Result<Vec<events::Event>> {
+        let url = self.url(path(&["rooms", format!("{}", room).trim(), "state"]).trim(), &HashMap::new());
+        http::json(self.get(url)).and_then(|js| {
+            match js.as_array() {
+                Some(evts) => Ok(evts.iter().map(|evt| {
                     trace!("<<< {}", evt);
-                    // FIXME: It'd be nice to return to the previous
-                    // callback-based mechanism to avoid memory bloat
-                    ret.push(events::Event::from_json(evt));
-                };
-            }
-            ret.push(events::Event {
-                data: events::EventData::EndOfSync,
-                id: None
-            });
-            Ok(ret)
+                    events::Event::from_json(evt)
+                }).collect()),
+                None => Err(ClientError::UrlNotFound)
+            }
+        })
+    }
+
+    /// Includes presence in syncs from now on
+    pub fn want_presence(&mut self) {
+        if !self.presence {
+            self.presence = true;
+            self.filter_id = None;
+        }
+    }
+
+    /// Replaces the default sync filter
+    pub fn set_filter(&mut self, filter: Json) {
+        self.filter = Some(filter);
+        self.filter_id = None;
+    }
+
+    /// The filter used unless one was set: only as much timeline as the sync
+    /// limit asks for, no presence unless it's wanted, and only the members
+    /// that are needed to make sense of the timeline.
+    fn default_filter(&self) -> Json {
+        let mut timeline = BTreeMap::new();
+        timeline.insert("limit".to_string(), Json::U64(self.sync_limit as u64));
+        timeline.insert("lazy_load_members".to_string(), Json::Boolean(true));
+        let mut state = BTreeMap::new();
+        state.insert("lazy_load_members".to_string(), Json::Boolean(true));
+        let mut room = BTreeMap::new();
+        room.insert("timeline".to_string(), Json::Object(timeline));
+        room.insert("state".to_string(), Json::Object(state));
+        let mut filter = BTreeMap::new();
+        filter.insert("room".to_string(), Json::Object(room));
+        if !self.presence {
+            let mut presence = BTreeMap::new();
+            presence.insert("not_types".to_string(), Json::Array(vec![Json::String("*".to_string())]));
+            filter.insert("presence".to_string(), Json::Object(presence));
+        }
+        Json::Object(filter)
+    }
+
+    /// The sync filter with the rooms we leave while polling, which
+    /// incremental syncs are asked for along with the rest
+    fn poll_filter(&self) -> Json {
+        let mut filter = self.filter.clone().unwrap_or(self.default_filter());
+        if let Json::Object(ref mut filter) = filter {
+            let room = filter.entry("room".to_string()).or_insert(Json::Object(BTreeMap::new()));
+            if let Json::Object(ref mut room) = *room {
+                room.insert("include_leave".to_string(), Json::Boolean(true));
+            }
+        }
+        filter
+    }
+
+    pub fn sync(&mut self) -> Result<SyncStream> {
+        debug!("Syncing...");
+        let filter = self.filter.clone().unwrap_or(self.default_filter());
+        if self.filter_id.is_none() {
+            match self.create_filter(&filter) {
+                Ok(id) => self.filter_id = Some(id),
+                Err(err) => warn!("Could not upload sync filter, sending it inline: {}", err)
+            }
+        }
+        // The sync endpoint also takes a filter definition in place of an ID
+        let filter_arg = self.filter_id.clone().unwrap_or(filter.to_string());
+        let mut args = HashMap::new();
+        args.insert("filter", filter_arg.trim());
+        let url = self.url("sync", &args);
+        http::response(self.get(url)).and_then(http::body).and_then(|body| {
+            Ok(SyncStream {
+                parser: json::Parser::new(mjson::Chars::new(body))
+            })
         })
     }
 }
+
+/// A sync response that is parsed as it is read, so that large accounts
+/// never need the whole room list in memory at once.
+pub struct SyncStream {
+    parser: json::Parser<mjson::Chars<http::Body>>
+}
+
+impl SyncStream {
+    /// Whether the parser is positioned at the start of a presence event
+    fn presence_event(&self) -> bool {
+        let stack = self.parser.stack();
+        stack.len() == 3 &&
+            stack.get(0) == json::StackElement::Key("presence") &&
+            stack.get(1) == json::StackElement::Key("events")
+    }
+
+    /// If the parser is positioned at the start of a room in the given
+    /// section of the room list, such as leave, returns the ID of that room.
+    fn room_in(&self, section: &str) -> Option<String> {
+        let stack = self.parser.stack();
+        if stack.len() != 3 ||
+            stack.get(0) != json::StackElement::Key("rooms") ||
+            stack.get(1) != json::StackElement::Key(section) {
+            return None;
+        }
+        match stack.get(2) {
+            json::StackElement::Key(id) => Some(id.to_string()),
+            _ => None
+        }
+    }
+
+    /// If the parser is positioned at the start of a state or timeline event
+    /// of a joined room, returns the ID of that room.
+    fn room_event(&self) -> Option<String> {
+        let stack = self.parser.stack();
+        if stack.len() != 6 ||
+            stack.get(0) != json::StackElement::Key("rooms") ||
+            stack.get(1) != json::StackElement::Key("join") ||
+            stack.get(4) != json::StackElement::Key("events") {
+            return None;
+        }
+        match (stack.get(2), stack.get(3)) {
+            (json::StackElement::Key(id), json::StackElement::Key("state")) |
+            (json::StackElement::Key(id), json::StackElement::Key("timeline")) =>
+                Some(id.to_string()),
+            _ => None
+        }
+    }
+
+    /// If the parser is at the prev_batch of a joined room's timeline,
+    /// returns the ID of that room
+    fn prev_batch_of(&self) -> Option<String> {
+        let stack = self.parser.stack();
+        if stack.len() != 5 ||
+            stack.get(0) != json::StackElement::Key("rooms") ||
+            stack.get(1) != json::StackElement::Key("join") ||
+            stack.get(3) != json::StackElement::Key("timeline") ||
+            stack.get(4) != json::StackElement::Key("prev_batch") {
+            return None;
+        }
+        match stack.get(2) {
+            json::StackElement::Key(id) => Some(id.to_string()),
+            _ => None
+        }
+    }
+
+    /// Calls `callback` with every room and presence event as soon as it has
+    /// been parsed, with a PrevBatch for where each room's timeline starts,
+    /// and with a LeftRoom or Invited for every room we left or were invited
+    /// to, followed by a final EndOfSync. Returns the token to poll from next.
+    pub fn each<F>(mut self, mut callback: F) -> Result<Option<String>>
+            where F: FnMut(events::Event) {
+        let next_batch = try!(self.read_events(&mut callback));
+        callback(events::Event {
+            data: events::EventData::EndOfSync,
+            timestamp: None,
+            txn_id: None,
+            mentions: vec![],
+            id: None
+        });
+        Ok(next_batch)
+    }
+
+    /// Like `each`, without the EndOfSync, for the syncs of a poll
+    fn read_events<F>(&mut self, callback: &mut F) -> Result<Option<String>>
+            where F: FnMut(events::Event) {
+        let mut next_batch = None;
+        while let Some(token) = self.parser.next() {
+            let room_id = match token {
+                json::JsonEvent::ObjectStart if self.presence_event() => {
+                    let evt = try!(mjson::build(&mut self.parser, json::JsonEvent::ObjectStart).map_err(|err| {
+                        ClientError::Json(err)
+                    }));
+                    trace!("<<< {}", evt);
+                    callback(events::Event::from_json(&evt));
+                    None
+                },
+                json::JsonEvent::ObjectStart if self.room_in("invite").is_some() => {
+                    let room_id = try!(parse_id(self.room_in("invite").unwrap().trim()));
+                    let room = try!(mjson::build(&mut self.parser, json::JsonEvent::ObjectStart).map_err(|err| {
+                        ClientError::Json(err)
+                    }));
+                    trace!("<<< {}", room);
+                    callback(events::Event {
+                        data: events::EventData::Invited(events::Invite::from_json(room_id, &room)),
+                        timestamp: None,
+                        txn_id: None,
+                        mentions: vec![],
+                        id: None
+                    });
+                    None
+                },
+                json::JsonEvent::ObjectStart => {
+                    if let Some(room_id) = self.room_in("leave") {
+                        callback(events::Event {
+                            data: events::EventData::LeftRoom(try!(parse_id(room_id.trim()))),
+                            timestamp: None,
+                            txn_id: None,
+                            mentions: vec![],
+                            id: None
+                        });
+                    }
+                    self.room_event()
+                },
+                json::JsonEvent::StringValue(ref value) => {
+                    if self.parser.stack().len() == 1 &&
+                        self.parser.stack().get(0) == json::StackElement::Key("next_batch") {
+                        next_batch = Some(value.clone());
+                    }
+                    if let Some(room_id) = self.prev_batch_of() {
+                        callback(events::Event {
+                            data: events::EventData::PrevBatch(try!(parse_id(room_id.trim())), value.clone()),
+                            timestamp: None,
+                            txn_id: None,
+                            mentions: vec![],
+                            id: None
+                        });
+                    }
+                    None
+                },
+                json::JsonEvent::Error(ref err) => return Err(ClientError::Json(err.clone())),
+                _ => None
+            };
+            if let Some(room_id) = room_id {
+                let mut evt = try!(mjson::build(&mut self.parser, token).map_err(|err| {
+                    ClientError::Json(err)
+                }));
+                // Events in a sync response don't repeat the room they're in
+                if let Json::Object(ref mut obj) = evt {
+                    obj.insert("room_id".to_string(), Json::String(room_id));
+                }
+                trace!("<<< {}", evt);
+                callback(events::Event::from_json(&evt));
+            }
+        }
+        Ok(next_batch)
+    }
+}
+
+/// A /members response that is parsed as it is read, so that the members
+/// of a huge room can be listed before the last of them has arrived.
+pub struct MemberStream {
+    parser: json::Parser<mjson::Chars<http::Body>>,
+    room: String
+}
+
+impl MemberStream {
+    /// Calls `callback` with every member event as soon as it has been
+    /// parsed
+    pub fn each<F>(mut self, mut callback: F) -> Result
+            where F: FnMut(events::Event) {
+        while let Some(token) = self.parser.next() {
+            match token {
+                json::JsonEvent::ObjectStart => {
+                    let in_chunk = {
+                        let stack = self.parser.stack();
+                        stack.len() == 2 && stack.get(0) == json::StackElement::Key("chunk")
+                    };
+                    if !in_chunk {
+                        continue;
+                    }
+                    let mut evt = try!(mjson::build(&mut self.parser, json::JsonEvent::ObjectStart).map_err(|err| {
+                        ClientError::Json(err)
+                    }));
+                    if let Json::Object(ref mut obj) = evt {
+                        if !obj.contains_key("room_id") {
+                            obj.insert("room_id".to_string(), Json::String(self.room.clone()));
+                        }
+                    }
+                    trace!("<<< {}", evt);
+                    callback(events::Event::from_json(&evt));
+                },
+                json::JsonEvent::Error(ref err) => return Err(ClientError::Json(err.clone())),
+                _ => ()
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ACCOUNT_DATA_TYPE, Client, ClientError, discover, path, pick_version};
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::collections::HashMap;
+    use matrix::events::{EventData, MembershipAction, MsgType, RoomEvent};
+    use matrix::model::{EventID, RoomID, UserID};
+    use rustc_serialize::json::Json;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Answers a single request with `response`, returning the base URL to
+    /// point a Client at
+    fn mock_server(response: &'static str) -> String {
+        mock_responses(vec![response]).0
+    }
+
+    /// Reads a request, returning it whole. The body has to be read anyway,
+    /// since hanging up on unread data resets the connection.
+    fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = vec![];
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+            let count = stream.read(&mut buf).unwrap();
+            if count == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..count]);
+        }
+        let text = String::from_utf8_lossy(&request).into_owned();
+        let head = text.split("\r\n\r\n").next().unwrap_or("").to_string();
+        let length = head.lines()
+            .filter_map(|line| {
+                let line = line.to_lowercase();
+                if line.starts_with("content-length:") {
+                    line["content-length:".len()..].trim().parse::<usize>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(0);
+        let mut remaining = (head.len() + 4 + length).saturating_sub(request.len());
+        while remaining > 0 {
+            let count = stream.read(&mut buf).unwrap();
+            if count == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..count]);
+            remaining = remaining.saturating_sub(count);
+        }
+        String::from_utf8_lossy(&request).into_owned()
+    }
+
+    /// Answers a single request with `raw` as it is, then hangs up
+    fn raw_server<T: Into<Vec<u8>>>(raw: T) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let raw = raw.into();
+        thread::spawn(move|| {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_request(&mut stream);
+            stream.write_all(&raw).unwrap();
+        });
+        format!("http://{}/_matrix/client/r0/", addr)
+    }
+
+    /// Answers one request per response, in order. Each request that came
+    /// in, headers and body, can be read from the receiver.
+    fn mock_responses(responses: Vec<&'static str>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (requests, received) = channel();
+        thread::spawn(move|| {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let _ = requests.send(read_request(&mut stream));
+                let body = response.split("\n\n").nth(1).unwrap_or("");
+                let status = response.split("\n\n").next().unwrap();
+                write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                       status, body.len(), body).unwrap();
+            }
+        });
+        (format!("http://{}/_matrix/client/r0/", addr), received)
+    }
+
+    #[test]
+    fn request_headers() {
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"access_token\": \"abc\", \"user_id\": \"@alice:127.0.0.1\"}",
+            "200 OK\n\n{\"event_id\": \"$sent\"}"
+        ]);
+        let mut client = Client::new(url.trim());
+        client.login("alice", "hunter2").unwrap();
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let me = client.uid.clone().unwrap();
+        client.send(EventData::Room(room, RoomEvent::Message(me, MsgType::Text, "hello".to_owned()))).unwrap();
+        for _ in 0..2 {
+            let head = requests.recv().unwrap().to_lowercase();
+            assert!(head.contains(concat!("user-agent: pto/", env!("CARGO_PKG_VERSION"))));
+            assert!(head.contains("content-type: application/json"));
+        }
+
+        // A configured one carries over to forks, like the poll's
+        let (url, requests) = mock_responses(vec!["200 OK\n\n{\"event_id\": \"$sent\"}"]);
+        let mut client = Client::new(url.trim());
+        client.set_user_agent("pto (example.com)".to_owned());
+        client.fork().send_text(&"!room:example.com".parse::<RoomID>().unwrap(), "hello", "1").unwrap();
+        assert!(requests.recv().unwrap().to_lowercase().contains("user-agent: pto (example.com)"));
+    }
+
+    #[test]
+    fn typed_messages() {
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"event_id\": \"$text\"}",
+            "200 OK\n\n{\"event_id\": \"$notice\"}",
+            "200 OK\n\n{\"event_id\": \"$emote\"}"
+        ]);
+        let mut client = Client::new(url.trim());
+        let txn_ids = vec![client.new_txn_id(), client.new_txn_id(), client.new_txn_id()];
+        assert_eq!(client.send_text(&room, "hello", txn_ids[0].trim()).unwrap(), "$text".parse::<EventID>().unwrap());
+        client.send_notice(&room, "beep", txn_ids[1].trim()).unwrap();
+        client.send_emote(&room, "waves", txn_ids[2].trim()).unwrap();
+        for (txn_id, content) in txn_ids.iter().zip(&[r#"{"body":"hello","msgtype":"m.text"}"#,
+                                                      r#"{"body":"beep","msgtype":"m.notice"}"#,
+                                                      r#"{"body":"waves","msgtype":"m.emote"}"#]) {
+            let request = requests.recv().unwrap();
+            let path = format!("PUT /_matrix/client/r0/rooms/%21room%3Aexample.com/send/m.room.message/{} ", txn_id);
+            assert!(request.starts_with(path.trim_right()), "{}", request);
+            assert!(request.ends_with(*content), "{}", request);
+        }
+    }
+
+    #[test]
+    fn file_upload() {
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"access_token\": \"abc\", \"user_id\": \"@alice:127.0.0.1\"}",
+            "200 OK\n\n{\"content_uri\": \"mxc://example.com/cat\"}",
+            "200 OK\n\n{\"event_id\": \"$file\"}"
+        ]);
+        let mut client = Client::new(url.trim());
+        client.login("alice", "hunter2").unwrap();
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        assert_eq!(client.send_file(&room, b"meow", "image/png", "cat.png").unwrap(), "$file".parse::<EventID>().unwrap());
+        requests.recv().unwrap();
+        let upload = requests.recv().unwrap();
+        assert!(upload.starts_with("POST /_matrix/media/r0/upload?"), "{}", upload);
+        assert!(upload.contains("filename=cat.png"));
+        let head = upload.to_lowercase();
+        assert!(head.contains("content-type: image/png"));
+        assert!(head.contains("content-length: 4"));
+        assert!(upload.ends_with("\r\n\r\nmeow"));
+        let message = requests.recv().unwrap();
+        assert!(message.contains(" /_matrix/client/r0/rooms/%21room%3Aexample.com/send/m.room.message"), "{}", message);
+        assert!(message.ends_with(r#"{"body":"cat.png","info":{"mimetype":"image/png","size":4},"msgtype":"m.image","url":"mxc://example.com/cat"}"#),
+                "{}", message);
+    }
+
+    #[test]
+    fn join_by_id_or_alias() {
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"room_id\": \"!room:example.com\"}",
+            "200 OK\n\n{\"room_id\": \"!room:example.com\"}",
+            "200 OK\n\n[{\"type\": \"m.room.topic\", \"sender\": \"@alice:example.com\", \"state_key\": \"\", \
+                \"content\": {\"topic\": \"Hello\"}}]",
+            "403 Forbidden\n\n{\"errcode\": \"M_FORBIDDEN\", \"error\": \"You are not invited to this room.\"}"
+        ]);
+        let mut client = Client::new(url.trim());
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        assert_eq!(client.join_room("!room:example.com").unwrap(), room);
+        assert!(requests.recv().unwrap().starts_with("POST /_matrix/client/r0/rooms/%21room%3Aexample.com/join?"));
+
+        let (id, state) = client.join_room_with_state("#pto:example.com").unwrap();
+        assert_eq!(id, room);
+        match state[0].data {
+            EventData::Room(_, RoomEvent::Topic(_, ref topic)) => assert_eq!(topic, "Hello"),
+            ref other => panic!("Expected a topic, got {:?}", other)
+        }
+        assert!(requests.recv().unwrap().starts_with("POST /_matrix/client/r0/join/%23pto%3Aexample.com?"));
+        assert!(requests.recv().unwrap().starts_with("GET /_matrix/client/r0/rooms/%21room%3Aexample.com/state?"));
+
+        match client.join_room("#secret:example.com") {
+            Err(ClientError::Matrix(ref err)) => assert_eq!(err.errcode, "M_FORBIDDEN"),
+            res => panic!("Unexpected {:?}", res)
+        }
+    }
+
+    #[test]
+    fn unique_txn_ids() {
+        let mut first = Client::new("http://127.0.0.1/_matrix/client/r0/");
+        let mut second = Client::new("http://127.0.0.1/_matrix/client/r0/");
+        let ids = vec![first.new_txn_id(), first.new_txn_id()];
+        assert!(ids[0] != ids[1]);
+        assert!(second.new_txn_id() != ids[0]);
+        assert!(first.fork().new_txn_id() != ids[0]);
+    }
+
+    #[test]
+    fn unban() {
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let bob = "@bob:example.org".parse::<UserID>().unwrap();
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{}",
+            "403 Forbidden\n\n{\"errcode\": \"M_FORBIDDEN\", \"error\": \"You don't have permission to unban\"}"
+        ]);
+        let mut client = Client::new(url.trim());
+        client.unban_user(&room, &bob).unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("POST /_matrix/client/r0/rooms/%21room%3Aexample.com/unban "));
+        assert!(request.ends_with(r#"{"user_id":"@bob:example.org"}"#));
+        match client.unban_user(&room, &bob) {
+            Err(ClientError::Forbidden(ref reason)) => assert_eq!(reason, "You don't have permission to unban"),
+            other => panic!("Expected Forbidden, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn account_data() {
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{}",
+            "200 OK\n\n{\"display_names\": true, \"muted\": [\"!room:example.com\"]}",
+            "404 Not Found\n\n{\"errcode\": \"M_NOT_FOUND\", \"error\": \"Account data not found\"}"
+        ]);
+        let mut client = Client::new(url.trim());
+        client.uid = Some("@alice:example.com".parse::<UserID>().unwrap());
+        let settings = Json::from_str(r#"{"display_names": true, "muted": ["!room:example.com"]}"#).unwrap();
+        client.set_account_data(ACCOUNT_DATA_TYPE, &settings).unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("PUT /_matrix/client/r0/user/%40alice%3Aexample.com/account_data/net.hackerbots.pto "));
+        assert!(request.ends_with(r#"{"display_names":true,"muted":["!room:example.com"]}"#));
+        assert_eq!(client.get_account_data(ACCOUNT_DATA_TYPE).unwrap(), settings);
+        assert!(requests.recv().unwrap().starts_with("GET /_matrix/client/r0/user/%40alice%3Aexample.com/account_data/net.hackerbots.pto "));
+        assert_eq!(client.get_account_data("net.hackerbots.pto.other").unwrap(), Json::from_str("{}").unwrap());
+    }
+
+    #[test]
+    fn redact() {
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let event = "$abc:example.com".parse::<EventID>().unwrap();
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"event_id\": \"$def:example.com\"}",
+            "403 Forbidden\n\n{\"errcode\": \"M_FORBIDDEN\", \"error\": \"You cannot redact this event\"}"
+        ]);
+        let mut client = Client::new(url.trim());
+        client.redact_event(&room, &event, Some("typo")).unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("PUT /_matrix/client/r0/rooms/%21room%3Aexample.com/redact/%24abc%3Aexample.com/"));
+        assert!(request.ends_with(r#"{"reason":"typo"}"#));
+        match client.redact_event(&room, &event, None) {
+            Err(ClientError::Forbidden(ref reason)) => assert_eq!(reason, "You cannot redact this event"),
+            other => panic!("Expected Forbidden, got {:?}", other)
+        }
+        assert!(requests.recv().unwrap().ends_with("{}"));
+    }
+
+    #[test]
+    fn logout() {
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"access_token\": \"abc\", \"user_id\": \"@alice:127.0.0.1\"}",
+            "200 OK\n\n{}"
+        ]);
+        let mut client = Client::new(url.trim());
+        client.login("alice", "hunter2").unwrap();
+        client.logout().unwrap();
+        requests.recv().unwrap();
+        assert!(requests.recv().unwrap().starts_with("POST /_matrix/client/r0/logout?access_token=abc&"));
+        assert!(!client.is_logged_in());
+        assert!(client.uid.is_none());
+    }
+
+    #[test]
+    fn logout_all() {
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"access_token\": \"abc\", \"user_id\": \"@alice:127.0.0.1\"}",
+            "200 OK\n\n{}"
+        ]);
+        let mut client = Client::new(url.trim());
+        client.login("alice", "hunter2").unwrap();
+        client.logout_all().unwrap();
+        requests.recv().unwrap();
+        assert!(requests.recv().unwrap().starts_with("POST /_matrix/client/r0/logout/all?access_token=abc&"));
+        assert!(!client.is_logged_in());
+        assert!(client.uid.is_none());
+
+        let mut client = Client::new(mock_server("401 Unauthorized\n\n{\"errcode\": \"M_UNKNOWN_TOKEN\", \
+            \"error\": \"Invalid token\"}").trim());
+        match client.logout_all() {
+            Err(ClientError::Unauthorized) => (),
+            res => panic!("Unexpected {:?}", res)
+        }
+    }
+
+    #[test]
+    fn devices() {
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"access_token\": \"abc\", \"user_id\": \"@alice:127.0.0.1\", \"device_id\": \"BRIDGE\"}",
+            "200 OK\n\n{\"devices\": [{\"device_id\": \"BRIDGE\", \"display_name\": \"pto IRC bridge\"}, \
+                {\"device_id\": \"PHONE\", \"last_seen_ip\": \"10.0.0.1\"}]}",
+            "401 Unauthorized\n\n{\"session\": \"xyz\", \"flows\": [{\"stages\": [\"m.login.password\"]}]}"
+        ]);
+        let mut client = Client::new(url.trim());
+        client.login("alice", "hunter2").unwrap();
+        assert_eq!(client.device_id(), Some("BRIDGE"));
+        let devices = client.devices().unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].display_name, Some("pto IRC bridge".to_owned()));
+        assert_eq!(devices[1].device_id, "PHONE");
+        assert_eq!(devices[1].last_seen_ip, Some("10.0.0.1".to_owned()));
+        match client.delete_device("PHONE") {
+            Err(ClientError::UnsupportedFlows(flows)) => assert_eq!(flows, vec!["m.login.password".to_owned()]),
+            res => panic!("Unexpected {:?}", res)
+        }
+        requests.recv().unwrap();
+        requests.recv().unwrap();
+        assert!(requests.recv().unwrap().starts_with("DELETE /_matrix/client/r0/devices/PHONE?"));
+    }
+
+    #[test]
+    fn sync_leave_and_invite() {
+        let mut client = Client::new(mock_server("200 OK\n\n{\"next_batch\": \"s2\", \"rooms\": {\"leave\": \
+            {\"!gone:example.com\": {\"timeline\": {\"events\": [{\"type\": \"m.room.member\", \
+            \"sender\": \"@alice:example.com\", \"state_key\": \"@alice:example.com\", \
+            \"content\": {\"membership\": \"leave\"}}]}}}, \"invite\": {\"!new:example.com\": \
+            {\"invite_state\": {\"events\": [{\"type\": \"m.room.member\", \"sender\": \"@bob:example.com\", \
+            \"state_key\": \"@alice:example.com\", \"content\": {\"membership\": \"invite\"}}]}}}}}").trim());
+        let mut left = vec![];
+        let mut invited = vec![];
+        let next_batch = client.sync().unwrap().each(|evt| {
+            match evt.data {
+                EventData::LeftRoom(id) => left.push(id),
+                EventData::Invited(invite) => invited.push((invite.room, invite.inviter)),
+                _ => ()
+            }
+        }).unwrap();
+        assert_eq!(left, vec!["!gone:example.com".parse::<RoomID>().unwrap()]);
+        assert_eq!(invited, vec![("!new:example.com".parse::<RoomID>().unwrap(),
+                                  Some("@bob:example.com".parse::<UserID>().unwrap()))]);
+        assert_eq!(next_batch, Some("s2".to_owned()));
+    }
+
+    #[test]
+    fn truncated_response() {
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let mut client = Client::new(raw_server("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+            Content-Length: 1000\r\n\r\n{\"topic\": \"Hel").trim());
+        match client.get_state(&room, "m.room.topic", "") {
+            Err(ClientError::Http(_)) | Err(ClientError::Json(_)) => (),
+            res => panic!("Unexpected {:?}", res)
+        }
+    }
+
+    #[test]
+    fn not_json() {
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let mut client = Client::new(raw_server("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\
+            Content-Length: 30\r\n\r\n<html>502 Bad Gateway</html>\r\n").trim());
+        match client.get_state(&room, "m.room.topic", "") {
+            Err(ClientError::NotJson(ref content_type, ref body)) => {
+                assert_eq!(content_type, "text/html");
+                assert_eq!(body, "<html>502 Bad Gateway</html>");
+            },
+            res => panic!("Unexpected {:?}", res)
+        }
+
+        let mut client = Client::new(raw_server("HTTP/1.1 200 OK\r\nContent-Type: application/json; charset=utf-8\r\n\
+            Content-Length: 18\r\n\r\n{\"topic\": \"Hello\"}").trim());
+        let topic = client.get_state(&room, "m.room.topic", "").unwrap();
+        assert_eq!(topic.find("topic").and_then(|t| t.as_string()), Some("Hello"));
+    }
+
+    #[test]
+    fn gzipped_response() {
+        let mut gzipped = GzEncoder::new(vec![], Compression::Default);
+        gzipped.write_all(b"{\"topic\": \"Hello\"}").unwrap();
+        let body = gzipped.finish().unwrap();
+        let mut raw = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Encoding: gzip\r\n\
+                               Content-Length: {}\r\n\r\n", body.len()).into_bytes();
+        raw.extend_from_slice(&body);
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let mut client = Client::new(raw_server(raw).trim());
+        let topic = client.get_state(&room, "m.room.topic", "").unwrap();
+        assert_eq!(topic.find("topic").and_then(|t| t.as_string()), Some("Hello"));
+    }
+
+    #[test]
+    fn well_known() {
+        let server = mock_server("200 OK\n\n{\"m.homeserver\": {\"base_url\": \"https://matrix.example.com/\"}}");
+        assert_eq!(discover(server.trim()), "https://matrix.example.com");
+        let server = mock_server("404 Not Found\n\n");
+        assert_eq!(discover(server.trim()), server.trim_right_matches("/_matrix/client/r0/"));
+    }
+
+    #[test]
+    fn poll_advances_since() {
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"next_batch\": \"s2\"}",
+            "200 OK\n\n{\"next_batch\": \"s3\", \"rooms\": {\"join\": {\"!room:example.com\": {\"timeline\": \
+                {\"events\": [{\"type\": \"m.room.message\", \"sender\": \"@alice:example.com\", \"event_id\": \"$1\", \
+                \"content\": {\"msgtype\": \"m.text\", \"body\": \"hi\"}}]}}}, \
+                \"leave\": {\"!gone:example.com\": {}}}}"
+        ]);
+        let mut client = Client::new(url.trim());
+        client.set_since("s1".to_string());
+        // A slice without events is followed by another from where it ended
+        let (events, next_batch) = client.poll_async(Arc::new(AtomicBool::new(false))).send().unwrap();
+        for since in &["s1", "s2"] {
+            let request = requests.recv().unwrap();
+            assert!(request.starts_with("GET /_matrix/client/r0/sync?"));
+            assert!(request.contains(format!("since={}&", since).trim()));
+            assert!(request.contains("timeout="));
+            assert!(request.contains("include_leave"));
+        }
+        // Polls carry on, so there's no EndOfSync
+        assert_eq!(events.len(), 2);
+        match events[0].data {
+            EventData::Room(ref id, RoomEvent::Message(_, _, ref body)) => {
+                assert_eq!(*id, "!room:example.com".parse::<RoomID>().unwrap());
+                assert_eq!(body, "hi");
+            },
+            ref other => panic!("Expected a message, got {:?}", other)
+        }
+        match events[1].data {
+            EventData::LeftRoom(ref id) => assert_eq!(*id, "!gone:example.com".parse::<RoomID>().unwrap()),
+            ref other => panic!("Expected a left room, got {:?}", other)
+        }
+        assert_eq!(next_batch, Some("s3".to_string()));
+        client.set_since(next_batch.unwrap());
+        assert_eq!(client.since(), Some("s3"));
+    }
+
+    #[test]
+    fn default_filter() {
+        let mut client = Client::new("https://example.com/_matrix/client/r0/");
+        client.set_sync_limit(20);
+        let filter = client.default_filter();
+        assert_eq!(filter.find_path(&["room", "timeline", "limit"]).and_then(|l| l.as_u64()), Some(20));
+        assert_eq!(filter.find_path(&["room", "state", "lazy_load_members"]).and_then(|l| l.as_boolean()), Some(true));
+        assert!(filter.find_path(&["presence", "not_types"]).is_some());
+        client.want_presence();
+        assert!(client.default_filter().find("presence").is_none());
+    }
+
+    #[test]
+    fn lazy_loading_filter() {
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"access_token\": \"abc\", \"user_id\": \"@alice:127.0.0.1\"}",
+            "200 OK\n\n{\"filter_id\": \"f1\"}",
+            "200 OK\n\n{\"next_batch\": \"s1\"}"
+        ]);
+        let mut client = Client::new(url.trim());
+        client.login("alice", "hunter2").unwrap();
+        client.sync().unwrap().each(|_| ()).unwrap();
+        requests.recv().unwrap();
+        let upload = requests.recv().unwrap();
+        assert!(upload.starts_with("POST /_matrix/client/r0/user/%40alice%3A127.0.0.1/filter?"));
+        let body = Json::from_str(upload.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        for section in &["timeline", "state"] {
+            assert_eq!(body.find_path(&["room", *section, "lazy_load_members"]).and_then(|l| l.as_boolean()),
+                       Some(true));
+        }
+        assert!(requests.recv().unwrap().starts_with("GET /_matrix/client/r0/sync?"));
+    }
+
+    #[test]
+    fn custom_filter() {
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"access_token\": \"abc\", \"user_id\": \"@alice:127.0.0.1\"}",
+            "200 OK\n\n{\"filter_id\": \"f1\"}",
+            "200 OK\n\n{\"next_batch\": \"s1\"}"
+        ]);
+        let mut client = Client::new(url.trim());
+        let filter = Json::from_str(r#"{"room": {"timeline": {"limit": 5}}}"#).unwrap();
+        client.set_filter(filter.clone());
+        client.login("alice", "hunter2").unwrap();
+        client.sync().unwrap().each(|_| ()).unwrap();
+        requests.recv().unwrap();
+        let upload = requests.recv().unwrap();
+        assert_eq!(Json::from_str(upload.split("\r\n\r\n").nth(1).unwrap()).unwrap(), filter);
+        assert!(requests.recv().unwrap().contains("filter=f1"));
+    }
+
+    #[test]
+    fn display_names() {
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"displayname\": \"Alice Liddell\"}",
+            "404 Not Found\n\n{\"errcode\": \"M_NOT_FOUND\", \"error\": \"Profile was not found\"}"
+        ]);
+        let mut client = Client::new(url.trim());
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        assert_eq!(client.get_display_name(&alice).unwrap(), Some("Alice Liddell".to_owned()));
+        assert!(requests.recv().unwrap().contains("/profile/%40alice%3Aexample.com/displayname?"));
+        let bob = "@bob:example.com".parse::<UserID>().unwrap();
+        assert_eq!(client.fork().get_display_name(&bob).unwrap(), None);
+    }
+
+    #[test]
+    fn cancelled_poll() {
+        let mut client = Client::new("http://127.0.0.1:1/_matrix/client/r0/");
+        match client.poll_async(Arc::new(AtomicBool::new(true))).send() {
+            Err(ClientError::Cancelled) => (),
+            res => panic!("Unexpected {:?}", res)
+        }
+
+        // A server holding on to the request doesn't hold up cancelling it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move|| {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_request(&mut stream);
+            thread::sleep(Duration::from_secs(30));
+        });
+        let mut client = Client::new(format!("http://{}/_matrix/client/r0/", addr).trim());
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut poll = client.poll_async(cancel.clone());
+        poll.slice = Duration::from_millis(200);
+        let started = Instant::now();
+        let poll = thread::spawn(move|| poll.send());
+        thread::sleep(Duration::from_millis(50));
+        cancel.store(true, Ordering::SeqCst);
+        match poll.join().unwrap() {
+            Err(ClientError::Cancelled) => (),
+            res => panic!("Unexpected {:?}", res)
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn soft_logout() {
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let mut client = Client::new(mock_server("401 Unauthorized\n\n{\"errcode\": \"M_UNKNOWN_TOKEN\", \
+            \"error\": \"Token expired\", \"soft_logout\": true}").trim());
+        match client.get_state(&room, "m.room.topic", "") {
+            Err(ClientError::SoftLogout) => (),
+            res => panic!("Unexpected {:?}", res)
+        }
+
+        let mut client = Client::new(mock_server("401 Unauthorized\n\n{\"errcode\": \"M_UNKNOWN_TOKEN\", \
+            \"error\": \"Invalid token\"}").trim());
+        match client.get_state(&room, "m.room.topic", "") {
+            Err(ClientError::Unauthorized) => (),
+            res => panic!("Unexpected {:?}", res)
+        }
+    }
+
+    #[test]
+    fn get_event() {
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let id = "$abc".parse::<EventID>().unwrap();
+        let mut client = Client::new(mock_server("200 OK\n\n{\"type\": \"m.room.message\", \"event_id\": \"$abc\", \
+            \"room_id\": \"!room:example.com\", \"sender\": \"@alice:example.com\", \
+            \"content\": {\"msgtype\": \"m.text\", \"body\": \"hello\"}}").trim());
+        for _ in 0..2 {
+            // The second lookup is answered from the cache
+            match client.get_event(&room, &id).unwrap().data {
+                EventData::Room(_, RoomEvent::Message(sender, msgtype, text)) => {
+                    assert_eq!(sender.nickname, "alice");
+                    assert_eq!(msgtype, MsgType::Text);
+                    assert_eq!(text, "hello");
+                },
+                data => panic!("Unexpected {:?}", data)
+            }
+        }
+
+        let mut client = Client::new(mock_server("404 Not Found\n\n{\"errcode\": \"M_NOT_FOUND\", \"error\": \"Event not found\"}").trim());
+        match client.get_event(&room, &id) {
+            Err(ClientError::EventNotFound) => (),
+            res => panic!("Unexpected {:?}", res)
+        }
+    }
+
+    #[test]
+    fn login_flows() {
+        let mut client = Client::new(mock_server("200 OK\n\n{\"flows\": [{\"type\": \"m.login.sso\"}, {\"type\": \"m.login.token\"}]}").trim());
+        let flows = vec!["m.login.sso".to_owned(), "m.login.token".to_owned()];
+        assert_eq!(client.login_flows().unwrap(), flows);
+        // The mock server is gone by now, so this has to come from the cache
+        assert_eq!(client.login_flows().unwrap(), flows);
+    }
+
+    #[test]
+    fn get_state() {
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let mut client = Client::new(mock_server("200 OK\n\n{\"topic\": \"Hello\"}").trim());
+        let topic = client.get_state(&room, "m.room.topic", "").unwrap();
+        assert_eq!(topic.find("topic").and_then(|t| t.as_string()), Some("Hello"));
+
+        let mut client = Client::new(mock_server("404 Not Found\n\n{\"errcode\": \"M_NOT_FOUND\", \"error\": \"Event not found\"}").trim());
+        match client.get_state(&room, "m.room.topic", "") {
+            Err(ClientError::Matrix(ref err)) => assert_eq!(err.errcode, "M_NOT_FOUND"),
+            other => panic!("Expected M_NOT_FOUND, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn members_at() {
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"chunk\": [{\"type\": \"m.room.member\", \"room_id\": \"!room:example.com\", \
+             \"sender\": \"@alice:example.com\", \"state_key\": \"@alice:example.com\", \
+             \"content\": {\"membership\": \"join\"}}, \
+             {\"type\": \"m.room.member\", \"room_id\": \"!room:example.com\", \
+             \"sender\": \"@bob:example.com\", \"state_key\": \"@bob:example.com\", \
+             \"content\": {\"membership\": \"join\"}}]}"
+        ]);
+        let mut client = Client::new(url.trim());
+        let mut members = vec![];
+        client.get_members(&room, Some("join"), Some("s42")).unwrap().each(|evt| {
+            match evt.data {
+                EventData::Room(_, RoomEvent::Membership(user, MembershipAction::Join)) => members.push(user.nickname),
+                data => panic!("Unexpected {:?}", data)
+            }
+        }).unwrap();
+        assert_eq!(members, vec!["alice".to_owned(), "bob".to_owned()]);
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("GET /_matrix/client/r0/rooms/%21room%3Aexample.com/members?"));
+        assert!(request.contains("membership=join"));
+        assert!(request.contains("at=s42"));
+    }
+
+    #[test]
+    fn members_as_they_arrive() {
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/_matrix/client/r0/", listener.local_addr().unwrap());
+        thread::spawn(move|| {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_request(&mut stream);
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                               Transfer-Encoding: chunked\r\n\r\n").unwrap();
+            let chunks = [
+                "{\"chunk\": [{\"type\": \"m.room.member\", \"sender\": \"@alice:example.com\", ",
+                "\"state_key\": \"@alice:example.com\", \"content\": {\"membership\": \"join\"}}, ",
+                "{\"type\": \"m.room.member\", \"sender\": \"@bob:example.com\", \
+                 \"state_key\": \"@bob:example.com\", \"content\": {\"membership\": \"join\"}}]}"
+            ];
+            for (i, chunk) in chunks.iter().enumerate() {
+                // The last member takes a while
+                if i == chunks.len() - 1 {
+                    thread::sleep(Duration::from_millis(500));
+                }
+                write!(stream, "{:x}\r\n{}\r\n", chunk.len(), chunk).unwrap();
+                stream.flush().unwrap();
+            }
+            stream.write_all(b"0\r\n\r\n").unwrap();
+        });
+        let mut client = Client::new(url.trim());
+        let start = Instant::now();
+        let mut arrivals = vec![];
+        client.get_members(&room, Some("join"), None).unwrap().each(|evt| {
+            match evt.data {
+                EventData::Room(ref id, RoomEvent::Membership(ref user, MembershipAction::Join)) => {
+                    assert_eq!(*id, room);
+                    arrivals.push((user.nickname.clone(), start.elapsed()));
+                },
+                ref data => panic!("Unexpected {:?}", data)
+            }
+        }).unwrap();
+        assert_eq!(arrivals.iter().map(|a| a.0.trim()).collect::<Vec<_>>(), vec!["alice", "bob"]);
+        assert!(arrivals[0].1 < Duration::from_millis(400));
+        assert!(arrivals[1].1 >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn older_messages() {
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"start\": \"t5\", \"end\": \"t3\", \"chunk\": [\
+             {\"type\": \"m.room.message\", \"sender\": \"@alice:example.com\", \
+             \"origin_server_ts\": 2000, \"content\": {\"msgtype\": \"m.text\", \"body\": \"second\"}}, \
+             {\"type\": \"m.room.message\", \"sender\": \"@alice:example.com\", \
+             \"origin_server_ts\": 1000, \"content\": {\"msgtype\": \"m.text\", \"body\": \"first\"}}]}",
+            "200 OK\n\n{\"start\": \"t3\", \"end\": \"t3\", \"chunk\": []}"
+        ]);
+        let mut client = Client::new(url.trim());
+        let (evts, end) = client.get_messages(&room, "t5", 2).unwrap();
+        let bodies: Vec<String> = evts.into_iter().map(|evt| match evt.data {
+            EventData::Room(ref id, RoomEvent::Message(_, MsgType::Text, ref body)) if *id == room => body.clone(),
+            data => panic!("Unexpected {:?}", data)
+        }).collect();
+        assert_eq!(bodies, vec!["first".to_owned(), "second".to_owned()]);
+        assert_eq!(end, Some("t3".to_owned()));
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("GET /_matrix/client/r0/rooms/%21room%3Aexample.com/messages?"));
+        assert!(request.contains("from=t5"));
+        assert!(request.contains("dir=b"));
+        assert!(request.contains("limit=2"));
+        let (evts, end) = client.get_messages(&room, "t3", 2).unwrap();
+        assert!(evts.is_empty());
+        assert_eq!(end, None);
+    }
+
+    #[test]
+    fn room_avatars() {
+        let room = "!room:example.com".parse::<RoomID>().unwrap();
+        let (url, requests) = mock_responses(vec![
+            "200 OK\n\n{\"event_id\": \"$avatar:example.com\"}",
+            "200 OK\n\n{\"url\": \"mxc://example.com/cat\"}",
+            "403 Forbidden\n\n{\"errcode\": \"M_FORBIDDEN\", \
+             \"error\": \"You don't have permission to post that to the room.\"}"
+        ]);
+        let mut client = Client::new(url.trim());
+        for uri in &["https://example.com/cat.png", "mxc://", "mxc://example.com", "mxc://example.com/", "mxc:///cat"] {
+            match client.set_room_avatar(&room, uri) {
+                Err(ClientError::InvalidMxc(ref invalid)) => assert_eq!(invalid, *uri),
+                other => panic!("Expected InvalidMxc, got {:?}", other)
+            }
+        }
+        client.set_room_avatar(&room, "mxc://example.com/cat").unwrap();
+        assert!(requests.recv().unwrap().starts_with("PUT /_matrix/client/r0/rooms/%21room%3Aexample.com/state/m.room.avatar/?"));
+        assert_eq!(client.get_room_avatar(&room).unwrap(), Some("mxc://example.com/cat".to_owned()));
+        match client.set_room_avatar(&room, "mxc://example.com/dog") {
+            Err(ClientError::Forbidden(ref reason)) => assert!(reason.contains("permission")),
+            other => panic!("Expected Forbidden, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn encoded_urls() {
+        assert_eq!(path(&["rooms", "!abc:example.com", "send", "m.room.message", "1"]),
+                   "rooms/%21abc%3Aexample.com/send/m.room.message/1");
+        assert_eq!(path(&["join", "#pto:oob.systems"]), "join/%23pto%3Aoob.systems");
+        let client = Client::new("https://example.com/_matrix/client/r0/");
+        let mut args = HashMap::new();
+        args.insert("filter", "{\"room\":{}}");
+        let url = client.url(path(&["rooms", "!abc:example.com", "members"]).trim(), &args);
+        assert_eq!(format!("{}", url),
+                   "https://example.com/_matrix/client/r0/rooms/%21abc%3Aexample.com/members?filter=%7B%22room%22%3A%7B%7D%7D&");
+        assert_eq!(client.download_url("mxc://example.com/AbC123"),
+                   Some("https://example.com/_matrix/media/r0/download/example.com/AbC123".to_owned()));
+        assert_eq!(client.download_url("https://example.com/cat.png"), None);
+    }
+
+    #[test]
+    fn normalized_base_urls() {
+        for base in &["https://example.com", "https://example.com/", "https://example.com/_matrix/client/r0",
+                      "https://example.com/_matrix/client/r0/", "https://example.com//"] {
+            let client = Client::new(base);
+            assert_eq!(format!("{}", client.url("login", &HashMap::new())),
+                       "https://example.com/_matrix/client/r0/login?");
+            assert_eq!(format!("{}", client.url("/sync", &HashMap::new())),
+                       "https://example.com/_matrix/client/r0/sync?");
+            assert_eq!(format!("{}", client.media_url("upload", &HashMap::new())),
+                       "https://example.com/_matrix/media/r0/upload?");
+        }
+        assert_eq!(format!("{}", Client::new("http://localhost:8008/").url("login", &HashMap::new())),
+                   "http://localhost:8008/_matrix/client/r0/login?");
+    }
+
+    #[test]
+    fn negotiate_version() {
+        let versions = |v: &[&str]| -> Vec<String> { v.iter().map(|v| v.to_string()).collect() };
+        assert_eq!(pick_version(&versions(&["r0.5.0", "r0.6.1"])), "r0");
+        assert_eq!(pick_version(&versions(&["r0.6.1", "v1.1", "v1.2"])), "v3");
+        assert_eq!(pick_version(&versions(&["v1.0"])), "r0");
+        assert_eq!(pick_version(&versions(&["v2.0"])), "v3");
+        assert_eq!(pick_version(&[]), "r0");
+
+        let mut client = Client::new(mock_server("200 OK\n\n{\"versions\": [\"r0.6.1\", \"v1.3\"]}").trim());
+        client.negotiate_version();
+        assert!(format!("{}", client.url("sync", &HashMap::new())).ends_with("/_matrix/client/v3/sync?"));
+        assert!(format!("{}", client.media_url("upload", &HashMap::new())).ends_with("/_matrix/media/v3/upload?"));
+
+        let mut client = Client::new(mock_server("404 Not Found\n\n").trim());
+        client.negotiate_version();
+        assert!(format!("{}", client.url("sync", &HashMap::new())).ends_with("/_matrix/client/r0/sync?"));
+    }
+}
@@ -16,8 +16,12 @@
 
 use rustc_serialize::json::Json;
 use rustc_serialize::json;
+use links;
 use matrix::json as mjson;
 use matrix::model;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
 
 #[derive(Debug)]
 pub enum MembershipAction {
@@ -25,6 +29,7 @@ pub enum MembershipAction {
     Leave,
     Ban,
     Invite,
+    Knock,
 }
 
 impl MembershipAction {
@@ -34,27 +39,216 @@ impl MembershipAction {
             "leave" => MembershipAction::Leave,
             "ban" => MembershipAction::Ban,
             "invite" => MembershipAction::Invite,
+            "knock" => MembershipAction::Knock,
             _ => panic!("unknown membership action {:?}", s)
         }
     }
 }
 
+/// A file that has been uploaded to the media repository
+#[derive(Debug)]
+pub struct FileInfo {
+    pub name: String,
+    /// The mxc:// URI of the upload
+    pub url: String,
+    pub mimetype: String,
+    pub size: u64
+}
+
+/// Which homeservers may take part in a room, from m.room.server_acl
+#[derive(Debug)]
+pub struct ServerAcl {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub allow_ip_literals: bool
+}
+
+/// Matches a server name against a glob where * is any run of characters
+/// and ? any single one
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(&'*'), _) =>
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..])),
+        (Some(&'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false
+    }
+}
+
+impl ServerAcl {
+    pub fn allows(&self, server: &str) -> bool {
+        let server: Vec<char> = server.to_lowercase().chars().collect();
+        let matches = |globs: &Vec<String>| {
+            globs.iter().any(|g| {
+                let pattern: Vec<char> = g.to_lowercase().chars().collect();
+                glob_match(&pattern, &server)
+            })
+        };
+        let host: String = server.iter().cloned().collect();
+        let ip_literal = host.starts_with("[") || host.parse::<IpAddr>().is_ok();
+        if ip_literal && !self.allow_ip_literals {
+            return false;
+        }
+        !matches(&self.deny) && matches(&self.allow)
+    }
+}
+
+/// The power levels of a room's members, from m.room.power_levels
+#[derive(Debug)]
+pub struct PowerLevels {
+    pub users: HashMap<model::UserID, i64>,
+    pub users_default: i64
+}
+
+/// The power level from which a member is a channel operator
+const OP_LEVEL: i64 = 50;
+
+impl PowerLevels {
+    pub fn level(&self, user: &model::UserID) -> i64 {
+        self.users.get(user).cloned().unwrap_or(self.users_default)
+    }
+
+    /// The channel prefixes a member's power level warrants, highest first:
+    /// op for moderators and up, voice for anyone above the default
+    pub fn prefixes(&self, user: &model::UserID) -> String {
+        let level = self.level(user);
+        let mut ret = String::new();
+        if level >= OP_LEVEL {
+            ret.push('@');
+        }
+        if level > self.users_default {
+            ret.push('+');
+        }
+        ret
+    }
+}
+
+/// What kind of message an m.room.message is, from its msgtype
+#[derive(Clone, Debug, PartialEq)]
+pub enum MsgType {
+    Text,
+    Emote,
+    Notice,
+    /// An uploaded image, file, audio or video clip, with its msgtype and
+    /// where to get it
+    Media(String, String),
+    /// Anything else, by msgtype. The body is still meant to be readable.
+    Other(String)
+}
+
+impl MsgType {
+    /// Reads the msgtype of a message's content
+    pub fn from_content(content: &Json) -> Self {
+        let msgtype = content.find("msgtype").and_then(|t| t.as_string()).unwrap_or("m.text");
+        match msgtype {
+            "m.text" => MsgType::Text,
+            "m.emote" => MsgType::Emote,
+            "m.notice" => MsgType::Notice,
+            "m.image" | "m.file" | "m.audio" | "m.video" => {
+                match content.find("url").and_then(|u| u.as_string()) {
+                    Some(url) => MsgType::Media(msgtype.to_string(), url.to_string()),
+                    // Encrypted media only has a file object, we can't fetch it
+                    None => MsgType::Other(msgtype.to_string())
+                }
+            },
+            other => MsgType::Other(other.to_string())
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            &MsgType::Text => "m.text",
+            &MsgType::Emote => "m.emote",
+            &MsgType::Notice => "m.notice",
+            &MsgType::Media(ref msgtype, _) => msgtype.trim(),
+            &MsgType::Other(ref msgtype) => msgtype.trim()
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum RoomEvent {
-    CanonicalAlias(String),
+    /// The room's main alias, None once it was removed, and its other ones
+    CanonicalAlias(Option<model::RoomAlias>, Vec<model::RoomAlias>),
     JoinRules(String),
     Membership(model::UserID, MembershipAction),
     HistoryVisibility(String),
+    /// Whether guests may join the room
+    GuestAccess(bool),
+    ServerAcl(ServerAcl),
     Create,
-    Aliases(Vec<String>),
-    Message(model::UserID, String),
-    PowerLevels,
+    Aliases(Vec<model::RoomAlias>),
+    Message(model::UserID, MsgType, String),
+    /// A message replying to an earlier event, with the quoted fallback
+    /// already stripped from its body
+    Reply(model::UserID, model::EventID, String),
+    /// An administrative message from the homeserver, with its
+    /// server_notice_type if it has one
+    ServerNotice(model::UserID, Option<String>, String),
+    File(model::UserID, FileInfo),
+    PowerLevels(PowerLevels),
     Name(model::UserID, String),
     Avatar(model::UserID, String),
     Topic(model::UserID, String),
+    Encrypted(model::UserID),
+    /// The room turned on end-to-end encryption, with the algorithm it uses
+    Encryption(String),
+    /// The room was upgraded and continues in another one
+    Tombstone(model::RoomID),
+    /// The full set of events pinned in the room, and who last changed it
+    PinnedEvents(model::UserID, Vec<model::EventID>),
+    /// An invite to someone without a Matrix account yet, by email or phone:
+    /// who sent it, its token, and the display name standing in for the
+    /// address. No display name means the invite was withdrawn.
+    ThirdPartyInvite(model::UserID, String, Option<String>),
+    /// A third-party invite made good, turned into an invite of the user
+    /// behind the address, with the token of the original invite
+    ThirdPartyInviteClaimed(model::UserID, String),
+    /// A member changed their display name, to None if they dropped it. It
+    /// comes as a join of someone who had already joined.
+    Renamed(model::UserID, Option<String>),
+    /// A member changed their avatar, likewise
+    MemberAvatar(model::UserID),
     Unknown(String, Json)
 }
 
+/// An invite to a room we aren't in, as far as the stripped state that
+/// comes with it tells
+#[derive(Debug)]
+pub struct Invite {
+    pub room: model::RoomID,
+    pub inviter: Option<model::UserID>,
+    pub name: Option<String>,
+    pub alias: Option<model::RoomAlias>
+}
+
+impl Invite {
+    /// Reads an invited room from the invite section of a sync
+    pub fn from_json(room: model::RoomID, json: &Json) -> Self {
+        let mut invite = Invite {
+            room: room,
+            inviter: None,
+            name: None,
+            alias: None
+        };
+        let events = json.find_path(&["invite_state", "events"]).and_then(|e| e.as_array());
+        for evt in events.unwrap_or(&vec![]) {
+            let content = |key: &str| {
+                evt.find_path(&["content", key]).and_then(|v| v.as_string()).map(|v| v.to_string())
+            };
+            match evt.find("type").and_then(|t| t.as_string()) {
+                Some("m.room.name") => invite.name = content("name"),
+                Some("m.room.canonical_alias") => invite.alias = content("alias").and_then(|a| a.parse().ok()),
+                Some("m.room.member") if content("membership") == Some("invite".to_string()) =>
+                    invite.inviter = evt.find("sender").and_then(|s| s.as_string()).and_then(|s| s.parse().ok()),
+                _ => ()
+            }
+        }
+        invite
+    }
+}
+
 #[derive(Debug)]
 pub struct TypingEvent {
     pub users: Vec<model::UserID>,
@@ -66,6 +260,14 @@ pub enum EventData {
     Room(model::RoomID, RoomEvent),
     Typing(TypingEvent),
     Presence(PresenceEvent),
+    /// We left the room, or were removed from it, from somewhere else. Only
+    /// a sync says so, in its leave section.
+    LeftRoom(model::RoomID),
+    /// Someone invited us to a room. Also only from a sync.
+    Invited(Invite),
+    /// Where the timeline a sync gave of a room starts, for paging further
+    /// back through its history
+    PrevBatch(model::RoomID, String),
     Unknown(String, Json),
     EndOfSync
 }
@@ -73,9 +275,15 @@ pub enum EventData {
 impl EventData {
     pub fn type_str(&self) -> String {
         match self {
-            &EventData::Room(_, RoomEvent::Message(_, _)) =>
+            &EventData::Room(_, RoomEvent::Message(_, _, _)) =>
+                "m.room.message".to_string(),
+            &EventData::Room(_, RoomEvent::Reply(_, _, _)) =>
+                "m.room.message".to_string(),
+            &EventData::Room(_, RoomEvent::ServerNotice(_, _, _)) =>
                 "m.room.message".to_string(),
-            &EventData::Room(_, RoomEvent::CanonicalAlias(_)) =>
+            &EventData::Room(_, RoomEvent::File(_, _)) =>
+                "m.room.message".to_string(),
+            &EventData::Room(_, RoomEvent::CanonicalAlias(_, _)) =>
                 "m.room.canonical_alias".to_string(),
             &EventData::Room(_, RoomEvent::JoinRules(_)) =>
                 "m.room.join_rules".to_string(),
@@ -83,11 +291,15 @@ impl EventData {
                 "m.room.member".to_string(),
             &EventData::Room(_, RoomEvent::HistoryVisibility(_)) =>
                 "m.room.history_visibility".to_string(),
+            &EventData::Room(_, RoomEvent::GuestAccess(_)) =>
+                "m.room.guest_access".to_string(),
+            &EventData::Room(_, RoomEvent::ServerAcl(_)) =>
+                "m.room.server_acl".to_string(),
             &EventData::Room(_, RoomEvent::Create )=>
                 "m.room.create".to_string(),
             &EventData::Room(_, RoomEvent::Aliases(_)) =>
                 "m.room.aliases".to_string(),
-            &EventData::Room(_, RoomEvent::PowerLevels) =>
+            &EventData::Room(_, RoomEvent::PowerLevels(_)) =>
                 "m.room.power_levels".to_string(),
             &EventData::Room(_, RoomEvent::Name(_, _)) =>
                 "m.room.name".to_string(),
@@ -95,13 +307,30 @@ impl EventData {
                 "m.room.avatar".to_string(),
             &EventData::Room(_, RoomEvent::Topic(_, _)) =>
                 "m.room.topic".to_string(),
+            &EventData::Room(_, RoomEvent::Encrypted(_)) =>
+                "m.room.encrypted".to_string(),
+            &EventData::Room(_, RoomEvent::Encryption(_)) =>
+                "m.room.encryption".to_string(),
+            &EventData::Room(_, RoomEvent::Tombstone(_)) =>
+                "m.room.tombstone".to_string(),
+            &EventData::Room(_, RoomEvent::PinnedEvents(_, _)) =>
+                "m.room.pinned_events".to_string(),
+            &EventData::Room(_, RoomEvent::ThirdPartyInvite(_, _, _)) =>
+                "m.room.third_party_invite".to_string(),
+            &EventData::Room(_, RoomEvent::ThirdPartyInviteClaimed(_, _)) |
+            &EventData::Room(_, RoomEvent::Renamed(_, _)) |
+            &EventData::Room(_, RoomEvent::MemberAvatar(_)) =>
+                "m.room.member".to_string(),
             &EventData::Room(_, RoomEvent::Unknown(ref unknown_type, _)) =>
                 format!("m.room.{}", unknown_type),
             &EventData::Typing(_) =>
                 "m.typing".to_string(),
             &EventData::Presence(_) =>
                 "m.presence".to_string(),
+            &EventData::LeftRoom(_) | &EventData::Invited(_) =>
+                "m.room.member".to_string(),
             &EventData::Unknown(ref unknown_type, _) => unknown_type.clone(),
+            &EventData::PrevBatch(_, _) => panic!("PrevBatch is a special value"),
             &EventData::EndOfSync => panic!("EndOfSync is a special value")
         }
     }
@@ -111,9 +340,22 @@ impl EventData {
         match self {
             &EventData::Room(ref _id, ref evt) => {
                 match evt {
-                    &RoomEvent::Message(_, ref text) => {
-                        ret.insert("msgtype".to_string(), json::Json::String("m.text".to_string()));
+                    &RoomEvent::Message(_, ref msgtype, ref text) => {
+                        ret.insert("msgtype".to_string(), json::Json::String(msgtype.as_str().to_string()));
                         ret.insert("body".to_string(), json::Json::String(text.clone()));
+                        if let &MsgType::Media(_, ref url) = msgtype {
+                            ret.insert("url".to_string(), json::Json::String(url.clone()));
+                        }
+                    },
+                    &RoomEvent::File(_, ref file) => {
+                        let msgtype = if file.mimetype.starts_with("image/") { "m.image" } else { "m.file" };
+                        let mut info = json::Object::new();
+                        info.insert("mimetype".to_string(), json::Json::String(file.mimetype.clone()));
+                        info.insert("size".to_string(), json::Json::U64(file.size));
+                        ret.insert("msgtype".to_string(), json::Json::String(msgtype.to_string()));
+                        ret.insert("body".to_string(), json::Json::String(file.name.clone()));
+                        ret.insert("url".to_string(), json::Json::String(file.url.clone()));
+                        ret.insert("info".to_string(), json::Json::Object(info));
                     },
                     _ => panic!("Can only serialize m.room.message events :(")
                 }
@@ -127,43 +369,202 @@ impl EventData {
 #[derive(Debug)]
 pub struct Event {
     pub id: Option<model::EventID>,
+    /// origin_server_ts, in milliseconds since the epoch
+    pub timestamp: Option<u64>,
+    /// Transaction ID of an event we sent ourselves
+    pub txn_id: Option<String>,
+    /// Users a message mentions with pills, along with the text standing
+    /// for them in its plain body
+    pub mentions: Vec<(model::UserID, String)>,
     pub data: EventData
 }
 
 #[derive(Debug)]
 pub struct PresenceEvent {
     pub presence: String,
-    pub user: model::UserID
+    pub user: model::UserID,
+    /// Whether the user has a client open right now, if the server says
+    pub currently_active: Option<bool>,
+    /// How long ago, in milliseconds, the user last did anything
+    pub last_active_ago: Option<u64>
+}
+
+/// Parses an identifier the server handed us, None if it's missing or
+/// malformed
+fn parse_id<T: FromStr>(id: Option<&Json>) -> Option<T> {
+    id.and_then(|id| id.as_string()).and_then(|id| id.parse().ok())
+}
+
+/// Who sent an event, if it says so properly. Older servers call it
+/// user_id.
+fn sender_id(json: &Json) -> Option<model::UserID> {
+    parse_id(json.find("sender").or(json.find("user_id")))
+}
+
+/// Whose membership a member event is about, which isn't necessarily its
+/// sender, for instance for invites and kicks.
+fn member_id(json: &Json) -> Option<model::UserID> {
+    match json.find("state_key").and_then(|s| s.as_string()) {
+        Some(id) if id.len() > 0 => id.parse().ok(),
+        _ => sender_id(json)
+    }
+}
+
+/// The sender of a room event, which `from_room_json` made sure of
+fn sender(json: &Json) -> model::UserID {
+    sender_id(json).expect("Room event without a valid sender")
+}
+
+/// Likewise for the user a member event is about
+fn member(json: &Json) -> model::UserID {
+    member_id(json).expect("Member event without a valid user")
+}
+
+fn content_str<'a>(content: Option<&'a Json>, key: &str) -> Option<&'a str> {
+    content.and_then(|c| c.find(key)).and_then(|v| v.as_string())
+}
+
+/// Tells what changed when a member who had already joined a room joins it
+/// again: their display name, their avatar, or nothing at all
+fn profile_change(json: &Json, prev_content: Option<&Json>) -> RoomEvent {
+    let content = json.find("content");
+    let displayname = content_str(content, "displayname");
+    if displayname != content_str(prev_content, "displayname") {
+        RoomEvent::Renamed(member(json), displayname.map(|name| name.to_string()))
+    } else if content_str(content, "avatar_url") != content_str(prev_content, "avatar_url") {
+        RoomEvent::MemberAvatar(member(json))
+    } else {
+        RoomEvent::Membership(member(json), MembershipAction::Join)
+    }
+}
+
+/// Splits the quoted fallback, made of "> " lines, off the body of a reply.
+/// Returns the sender the quote names, if any, and the reply itself.
+pub fn strip_reply_fallback(body: &str) -> (Option<model::UserID>, String) {
+    let mut lines = body.lines().peekable();
+    let mut quoted = None;
+    while lines.peek().map(|l| l.starts_with(">")).unwrap_or(false) {
+        let line = lines.next().unwrap();
+        if quoted.is_none() {
+            // The first line reads "> <@alice:example.com> what they said"
+            quoted = line.find("<@").and_then(|start| {
+                let rest = &line[start + 1..];
+                rest.find('>').map(|end| &rest[..end])
+            }).and_then(|id| id.parse().ok());
+        }
+    }
+    if lines.peek() == Some(&"") {
+        lines.next();
+    }
+    let rest: Vec<&str> = lines.collect();
+    (quoted, rest.join("\n"))
+}
+
+/// Cuts the quoted fallback of a reply, its `<mx-reply>` block, out of an
+/// HTML body. What comes after it is the reply itself.
+pub fn strip_html_reply_fallback(html: &str) -> String {
+    let end = match html.find("</mx-reply>") {
+        Some(end) => end + "</mx-reply>".len(),
+        None => return html.to_string()
+    };
+    let start = match html.find("<mx-reply>") {
+        Some(start) if start < end => start,
+        _ => 0
+    };
+    format!("{}{}", &html[..start], &html[end..])
+}
+
+/// The HTML body of a message, if it has one
+fn formatted_body(json: &Json) -> Option<&str> {
+    if json.find_path(&["content", "format"]).and_then(|f| f.as_string()) != Some("org.matrix.custom.html") {
+        return None;
+    }
+    json.find_path(&["content", "formatted_body"]).and_then(|b| b.as_string())
+}
+
+/// The text of a reply, without the quote of what it replies to. Clients
+/// that send an HTML body without a quote don't quote in the plain body
+/// either, so lines it starts with that look quoted are kept then.
+fn reply_body(json: &Json) -> String {
+    let body = mjson::string(json, "content.body");
+    match formatted_body(json) {
+        Some(html) if !html.contains("</mx-reply>") => body.to_string(),
+        _ => strip_reply_fallback(body).1
+    }
+}
+
+/// Finds who a message mentions with pills in its HTML body, along with the
+/// text standing for them, usually a display name. The quote of a reply is
+/// left out, so are pills to rooms.
+fn mentions(json: &Json) -> Vec<(model::UserID, String)> {
+    let html = match formatted_body(json) {
+        Some(html) => strip_html_reply_fallback(html),
+        None => return vec![]
+    };
+    links::pills(&html).into_iter().filter_map(|(link, text)| match link {
+        links::Link::User(id) => id.parse().ok().map(|user| (user, text)),
+        links::Link::Room(_) => None
+    }).collect()
+}
+
+/// Orders a batch of events by origin_server_ts. The sort is stable, and an
+/// event without a timestamp stays right after the event it followed.
+pub fn sort_chronologically(events: Vec<Event>) -> Vec<Event> {
+    let mut last_ts = 0;
+    let mut keyed: Vec<(u64, Event)> = events.into_iter().map(|evt| {
+        last_ts = evt.timestamp.unwrap_or(last_ts);
+        (last_ts, evt)
+    }).collect();
+    keyed.sort_by(|a, b| a.0.cmp(&b.0));
+    keyed.into_iter().map(|(_, evt)| evt).collect()
 }
 
 impl Event {
     pub fn from_json(json: &Json) -> Self {
         let tokens: Vec<&str> = mjson::string(json, "type").trim().split(".").collect();
-        let id = match json.as_object().unwrap().get("event_id") {
-            Some(i) => Some(model::EventID::from_str(i.as_string().unwrap())),
-            None => None
-        };
+        let id = parse_id(json.find("event_id"));
+        let timestamp = json.find("origin_server_ts").and_then(|ts| ts.as_u64());
+        let txn_id = json.find_path(&["unsigned", "transaction_id"])
+            .and_then(|t| t.as_string())
+            .map(|t| t.to_string());
         if tokens[0] != "m" {
             Event {
                 id: id,
+                timestamp: timestamp,
+                txn_id: txn_id,
+                mentions: vec![],
                 data: EventData::Unknown(json.as_object().unwrap().get("type").unwrap().as_string().unwrap().to_string(), json.clone()),
             }
         } else {
             Event {
                 id: id,
+                timestamp: timestamp,
+                txn_id: txn_id,
+                mentions: mentions(json),
                 data: match tokens[1] {
                     "room" =>
                         Self::from_room_json(tokens[2], json),
-                    "typing" =>
-                        EventData::Typing(TypingEvent {
-                            users: vec![],
-                            room: model::RoomID::from_str(mjson::string(json, "room_id"))
-                        }),
-                    "presence" =>
-                        EventData::Presence(PresenceEvent{
-                            presence: mjson::string(json, "content.presence").to_string(),
-                            user: model::UserID::from_str(mjson::string(json, "content.user_id"))
-                        }),
+                    "typing" => match parse_id(json.find("room_id")) {
+                        Some(room) =>
+                            EventData::Typing(TypingEvent {
+                                users: vec![],
+                                room: room
+                            }),
+                        None => EventData::Unknown("m.typing".to_string(), json.clone())
+                    },
+                    // Older servers name the user in the content
+                    "presence" => match parse_id(json.find_path(&["content", "user_id"])).or(sender_id(json)) {
+                        Some(user) =>
+                            EventData::Presence(PresenceEvent{
+                                presence: mjson::string(json, "content.presence").to_string(),
+                                user: user,
+                                currently_active: json.find_path(&["content", "currently_active"])
+                                    .and_then(|b| b.as_boolean()),
+                                last_active_ago: json.find_path(&["content", "last_active_ago"])
+                                    .and_then(|ago| ago.as_u64())
+                            }),
+                        None => EventData::Unknown("m.presence".to_string(), json.clone())
+                    },
                     e =>
                         EventData::Unknown(e.to_string(), json.clone())
                 }
@@ -172,11 +573,28 @@ impl Event {
     }
 
     fn from_room_json(event_type: &str, json: &Json) -> EventData {
+        // What can't be told apart from a forged or broken event isn't
+        // handled as one
+        let room = parse_id(json.find("room_id"));
+        let valid = sender_id(json).is_some() && (event_type != "member" || member_id(json).is_some());
+        let room = match room {
+            Some(room) if valid => room,
+            _ => return EventData::Unknown(format!("m.room.{}", event_type), json.clone())
+        };
         EventData::Room(
-            model::RoomID::from_str(mjson::string(json, "room_id")),
+            room,
             match event_type {
                 "canonical_alias" =>
-                    RoomEvent::CanonicalAlias(mjson::string(json, "content.alias").to_string()),
+                    RoomEvent::CanonicalAlias(
+                        json.find_path(&["content", "alias"])
+                            .and_then(|alias| alias.as_string())
+                            .and_then(|alias| alias.parse().ok()),
+                        json.find_path(&["content", "alt_aliases"])
+                            .and_then(|aliases| aliases.as_array())
+                            .map(|aliases| aliases.iter()
+                                 .filter_map(|alias| alias.as_string().and_then(|alias| alias.parse().ok()))
+                                 .collect())
+                            .unwrap_or(vec![])),
                 "join_rules" => {
                         if json.find_path(&["content", "join_rules"]) == None {
                             RoomEvent::JoinRules(mjson::string(json, "content.join_rule").to_string())
@@ -184,32 +602,466 @@ impl Event {
                             RoomEvent::JoinRules(mjson::string(json, "content.join_rules").to_string())
                         }
                     },
-                "member" =>
-                    RoomEvent::Membership(model::UserID::from_str(mjson::string(json, "user_id")), MembershipAction::from_str(mjson::string(json, "content.membership"))),
+                "member" => {
+                    let membership = mjson::string(json, "content.membership");
+                    // Older servers put what the event replaced at the top
+                    let prev_content = json.find_path(&["unsigned", "prev_content"]).or(json.find("prev_content"));
+                    match json.find_path(&["content", "third_party_invite", "signed", "token"]).and_then(|t| t.as_string()) {
+                        Some(token) if membership == "invite" =>
+                            RoomEvent::ThirdPartyInviteClaimed(member(json), token.to_string()),
+                        _ if membership == "join" && content_str(prev_content, "membership") == Some("join") =>
+                            profile_change(json, prev_content),
+                        _ => RoomEvent::Membership(member(json), MembershipAction::from_str(membership))
+                    }
+                },
+                "third_party_invite" =>
+                    RoomEvent::ThirdPartyInvite(
+                        sender(json),
+                        mjson::string(json, "state_key").to_string(),
+                        json.find_path(&["content", "display_name"])
+                            .and_then(|name| name.as_string())
+                            .map(|name| name.to_string())),
                 "history_visibility" =>
                     RoomEvent::HistoryVisibility(mjson::string(json, "content.history_visibility").to_string()),
+                "guest_access" =>
+                    RoomEvent::GuestAccess(mjson::string(json, "content.guest_access") == "can_join"),
+                "server_acl" => {
+                    let globs = |key: &str| -> Vec<String> {
+                        json.find_path(&["content", key])
+                            .and_then(|list| list.as_array())
+                            .map(|list| list.iter().filter_map(|g| g.as_string()).map(|g| g.to_string()).collect())
+                            .unwrap_or(vec![])
+                    };
+                    RoomEvent::ServerAcl(ServerAcl {
+                        allow: globs("allow"),
+                        deny: globs("deny"),
+                        allow_ip_literals: json.find_path(&["content", "allow_ip_literals"])
+                            .and_then(|b| b.as_boolean())
+                            .unwrap_or(true)
+                    })
+                },
                 "create" =>
                     RoomEvent::Create,
-                "aliases" => {
-                    let aliases = mjson::array(json, "content.aliases");
-                    let mut alias_list: Vec<String> = vec![];
-                    for alias in aliases {
-                        alias_list.push(alias.as_string().unwrap().to_string());
+                "aliases" =>
+                    RoomEvent::Aliases(json.find_path(&["content", "aliases"])
+                        .and_then(|aliases| aliases.as_array())
+                        .map(|aliases| aliases.iter()
+                             .filter_map(|alias| alias.as_string().and_then(|alias| alias.parse().ok()))
+                             .collect())
+                        .unwrap_or(vec![])),
+                "power_levels" => {
+                    let mut users = HashMap::new();
+                    if let Some(levels) = json.find_path(&["content", "users"]).and_then(|u| u.as_object()) {
+                        for (id, level) in levels {
+                            if let (Ok(id), Some(level)) = (id.parse::<model::UserID>(), level.as_i64()) {
+                                users.insert(id, level);
+                            }
+                        }
+                    }
+                    RoomEvent::PowerLevels(PowerLevels {
+                        users: users,
+                        users_default: json.find_path(&["content", "users_default"])
+                            .and_then(|l| l.as_i64())
+                            .unwrap_or(0)
+                    })
+                },
+                "message" => {
+                    match json.find_path(&["content", "msgtype"]).and_then(|t| t.as_string()) {
+                        Some("m.server_notice") =>
+                            RoomEvent::ServerNotice(
+                                sender(json),
+                                json.find_path(&["content", "server_notice_type"])
+                                    .and_then(|t| t.as_string())
+                                    .map(|t| t.to_string()),
+                                mjson::string(json, "content.body").to_string()),
+                        _ => match parse_id(json.find_path(&["content", "m.relates_to", "m.in_reply_to", "event_id"])) {
+                            Some(id) =>
+                                RoomEvent::Reply(
+                                    sender(json),
+                                    id,
+                                    reply_body(json)),
+                            None =>
+                                RoomEvent::Message(
+                                    sender(json),
+                                    MsgType::from_content(json.find("content").unwrap_or(&Json::Null)),
+                                    mjson::string(json, "content.body").to_string())
+                        }
                     }
-                    RoomEvent::Aliases(alias_list)
                 },
-                "power_levels" =>
-                    RoomEvent::PowerLevels,
-                "message" =>
-                    RoomEvent::Message(model::UserID::from_str(mjson::string(json, "user_id")), mjson::string(json, "content.body").to_string()),
                 "name" =>
-                    RoomEvent::Name(model::UserID::from_str(mjson::string(json, "user_id")), mjson::string(json, "content.name").to_string()),
+                    RoomEvent::Name(sender(json), mjson::string(json, "content.name").to_string()),
                 "topic" =>
-                    RoomEvent::Topic(model::UserID::from_str(mjson::string(json, "user_id")), mjson::string(json, "content.topic").to_string()),
+                    RoomEvent::Topic(sender(json), mjson::string(json, "content.topic").to_string()),
                 "avatar" =>
-                    RoomEvent::Avatar(model::UserID::from_str(mjson::string(json, "user_id")), mjson::string(json, "content.url").to_string()),
+                    RoomEvent::Avatar(sender(json), mjson::string(json, "content.url").to_string()),
+                "encrypted" =>
+                    RoomEvent::Encrypted(sender(json)),
+                "encryption" =>
+                    RoomEvent::Encryption(json.find_path(&["content", "algorithm"])
+                        .and_then(|algorithm| algorithm.as_string())
+                        .unwrap_or("unknown")
+                        .to_string()),
+                "tombstone" => match parse_id(json.find_path(&["content", "replacement_room"])) {
+                    Some(room) => RoomEvent::Tombstone(room),
+                    None => RoomEvent::Unknown("tombstone".to_string(), json.clone())
+                },
+                "pinned_events" => {
+                    let pinned = json.find_path(&["content", "pinned"])
+                        .and_then(|list| list.as_array())
+                        .map(|list| list.iter()
+                             .filter_map(|id| id.as_string())
+                             .filter_map(|id| id.parse().ok())
+                             .collect())
+                        .unwrap_or(vec![]);
+                    RoomEvent::PinnedEvents(sender(json), pinned)
+                },
                 unknown_type => RoomEvent::Unknown(unknown_type.to_string(), json.clone())
             }
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::json::Json;
+    use matrix::model;
+
+    #[test]
+    fn reply_fallback() {
+        let js = Json::from_str(r#"{
+            "type": "m.room.message",
+            "event_id": "$1529957153409XPqbC:matrix.org",
+            "room_id": "!room:matrix.org",
+            "sender": "@bob:example.com",
+            "origin_server_ts": 1529957153000,
+            "content": {
+                "msgtype": "m.text",
+                "body": "> <@alice:example.com> Is this thing on?\n> Hello?\n\nLoud and clear",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<mx-reply><blockquote>Is this thing on?</blockquote></mx-reply>Loud and clear",
+                "m.relates_to": {
+                    "m.in_reply_to": {
+                        "event_id": "$1529957000000abcde:example.com"
+                    }
+                }
+            }
+        }"#).unwrap();
+        match Event::from_json(&js).data {
+            EventData::Room(_, RoomEvent::Reply(user, target, body)) => {
+                assert_eq!(user, "@bob:example.com".parse::<model::UserID>().unwrap());
+                assert_eq!(target, "$1529957000000abcde:example.com".parse::<model::EventID>().unwrap());
+                assert_eq!(body, "Loud and clear");
+            },
+            other => panic!("Expected a reply, got {:?}", other)
+        }
+        assert_eq!(strip_reply_fallback("> <@alice:example.com> hi\n\nhello"),
+                   (Some("@alice:example.com".parse::<model::UserID>().unwrap()), "hello".to_owned()));
+        assert_eq!(strip_reply_fallback("no quote here"), (None, "no quote here".to_owned()));
+    }
+
+    #[test]
+    fn html_reply_fallback() {
+        assert_eq!(strip_html_reply_fallback("<mx-reply><blockquote><a href=\"https://matrix.to/#/@alice:example.com\">Alice</a> \
+                                              <p>Lunch?</p></blockquote></mx-reply><p>Sure, <b>noon</b></p>"),
+                   "<p>Sure, <b>noon</b></p>");
+        assert_eq!(strip_html_reply_fallback("<p>No <i>quote</i> here</p>"), "<p>No <i>quote</i> here</p>");
+
+        // Both bodies quote: each loses its quote, the pill in it included
+        let js = Json::from_str(r#"{
+            "type": "m.room.message",
+            "room_id": "!room:matrix.org",
+            "sender": "@bob:example.com",
+            "content": {
+                "msgtype": "m.text",
+                "body": "> <@alice:example.com> Lunch, Carol?\n\nSure, noon",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<mx-reply><blockquote>Lunch, <a href=\"https://matrix.to/#/@carol:example.com\">Carol</a>?</blockquote></mx-reply>Sure, noon",
+                "m.relates_to": {"m.in_reply_to": {"event_id": "$lunch:example.com"}}
+            }
+        }"#).unwrap();
+        let evt = Event::from_json(&js);
+        assert!(evt.mentions.is_empty());
+        match evt.data {
+            EventData::Room(_, RoomEvent::Reply(_, _, ref body)) => assert_eq!(body, "Sure, noon"),
+            ref other => panic!("Expected a reply, got {:?}", other)
+        }
+
+        // Without a quote in the HTML, the plain body has none either
+        let js = Json::from_str(r#"{
+            "type": "m.room.message",
+            "room_id": "!room:matrix.org",
+            "sender": "@bob:example.com",
+            "content": {
+                "msgtype": "m.text",
+                "body": "> as the docs say\nit works",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<blockquote>as the docs say</blockquote>it works",
+                "m.relates_to": {"m.in_reply_to": {"event_id": "$lunch:example.com"}}
+            }
+        }"#).unwrap();
+        match Event::from_json(&js).data {
+            EventData::Room(_, RoomEvent::Reply(_, _, ref body)) => assert_eq!(body, "> as the docs say\nit works"),
+            other => panic!("Expected a reply, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn pills() {
+        let js = Json::from_str(r#"{
+            "type": "m.room.message",
+            "room_id": "!room:matrix.org",
+            "sender": "@bob:example.com",
+            "content": {
+                "msgtype": "m.text",
+                "body": "Alice: have you seen #pto?",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<a href=\"https://matrix.to/#/@alice:example.com\">Alice</a>: have you seen <a href=\"https://matrix.to/#/#pto:oob.systems\">#pto</a>?"
+            }
+        }"#).unwrap();
+        assert_eq!(Event::from_json(&js).mentions,
+                   vec![("@alice:example.com".parse::<model::UserID>().unwrap(), "Alice".to_owned())]);
+    }
+
+    #[test]
+    fn membership_transitions() {
+        let member = |content: &str, unsigned: &str| {
+            let js = Json::from_str(format!(r#"{{"type": "m.room.member", "room_id": "!room:example.com",
+                "sender": "@alice:example.com", "state_key": "@alice:example.com",
+                "content": {}, "unsigned": {}}}"#, content, unsigned).trim()).unwrap();
+            match Event::from_json(&js).data {
+                EventData::Room(_, evt) => evt,
+                data => panic!("Unexpected {:?}", data)
+            }
+        };
+        let alice = "@alice:example.com".parse::<model::UserID>().unwrap();
+        match member(r#"{"membership": "join", "displayname": "Alice"}"#, "{}") {
+            RoomEvent::Membership(ref user, MembershipAction::Join) if *user == alice => (),
+            evt => panic!("Expected a first join, got {:?}", evt)
+        }
+        match member(r#"{"membership": "join", "displayname": "Alice"}"#,
+                     r#"{"prev_content": {"membership": "leave", "displayname": "Alice"}}"#) {
+            RoomEvent::Membership(ref user, MembershipAction::Join) if *user == alice => (),
+            evt => panic!("Expected a join, got {:?}", evt)
+        }
+        match member(r#"{"membership": "join", "displayname": "Alice Liddell"}"#,
+                     r#"{"prev_content": {"membership": "join", "displayname": "Alice"}}"#) {
+            RoomEvent::Renamed(ref user, Some(ref name)) if *user == alice => assert_eq!(name, "Alice Liddell"),
+            evt => panic!("Expected a rename, got {:?}", evt)
+        }
+        match member(r#"{"membership": "join", "avatar_url": "mxc://example.com/new"}"#,
+                     r#"{"prev_content": {"membership": "join", "avatar_url": "mxc://example.com/old"}}"#) {
+            RoomEvent::MemberAvatar(ref user) if *user == alice => (),
+            evt => panic!("Expected an avatar change, got {:?}", evt)
+        }
+    }
+
+    #[test]
+    fn alt_aliases() {
+        let js = Json::from_str(r#"{"type": "m.room.canonical_alias", "room_id": "!room:example.com",
+            "sender": "@alice:example.com", "state_key": "",
+            "content": {"alias": "#main:example.com", "alt_aliases": ["#other:example.org", "invalid"]}}"#).unwrap();
+        match Event::from_json(&js).data {
+            EventData::Room(_, RoomEvent::CanonicalAlias(alias, alt_aliases)) => {
+                assert_eq!(alias, Some("#main:example.com".parse::<model::RoomAlias>().unwrap()));
+                assert_eq!(alt_aliases, vec!["#other:example.org".parse::<model::RoomAlias>().unwrap()]);
+            },
+            other => panic!("Expected a canonical alias, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn alias_removed() {
+        let js = Json::from_str(r#"{"type": "m.room.canonical_alias", "room_id": "!room:example.com",
+            "sender": "@alice:example.com", "state_key": "",
+            "content": {"alt_aliases": ["#other:example.org"]}}"#).unwrap();
+        match Event::from_json(&js).data {
+            EventData::Room(_, RoomEvent::CanonicalAlias(None, alt_aliases)) =>
+                assert_eq!(alt_aliases, vec!["#other:example.org".parse::<model::RoomAlias>().unwrap()]),
+            other => panic!("Expected a canonical alias, got {:?}", other)
+        }
+        let js = Json::from_str(r#"{"type": "m.room.canonical_alias", "room_id": "!room:example.com",
+            "sender": "@alice:example.com", "state_key": "", "content": {}}"#).unwrap();
+        match Event::from_json(&js).data {
+            EventData::Room(_, RoomEvent::CanonicalAlias(None, ref alt_aliases)) if alt_aliases.is_empty() => (),
+            other => panic!("Expected an empty canonical alias, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn encryption() {
+        let js = Json::from_str(r#"{"type": "m.room.encryption", "room_id": "!room:example.com",
+            "sender": "@alice:example.com", "state_key": "",
+            "content": {"algorithm": "m.megolm.v1.aes-sha2"}}"#).unwrap();
+        match Event::from_json(&js).data {
+            EventData::Room(_, RoomEvent::Encryption(ref algorithm)) => assert_eq!(algorithm, "m.megolm.v1.aes-sha2"),
+            other => panic!("Expected encryption, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn out_of_order_chunk() {
+        let chunk = Json::from_str(r#"[
+            {"type": "m.room.message", "room_id": "!a:example.com", "sender": "@alice:example.com",
+             "origin_server_ts": 300, "content": {"msgtype": "m.text", "body": "third"}},
+            {"type": "m.room.message", "room_id": "!b:example.com", "sender": "@bob:example.com",
+             "origin_server_ts": 100, "content": {"msgtype": "m.text", "body": "first"}},
+            {"type": "m.typing", "room_id": "!b:example.com", "content": {"user_ids": []}},
+            {"type": "m.room.message", "room_id": "!a:example.com", "sender": "@alice:example.com",
+             "origin_server_ts": 200, "content": {"msgtype": "m.text", "body": "second"}}
+        ]"#).unwrap();
+        let events = chunk.as_array().unwrap().iter().map(|evt| Event::from_json(evt)).collect();
+        let order: Vec<String> = sort_chronologically(events).into_iter().map(|evt| {
+            match evt.data {
+                EventData::Room(_, RoomEvent::Message(_, _, text)) => text,
+                other => other.type_str()
+            }
+        }).collect();
+        assert_eq!(order, vec!["first", "m.typing", "second", "third"]);
+    }
+
+    #[test]
+    fn message_types() {
+        let msgtype = |content: &str| {
+            let js = Json::from_str(format!(r#"{{"type": "m.room.message", "room_id": "!room:example.com",
+                "sender": "@alice:example.com", "content": {}}}"#, content).trim()).unwrap();
+            match Event::from_json(&js).data {
+                EventData::Room(_, RoomEvent::Message(_, msgtype, _)) => msgtype,
+                data => panic!("Unexpected {:?}", data)
+            }
+        };
+        assert_eq!(msgtype(r#"{"msgtype": "m.text", "body": "hi"}"#), MsgType::Text);
+        assert_eq!(msgtype(r#"{"msgtype": "m.emote", "body": "waves"}"#), MsgType::Emote);
+        assert_eq!(msgtype(r#"{"msgtype": "m.notice", "body": "beep"}"#), MsgType::Notice);
+        assert_eq!(msgtype(r#"{"msgtype": "m.image", "body": "cat.png", "url": "mxc://example.com/abc"}"#),
+                   MsgType::Media("m.image".to_owned(), "mxc://example.com/abc".to_owned()));
+        assert_eq!(msgtype(r#"{"msgtype": "m.location", "body": "Here", "geo_uri": "geo:0,0"}"#),
+                   MsgType::Other("m.location".to_owned()));
+    }
+
+    #[test]
+    fn server_acl() {
+        let acl = ServerAcl {
+            allow: vec!["*".to_owned()],
+            deny: vec!["*.evil.com".to_owned(), "spam?.net".to_owned()],
+            allow_ip_literals: false
+        };
+        assert!(acl.allows("example.com"));
+        assert!(!acl.allows("matrix.evil.com"));
+        assert!(!acl.allows("MATRIX.EVIL.COM"));
+        assert!(acl.allows("evil.com"));
+        assert!(!acl.allows("spam1.net"));
+        assert!(acl.allows("spam12.net"));
+        assert!(!acl.allows("1.2.3.4"));
+        assert!(!acl.allows("[::1]"));
+        let empty = ServerAcl { allow: vec![], deny: vec![], allow_ip_literals: true };
+        assert!(!empty.allows("example.com"));
+    }
+
+    #[test]
+    fn power_level_prefixes() {
+        let js = Json::from_str(r#"{
+            "type": "m.room.power_levels",
+            "room_id": "!room:example.com",
+            "sender": "@admin:example.com",
+            "state_key": "",
+            "content": {"users": {"@admin:example.com": 100, "@mod:example.com": 50, "@helper:example.com": 10},
+                        "users_default": 0}
+        }"#).unwrap();
+        let levels = match Event::from_json(&js).data {
+            EventData::Room(_, RoomEvent::PowerLevels(levels)) => levels,
+            data => panic!("Unexpected {:?}", data)
+        };
+        let prefixes = |id: &str| levels.prefixes(&id.parse::<model::UserID>().unwrap());
+        assert_eq!(prefixes("@admin:example.com"), "@+");
+        assert_eq!(prefixes("@mod:example.com"), "@+");
+        assert_eq!(prefixes("@helper:example.com"), "+");
+        assert_eq!(prefixes("@nobody:example.com"), "");
+    }
+
+    #[test]
+    fn invite_from_stripped_state() {
+        let js = Json::from_str(r#"{"invite_state": {"events": [
+            {"type": "m.room.name", "sender": "@alice:example.com", "state_key": "", "content": {"name": "Hideout"}},
+            {"type": "m.room.canonical_alias", "sender": "@alice:example.com", "state_key": "",
+             "content": {"alias": "#hideout:example.com"}},
+            {"type": "m.room.member", "sender": "@alice:example.com", "state_key": "@me:example.com",
+             "content": {"membership": "invite"}}
+        ]}}"#).unwrap();
+        let room = "!hideout:example.com".parse::<model::RoomID>().unwrap();
+        let invite = Invite::from_json(room.clone(), &js);
+        assert_eq!(invite.room, room);
+        assert_eq!(invite.inviter, Some("@alice:example.com".parse::<model::UserID>().unwrap()));
+        assert_eq!(invite.name, Some("Hideout".to_owned()));
+        assert_eq!(invite.alias, Some("#hideout:example.com".parse::<model::RoomAlias>().unwrap()));
+    }
+
+    #[test]
+    fn third_party_invites() {
+        let js = Json::from_str(r#"{
+            "type": "m.room.third_party_invite",
+            "room_id": "!room:example.com",
+            "sender": "@alice:example.com",
+            "state_key": "abc123",
+            "content": {"display_name": "b...@example.org", "key_validity_url": "https://id.example.org/isvalid",
+                        "public_key": "def456"}
+        }"#).unwrap();
+        match Event::from_json(&js).data {
+            EventData::Room(_, RoomEvent::ThirdPartyInvite(sender, token, name)) => {
+                assert_eq!(sender, "@alice:example.com".parse::<model::UserID>().unwrap());
+                assert_eq!(token, "abc123");
+                assert_eq!(name, Some("b...@example.org".to_owned()));
+            },
+            data => panic!("Unexpected {:?}", data)
+        }
+
+        let withdrawn = Json::from_str(r#"{"type": "m.room.third_party_invite", "room_id": "!room:example.com",
+            "sender": "@alice:example.com", "state_key": "abc123", "content": {}}"#).unwrap();
+        match Event::from_json(&withdrawn).data {
+            EventData::Room(_, RoomEvent::ThirdPartyInvite(_, _, None)) => (),
+            data => panic!("Unexpected {:?}", data)
+        }
+
+        let claimed = Json::from_str(r#"{"type": "m.room.member", "room_id": "!room:example.com",
+            "sender": "@alice:example.com", "state_key": "@bob:example.org",
+            "content": {"membership": "invite", "third_party_invite": {"display_name": "b...@example.org",
+                        "signed": {"mxid": "@bob:example.org", "token": "abc123", "signatures": {}}}}}"#).unwrap();
+        match Event::from_json(&claimed).data {
+            EventData::Room(_, RoomEvent::ThirdPartyInviteClaimed(user, token)) => {
+                assert_eq!(user, "@bob:example.org".parse::<model::UserID>().unwrap());
+                assert_eq!(token, "abc123");
+            },
+            data => panic!("Unexpected {:?}", data)
+        }
+    }
+
+    #[test]
+    fn malformed_ids() {
+        let parse = |js: &str| Event::from_json(&Json::from_str(js).unwrap());
+        let evt = parse(r#"{"type": "m.room.tombstone", "room_id": "!room:example.com", "event_id": "nope",
+            "sender": "@alice:example.com", "state_key": "", "content": {"replacement_room": "garbage"}}"#);
+        assert!(evt.id.is_none());
+        match evt.data {
+            EventData::Room(_, RoomEvent::Unknown(ref kind, _)) if kind == "tombstone" => (),
+            other => panic!("Expected an unknown room event, got {:?}", other)
+        }
+        for js in &[r#"{"type": "m.room.message", "room_id": "room:example.com", "sender": "@alice:example.com",
+                        "content": {"msgtype": "m.text", "body": "hi"}}"#,
+                    r#"{"type": "m.room.message", "room_id": "!room:example.com", "sender": "alice",
+                        "content": {"msgtype": "m.text", "body": "hi"}}"#,
+                    r#"{"type": "m.room.member", "room_id": "!room:example.com", "sender": "@alice:example.com",
+                        "state_key": "bob", "content": {"membership": "invite"}}"#] {
+            match parse(js).data {
+                EventData::Unknown(ref kind, _) if kind.starts_with("m.room.") => (),
+                other => panic!("Expected an unknown event, got {:?}", other)
+            }
+        }
+        // A reply to something malformed is still a message
+        match parse(r#"{"type": "m.room.message", "room_id": "!room:example.com", "sender": "@alice:example.com",
+                        "content": {"msgtype": "m.text", "body": "hi",
+                                    "m.relates_to": {"m.in_reply_to": {"event_id": "1"}}}}"#).data {
+            EventData::Room(_, RoomEvent::Message(_, _, ref body)) if body == "hi" => (),
+            other => panic!("Expected a message, got {:?}", other)
+        }
+    }
+}
@@ -14,7 +14,12 @@
  * limitations under the License.
  */
 
-use rustc_serialize::json::{Json,Array};
+use rustc_serialize::json::{Json,Array,JsonEvent,Parser,ParserError,ErrorCode,StackElement};
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Read;
+use std::result;
+use std::str;
 
 pub fn path<'a>(json: &'a Json, path: &str) -> &'a Json {
     let parts = path.split(".");
@@ -41,3 +46,93 @@ pub fn string<'a>(json: &'a Json, path: &str) -> &'a str{
         None => panic!("{} in {:?} is not an array", path, json)
     }
 }
+
+/// Decodes UTF-8 from a reader one character at a time, so a streaming
+/// Parser can work through a response without buffering all of it.
+pub struct Chars<R: Read> {
+    bytes: io::Bytes<io::BufReader<R>>
+}
+
+impl<R: Read> Chars<R> {
+    pub fn new(reader: R) -> Self {
+        Chars {
+            bytes: io::BufReader::new(reader).bytes()
+        }
+    }
+}
+
+impl<R: Read> Iterator for Chars<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let first = match self.bytes.next() {
+            Some(Ok(b)) => b,
+            _ => return None
+        };
+        let width = match first {
+            0x00...0x7F => 1,
+            0xC0...0xDF => 2,
+            0xE0...0xEF => 3,
+            0xF0...0xF7 => 4,
+            _ => return Some('\u{FFFD}')
+        };
+        let mut buf = [first, 0, 0, 0];
+        for i in 1..width {
+            match self.bytes.next() {
+                Some(Ok(b)) => buf[i] = b,
+                _ => return Some('\u{FFFD}')
+            }
+        }
+        match str::from_utf8(&buf[..width]) {
+            Ok(c) => c.chars().next(),
+            Err(_) => Some('\u{FFFD}')
+        }
+    }
+}
+
+fn syntax_error(code: ErrorCode) -> ParserError {
+    ParserError::SyntaxError(code, 0, 0)
+}
+
+/// Builds the value that starts with `token` out of a streaming parser,
+/// leaving the parser positioned right after it.
+pub fn build<T: Iterator<Item = char>>(parser: &mut Parser<T>, token: JsonEvent) -> result::Result<Json, ParserError> {
+    match token {
+        JsonEvent::ObjectStart => {
+            let mut obj = BTreeMap::new();
+            loop {
+                let next = match parser.next() {
+                    Some(JsonEvent::ObjectEnd) => return Ok(Json::Object(obj)),
+                    Some(t) => t,
+                    None => return Err(syntax_error(ErrorCode::EOFWhileParsingObject))
+                };
+                let key = match parser.stack().top() {
+                    Some(StackElement::Key(k)) => k.to_string(),
+                    _ => return Err(syntax_error(ErrorCode::InvalidSyntax))
+                };
+                let value = try!(build(parser, next));
+                obj.insert(key, value);
+            }
+        },
+        JsonEvent::ArrayStart => {
+            let mut arr = vec![];
+            loop {
+                let next = match parser.next() {
+                    Some(JsonEvent::ArrayEnd) => return Ok(Json::Array(arr)),
+                    Some(t) => t,
+                    None => return Err(syntax_error(ErrorCode::EOFWhileParsingArray))
+                };
+                arr.push(try!(build(parser, next)));
+            }
+        },
+        JsonEvent::ObjectEnd | JsonEvent::ArrayEnd =>
+            Err(syntax_error(ErrorCode::InvalidSyntax)),
+        JsonEvent::BooleanValue(b) => Ok(Json::Boolean(b)),
+        JsonEvent::I64Value(i) => Ok(Json::I64(i)),
+        JsonEvent::U64Value(u) => Ok(Json::U64(u)),
+        JsonEvent::F64Value(f) => Ok(Json::F64(f)),
+        JsonEvent::StringValue(s) => Ok(Json::String(s)),
+        JsonEvent::NullValue => Ok(Json::Null),
+        JsonEvent::Error(e) => Err(e)
+    }
+}
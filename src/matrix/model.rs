@@ -1,4 +1,47 @@
 use std::fmt;
+use std::str::FromStr;
+use channels;
+use config::Config;
+
+/// Why a Matrix identifier couldn't be parsed
+#[derive(Debug, PartialEq)]
+pub enum IdError {
+    Empty,
+    /// The identifier didn't start with the sigil it should have
+    WrongSigil(char),
+    /// The identifier has no server part, or no local part before it
+    MissingServer
+}
+
+impl fmt::Display for IdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &IdError::Empty => write!(f, "Empty identifier"),
+            &IdError::WrongSigil(sigil) => write!(f, "Identifier doesn't start with {}", sigil),
+            &IdError::MissingServer => write!(f, "Identifier isn't of the form localpart:server")
+        }
+    }
+}
+
+/// Checks the sigil of an identifier, returning what follows it
+fn strip_sigil(s: &str, sigil: char) -> Result<&str, IdError> {
+    match s.chars().next() {
+        None => Err(IdError::Empty),
+        Some(c) if c == sigil => Ok(&s[c.len_utf8()..]),
+        Some(_) => Err(IdError::WrongSigil(sigil))
+    }
+}
+
+/// Splits `<sigil>localpart:server` into its parts. The server may have a
+/// port, which stays part of it.
+fn split_id(s: &str, sigil: char) -> Result<(String, String), IdError> {
+    let rest = try!(strip_sigil(s, sigil));
+    match rest.find(':') {
+        Some(idx) if idx > 0 && idx + 1 < rest.len() =>
+            Ok((rest[..idx].to_string(), rest[idx + 1..].to_string())),
+        _ => Err(IdError::MissingServer)
+    }
+}
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct RoomID {
@@ -12,44 +55,139 @@ impl fmt::Display for RoomID {
     }
 }
 
-impl RoomID {
-    pub fn from_str(s: &str) -> Self {
-        let parts: Vec<&str> = s.split(":").collect();
-        RoomID {
-            id: parts[0][1..].to_string(),
-            homeserver: parts[1].to_string()
-        }
+impl FromStr for RoomID {
+    type Err = IdError;
+
+    fn from_str(s: &str) -> Result<Self, IdError> {
+        let (id, homeserver) = try!(split_id(s, '!'));
+        Ok(RoomID {
+            id: id,
+            homeserver: homeserver
+        })
     }
 }
 
-#[derive(PartialEq, Clone, Debug)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct UserID {
     pub nickname: String,
-    pub homeserver: String 
+    pub homeserver: String
 }
 
-impl UserID {
-    pub fn from_str(s: &str) -> Self {
-        let parts: Vec<&str> = s.split(":").collect();
-        UserID {
-            nickname: parts[0][1..].to_string(),
-            homeserver: parts[1].to_string()
-        }
+impl fmt::Display for UserID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "@{}:{}", self.nickname, self.homeserver)
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct EventID {
-    pub id: String,
+impl FromStr for UserID {
+    type Err = IdError;
+
+    fn from_str(s: &str) -> Result<Self, IdError> {
+        let (nickname, homeserver) = try!(split_id(s, '@'));
+        Ok(UserID {
+            nickname: nickname,
+            homeserver: homeserver
+        })
+    }
+}
+
+/// A room alias, such as #pto:oob.systems. Unlike a room ID it can move to
+/// another room, and a room can have several.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct RoomAlias {
+    pub alias: String,
     pub homeserver: String
 }
 
-impl EventID {
-    pub fn from_str(s: &str) -> Self {
-        let parts: Vec<&str> = s.split(":").collect();
-        EventID {
-            id: parts[0][1..].to_string(),
-            homeserver: parts[1].to_string()
+impl RoomAlias {
+    /// The channel this alias is bridged to
+    pub fn to_irc_channel(&self, config: &Config) -> String {
+        channels::sanitize(format!("{}", self).trim(), config)
+    }
+}
+
+impl fmt::Display for RoomAlias {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}:{}", self.alias, self.homeserver)
+    }
+}
+
+impl FromStr for RoomAlias {
+    type Err = IdError;
+
+    fn from_str(s: &str) -> Result<Self, IdError> {
+        let (alias, homeserver) = try!(split_id(s, '#'));
+        Ok(RoomAlias {
+            alias: alias,
+            homeserver: homeserver
+        })
+    }
+}
+
+/// An event ID. Since room version 3 these are opaque hashes without a
+/// server part, so everything after the sigil is kept as it is.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct EventID {
+    pub id: String
+}
+
+impl fmt::Display for EventID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${}", self.id)
+    }
+}
+
+impl FromStr for EventID {
+    type Err = IdError;
+
+    fn from_str(s: &str) -> Result<Self, IdError> {
+        let id = try!(strip_sigil(s, '$'));
+        if id.len() == 0 {
+            return Err(IdError::Empty);
         }
+        Ok(EventID {
+            id: id.to_string()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+
+    #[test]
+    fn parse_ids() {
+        let room: RoomID = "!abc:example.com:8448".parse().unwrap();
+        assert_eq!(room.id, "abc");
+        assert_eq!(room.homeserver, "example.com:8448");
+        assert_eq!(format!("{}", room), "!abc:example.com:8448");
+
+        let alias: RoomAlias = "#pto:oob.systems".parse().unwrap();
+        assert_eq!(alias.alias, "pto");
+        assert_eq!(alias.homeserver, "oob.systems");
+        assert_eq!(format!("{}", alias), "#pto:oob.systems");
+        assert_eq!(alias.to_irc_channel(&Config::new()), "#pto/oob.systems");
+
+        let user: UserID = "@alice:example.com".parse().unwrap();
+        assert_eq!(user.nickname, "alice");
+        assert_eq!(format!("{}", user), "@alice:example.com");
+
+        assert_eq!(format!("{}", "$abc:example.com".parse::<EventID>().unwrap()), "$abc:example.com");
+        assert_eq!(format!("{}", "$Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg".parse::<EventID>().unwrap()),
+                   "$Rqnc-F-dvnEYJTyHq_iKxU2bZ1CI92-kuZq3a5lr5Zg");
+    }
+
+    #[test]
+    fn invalid_ids() {
+        assert_eq!("@alice:example.com".parse::<RoomID>(), Err(IdError::WrongSigil('!')));
+        assert_eq!("#room:example.com".parse::<UserID>(), Err(IdError::WrongSigil('@')));
+        assert_eq!("!abc:example.com".parse::<RoomAlias>(), Err(IdError::WrongSigil('#')));
+        assert_eq!("#room".parse::<RoomAlias>(), Err(IdError::MissingServer));
+        assert_eq!("!abc".parse::<RoomID>(), Err(IdError::MissingServer));
+        assert_eq!("@alice:".parse::<UserID>(), Err(IdError::MissingServer));
+        assert_eq!("@:example.com".parse::<UserID>(), Err(IdError::MissingServer));
+        assert_eq!("".parse::<RoomID>(), Err(IdError::Empty));
+        assert_eq!("$".parse::<EventID>(), Err(IdError::Empty));
     }
 }
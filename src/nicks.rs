@@ -0,0 +1,339 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use matrix::events::PresenceEvent;
+use matrix::model::UserID;
+use std::ascii::AsciiExt;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long, in seconds, a user who is online but has no client open may
+/// be idle before they're shown as away
+const IDLE_AWAY_SECS: u64 = 15 * 60;
+
+/// Nicknames in use by IRC logins, shared between every bridge in the process
+pub type SharedLogins = Arc<Mutex<HashSet<String>>>;
+
+pub fn new_shared_logins() -> SharedLogins {
+    Arc::new(Mutex::new(HashSet::new()))
+}
+
+/// Folds a nickname according to CASEMAPPING=rfc1459, as advertised in 005
+pub fn irc_lower(nick: &str) -> String {
+    nick.chars().map(|c| {
+        match c {
+            '[' => '{',
+            ']' => '}',
+            '\\' => '|',
+            '~' => '^',
+            c => c.to_ascii_lowercase()
+        }
+    }).collect()
+}
+
+/// A Matrix user appearing on IRC
+struct Ghost {
+    user: UserID,
+    /// The user's Matrix presence, once it's known
+    presence: Option<String>,
+    /// Whether they have a client open, if the server said
+    currently_active: Option<bool>,
+    /// How long they had been idle when the server said, and when that was
+    last_active: Option<(Duration, Instant)>,
+    /// The user's display name once fetched, None inside if they have none
+    display_name: Option<Option<String>>,
+    /// Whether the display name has been asked for
+    fetching: bool
+}
+
+impl Ghost {
+    fn is_away(&self) -> bool {
+        match self.presence.as_ref().map(|p| p.trim()) {
+            Some("unavailable") => true,
+            // Online without a client open for a while is as good as away
+            Some("online") => self.currently_active == Some(false) && self.last_active.map(|(ago, at)| {
+                ago + at.elapsed() >= Duration::from_secs(IDLE_AWAY_SECS)
+            }).unwrap_or(false),
+            _ => false
+        }
+    }
+}
+
+/// Keeps track of which IRC nicknames are spoken for, either by a Matrix
+/// user appearing as a ghost or by an IRC login.
+pub struct NickRegistry {
+    ghosts: HashMap<String, Ghost>,
+    logins: SharedLogins,
+    login: Option<String>
+}
+
+impl NickRegistry {
+    pub fn new(logins: SharedLogins) -> Self {
+        NickRegistry {
+            ghosts: HashMap::new(),
+            logins: logins,
+            login: None
+        }
+    }
+
+    /// Reserves the ghost nickname for a Matrix user. The first user to claim
+    /// a nickname keeps it.
+    pub fn reserve(&mut self, user: &UserID) {
+        let key = irc_lower(user.nickname.trim());
+        if let Some(owner) = self.ghosts.get(&key) {
+            if &owner.user != user {
+                warn!("Ghost nickname {} is already reserved for {:?}", user.nickname, owner.user);
+            }
+            return;
+        }
+        self.ghosts.insert(key, Ghost {
+            user: user.clone(),
+            presence: None,
+            currently_active: None,
+            last_active: None,
+            display_name: None,
+            fetching: false
+        });
+    }
+
+    /// Frees the ghost nickname of a Matrix user we no longer share a room
+    /// with
+    pub fn release(&mut self, user: &UserID) {
+        let key = irc_lower(user.nickname.trim());
+        if self.user_for(user.nickname.trim()) == Some(user) {
+            self.ghosts.remove(&key);
+        }
+    }
+
+    /// Records the presence of a ghost. Returns whether it is now away if
+    /// that changed, so that away-notify clients can be told.
+    pub fn set_presence(&mut self, presence: &PresenceEvent) -> Option<bool> {
+        let user = &presence.user;
+        let ghost = match self.ghosts.get_mut(&irc_lower(user.nickname.trim())) {
+            Some(ghost) => ghost,
+            None => return None
+        };
+        if &ghost.user != user {
+            return None;
+        }
+        let was_away = ghost.is_away();
+        ghost.presence = Some(presence.presence.clone());
+        ghost.currently_active = presence.currently_active;
+        ghost.last_active = presence.last_active_ago.map(|ago| {
+            (Duration::from_millis(ago), Instant::now())
+        });
+        if ghost.is_away() != was_away {
+            Some(!was_away)
+        } else {
+            None
+        }
+    }
+
+    /// Whether a ghost is away, which is the case neither for unknown users
+    /// nor for those whose presence isn't known
+    pub fn is_away(&self, user: &UserID) -> bool {
+        match self.ghosts.get(&irc_lower(user.nickname.trim())) {
+            Some(ghost) if &ghost.user == user => ghost.is_away(),
+            _ => false
+        }
+    }
+
+    /// What a ghost's realname is, its display name or else its localpart.
+    /// None until the display name is known.
+    pub fn realname(&self, user: &UserID) -> Option<String> {
+        match self.ghosts.get(&irc_lower(user.nickname.trim())) {
+            Some(ghost) if &ghost.user == user => ghost.display_name.as_ref().map(|name| {
+                name.clone().unwrap_or(user.nickname.clone())
+            }),
+            _ => None
+        }
+    }
+
+    /// Whether the display name of a ghost should be fetched. Says so only
+    /// once, so it's fetched only once.
+    pub fn want_display_name(&mut self, user: &UserID) -> bool {
+        if let Some(ghost) = self.ghosts.get_mut(&irc_lower(user.nickname.trim())) {
+            if &ghost.user == user && ghost.display_name.is_none() && !ghost.fetching {
+                ghost.fetching = true;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Lets the display name of a ghost be fetched again after a fetch
+    /// that failed
+    pub fn display_name_failed(&mut self, user: &UserID) {
+        if let Some(ghost) = self.ghosts.get_mut(&irc_lower(user.nickname.trim())) {
+            if &ghost.user == user {
+                ghost.fetching = false;
+            }
+        }
+    }
+
+    /// Records the display name of a ghost, None if it has none
+    pub fn set_display_name(&mut self, user: &UserID, name: Option<String>) {
+        if let Some(ghost) = self.ghosts.get_mut(&irc_lower(user.nickname.trim())) {
+            if &ghost.user == user {
+                ghost.display_name = Some(name);
+            }
+        }
+    }
+
+    /// Whether a ghost goes by the given nickname
+    #[cfg(test)]
+    pub fn is_reserved(&self, nick: &str) -> bool {
+        self.ghosts.contains_key(&irc_lower(nick))
+    }
+
+    /// Every Matrix user with a ghost nickname
+    #[cfg(test)]
+    pub fn reserved(&self) -> Vec<&UserID> {
+        self.ghosts.values().map(|ghost| &ghost.user).collect()
+    }
+
+    /// The Matrix user whose ghost goes by the given nickname
+    pub fn user_for(&self, nick: &str) -> Option<&UserID> {
+        self.ghosts.get(&irc_lower(nick)).map(|ghost| &ghost.user)
+    }
+
+    /// Whether an IRC login may not use the given nickname. `me` is the
+    /// Matrix user of this login, who may always use their own ghost nick.
+    pub fn is_taken(&self, nick: &str, me: Option<&UserID>) -> bool {
+        let key = irc_lower(nick);
+        if self.login.as_ref() == Some(&key) {
+            return false;
+        }
+        let ghost_taken = match self.ghosts.get(&key) {
+            Some(owner) => Some(&owner.user) != me,
+            None => false
+        };
+        ghost_taken || self.logins.lock().unwrap().contains(&key)
+    }
+
+    /// Records the nickname of this bridge's IRC login, releasing any
+    /// previously held one.
+    pub fn claim_login(&mut self, nick: &str) {
+        let key = irc_lower(nick);
+        let mut logins = self.logins.lock().unwrap();
+        if let Some(ref old) = self.login {
+            logins.remove(old);
+        }
+        logins.insert(key.clone());
+        self.login = Some(key);
+    }
+}
+
+impl Drop for NickRegistry {
+    fn drop(&mut self) {
+        if let Some(ref nick) = self.login {
+            self.logins.lock().unwrap().remove(nick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix::events::PresenceEvent;
+    use matrix::model::UserID;
+
+    fn presence(user: &UserID, presence: &str) -> PresenceEvent {
+        PresenceEvent {
+            presence: presence.to_string(),
+            user: user.clone(),
+            currently_active: None,
+            last_active_ago: None
+        }
+    }
+
+    #[test]
+    fn ghost_collisions() {
+        let mut nicks = NickRegistry::new(new_shared_logins());
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let bob = "@bob:example.com".parse::<UserID>().unwrap();
+        nicks.reserve(&alice);
+        assert!(nicks.is_reserved("alice"));
+        assert!(nicks.is_reserved("ALICE"));
+        assert!(!nicks.is_reserved("bob"));
+        // Only the first to go by a nickname gets it
+        nicks.reserve(&"@alice:elsewhere.com".parse::<UserID>().unwrap());
+        assert_eq!(nicks.reserved(), vec![&alice]);
+        assert!(nicks.is_taken("Alice", None));
+        assert!(nicks.is_taken("alice", Some(&bob)));
+        assert!(!nicks.is_taken("alice", Some(&alice)));
+        assert_eq!(nicks.user_for("alice"), Some(&alice));
+    }
+
+    #[test]
+    fn presence_changes() {
+        let mut nicks = NickRegistry::new(new_shared_logins());
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let impostor = "@alice:elsewhere.com".parse::<UserID>().unwrap();
+        assert_eq!(nicks.set_presence(&presence(&alice, "unavailable")), None);
+        nicks.reserve(&alice);
+        assert_eq!(nicks.set_presence(&presence(&alice, "online")), None);
+        assert_eq!(nicks.set_presence(&presence(&alice, "unavailable")), Some(true));
+        assert!(nicks.is_away(&alice));
+        assert_eq!(nicks.set_presence(&presence(&alice, "unavailable")), None);
+        assert_eq!(nicks.set_presence(&presence(&impostor, "online")), None);
+        assert!(!nicks.is_away(&impostor));
+        assert_eq!(nicks.set_presence(&presence(&alice, "online")), Some(false));
+
+        // Online but idle for an hour without a client open
+        let mut idle = presence(&alice, "online");
+        idle.currently_active = Some(false);
+        idle.last_active_ago = Some(60 * 60 * 1000);
+        assert_eq!(nicks.set_presence(&idle), Some(true));
+        idle.currently_active = Some(true);
+        assert_eq!(nicks.set_presence(&idle), Some(false));
+        assert!(!nicks.is_away(&alice));
+    }
+
+    #[test]
+    fn display_names() {
+        let mut nicks = NickRegistry::new(new_shared_logins());
+        let alice = "@alice:example.com".parse::<UserID>().unwrap();
+        let bob = "@bob:example.com".parse::<UserID>().unwrap();
+        assert!(!nicks.want_display_name(&alice));
+        nicks.reserve(&alice);
+        nicks.reserve(&bob);
+        assert_eq!(nicks.realname(&alice), None);
+        assert!(nicks.want_display_name(&alice));
+        assert!(!nicks.want_display_name(&alice));
+        nicks.display_name_failed(&alice);
+        assert!(nicks.want_display_name(&alice));
+        nicks.set_display_name(&alice, Some("Alice Liddell".to_owned()));
+        assert_eq!(nicks.realname(&alice), Some("Alice Liddell".to_owned()));
+        // Without a display name, the localpart stands in
+        nicks.set_display_name(&bob, None);
+        assert_eq!(nicks.realname(&bob), Some("bob".to_owned()));
+        assert!(!nicks.want_display_name(&bob));
+    }
+
+    #[test]
+    fn login_collisions() {
+        let logins = new_shared_logins();
+        let mut first = NickRegistry::new(logins.clone());
+        let second = NickRegistry::new(logins.clone());
+        first.claim_login("nick[1]");
+        assert!(!first.is_taken("nick[1]", None));
+        assert!(second.is_taken("NICK{1}", None));
+        drop(first);
+        assert!(!second.is_taken("nick[1]", None));
+    }
+}
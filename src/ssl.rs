@@ -1,18 +1,100 @@
-use irc::streams::{Server, Client, AsEvented};
-use mio::tcp::TcpListener;
+use irc::streams::{Server, Client, AsEvented, IrcStream};
+use mio::tcp::{TcpListener, TcpStream};
 use mio::Evented;
-use openssl::ssl::{SslContext, SslStream};
+use openssl::ssl::{SslContext, SslMethod, NonblockingSslStream};
+use openssl::ssl::error::NonblockingSslError;
+use openssl::x509::X509FileType;
+use std::io;
+use std::io::{Read, Write};
 use std::net::SocketAddr;
+use std::path::Path;
+
+/// Sets up TLS with a PEM certificate and private key
+pub fn context(cert: &str, key: &str) -> io::Result<SslContext> {
+    let error = |what: String| io::Error::new(io::ErrorKind::Other, what);
+    let mut ssl = try!(SslContext::new(SslMethod::Sslv23).map_err(|err| error(format!("TLS setup failed: {}", err))));
+    try!(ssl.set_certificate_file(Path::new(cert), X509FileType::PEM)
+         .map_err(|err| error(format!("Could not load the certificate {}: {}", cert, err))));
+    try!(ssl.set_private_key_file(Path::new(key), X509FileType::PEM)
+         .map_err(|err| error(format!("Could not load the private key {}: {}", key, err))));
+    Ok(ssl)
+}
+
+/// A TLS session over a non-blocking socket. The handshake happens as part
+/// of the first reads and writes. OpenSSL needing the socket to be readable
+/// or writable before it can go on shows up as WouldBlock, so the session's
+/// event loop drives the handshake like any other I/O.
+pub struct TlsStream {
+    stream: NonblockingSslStream<TcpStream>
+}
+
+fn tls_error(err: NonblockingSslError) -> io::Error {
+    match err {
+        NonblockingSslError::WantRead | NonblockingSslError::WantWrite =>
+            io::Error::new(io::ErrorKind::WouldBlock, "TLS is waiting for the socket"),
+        NonblockingSslError::SslError(err) =>
+            io::Error::new(io::ErrorKind::Other, format!("TLS error: {}", err))
+    }
+}
+
+impl TlsStream {
+    /// Starts the server side of a TLS handshake on a connected socket. A
+    /// handshake that fails only aborts that connection.
+    pub fn accept(ssl: &SslContext, socket: TcpStream) -> io::Result<Self> {
+        let stream = try!(NonblockingSslStream::accept(ssl, socket).map_err(|err| {
+            io::Error::new(io::ErrorKind::ConnectionAborted, format!("TLS handshake failed: {}", err))
+        }));
+        Ok(TlsStream {
+            stream: stream
+        })
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf).map_err(tls_error)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf).map_err(tls_error)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsEvented for TlsStream {
+    fn as_evented(&self) -> &Evented {
+        self.stream.get_ref()
+    }
+}
+
+impl IrcStream for TlsStream {
+    fn is_tls(&self) -> bool {
+        true
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.stream.get_ref().peer_addr().ok()
+    }
+
+    fn start_tls(self: Box<Self>, _ssl: &SslContext) -> Result<Box<IrcStream>, (io::Error, Box<IrcStream>)> {
+        Err((io::Error::new(io::ErrorKind::Other, "TLS is already in use"), self))
+    }
+}
 
 pub struct TcpServer {
     listener: TcpListener
 }
 
 impl TcpServer {
-    pub fn new(addr: &SocketAddr) -> Self {
-        TcpServer {
-            listener: TcpListener::bind(addr).unwrap()
-        }
+    pub fn new(addr: &SocketAddr) -> io::Result<Self> {
+        Ok(TcpServer {
+            listener: try!(TcpListener::bind(addr))
+        })
     }
 }
 
@@ -22,11 +104,11 @@ pub struct SslServer {
 }
 
 impl SslServer {
-    pub fn new(addr: &SocketAddr, ssl: SslContext) -> Self {
-        SslServer {
-            listener: TcpListener::bind(addr).unwrap(),
+    pub fn new(addr: &SocketAddr, ssl: SslContext) -> io::Result<Self> {
+        Ok(SslServer {
+            listener: try!(TcpListener::bind(addr)),
             ssl: ssl
-        }
+        })
     }
 }
 
@@ -43,26 +125,23 @@ impl AsEvented for TcpServer {
 }
 
 impl Server for SslServer {
-    fn accept(&mut self) -> Option<Client> {
-         match self.listener.accept() {
-             Ok(None) => None,
-             Ok(Some((socket, _))) => {
-                 let ssl = SslStream::accept(&self.ssl, socket).expect("Could not construct SSL stream");
-                 Some(Client::new(Box::new(ssl)))
-             },
-             Err(e) => panic!(e),
+    fn accept(&mut self) -> io::Result<Option<Client>> {
+         match try!(self.listener.accept()) {
+             None => Ok(None),
+             Some((socket, _)) => {
+                 // Only starts the handshake, the session finishes it
+                 let stream = try!(TlsStream::accept(&self.ssl, socket));
+                 Ok(Some(Client::new(Box::new(stream))))
+             }
          }
     }
 }
 
 impl Server for TcpServer {
-    fn accept(&mut self) -> Option<Client> {
-         match self.listener.accept() {
-             Ok(None) => None,
-             Ok(Some((socket, _))) => {
-                 Some(Client::new(Box::new(socket)))
-             },
-             Err(e) => panic!(e),
+    fn accept(&mut self) -> io::Result<Option<Client>> {
+         match try!(self.listener.accept()) {
+             None => Ok(None),
+             Some((socket, _)) => Ok(Some(Client::new(Box::new(socket))))
          }
     }
 }
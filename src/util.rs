@@ -0,0 +1,30 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+/// Writes a file only its owner may read, whether or not it was there
+/// before. The mode only applies to new files, so one that was already
+/// readable by others is locked down too.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = try!(fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path));
+    try!(fs::set_permissions(path, fs::Permissions::from_mode(0o600)));
+    file.write_all(contents)
+}